@@ -1,7 +1,11 @@
 use failure::Error;
-use lachesis_rs::{BTreeHashgraph, EventHash, HashgraphWire, Node, Peer, PeerId, Swirlds};
+use lachesis_rs::{
+    BTreeHashgraph, ConsensusConfig, EventHash, HashgraphWire, KnownHeights, Node, Peer, PeerId,
+    Swirlds, SystemClock,
+};
 use ring::rand::SystemRandom;
 use ring::signature;
+use std::sync::Arc;
 
 fn create_node(rng: &mut SystemRandom) -> Result<Swirlds<DummyNode, BTreeHashgraph>, Error> {
     let hashgraph = BTreeHashgraph::new();
@@ -9,7 +13,12 @@ fn create_node(rng: &mut SystemRandom) -> Result<Swirlds<DummyNode, BTreeHashgra
         .map_err(|e| Error::from_boxed_compat(Box::new(e)))?;
     let kp = signature::Ed25519KeyPair::from_pkcs8(untrusted::Input::from(&pkcs8_bytes))
         .map_err(|e| Error::from_boxed_compat(Box::new(e)))?;
-    Swirlds::new(kp, hashgraph)
+    Swirlds::new(
+        kp,
+        hashgraph,
+        ConsensusConfig::default(),
+        Arc::new(SystemClock),
+    )
 }
 
 pub struct DummyNode {
@@ -29,13 +38,13 @@ impl DummyNode {
     }
 }
 
-impl Peer<BTreeHashgraph> for DummyNode {
+impl Peer<BTreeHashgraph, KnownHeights> for DummyNode {
     fn get_sync(
         &self,
         _pk: PeerId,
-        _h: Option<&BTreeHashgraph>,
+        known: Option<&KnownHeights>,
     ) -> Result<(EventHash, BTreeHashgraph), Error> {
-        let (eh, wire): (EventHash, HashgraphWire) = self.node.respond_message(None)?;
+        let (eh, wire): (EventHash, HashgraphWire) = self.node.respond_message(known.cloned())?;
         let hashgraph = BTreeHashgraph::from(wire);
         Ok((eh, hashgraph))
     }
@@ -1,18 +1,24 @@
 #[macro_use]
+extern crate log;
+#[macro_use]
 extern crate serde_derive;
 
 use bincode::{deserialize, serialize};
 use configure::Configure;
 use failure::{Error, Fail};
+use lachesis_rs::tcp_server::gossip_config::GossipConfig;
 use lachesis_rs::tcp_server::{TcpApp, TcpNode, TcpPeer};
 use lachesis_rs::{BTreeHashgraph, Node, Swirlds};
+use ring::signature::Ed25519KeyPair;
 use std::collections::HashMap;
+use std::fs;
 use std::io::{Read, Write};
 use std::net::TcpListener;
+use std::path::PathBuf;
 use std::str::FromStr;
 use std::sync::{Arc, Mutex};
-use std::thread::{sleep, spawn, JoinHandle};
-use std::time::Duration;
+use std::thread::{spawn, JoinHandle};
+use tokio::runtime::Runtime;
 
 #[derive(Debug, Fail)]
 enum KvdbError {
@@ -23,18 +29,28 @@ enum KvdbError {
 #[derive(Configure, Deserialize)]
 #[serde(default)]
 struct Config {
+    // Where the node key is kept, so its id stays the same across restarts
+    key_file: String,
     lachesis_port: usize,
+    // Seed peers, the rest are discovered through them
     peer_hosts: String,
     peer_ids: String,
+    // Where discovered peers are kept, so they're found again on restart
+    peer_table: String,
+    // The address announced to other peers, if it isn't the lachesis port on every interface
+    public_address: String,
     server_port: usize,
 }
 
 impl Default for Config {
     fn default() -> Config {
         Config {
+            key_file: String::from(""),
             lachesis_port: 9000,
             peer_ids: String::from(""),
             peer_hosts: String::from(""),
+            peer_table: String::from(""),
+            public_address: String::from(""),
             server_port: 8080,
         }
     }
@@ -71,28 +87,23 @@ impl Server {
 
     fn run(self) -> (JoinHandle<()>, JoinHandle<()>) {
         let server = self.get_server_handle();
-        let node = self.node.clone();
         let db_mutex = self.db.clone();
+        let finalized_events = self.node.node.subscribe().unwrap();
         let queue_consumer = spawn(move || {
-            let next_to_process = 0;
-            loop {
-                let events = node.node.get_ordered_events().unwrap();
-                let transactions: Vec<Vec<u8>> = events.iter().flat_map(|e| e.payload()).collect();
-                if transactions.len() > next_to_process {
-                    for i in next_to_process..transactions.len() - 1 {
-                        let transaction = &transactions[i];
-                        match deserialize(transaction).unwrap() {
-                            ServerMessage::Put(id, value) => {
-                                db_mutex.lock().unwrap().insert(id, value);
-                            }
-                            ServerMessage::Delete(id) => {
-                                db_mutex.lock().unwrap().remove(&id);
-                            }
-                            _ => {}
-                        };
-                    }
+            for finalized in finalized_events.iter() {
+                for transaction in finalized.event.payload() {
+                    // Any peer can put a payload in an event, so not all of them are ours
+                    match deserialize(&transaction) {
+                        Ok(ServerMessage::Put(id, value)) => {
+                            db_mutex.lock().unwrap().insert(id, value);
+                        }
+                        Ok(ServerMessage::Delete(id)) => {
+                            db_mutex.lock().unwrap().remove(&id);
+                        }
+                        Ok(_) => {}
+                        Err(e) => warn!("Skipping an undecodable transaction: {}", e),
+                    };
                 }
-                sleep(Duration::from_millis(100));
             }
         });
         (server, queue_consumer)
@@ -144,38 +155,70 @@ fn parse_peer(input: String) -> Result<(String, usize), Error> {
 fn parse_peers(input: String) -> Result<Vec<(String, usize)>, Error> {
     input
         .split(',')
+        .filter(|ps| !ps.is_empty())
         .map(|ps| parse_peer(ps.to_string()))
         .collect()
 }
 
+fn load_key(key_file: &str) -> Result<Vec<u8>, Error> {
+    if !key_file.is_empty() {
+        if let Ok(pkcs8_bytes) = fs::read(key_file) {
+            return Ok(pkcs8_bytes);
+        }
+    }
+    let rng = ring::rand::SystemRandom::new();
+    let pkcs8_bytes = Ed25519KeyPair::generate_pkcs8(&rng)?.to_vec();
+    if !key_file.is_empty() {
+        fs::write(key_file, &pkcs8_bytes)?;
+    }
+    Ok(pkcs8_bytes)
+}
+
 fn main() {
     env_logger::init();
     let config: Config = Config::generate().unwrap();
-    let ids: Vec<String> = config.peer_ids.split(',').map(|s| s.to_string()).collect();
+    let ids: Vec<String> = config
+        .peer_ids
+        .split(',')
+        .filter(|s| !s.is_empty())
+        .map(|s| s.to_string())
+        .collect();
     let peers = parse_peers(config.peer_hosts).unwrap();
     if peers.len() != ids.len() {
         panic!("Number of peer ids mismatches number of peer addresses");
     }
+    let local_address = format!("0.0.0.0:{}", config.lachesis_port);
+    let pkcs8_bytes = load_key(&config.key_file).unwrap();
+    let gossip_config = GossipConfig {
+        public_address: if config.public_address.is_empty() {
+            None
+        } else {
+            Some(config.public_address.clone())
+        },
+        ..GossipConfig::default()
+    };
+    let node = Arc::new(TcpNode::from_pkcs8(&pkcs8_bytes, local_address, gossip_config).unwrap());
+    println!("Node id: {}", base64::encode(&node.node.get_id()));
+    // Peer ids are the base64 encoded public keys the peers print on start
     let peers: Vec<TcpPeer> = ids
         .iter()
         .zip(peers.iter())
-        .map(|(id, (a, p))| TcpPeer {
-            address: format!("{}:{}", a, p),
-            id: id.as_bytes().to_vec(),
-        })
+        .map(|(id, (a, p))| node.peer(format!("{}:{}", a, p), base64::decode(id).unwrap()))
         .collect();
-    let mut rng = ring::rand::SystemRandom::new();
-    let local_address = format!("0.0.0.0:{}", config.lachesis_port);
-    let node = Arc::new(TcpNode::new(&mut rng, local_address).unwrap());
     for peer in peers.iter() {
         node.node.add_node(Arc::new(peer.clone())).unwrap();
     }
+    if !config.peer_table.is_empty() {
+        let n_stored = node
+            .open_peer_table(PathBuf::from(&config.peer_table))
+            .unwrap();
+        println!("Peers found in the peer table: {}", n_stored);
+    }
     let app = TcpApp::new(node.clone());
     let server = Server::new(config.server_port, node.clone());
-    let (handle1, handle2) = app.run().unwrap();
+    let runtime = Runtime::new().unwrap();
+    let _app_handle = app.spawn(&runtime.executor()).unwrap();
     let (server_handle1, server_handle2) = server.run();
-    handle1.join().unwrap();
-    handle2.join().unwrap();
     server_handle1.join().unwrap();
     server_handle2.join().unwrap();
 }
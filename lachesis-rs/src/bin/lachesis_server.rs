@@ -1,7 +1,13 @@
-use lachesis_rs::Server;
+use lachesis_rs::tcp_server::TcpPeer;
+use lachesis_rs::{BTreeHashgraph, ConsensusConfig, Server, Swirlds, SystemClock};
+use ring::{rand, signature};
+use std::sync::Arc;
+
+const WS_THREADS: usize = 4;
 
 /**
- * Main lachesis-rs entrypoint. Starts HTTP server.
+ * Main lachesis-rs entrypoint. Starts HTTP server. With --ws it serves the unauthenticated
+ * internode /ws endpoint too.
  */
 fn main() {
     std::env::set_var("RUST_LOG", "actix_web=info");
@@ -9,8 +15,26 @@ fn main() {
 
     let sys = actix::System::new("heartbeat-example");
 
+    let rng = rand::SystemRandom::new();
+    let pkcs8_bytes = signature::Ed25519KeyPair::generate_pkcs8(&rng).unwrap();
+    let kp = signature::Ed25519KeyPair::from_pkcs8(untrusted::Input::from(&pkcs8_bytes)).unwrap();
+    let node: Swirlds<TcpPeer, BTreeHashgraph> = Swirlds::new(
+        kp,
+        BTreeHashgraph::new(),
+        ConsensusConfig::default(),
+        Arc::new(SystemClock),
+    )
+    .unwrap();
+
     let host = "127.0.0.1:8080";
-    Server::init().bind(host).unwrap().start();
+    if std::env::args().any(|arg| arg == "--ws") {
+        Server::init_with_ws(Arc::new(node), WS_THREADS)
+            .bind(host)
+            .unwrap()
+            .start();
+    } else {
+        Server::init(Arc::new(node)).bind(host).unwrap().start();
+    }
 
     println!("Started http server: {}", host);
     let _ = sys.run();
@@ -1,11 +1,75 @@
 extern crate lachesis_rs;
 
+use futures::Future;
+use lachesis_rs::tcp_server::gossip_config::GossipConfig;
 use lachesis_rs::tcp_server::{TcpApp, TcpNode, TcpPeer};
+use lachesis_rs::{Node, PeerDiscovery, PeerId};
+use ring::rand::SystemRandom;
+use ring::signature::Ed25519KeyPair;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
 use std::env::args;
 use std::sync::Arc;
+use tokio::runtime::Runtime;
 
 const BASE_PORT: usize = 9000;
-const USAGE: &'static str = "Usage: tcp-client [number of nodes] [consensus-algorithm]";
+const USAGE: &'static str = "Usage: lachesis_tcp [swirlds|lachesis] [number of nodes]";
+
+// Keys are generated first, so every node starts knowing the ids of all of them
+fn create_nodes<N, F>(n_nodes: usize, mut create_node: F) -> Vec<Arc<TcpNode<N>>>
+where
+    N: Node,
+    F: FnMut(&[u8], String, GossipConfig, &[PeerId]) -> TcpNode<N>,
+{
+    let rng = SystemRandom::new();
+    let pkcs8s: Vec<_> = (0..n_nodes)
+        .map(|_| Ed25519KeyPair::generate_pkcs8(&rng).unwrap())
+        .collect();
+    let ids: Vec<PeerId> = pkcs8s
+        .iter()
+        .map(|pkcs8| {
+            Ed25519KeyPair::from_pkcs8(untrusted::Input::from(pkcs8))
+                .unwrap()
+                .public_key_bytes()
+                .to_vec()
+        })
+        .collect();
+    let mut nodes = Vec::with_capacity(n_nodes);
+    for (i, pkcs8) in pkcs8s.iter().enumerate() {
+        let a = format!("0.0.0.0:{}", BASE_PORT + i);
+        let config = GossipConfig {
+            public_address: Some(format!("127.0.0.1:{}", BASE_PORT + i)),
+            ..GossipConfig::default()
+        };
+        nodes.push(Arc::new(create_node(pkcs8, a, config, &ids)));
+    }
+    nodes
+}
+
+fn launch<N>(nodes: Vec<Arc<TcpNode<N>>>)
+where
+    N: Node + PeerDiscovery<TcpPeer> + Send + Sync + 'static,
+    N::D: Serialize,
+    N::K: DeserializeOwned,
+{
+    // Only the first node is known to the others, they find the rest through it
+    let seed_address = format!("127.0.0.1:{}", BASE_PORT);
+    let seed_announcement = nodes[0].announcement(seed_address.clone()).unwrap();
+    for node in nodes.iter().skip(1) {
+        let peer = node.peer(seed_address.clone(), nodes[0].id());
+        node.node
+            .add_announced_peer(seed_announcement.clone(), Arc::new(peer))
+            .unwrap();
+    }
+    // All nodes share the runtime, instead of running two threads each
+    let runtime = Runtime::new().unwrap();
+    let mut handles = Vec::with_capacity(nodes.len());
+    for node in nodes {
+        let app = TcpApp::new(node.clone());
+        handles.push(app.spawn(&runtime.executor()).unwrap());
+    }
+    runtime.shutdown_on_idle().wait().unwrap();
+}
 
 /**
  * Main lachesis-rs TCP client entrypoint. Starts multiple TCP node peers.
@@ -16,35 +80,15 @@ fn main() {
     if args.len() != 3 {
         panic!(USAGE);
     }
-    let mut rng = ring::rand::SystemRandom::new();
-    let n_nodes = args[1].parse::<usize>().unwrap();
-    let algorithm = args[2].clone();
-    let mut nodes = Vec::with_capacity(n_nodes);
-    let mut peers = Vec::with_capacity(n_nodes);
-    for i in 0..n_nodes {
-        let a = format!("0.0.0.0:{}", BASE_PORT + i);
-        let node = TcpNode::new(&mut rng, a.clone()).unwrap();
-        peers.push(TcpPeer {
-            address: a,
-            id: node.node.get_id().clone(),
-        });
-        nodes.push(Arc::new(node));
-    }
-    for node in nodes.iter() {
-        for peer in peers.iter() {
-            if peer.id.clone() != node.node.get_id() {
-                node.node.add_node(Arc::new(peer.clone())).unwrap();
-            }
-        }
-    }
-    let mut handles = Vec::with_capacity(n_nodes * 2);
-    for node in nodes {
-        let app = TcpApp::new(node.clone());
-        let (handle1, handle2) = app.run().unwrap();
-        handles.push(handle1);
-        handles.push(handle2);
-    }
-    for handle in handles {
-        handle.join().unwrap();
+    let n_nodes = args[2].parse::<usize>().expect(USAGE);
+    match args[1].as_str() {
+        "swirlds" => launch(create_nodes(n_nodes, |pkcs8, a, config, ids| {
+            let stakes = ids.iter().map(|id| (id.clone(), 1)).collect();
+            TcpNode::with_stakes(pkcs8, a, config, stakes).unwrap()
+        })),
+        "lachesis" => launch(create_nodes(n_nodes, |pkcs8, a, config, ids| {
+            TcpNode::lachesis_from_pkcs8(pkcs8, a, config, ids.to_vec()).unwrap()
+        })),
+        _ => panic!(USAGE),
     }
 }
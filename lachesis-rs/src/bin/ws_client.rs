@@ -6,12 +6,14 @@ use actix::*;
 extern crate log;
 
 use actix_web::ws::{Client, ClientWriter, Message, ProtocolError};
+use bincode::deserialize;
 use futures::Future;
 
-use lachesis_rs::InternodeMessage;
+use lachesis_rs::{InternodeMessage, PROTOCOL_VERSION};
 
 /**
- * Main lachesis-rs WebSocket client entrypoint. Starts client and connects to server.
+ * Main lachesis-rs WebSocket client entrypoint. Starts client and connects to a server
+ * started with --ws.
  */
 fn main() {
     ::std::env::set_var("RUST_LOG", "actix_web=info");
@@ -37,7 +39,11 @@ fn main() {
                     if io::stdin().read_line(&mut cmd).is_err() {
                         error!("error");
                     }
-                    addr.do_send(InternodeMessage::SyncRequest)
+                    addr.do_send(InternodeMessage::SyncRequest {
+                        version: PROTOCOL_VERSION,
+                        requester: vec![],
+                        known: None,
+                    })
                 });
 
                 ()
@@ -84,7 +90,10 @@ impl Handler<InternodeMessage> for InternodeClient {
 impl StreamHandler<Message, ProtocolError> for InternodeClient {
     fn handle(&mut self, msg: Message, _ctx: &mut Context<Self>) {
         match msg {
-            Message::Binary(bin) => println!("Server: {:?}", bin),
+            Message::Binary(mut bin) => match deserialize::<InternodeMessage>(&bin.take()) {
+                Ok(message) => println!("Server: {:?}", message),
+                Err(e) => error!("Undecodable server message: {}", e),
+            },
             Message::Text(text) => println!("Server: {:?}", text),
             _ => (),
         }
@@ -0,0 +1,77 @@
+use crate::errors::ResourceClockPoisonError;
+use failure::Error;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// Milliseconds since the unix epoch. Nodes read the time only through it, so tests and
+// simulations can decide what time it is.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Result<u64, Error>;
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    // A clock set before the epoch is an error instead of a panic
+    fn now(&self) -> Result<u64, Error> {
+        Ok(duration_millis(
+            SystemTime::now().duration_since(UNIX_EPOCH)?,
+        ))
+    }
+}
+
+#[inline]
+pub(crate) fn duration_millis(d: Duration) -> u64 {
+    d.as_secs() * 1_000 + u64::from(d.subsec_millis())
+}
+
+// Only moves when it's told to
+pub struct ManualClock {
+    now: Mutex<u64>,
+}
+
+impl ManualClock {
+    pub fn new(now: u64) -> ManualClock {
+        ManualClock {
+            now: Mutex::new(now),
+        }
+    }
+
+    pub fn set(&self, now: u64) -> Result<(), Error> {
+        *get_from_mutex!(self.now, ResourceClockPoisonError)? = now;
+        Ok(())
+    }
+
+    pub fn advance(&self, millis: u64) -> Result<(), Error> {
+        *get_from_mutex!(self.now, ResourceClockPoisonError)? += millis;
+        Ok(())
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Result<u64, Error> {
+        Ok(*get_from_mutex!(self.now, ResourceClockPoisonError)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Clock, ManualClock, SystemClock};
+
+    #[test]
+    fn manual_clock_should_only_move_when_told() {
+        let clock = ManualClock::new(1_000);
+        assert_eq!(clock.now().unwrap(), 1_000);
+        clock.advance(500).unwrap();
+        assert_eq!(clock.now().unwrap(), 1_500);
+        clock.set(42).unwrap();
+        assert_eq!(clock.now().unwrap(), 42);
+    }
+
+    #[test]
+    fn system_clock_should_count_milliseconds() {
+        let before = SystemClock.now().unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(5));
+        assert!(SystemClock.now().unwrap() >= before + 5);
+    }
+}
@@ -2,8 +2,11 @@ use crate::event::event_hash::EventHash;
 use crate::failure::Backtrace;
 use crate::peer::PeerId;
 use crate::printable_hash::PrintableHash;
+use crate::server::ws_message::InternodeError;
 
+use failure::Error;
 use std::fmt;
+use std::io;
 use std::sync::PoisonError;
 
 #[derive(Debug, Fail)]
@@ -14,17 +17,48 @@ pub enum ParentsError {
 
 #[derive(Debug)]
 pub(crate) enum NodeErrorType {
+    CheckpointKeyMismatch,
     PeerNotFound(PeerId),
     EmptyNetwork,
+    InconsistentOrder(PeerId, usize),
+    InvalidCoinFrequency(usize),
+    InvalidPeerAnnouncement(PeerId),
     NoHead,
+    ReservedPayload,
+    RoundAlreadyStarted(usize),
+    UnsupportedCheckpointVersion(u32),
 }
 
 impl fmt::Display for NodeErrorType {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         let msg = match self {
+            NodeErrorType::CheckpointKeyMismatch => {
+                String::from("The checkpoint head wasn't created with this key")
+            }
             NodeErrorType::EmptyNetwork => String::from("The node network it's empty"),
+            NodeErrorType::InconsistentOrder(p, i) => format!(
+                "Peer {} finalized a different event at position {}",
+                p.printable_hash(),
+                i
+            ),
+            NodeErrorType::InvalidCoinFrequency(c) => {
+                format!("Coin rounds frequency {} isn't above 2", c)
+            }
+            NodeErrorType::InvalidPeerAnnouncement(p) => {
+                format!(
+                    "Peer {} announcement isn't signed by it",
+                    p.printable_hash()
+                )
+            }
             NodeErrorType::NoHead => String::from("The node has no head"),
             NodeErrorType::PeerNotFound(p) => format!("Peer {} not found", p.printable_hash()),
+            NodeErrorType::ReservedPayload => {
+                String::from("The transaction payload is reserved for validators proposals")
+            }
+            NodeErrorType::RoundAlreadyStarted(r) => format!("Round {} has already started", r),
+            NodeErrorType::UnsupportedCheckpointVersion(v) => {
+                format!("Checkpoint version {} isn't supported", v)
+            }
         };
         write!(f, "{}", msg)
     }
@@ -52,11 +86,16 @@ impl NodeError {
 #[derive(Debug, Fail)]
 pub(crate) enum EventErrorType {
     UnsignedEvent { hash: EventHash },
+    HashMismatch { hash: EventHash },
+    InvalidLamportTimestamp { hash: EventHash },
+    InvalidSignature { hash: EventHash },
+    UnknownParents { hash: EventHash },
     RoundNotSet { hash: EventHash },
     NoSelfParent { hash: EventHash },
     NoParents { hash: EventHash },
     NoSignature { hash: EventHash },
     NoTimestamp { hash: EventHash },
+    TooFarInTheFuture { hash: EventHash },
 }
 
 impl fmt::Display for EventErrorType {
@@ -65,6 +104,20 @@ impl fmt::Display for EventErrorType {
             EventErrorType::UnsignedEvent { hash } => {
                 format!("The event {} it's unsigned", hash.printable_hash())
             }
+            EventErrorType::HashMismatch { hash } => {
+                format!("The event {} doesn't match its hash", hash.printable_hash())
+            }
+            EventErrorType::InvalidLamportTimestamp { hash } => format!(
+                "The event {} Lamport timestamp doesn't follow its parents",
+                hash.printable_hash()
+            ),
+            EventErrorType::InvalidSignature { hash } => format!(
+                "The event {} isn't signed by its creator",
+                hash.printable_hash()
+            ),
+            EventErrorType::UnknownParents { hash } => {
+                format!("The event {} parents are unknown", hash.printable_hash())
+            }
             EventErrorType::RoundNotSet { hash } => {
                 format!("The event {} round isn't set", hash.printable_hash())
             }
@@ -80,6 +133,10 @@ impl fmt::Display for EventErrorType {
             EventErrorType::NoTimestamp { hash } => {
                 format!("The event {} timestamp isn't set", hash.printable_hash())
             }
+            EventErrorType::TooFarInTheFuture { hash } => format!(
+                "The event {} timestamp is too far in the future",
+                hash.printable_hash()
+            ),
         };
         write!(f, "{}", msg)
     }
@@ -102,19 +159,23 @@ impl EventError {
             error_type,
         }
     }
+
+    pub(crate) fn error_type(&self) -> &EventErrorType {
+        &self.error_type
+    }
 }
 
 #[derive(Debug)]
 pub(crate) enum HashgraphErrorType {
+    CorruptedLog,
     EventNotFound,
-    NoLamportTimeSet,
 }
 
 impl fmt::Display for HashgraphErrorType {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         let msg = match self {
+            HashgraphErrorType::CorruptedLog => "Hashgraph log is corrupted",
             HashgraphErrorType::EventNotFound => "Event not found in hashgraph",
-            HashgraphErrorType::NoLamportTimeSet => "No root has lamport timestamp set",
         };
         write!(f, "{}", msg)
     }
@@ -139,6 +200,86 @@ impl HashgraphError {
     }
 }
 
+#[derive(Debug)]
+pub(crate) enum ProtocolErrorType {
+    ConnectionClosed,
+    DecryptionFailed,
+    FrameTooLarge(usize),
+    HandshakeFailed,
+    InvalidHandshakeSignature,
+    PeerKeyMismatch(PeerId),
+    Remote(InternodeError),
+    TimedOut,
+    UnexpectedMessage,
+    UnsupportedVersion(u32),
+}
+
+impl fmt::Display for ProtocolErrorType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let msg = match self {
+            ProtocolErrorType::ConnectionClosed => String::from("Connection closed by peer"),
+            ProtocolErrorType::DecryptionFailed => String::from("Frame could not be decrypted"),
+            ProtocolErrorType::FrameTooLarge(s) => format!("Frame of {} bytes is too large", s),
+            ProtocolErrorType::HandshakeFailed => String::from("Handshake failed"),
+            ProtocolErrorType::InvalidHandshakeSignature => {
+                String::from("Peer handshake signature is invalid")
+            }
+            ProtocolErrorType::PeerKeyMismatch(id) => format!(
+                "Peer authenticated as unexpected key {}",
+                id.printable_hash()
+            ),
+            ProtocolErrorType::Remote(e) => format!("Peer answered with error: {}", e),
+            ProtocolErrorType::TimedOut => String::from("Request timed out"),
+            ProtocolErrorType::UnexpectedMessage => String::from("Unexpected message"),
+            ProtocolErrorType::UnsupportedVersion(v) => {
+                format!("Peer speaks unsupported protocol version {}", v)
+            }
+        };
+        write!(f, "{}", msg)
+    }
+}
+
+#[derive(Debug, Fail)]
+#[fail(
+    display = "Protocol failed with error: {}\nTraceback: {}",
+    error_type, backtrace
+)]
+pub(crate) struct ProtocolError {
+    backtrace: Backtrace,
+    error_type: ProtocolErrorType,
+}
+
+impl ProtocolError {
+    pub(crate) fn new(error_type: ProtocolErrorType) -> ProtocolError {
+        ProtocolError {
+            backtrace: Backtrace::new(),
+            error_type,
+        }
+    }
+}
+
+// Events can arrive without their parents from honest peers, as what was sent is worked out
+// from heights, so they aren't misbehaviour
+pub(crate) fn is_unknown_parents(e: &Error) -> bool {
+    match e.downcast_ref::<EventError>().map(|e| e.error_type()) {
+        Some(EventErrorType::UnknownParents { .. }) => true,
+        _ => false,
+    }
+}
+
+// Peers that time out are told apart from the ones refusing or failing requests
+pub(crate) fn is_timeout(e: &Error) -> bool {
+    if let Some(e) = e.downcast_ref::<ProtocolError>() {
+        if let ProtocolErrorType::TimedOut = e.error_type {
+            return true;
+        }
+    }
+    match e.downcast_ref::<io::Error>() {
+        Some(e) => e.kind() == io::ErrorKind::TimedOut || e.kind() == io::ErrorKind::WouldBlock,
+        None => false,
+    }
+}
+
 #[derive(Debug, Fail)]
 #[fail(display = "Hashgraph Mutex was poisoned")]
 pub struct ResourceHashgraphPoisonError {
@@ -160,6 +301,27 @@ impl<T> From<PoisonError<T>> for ResourceHashgraphPoisonError {
     }
 }
 
+#[derive(Debug, Fail)]
+#[fail(display = "Consensus Mutex was poisoned")]
+pub struct ResourceConsensusPoisonError {
+    backtrace: Backtrace,
+}
+
+impl ResourceConsensusPoisonError {
+    pub fn new() -> ResourceConsensusPoisonError {
+        ResourceConsensusPoisonError {
+            backtrace: Backtrace::new(),
+        }
+    }
+}
+
+//for op-?, "auto" type conversion
+impl<T> From<PoisonError<T>> for ResourceConsensusPoisonError {
+    fn from(_: PoisonError<T>) -> Self {
+        ResourceConsensusPoisonError::new()
+    }
+}
+
 #[derive(Debug, Fail)]
 #[fail(display = "Frames Mutex was poisoned")]
 pub struct ResourceFramesPoisonError {
@@ -243,3 +405,108 @@ impl<T> From<PoisonError<T>> for ResourceNodeInternalStatePoisonError {
         ResourceNodeInternalStatePoisonError::new()
     }
 }
+
+#[derive(Debug, Fail)]
+#[fail(display = "Subscribers Mutex was poisoned")]
+pub struct ResourceSubscribersPoisonError {
+    backtrace: Backtrace,
+}
+
+impl ResourceSubscribersPoisonError {
+    pub fn new() -> ResourceSubscribersPoisonError {
+        ResourceSubscribersPoisonError {
+            backtrace: Backtrace::new(),
+        }
+    }
+}
+
+//for op-?, "auto" type conversion
+impl<T> From<PoisonError<T>> for ResourceSubscribersPoisonError {
+    fn from(_: PoisonError<T>) -> Self {
+        ResourceSubscribersPoisonError::new()
+    }
+}
+
+#[derive(Debug, Fail)]
+#[fail(display = "Transactions Mutex was poisoned")]
+pub struct ResourceTransactionsPoisonError {
+    backtrace: Backtrace,
+}
+
+impl ResourceTransactionsPoisonError {
+    pub fn new() -> ResourceTransactionsPoisonError {
+        ResourceTransactionsPoisonError {
+            backtrace: Backtrace::new(),
+        }
+    }
+}
+
+//for op-?, "auto" type conversion
+impl<T> From<PoisonError<T>> for ResourceTransactionsPoisonError {
+    fn from(_: PoisonError<T>) -> Self {
+        ResourceTransactionsPoisonError::new()
+    }
+}
+
+#[derive(Debug, Fail)]
+#[fail(display = "Connection Mutex was poisoned")]
+pub struct ResourceConnectionPoisonError {
+    backtrace: Backtrace,
+}
+
+impl ResourceConnectionPoisonError {
+    pub fn new() -> ResourceConnectionPoisonError {
+        ResourceConnectionPoisonError {
+            backtrace: Backtrace::new(),
+        }
+    }
+}
+
+//for op-?, "auto" type conversion
+impl<T> From<PoisonError<T>> for ResourceConnectionPoisonError {
+    fn from(_: PoisonError<T>) -> Self {
+        ResourceConnectionPoisonError::new()
+    }
+}
+
+#[derive(Debug, Fail)]
+#[fail(display = "Simulated network Mutex was poisoned")]
+pub struct ResourceSimNetworkPoisonError {
+    backtrace: Backtrace,
+}
+
+impl ResourceSimNetworkPoisonError {
+    pub fn new() -> ResourceSimNetworkPoisonError {
+        ResourceSimNetworkPoisonError {
+            backtrace: Backtrace::new(),
+        }
+    }
+}
+
+//for op-?, "auto" type conversion
+impl<T> From<PoisonError<T>> for ResourceSimNetworkPoisonError {
+    fn from(_: PoisonError<T>) -> Self {
+        ResourceSimNetworkPoisonError::new()
+    }
+}
+
+#[derive(Debug, Fail)]
+#[fail(display = "Clock Mutex was poisoned")]
+pub struct ResourceClockPoisonError {
+    backtrace: Backtrace,
+}
+
+impl ResourceClockPoisonError {
+    pub fn new() -> ResourceClockPoisonError {
+        ResourceClockPoisonError {
+            backtrace: Backtrace::new(),
+        }
+    }
+}
+
+//for op-?, "auto" type conversion
+impl<T> From<PoisonError<T>> for ResourceClockPoisonError {
+    fn from(_: PoisonError<T>) -> Self {
+        ResourceClockPoisonError::new()
+    }
+}
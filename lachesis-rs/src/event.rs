@@ -8,6 +8,7 @@ use std::collections::HashMap;
 
 pub mod event_hash;
 pub mod event_signature;
+pub mod fork_evidence;
 pub mod parents;
 
 use self::event_hash::EventHash;
@@ -19,7 +20,11 @@ pub struct Event<P: Parents + Clone + Serialize> {
     #[serde(skip)]
     can_see: HashMap<PeerId, EventHash>,
     #[serde(skip)]
+    consensus_timestamp: Option<u64>,
+    #[serde(skip)]
     famous: Option<bool>,
+    // Only set by the algorithms ordering events by it
+    lamport_timestamp: Option<usize>,
     payload: Vec<Vec<u8>>,
     parents: Option<P>,
     timestamp: Option<u64>,
@@ -31,12 +36,25 @@ pub struct Event<P: Parents + Clone + Serialize> {
     round_received: Option<usize>,
 }
 
+// Everything about an event that's decided locally instead of being part of its hash
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub(crate) struct EventMetadata {
+    can_see: HashMap<PeerId, EventHash>,
+    consensus_timestamp: Option<u64>,
+    famous: Option<bool>,
+    round: Option<usize>,
+    round_received: Option<usize>,
+    timestamp: Option<u64>,
+}
+
 impl<P: Parents + Clone + Serialize> Event<P> {
     pub fn new(payload: Vec<Vec<u8>>, parents: Option<P>, creator: PeerId) -> Event<P> {
         Event {
             can_see: HashMap::new(),
+            consensus_timestamp: None,
             creator,
             famous: None,
+            lamport_timestamp: None,
             payload,
             parents,
             round: None,
@@ -60,11 +78,36 @@ impl<P: Parents + Clone + Serialize> Event<P> {
             })))
     }
 
+    #[inline]
+    pub fn set_lamport_timestamp(&mut self, lamport_timestamp: usize) {
+        self.lamport_timestamp = Some(lamport_timestamp);
+    }
+
+    #[inline]
+    pub fn lamport_timestamp(&self) -> Option<usize> {
+        self.lamport_timestamp
+    }
+
     #[inline]
     pub fn set_round_received(&mut self, round_received: usize) {
         self.round_received = Some(round_received);
     }
 
+    #[inline]
+    pub fn round_received(&self) -> Option<usize> {
+        self.round_received
+    }
+
+    #[inline]
+    pub fn set_consensus_timestamp(&mut self, consensus_timestamp: u64) {
+        self.consensus_timestamp = Some(consensus_timestamp);
+    }
+
+    #[inline]
+    pub fn consensus_timestamp(&self) -> Option<u64> {
+        self.consensus_timestamp
+    }
+
     #[inline]
     pub fn is_self_parent(&self, hash: &EventHash) -> Result<bool, Error> {
         let mut error: Option<Error> = None;
@@ -195,6 +238,26 @@ impl<P: Parents + Clone + Serialize> Event<P> {
         &self.creator
     }
 
+    pub(crate) fn metadata(&self) -> EventMetadata {
+        EventMetadata {
+            can_see: self.can_see.clone(),
+            consensus_timestamp: self.consensus_timestamp,
+            famous: self.famous,
+            round: self.round,
+            round_received: self.round_received,
+            timestamp: self.timestamp,
+        }
+    }
+
+    pub(crate) fn set_metadata(&mut self, metadata: EventMetadata) {
+        self.can_see = metadata.can_see;
+        self.consensus_timestamp = metadata.consensus_timestamp;
+        self.famous = metadata.famous;
+        self.round = metadata.round;
+        self.round_received = metadata.round_received;
+        self.timestamp = metadata.timestamp;
+    }
+
     pub fn sign(&mut self, signature: EventSignature) {
         self.signature = Some(signature);
     }
@@ -210,6 +273,7 @@ impl<P: Parents + Clone + Serialize> Event<P> {
             self.parents.clone(),
             self.timestamp.clone(),
             self.creator.clone(),
+            self.lamport_timestamp.clone(),
         );
         let bytes = serialize(&value)?;
         Ok(EventHash::new(digest(&SHA256, bytes.as_ref()).as_ref()))
@@ -0,0 +1,83 @@
+use crate::event::parents::Parents;
+use crate::event::Event;
+use crate::peer::PeerId;
+use failure::Error;
+use serde::Serialize;
+
+// Two different events signed by the same creator on top of the same self parent, or two
+// roots. Both carry the creator's signature, so anyone can check the evidence.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct ForkEvidence<P: Parents + Clone + Serialize> {
+    pub creator: PeerId,
+    pub event_a: Event<P>,
+    pub event_b: Event<P>,
+}
+
+impl<P: Parents + Clone + Serialize> ForkEvidence<P> {
+    pub fn new(event_a: Event<P>, event_b: Event<P>) -> ForkEvidence<P> {
+        ForkEvidence {
+            creator: event_a.creator().clone(),
+            event_a,
+            event_b,
+        }
+    }
+
+    pub fn is_valid(&self) -> Result<bool, Error> {
+        let hash_a = self.event_a.hash()?;
+        let hash_b = self.event_b.hash()?;
+        if hash_a == hash_b
+            || self.event_a.creator() != &self.creator
+            || self.event_b.creator() != &self.creator
+        {
+            return Ok(false);
+        }
+        if !self.event_a.is_valid(&hash_a)? || !self.event_b.is_valid(&hash_b)? {
+            return Ok(false);
+        }
+        let self_parent_a = match self.event_a.parents() {
+            Some(parents) => Some(parents.self_parent()?),
+            None => None,
+        };
+        let self_parent_b = match self.event_b.parents() {
+            Some(parents) => Some(parents.self_parent()?),
+            None => None,
+        };
+        Ok(self_parent_a == self_parent_b)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ForkEvidence;
+    use crate::event::{event_signature::EventSignature, parents::ParentsPair, Event};
+    use ring::{rand, signature};
+
+    fn create_signed_event(
+        kp: &signature::Ed25519KeyPair,
+        payload: Vec<Vec<u8>>,
+    ) -> Event<ParentsPair> {
+        let mut event = Event::new(payload, None, kp.public_key_bytes().to_vec());
+        let hash = event.hash().unwrap();
+        event.sign(EventSignature::new(kp.sign(hash.as_ref()).as_ref()));
+        event
+    }
+
+    #[test]
+    fn it_should_validate_fork_evidence() {
+        let rng = rand::SystemRandom::new();
+        let pkcs8_bytes = signature::Ed25519KeyPair::generate_pkcs8(&rng).unwrap();
+        let kp =
+            signature::Ed25519KeyPair::from_pkcs8(untrusted::Input::from(&pkcs8_bytes)).unwrap();
+        let event_a = create_signed_event(&kp, vec![b"42".to_vec()]);
+        let event_b = create_signed_event(&kp, vec![b"fish".to_vec()]);
+        assert!(ForkEvidence::new(event_a.clone(), event_b.clone())
+            .is_valid()
+            .unwrap());
+        assert!(!ForkEvidence::new(event_a.clone(), event_a.clone())
+            .is_valid()
+            .unwrap());
+        let mut forged = ForkEvidence::new(event_a, event_b);
+        forged.creator = vec![42];
+        assert!(!forged.is_valid().unwrap());
+    }
+}
@@ -11,7 +11,7 @@ pub trait Parents {
 pub struct ParentsPair(pub EventHash, pub EventHash);
 
 impl ParentsPair {
-    pub fn max_round<H: Hashgraph>(&self, hg: H) -> Result<usize, Error> {
+    pub fn max_round<H: Hashgraph>(&self, hg: &H) -> Result<usize, Error> {
         let other_round = hg.get(&self.1)?.round()?;
         let self_round = hg.get(&self.0)?.round()?;
         Ok(max(other_round, self_round))
@@ -4,16 +4,25 @@ use crate::event::parents::ParentsPair;
 use crate::event::Event;
 use crate::peer::PeerId;
 use failure::Error;
-use std::collections::{BTreeMap, HashMap};
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::iter::repeat_with;
 
+pub mod disk_hashgraph;
+
+// Height of the last event a node has from each creator
+pub type KnownHeights = HashMap<PeerId, usize>;
+
+// Events are sent parents first
 #[derive(Deserialize, Serialize)]
-pub struct HashgraphWire(BTreeMap<EventHash, Event<ParentsPair>>);
+pub struct HashgraphWire(Vec<(EventHash, Event<ParentsPair>)>);
 
 pub trait Hashgraph: Send + Sync {
     fn get_mut(&mut self, id: &EventHash) -> Result<&mut Event<ParentsPair>, Error>;
-    fn get(&self, id: &EventHash) -> Result<&Event<ParentsPair>, Error>;
-    fn insert(&mut self, hash: EventHash, event: Event<ParentsPair>);
+    // Borrowed when the event is kept in memory, owned when it had to be read from disk
+    fn get(&self, id: &EventHash) -> Result<Cow<Event<ParentsPair>>, Error>;
+    fn insert(&mut self, hash: EventHash, event: Event<ParentsPair>) -> Result<(), Error>;
+    fn remove(&mut self, id: &EventHash) -> Result<Event<ParentsPair>, Error>;
     fn ancestors<'a>(&'a self, id: &'a EventHash) -> Result<Vec<&'a EventHash>, Error>;
     fn other_ancestors<'a>(&'a self, id: &'a EventHash) -> Result<Vec<&'a EventHash>, Error>;
     fn self_ancestors<'a>(&'a self, id: &'a EventHash) -> Result<Vec<&'a EventHash>, Error>;
@@ -23,24 +32,137 @@ pub trait Hashgraph: Send + Sync {
     fn difference<H: Hashgraph>(&self, g: H) -> Vec<EventHash>;
     fn is_valid_event(&self, event: &Event<ParentsPair>) -> Result<bool, Error>;
     fn contains_key(&self, id: &EventHash) -> bool;
-    fn wire(&self) -> HashgraphWire;
+    fn wire(&self) -> Result<HashgraphWire, Error>;
     fn find_roots(&self) -> Vec<EventHash>;
     fn find_self_child(&self, eh: &EventHash) -> Result<Option<EventHash>, Error>;
     fn get_events(&self) -> Vec<EventHash>;
+    fn height(&self, id: &EventHash) -> Result<usize, Error>;
+    fn children(&self, id: &EventHash) -> Vec<EventHash>;
+    fn last_event_of(&self, creator: &PeerId) -> Option<EventHash>;
+    fn known_heights(&self) -> KnownHeights;
+    fn missing_events(&self, known: &KnownHeights) -> Result<Vec<EventHash>, Error>;
+    fn wire_events(&self, events: &[EventHash]) -> Result<HashgraphWire, Error>;
+}
+
+// Sorts the events so parents go before their children. Parents that aren't among the
+// events are ignored, so it works on partial graphs too.
+pub(crate) fn sort_topologically<H: Hashgraph>(
+    graph: &H,
+    mut events: Vec<EventHash>,
+) -> Result<Vec<EventHash>, Error> {
+    events.sort();
+    let pending: HashSet<EventHash> = events.iter().cloned().collect();
+    let mut visited: HashSet<EventHash> = HashSet::with_capacity(events.len());
+    let mut sorted = Vec::with_capacity(events.len());
+    for start in events.into_iter() {
+        let mut stack = vec![(start, false)];
+        while let Some((hash, expanded)) = stack.pop() {
+            if expanded {
+                sorted.push(hash);
+                continue;
+            }
+            if visited.contains(&hash) {
+                continue;
+            }
+            visited.insert(hash.clone());
+            stack.push((hash.clone(), true));
+            if let Some(ParentsPair(self_parent, other_parent)) = graph.get(&hash)?.parents() {
+                for parent in [other_parent, self_parent].iter() {
+                    if pending.contains(*parent) && !visited.contains(*parent) {
+                        stack.push(((*parent).clone(), false));
+                    }
+                }
+            }
+        }
+    }
+    Ok(sorted)
 }
 
 #[derive(Clone, Debug)]
-pub struct BTreeHashgraph(BTreeMap<EventHash, Event<ParentsPair>>);
+pub struct BTreeHashgraph {
+    children: BTreeMap<EventHash, Vec<EventHash>>,
+    events: BTreeMap<EventHash, Event<ParentsPair>>,
+    heights: BTreeMap<EventHash, usize>,
+    last_events: BTreeMap<PeerId, EventHash>,
+}
 
 impl BTreeHashgraph {
     pub fn new() -> BTreeHashgraph {
-        BTreeHashgraph(BTreeMap::new())
+        BTreeHashgraph {
+            children: BTreeMap::new(),
+            events: BTreeMap::new(),
+            heights: BTreeMap::new(),
+            last_events: BTreeMap::new(),
+        }
+    }
+
+    fn add(&mut self, hash: EventHash, event: Event<ParentsPair>) {
+        if self.events.contains_key(&hash) {
+            self.events.insert(hash, event);
+            return;
+        }
+        if let Some(ParentsPair(self_parent, other_parent)) = event.parents() {
+            for parent in [self_parent, other_parent].iter() {
+                self.children
+                    .entry((*parent).clone())
+                    .or_insert_with(Vec::new)
+                    .push(hash.clone());
+            }
+        }
+        self.events.insert(hash.clone(), event);
+        self.update_heights(hash);
+    }
+
+    // Events can arrive before their self parent, so once a height is known it's
+    // propagated to the self children that were waiting for it
+    fn update_heights(&mut self, hash: EventHash) {
+        let mut pending = vec![hash];
+        while let Some(current) = pending.pop() {
+            let event = &self.events[&current];
+            let height = match event.parents() {
+                Some(ParentsPair(self_parent, _)) => self.heights.get(self_parent).map(|h| h + 1),
+                None => Some(0),
+            };
+            if let Some(height) = height {
+                let creator = event.creator().clone();
+                let is_last = self
+                    .last_events
+                    .get(&creator)
+                    .map(|last| self.heights[last] < height)
+                    .unwrap_or(true);
+                if is_last {
+                    self.last_events.insert(creator, current.clone());
+                }
+                self.heights.insert(current.clone(), height);
+                pending.extend(self.self_children(&current));
+            }
+        }
+    }
+
+    fn self_children(&self, hash: &EventHash) -> Vec<EventHash> {
+        self.children
+            .get(hash)
+            .map(|children| {
+                children
+                    .iter()
+                    .filter(|c| match self.events[*c].parents() {
+                        Some(ParentsPair(self_parent, _)) => self_parent == hash,
+                        None => false,
+                    })
+                    .cloned()
+                    .collect()
+            })
+            .unwrap_or_default()
     }
 }
 
 impl From<HashgraphWire> for BTreeHashgraph {
     fn from(v: HashgraphWire) -> Self {
-        BTreeHashgraph(v.0)
+        let mut hashgraph = BTreeHashgraph::new();
+        for (hash, event) in v.0.into_iter() {
+            hashgraph.add(hash, event);
+        }
+        hashgraph
     }
 }
 
@@ -49,6 +171,7 @@ enum ParentPairElem {
     SelfParent,
 }
 
+// The walk stops at parents that aren't in the graph anymore because they were removed
 fn _get_ancestors<'a>(
     graph: &'a BTreeHashgraph,
     id: &'a EventHash,
@@ -59,7 +182,12 @@ fn _get_ancestors<'a>(
     let v_eh: Vec<&EventHash> = repeat_with(|| {
         if let Some(previous) = prev {
             let send = Some(previous);
-            match graph.get(previous) {
+            match graph
+                .events
+                .get(previous)
+                .ok_or(Error::from(HashgraphError::new(
+                    HashgraphErrorType::EventNotFound,
+                ))) {
                 Ok(event) => {
                     prev = match event.parents() {
                         Some(ParentsPair(self_parent, other_parent)) => {
@@ -69,7 +197,8 @@ fn _get_ancestors<'a>(
                             })
                         }
                         None => None,
-                    };
+                    }
+                    .filter(|parent| graph.contains_key(parent));
                     send
                 }
                 Err(e) => {
@@ -92,19 +221,56 @@ fn _get_ancestors<'a>(
 
 impl Hashgraph for BTreeHashgraph {
     fn get_mut(&mut self, id: &EventHash) -> Result<&mut Event<ParentsPair>, Error> {
-        self.0.get_mut(id).ok_or(Error::from(HashgraphError::new(
-            HashgraphErrorType::EventNotFound,
-        )))
-    }
-
-    fn get(&self, id: &EventHash) -> Result<&Event<ParentsPair>, Error> {
-        self.0.get(id).ok_or(Error::from(HashgraphError::new(
-            HashgraphErrorType::EventNotFound,
-        )))
-    }
-
-    fn insert(&mut self, hash: EventHash, event: Event<ParentsPair>) {
-        self.0.insert(hash, event);
+        self.events
+            .get_mut(id)
+            .ok_or(Error::from(HashgraphError::new(
+                HashgraphErrorType::EventNotFound,
+            )))
+    }
+
+    fn get(&self, id: &EventHash) -> Result<Cow<Event<ParentsPair>>, Error> {
+        self.events
+            .get(id)
+            .map(Cow::Borrowed)
+            .ok_or(Error::from(HashgraphError::new(
+                HashgraphErrorType::EventNotFound,
+            )))
+    }
+
+    fn insert(&mut self, hash: EventHash, event: Event<ParentsPair>) -> Result<(), Error> {
+        self.add(hash, event);
+        Ok(())
+    }
+
+    // Children of a removed event keep their heights, but their ancestry stops there
+    fn remove(&mut self, id: &EventHash) -> Result<Event<ParentsPair>, Error> {
+        let event = self
+            .events
+            .remove(id)
+            .ok_or(Error::from(HashgraphError::new(
+                HashgraphErrorType::EventNotFound,
+            )))?;
+        if let Some(ParentsPair(self_parent, other_parent)) = event.parents() {
+            for parent in [self_parent, other_parent].iter() {
+                if let Some(children) = self.children.get_mut(*parent) {
+                    children.retain(|c| c != id);
+                }
+            }
+        }
+        self.children.remove(id);
+        self.heights.remove(id);
+        if self.last_events.get(event.creator()) == Some(id) {
+            match event.parents() {
+                Some(ParentsPair(self_parent, _)) if self.events.contains_key(self_parent) => {
+                    self.last_events
+                        .insert(event.creator().clone(), self_parent.clone());
+                }
+                _ => {
+                    self.last_events.remove(event.creator());
+                }
+            }
+        }
+        Ok(event)
     }
 
     fn ancestors<'a>(&'a self, id: &'a EventHash) -> Result<Vec<&'a EventHash>, Error> {
@@ -125,15 +291,9 @@ impl Hashgraph for BTreeHashgraph {
 
     #[inline]
     fn higher(&self, a: &EventHash, b: &EventHash) -> Result<bool, Error> {
-        let a_self_ancestors = self.self_ancestors(a)?;
-        if a_self_ancestors.contains(&b) {
-            return Ok(true);
-        }
-        let b_self_ancestors = self.self_ancestors(b)?;
-        if b_self_ancestors.contains(&a) {
-            return Ok(false);
-        }
-        Ok(a_self_ancestors.len() > b_self_ancestors.len())
+        let a_height = self.height(a)?;
+        let b_height = self.height(b)?;
+        Ok(a_height > b_height || (a_height == b_height && a == b))
     }
 
     #[inline]
@@ -166,7 +326,7 @@ impl Hashgraph for BTreeHashgraph {
     }
 
     fn difference<H: Hashgraph>(&self, g: H) -> Vec<EventHash> {
-        self.0
+        self.events
             .keys()
             .filter(|e| !g.contains_key(e))
             .map(|e| (*e).clone())
@@ -175,24 +335,27 @@ impl Hashgraph for BTreeHashgraph {
 
     fn is_valid_event(&self, event: &Event<ParentsPair>) -> Result<bool, Error> {
         match event.parents() {
-            Some(ParentsPair(self_parent, other_parent)) => Ok(self.0.contains_key(self_parent)
-                && self.0.contains_key(other_parent)
-                && self.0[self_parent].creator() == event.creator()
-                && self.0[other_parent].creator() != event.creator()),
+            Some(ParentsPair(self_parent, other_parent)) => {
+                Ok(self.events.contains_key(self_parent)
+                    && self.events.contains_key(other_parent)
+                    && self.events[self_parent].creator() == event.creator()
+                    && self.events[other_parent].creator() != event.creator())
+            }
             None => Ok(true),
         }
     }
 
     fn contains_key(&self, id: &EventHash) -> bool {
-        self.0.contains_key(id)
+        self.events.contains_key(id)
     }
 
-    fn wire(&self) -> HashgraphWire {
-        HashgraphWire(self.0.clone())
+    fn wire(&self) -> Result<HashgraphWire, Error> {
+        let events = sort_topologically(self, self.get_events())?;
+        self.wire_events(&events)
     }
 
     fn find_roots(&self) -> Vec<EventHash> {
-        self.0
+        self.events
             .values()
             .filter(|e| e.is_root())
             .map(|e| match e.hash() {
@@ -208,45 +371,69 @@ impl Hashgraph for BTreeHashgraph {
     }
 
     fn find_self_child(&self, eh: &EventHash) -> Result<Option<EventHash>, Error> {
-        let error: Option<Error> = None;
-        let r = self
-            .0
-            .values()
-            .find(|e| {
-                let e = *e;
-                match e.parents() {
-                    Some(ParentsPair(sp, _)) => sp == eh,
-                    None => false,
-                }
-            })
-            .map(|e| match e.hash() {
-                Ok(parents_pair) => Some(parents_pair),
-                Err(e) => {
-                    debug!(target: "hashgraph", "{}", e);
-                    None
-                }
-            });
-        if error.is_some() {
-            Err(error.unwrap())
-        } else if r.is_some() {
-            Ok(r.unwrap())
-        } else {
-            Err(format_err!("find_self_child() returned None"))
-        }
+        Ok(self.self_children(eh).into_iter().next())
     }
 
     fn get_events(&self) -> Vec<EventHash> {
-        self.0
+        self.events
             .keys()
             .map(|h| h.clone())
             .collect::<Vec<EventHash>>()
             .clone()
     }
+
+    fn height(&self, id: &EventHash) -> Result<usize, Error> {
+        self.heights
+            .get(id)
+            .map(|h| h.clone())
+            .ok_or(Error::from(HashgraphError::new(
+                HashgraphErrorType::EventNotFound,
+            )))
+    }
+
+    fn children(&self, id: &EventHash) -> Vec<EventHash> {
+        self.children.get(id).cloned().unwrap_or_default()
+    }
+
+    fn last_event_of(&self, creator: &PeerId) -> Option<EventHash> {
+        self.last_events.get(creator).cloned()
+    }
+
+    fn known_heights(&self) -> KnownHeights {
+        self.last_events
+            .iter()
+            .map(|(creator, last)| (creator.clone(), self.heights[last]))
+            .collect()
+    }
+
+    // Events whose height is unknown hang from a missing self parent, so they're sent too
+    fn missing_events(&self, known: &KnownHeights) -> Result<Vec<EventHash>, Error> {
+        let missing = self
+            .events
+            .iter()
+            .filter(
+                |(hash, event)| match (self.heights.get(*hash), known.get(event.creator())) {
+                    (Some(height), Some(known_height)) => height > known_height,
+                    _ => true,
+                },
+            )
+            .map(|(hash, _)| hash.clone())
+            .collect();
+        sort_topologically(self, missing)
+    }
+
+    fn wire_events(&self, events: &[EventHash]) -> Result<HashgraphWire, Error> {
+        let mut wire = Vec::with_capacity(events.len());
+        for hash in events {
+            wire.push((hash.clone(), self.get(hash)?.into_owned()));
+        }
+        Ok(HashgraphWire(wire))
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::{BTreeHashgraph, Hashgraph};
+    use super::{BTreeHashgraph, Hashgraph, KnownHeights};
     use crate::event::{event_hash::EventHash, parents::ParentsPair, Event};
     use std::collections::HashMap;
 
@@ -255,7 +442,7 @@ mod tests {
         let mut hashgraph = BTreeHashgraph::new();
         let event = Event::new(vec![], None, Vec::new());
         let hash = event.hash().unwrap();
-        hashgraph.insert(hash.clone(), event.clone());
+        hashgraph.insert(hash.clone(), event.clone()).unwrap();
         assert!(hashgraph.is_valid_event(&event).unwrap());
     }
 
@@ -274,9 +461,9 @@ mod tests {
             n1,
         );
         let hash = event.hash().unwrap();
-        hashgraph.insert(ophash.clone(), other_parent);
-        hashgraph.insert(sphash.clone(), self_parent);
-        hashgraph.insert(hash.clone(), event.clone());
+        hashgraph.insert(ophash.clone(), other_parent).unwrap();
+        hashgraph.insert(sphash.clone(), self_parent).unwrap();
+        hashgraph.insert(hash.clone(), event.clone()).unwrap();
         assert!(hashgraph.is_valid_event(&event).unwrap());
     }
 
@@ -296,9 +483,9 @@ mod tests {
             n3,
         );
         let hash = event.hash().unwrap();
-        hashgraph.insert(ophash.clone(), other_parent);
-        hashgraph.insert(sphash.clone(), self_parent);
-        hashgraph.insert(hash.clone(), event.clone());
+        hashgraph.insert(ophash.clone(), other_parent).unwrap();
+        hashgraph.insert(sphash.clone(), self_parent).unwrap();
+        hashgraph.insert(hash.clone(), event.clone()).unwrap();
         assert!(!hashgraph.is_valid_event(&event).unwrap());
     }
 
@@ -317,9 +504,9 @@ mod tests {
             n2.clone(),
         );
         let hash = event.hash().unwrap();
-        hashgraph.insert(ophash.clone(), other_parent);
-        hashgraph.insert(sphash.clone(), self_parent);
-        hashgraph.insert(hash.clone(), event.clone());
+        hashgraph.insert(ophash.clone(), other_parent).unwrap();
+        hashgraph.insert(sphash.clone(), self_parent).unwrap();
+        hashgraph.insert(hash.clone(), event.clone()).unwrap();
         assert!(!hashgraph.is_valid_event(&event).unwrap());
     }
 
@@ -338,8 +525,8 @@ mod tests {
             n2.clone(),
         );
         let hash = event.hash().unwrap();
-        hashgraph.insert(ophash.clone(), other_parent);
-        hashgraph.insert(hash.clone(), event.clone());
+        hashgraph.insert(ophash.clone(), other_parent).unwrap();
+        hashgraph.insert(hash.clone(), event.clone()).unwrap();
         assert!(!hashgraph.is_valid_event(&event).unwrap());
     }
 
@@ -358,8 +545,8 @@ mod tests {
             n2.clone(),
         );
         let hash = event.hash().unwrap();
-        hashgraph.insert(sphash.clone(), self_parent);
-        hashgraph.insert(hash.clone(), event.clone());
+        hashgraph.insert(sphash.clone(), self_parent).unwrap();
+        hashgraph.insert(hash.clone(), event.clone()).unwrap();
         assert!(!hashgraph.is_valid_event(&event).unwrap());
     }
 
@@ -373,9 +560,9 @@ mod tests {
         let hash3 = event3.hash().unwrap();
         let mut hg1 = BTreeHashgraph::new();
         let mut hg2 = BTreeHashgraph::new();
-        hg1.insert(hash1.clone(), event1);
-        hg1.insert(hash2.clone(), event2);
-        hg2.insert(hash3.clone(), event3);
+        hg1.insert(hash1.clone(), event1).unwrap();
+        hg1.insert(hash2.clone(), event2).unwrap();
+        hg2.insert(hash3.clone(), event3).unwrap();
         let mut expected = vec![hash1.clone(), hash2.clone()];
         expected.sort();
         let mut actual = hg1.difference(hg2);
@@ -412,13 +599,13 @@ mod tests {
         );
         let hash7 = event7.hash().unwrap();
         let mut hashgraph = BTreeHashgraph::new();
-        hashgraph.insert(hash1.clone(), event1.clone());
-        hashgraph.insert(hash2.clone(), event2.clone());
-        hashgraph.insert(hash3.clone(), event3.clone());
-        hashgraph.insert(hash4.clone(), event4.clone());
-        hashgraph.insert(hash5.clone(), event5.clone());
-        hashgraph.insert(hash6.clone(), event6.clone());
-        hashgraph.insert(hash7.clone(), event7.clone());
+        hashgraph.insert(hash1.clone(), event1.clone()).unwrap();
+        hashgraph.insert(hash2.clone(), event2.clone()).unwrap();
+        hashgraph.insert(hash3.clone(), event3.clone()).unwrap();
+        hashgraph.insert(hash4.clone(), event4.clone()).unwrap();
+        hashgraph.insert(hash5.clone(), event5.clone()).unwrap();
+        hashgraph.insert(hash6.clone(), event6.clone()).unwrap();
+        hashgraph.insert(hash7.clone(), event7.clone()).unwrap();
         let mut expected = vec![&hash1, &hash3, &hash5, &hash7];
         expected.sort();
         let mut actual = hashgraph.self_ancestors(&hash7).unwrap();
@@ -455,13 +642,13 @@ mod tests {
         );
         let hash7 = event7.hash().unwrap();
         let mut hashgraph = BTreeHashgraph::new();
-        hashgraph.insert(hash1.clone(), event1.clone());
-        hashgraph.insert(hash2.clone(), event2.clone());
-        hashgraph.insert(hash3.clone(), event3.clone());
-        hashgraph.insert(hash4.clone(), event4.clone());
-        hashgraph.insert(hash5.clone(), event5.clone());
-        hashgraph.insert(hash6.clone(), event6.clone());
-        hashgraph.insert(hash7.clone(), event7.clone());
+        hashgraph.insert(hash1.clone(), event1.clone()).unwrap();
+        hashgraph.insert(hash2.clone(), event2.clone()).unwrap();
+        hashgraph.insert(hash3.clone(), event3.clone()).unwrap();
+        hashgraph.insert(hash4.clone(), event4.clone()).unwrap();
+        hashgraph.insert(hash5.clone(), event5.clone()).unwrap();
+        hashgraph.insert(hash6.clone(), event6.clone()).unwrap();
+        hashgraph.insert(hash7.clone(), event7.clone()).unwrap();
         let mut expected = vec![&hash1, &hash3, &hash5, &hash7];
         expected.sort();
         let mut actual = hashgraph.other_ancestors(&hash7).unwrap();
@@ -498,13 +685,13 @@ mod tests {
         );
         let hash7 = event7.hash().unwrap();
         let mut hashgraph = BTreeHashgraph::new();
-        hashgraph.insert(hash1.clone(), event1.clone());
-        hashgraph.insert(hash2.clone(), event2.clone());
-        hashgraph.insert(hash3.clone(), event3.clone());
-        hashgraph.insert(hash4.clone(), event4.clone());
-        hashgraph.insert(hash5.clone(), event5.clone());
-        hashgraph.insert(hash6.clone(), event6.clone());
-        hashgraph.insert(hash7.clone(), event7.clone());
+        hashgraph.insert(hash1.clone(), event1.clone()).unwrap();
+        hashgraph.insert(hash2.clone(), event2.clone()).unwrap();
+        hashgraph.insert(hash3.clone(), event3.clone()).unwrap();
+        hashgraph.insert(hash4.clone(), event4.clone()).unwrap();
+        hashgraph.insert(hash5.clone(), event5.clone()).unwrap();
+        hashgraph.insert(hash6.clone(), event6.clone()).unwrap();
+        hashgraph.insert(hash7.clone(), event7.clone()).unwrap();
         let mut expected = vec![&hash1, &hash3, &hash5, &hash6, &hash7];
         expected.sort();
         let mut actual = hashgraph.ancestors(&hash7).unwrap();
@@ -541,13 +728,13 @@ mod tests {
         );
         let hash7 = event7.hash().unwrap();
         let mut hashgraph = BTreeHashgraph::new();
-        hashgraph.insert(hash1.clone(), event1.clone());
-        hashgraph.insert(hash2.clone(), event2.clone());
-        hashgraph.insert(hash3.clone(), event3.clone());
-        hashgraph.insert(hash4.clone(), event4.clone());
-        hashgraph.insert(hash5.clone(), event5.clone());
-        hashgraph.insert(hash6.clone(), event6.clone());
-        hashgraph.insert(hash7.clone(), event7.clone());
+        hashgraph.insert(hash1.clone(), event1.clone()).unwrap();
+        hashgraph.insert(hash2.clone(), event2.clone()).unwrap();
+        hashgraph.insert(hash3.clone(), event3.clone()).unwrap();
+        hashgraph.insert(hash4.clone(), event4.clone()).unwrap();
+        hashgraph.insert(hash5.clone(), event5.clone()).unwrap();
+        hashgraph.insert(hash6.clone(), event6.clone()).unwrap();
+        hashgraph.insert(hash7.clone(), event7.clone()).unwrap();
         assert!(!hashgraph.higher(&hash6, &hash7).unwrap());
     }
 
@@ -582,13 +769,13 @@ mod tests {
         );
         let hash7 = event7.hash().unwrap();
         let mut hashgraph = BTreeHashgraph::new();
-        hashgraph.insert(hash1.clone(), event1.clone());
-        hashgraph.insert(hash2.clone(), event2.clone());
-        hashgraph.insert(hash3.clone(), event3.clone());
-        hashgraph.insert(hash4.clone(), event4.clone());
-        hashgraph.insert(hash5.clone(), event5.clone());
-        hashgraph.insert(hash6.clone(), event6.clone());
-        hashgraph.insert(hash7.clone(), event7.clone());
+        hashgraph.insert(hash1.clone(), event1.clone()).unwrap();
+        hashgraph.insert(hash2.clone(), event2.clone()).unwrap();
+        hashgraph.insert(hash3.clone(), event3.clone()).unwrap();
+        hashgraph.insert(hash4.clone(), event4.clone()).unwrap();
+        hashgraph.insert(hash5.clone(), event5.clone()).unwrap();
+        hashgraph.insert(hash6.clone(), event6.clone()).unwrap();
+        hashgraph.insert(hash7.clone(), event7.clone()).unwrap();
         assert!(hashgraph.higher(&hash7, &hash6).unwrap());
     }
 
@@ -621,13 +808,13 @@ mod tests {
         );
         let hash7 = event7.hash().unwrap();
         let mut hashgraph = BTreeHashgraph::new();
-        hashgraph.insert(hash1.clone(), event1.clone());
-        hashgraph.insert(hash2.clone(), event2.clone());
-        hashgraph.insert(hash3.clone(), event3.clone());
-        hashgraph.insert(hash4.clone(), event4.clone());
-        hashgraph.insert(hash5.clone(), event5.clone());
-        hashgraph.insert(hash6.clone(), event6.clone());
-        hashgraph.insert(hash7.clone(), event7.clone());
+        hashgraph.insert(hash1.clone(), event1.clone()).unwrap();
+        hashgraph.insert(hash2.clone(), event2.clone()).unwrap();
+        hashgraph.insert(hash3.clone(), event3.clone()).unwrap();
+        hashgraph.insert(hash4.clone(), event4.clone()).unwrap();
+        hashgraph.insert(hash5.clone(), event5.clone()).unwrap();
+        hashgraph.insert(hash6.clone(), event6.clone()).unwrap();
+        hashgraph.insert(hash7.clone(), event7.clone()).unwrap();
         assert!(hashgraph.higher(&hash5, &hash6).unwrap());
     }
 
@@ -663,13 +850,13 @@ mod tests {
         );
         let hash7 = event7.hash().unwrap();
         let mut hashgraph = BTreeHashgraph::new();
-        hashgraph.insert(hash1.clone(), event1.clone());
-        hashgraph.insert(hash2.clone(), event2.clone());
-        hashgraph.insert(hash3.clone(), event3.clone());
-        hashgraph.insert(hash4.clone(), event4.clone());
-        hashgraph.insert(hash5.clone(), event5.clone());
-        hashgraph.insert(hash6.clone(), event6.clone());
-        hashgraph.insert(hash7.clone(), event7.clone());
+        hashgraph.insert(hash1.clone(), event1.clone()).unwrap();
+        hashgraph.insert(hash2.clone(), event2.clone()).unwrap();
+        hashgraph.insert(hash3.clone(), event3.clone()).unwrap();
+        hashgraph.insert(hash4.clone(), event4.clone()).unwrap();
+        hashgraph.insert(hash5.clone(), event5.clone()).unwrap();
+        hashgraph.insert(hash6.clone(), event6.clone()).unwrap();
+        hashgraph.insert(hash7.clone(), event7.clone()).unwrap();
         let actual = hashgraph.events_parents_can_see(&hash7).unwrap();
         let expected: HashMap<Vec<u8>, EventHash> =
             [(vec![2], hash3.clone()), (vec![1], hash4.clone())]
@@ -678,4 +865,141 @@ mod tests {
                 .collect();
         assert_eq!(expected, actual);
     }
+
+    #[test]
+    fn it_should_index_heights_children_and_last_events() {
+        let event1 = Event::new(vec![b"42".to_vec()], None, vec![1]);
+        let hash1 = event1.hash().unwrap();
+        let event2 = Event::new(vec![b"fish".to_vec()], None, vec![2]);
+        let hash2 = event2.hash().unwrap();
+        let event3 = Event::new(
+            vec![b"ford prefect".to_vec()],
+            Some(ParentsPair(hash1.clone(), hash2.clone())),
+            vec![1],
+        );
+        let hash3 = event3.hash().unwrap();
+        let event4 = Event::new(
+            vec![b"ford prefect".to_vec()],
+            Some(ParentsPair(hash2.clone(), hash3.clone())),
+            vec![2],
+        );
+        let hash4 = event4.hash().unwrap();
+        let mut hashgraph = BTreeHashgraph::new();
+        hashgraph.insert(hash1.clone(), event1.clone()).unwrap();
+        hashgraph.insert(hash2.clone(), event2.clone()).unwrap();
+        hashgraph.insert(hash3.clone(), event3.clone()).unwrap();
+        hashgraph.insert(hash4.clone(), event4.clone()).unwrap();
+        assert_eq!(hashgraph.height(&hash1).unwrap(), 0);
+        assert_eq!(hashgraph.height(&hash3).unwrap(), 1);
+        assert_eq!(hashgraph.height(&hash4).unwrap(), 1);
+        let mut expected = vec![hash3.clone(), hash4.clone()];
+        expected.sort();
+        let mut actual = hashgraph.children(&hash2);
+        actual.sort();
+        assert_eq!(expected, actual);
+        assert_eq!(hashgraph.children(&hash4), Vec::<EventHash>::new());
+        assert_eq!(hashgraph.last_event_of(&vec![1]), Some(hash3.clone()));
+        assert_eq!(hashgraph.last_event_of(&vec![2]), Some(hash4.clone()));
+        assert_eq!(hashgraph.last_event_of(&vec![3]), None);
+        assert_eq!(
+            hashgraph.find_self_child(&hash1).unwrap(),
+            Some(hash3.clone())
+        );
+        assert_eq!(hashgraph.find_self_child(&hash3).unwrap(), None);
+    }
+
+    #[test]
+    fn it_should_index_events_inserted_before_their_self_parent() {
+        let event1 = Event::new(vec![b"42".to_vec()], None, vec![1]);
+        let hash1 = event1.hash().unwrap();
+        let event2 = Event::new(vec![b"fish".to_vec()], None, vec![2]);
+        let hash2 = event2.hash().unwrap();
+        let event3 = Event::new(
+            vec![b"ford prefect".to_vec()],
+            Some(ParentsPair(hash1.clone(), hash2.clone())),
+            vec![1],
+        );
+        let hash3 = event3.hash().unwrap();
+        let mut hashgraph = BTreeHashgraph::new();
+        hashgraph.insert(hash3.clone(), event3.clone()).unwrap();
+        assert!(hashgraph.height(&hash3).is_err());
+        assert_eq!(hashgraph.last_event_of(&vec![1]), None);
+        hashgraph.insert(hash2.clone(), event2.clone()).unwrap();
+        hashgraph.insert(hash1.clone(), event1.clone()).unwrap();
+        assert_eq!(hashgraph.height(&hash3).unwrap(), 1);
+        assert_eq!(hashgraph.last_event_of(&vec![1]), Some(hash3.clone()));
+        assert!(hashgraph.higher(&hash3, &hash1).unwrap());
+        assert!(!hashgraph.higher(&hash1, &hash3).unwrap());
+    }
+
+    #[test]
+    fn it_should_remove_events() {
+        let event1 = Event::new(vec![b"42".to_vec()], None, vec![1]);
+        let hash1 = event1.hash().unwrap();
+        let event2 = Event::new(vec![b"fish".to_vec()], None, vec![2]);
+        let hash2 = event2.hash().unwrap();
+        let event3 = Event::new(
+            vec![b"ford prefect".to_vec()],
+            Some(ParentsPair(hash1.clone(), hash2.clone())),
+            vec![1],
+        );
+        let hash3 = event3.hash().unwrap();
+        let mut hashgraph = BTreeHashgraph::new();
+        hashgraph.insert(hash1.clone(), event1.clone()).unwrap();
+        hashgraph.insert(hash2.clone(), event2.clone()).unwrap();
+        hashgraph.insert(hash3.clone(), event3.clone()).unwrap();
+        assert_eq!(hashgraph.remove(&hash1).unwrap(), event1);
+        assert!(!hashgraph.contains_key(&hash1));
+        assert!(hashgraph.remove(&hash1).is_err());
+        assert!(hashgraph.children(&hash1).is_empty());
+        assert_eq!(hashgraph.height(&hash3).unwrap(), 1);
+        assert_eq!(hashgraph.self_ancestors(&hash3).unwrap(), vec![&hash3]);
+        assert_eq!(hashgraph.ancestors(&hash3).unwrap().len(), 2);
+        hashgraph.remove(&hash3).unwrap();
+        assert_eq!(hashgraph.last_event_of(&vec![1]), None);
+        assert!(hashgraph.children(&hash2).is_empty());
+    }
+
+    #[test]
+    fn it_should_send_only_missing_events_parents_first() {
+        let mut hashgraph = BTreeHashgraph::new();
+        let n1 = vec![42];
+        let n2 = vec![43];
+        let a0 = Event::new(vec![], None, n1.clone());
+        let b0 = Event::new(vec![], None, n2.clone());
+        let a0_hash = a0.hash().unwrap();
+        let b0_hash = b0.hash().unwrap();
+        let a1 = Event::new(
+            vec![],
+            Some(ParentsPair(a0_hash.clone(), b0_hash.clone())),
+            n1.clone(),
+        );
+        let a1_hash = a1.hash().unwrap();
+        let b1 = Event::new(
+            vec![],
+            Some(ParentsPair(b0_hash.clone(), a1_hash.clone())),
+            n2.clone(),
+        );
+        let b1_hash = b1.hash().unwrap();
+        hashgraph.insert(b1_hash.clone(), b1).unwrap();
+        hashgraph.insert(a1_hash.clone(), a1).unwrap();
+        hashgraph.insert(b0_hash.clone(), b0).unwrap();
+        hashgraph.insert(a0_hash.clone(), a0).unwrap();
+
+        let mut known = KnownHeights::new();
+        known.insert(n1, 0);
+        known.insert(n2, 0);
+        assert_eq!(
+            hashgraph.missing_events(&known).unwrap(),
+            vec![a1_hash.clone(), b1_hash.clone()]
+        );
+        assert_eq!(hashgraph.known_heights()[&vec![43]], 1);
+
+        let all = hashgraph.missing_events(&KnownHeights::new()).unwrap();
+        assert_eq!(all.len(), 4);
+        let position = |hash| all.iter().position(|h| h == hash).unwrap();
+        assert!(position(&a0_hash) < position(&a1_hash));
+        assert!(position(&b0_hash) < position(&a1_hash));
+        assert!(position(&a1_hash) < position(&b1_hash));
+    }
 }
@@ -0,0 +1,1209 @@
+use super::{sort_topologically, Hashgraph, HashgraphWire, KnownHeights};
+use crate::errors::{HashgraphError, HashgraphErrorType, ResourceHashgraphPoisonError};
+use crate::event::event_hash::EventHash;
+use crate::event::parents::ParentsPair;
+use crate::event::{Event, EventMetadata};
+use crate::peer::PeerId;
+use bincode::{deserialize, serialize};
+use byteorder::{ByteOrder, LittleEndian, WriteBytesExt};
+use failure::Error;
+use ring::digest::{digest, SHA256};
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::fmt;
+use std::fs::{create_dir_all, read_dir, remove_file, rename, File, OpenOptions};
+use std::io::{ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+// Events are appended to numbered segment files. Every record is laid out as
+// [payload length: u64][first 8 bytes of SHA256(payload)][payload], where the payload
+// is the bincode encoding of (EventHash, Event, EventMetadata). Changing an event appends
+// a new record for it, and the last one is the one that counts.
+// The index file holds one fixed size entry per record: [event hash][segment: u64][offset: u64]
+// Only the parents and creator of every event and where its last record is are kept in
+// memory. Events are read from the segments, and the last ones read are cached.
+// Removed events are appended by hash to the removed file and skipped on open, so inserting
+// one of them again doesn't survive a reopen until that file is emptied.
+// Sealed segments where most records were replaced or removed get their live records copied
+// to the active segment and are deleted. The index is rewritten first with only the live
+// entries, ending in an all-zero hash that marks where the log continues, and the removed
+// file is emptied.
+const DEFAULT_MAX_CACHED_EVENTS: usize = 4096;
+const DEFAULT_SEGMENT_SIZE: u64 = 64 * 1024 * 1024;
+const HASH_SIZE: usize = 32;
+const INDEX_FILE: &str = "index";
+const INDEX_TMP_FILE: &str = "index.tmp";
+const INDEX_ENTRY_SIZE: usize = HASH_SIZE + 8 + 8;
+const RECORD_HEADER_SIZE: usize = 8 + 8;
+const REMOVED_FILE: &str = "removed";
+const SEGMENT_PREFIX: &str = "segment-";
+const SEGMENT_SUFFIX: &str = ".log";
+
+#[inline]
+fn corrupted_log() -> Error {
+    Error::from(HashgraphError::new(HashgraphErrorType::CorruptedLog))
+}
+
+#[inline]
+fn event_not_found() -> Error {
+    Error::from(HashgraphError::new(HashgraphErrorType::EventNotFound))
+}
+
+#[inline]
+fn checksum(payload: &[u8]) -> [u8; 8] {
+    let mut result = [0; 8];
+    result.copy_from_slice(&digest(&SHA256, payload).as_ref()[..8]);
+    result
+}
+
+#[inline]
+fn segment_path(dir: &Path, id: u64) -> PathBuf {
+    dir.join(format!("{}{:010}{}", SEGMENT_PREFIX, id, SEGMENT_SUFFIX))
+}
+
+fn list_segments(dir: &Path) -> Result<Vec<u64>, Error> {
+    let mut ids = Vec::new();
+    for entry in read_dir(dir)? {
+        let name = entry?.file_name().to_string_lossy().into_owned();
+        if name.starts_with(SEGMENT_PREFIX) && name.ends_with(SEGMENT_SUFFIX) {
+            let id = &name[SEGMENT_PREFIX.len()..name.len() - SEGMENT_SUFFIX.len()];
+            if let Ok(id) = id.parse::<u64>() {
+                ids.push(id);
+            }
+        }
+    }
+    ids.sort();
+    Ok(ids)
+}
+
+fn read_file(path: &Path) -> Result<Vec<u8>, Error> {
+    let mut content = Vec::new();
+    match File::open(path) {
+        Ok(mut file) => {
+            file.read_to_end(&mut content)?;
+            Ok(content)
+        }
+        Err(ref e) if e.kind() == ErrorKind::NotFound => Ok(content),
+        Err(e) => Err(Error::from(e)),
+    }
+}
+
+fn encode_record(hash: &EventHash, event: &Event<ParentsPair>) -> Result<Vec<u8>, Error> {
+    let payload = serialize(&(hash, event, event.metadata()))?;
+    let mut record = Vec::with_capacity(RECORD_HEADER_SIZE + payload.len());
+    record.write_u64::<LittleEndian>(payload.len() as u64)?;
+    record.extend_from_slice(&checksum(&payload));
+    record.extend_from_slice(&payload);
+    Ok(record)
+}
+
+// Returns None on anything that isn't a complete, uncorrupted record
+fn decode_record(record: &[u8]) -> Option<(EventHash, Event<ParentsPair>)> {
+    if record.len() < RECORD_HEADER_SIZE {
+        return None;
+    }
+    let payload = &record[RECORD_HEADER_SIZE..];
+    if LittleEndian::read_u64(&record[..8]) != payload.len() as u64
+        || checksum(payload)[..] != record[8..RECORD_HEADER_SIZE]
+    {
+        return None;
+    }
+    let (hash, mut event, metadata): (EventHash, Event<ParentsPair>, EventMetadata) =
+        deserialize(payload).ok()?;
+    event.set_metadata(metadata);
+    Some((hash, event))
+}
+
+// Reads the record at the offset along with its length. The length in the header is checked
+// against the file first, so a torn one doesn't get allocated.
+fn read_record_at(
+    file: &mut File,
+    file_len: u64,
+    offset: u64,
+) -> Result<Option<(EventHash, Event<ParentsPair>, u64)>, Error> {
+    let header_end = offset + RECORD_HEADER_SIZE as u64;
+    if header_end > file_len {
+        return Ok(None);
+    }
+    let mut record = vec![0; RECORD_HEADER_SIZE];
+    file.seek(SeekFrom::Start(offset))?;
+    file.read_exact(&mut record)?;
+    let record_end = match header_end.checked_add(LittleEndian::read_u64(&record[..8])) {
+        Some(end) if end <= file_len => end,
+        _ => return Ok(None),
+    };
+    record.resize((record_end - offset) as usize, 0);
+    file.read_exact(&mut record[RECORD_HEADER_SIZE..])?;
+    Ok(decode_record(&record).map(|(hash, event)| (hash, event, record_end - offset)))
+}
+
+fn encode_index_entry(hash: &EventHash, segment: u64, offset: u64) -> Result<Vec<u8>, Error> {
+    let mut entry = Vec::with_capacity(INDEX_ENTRY_SIZE);
+    entry.extend_from_slice(hash.as_ref());
+    entry.write_u64::<LittleEndian>(segment)?;
+    entry.write_u64::<LittleEndian>(offset)?;
+    Ok(entry)
+}
+
+#[inline]
+fn end_marker() -> EventHash {
+    EventHash::new(&[0; HASH_SIZE])
+}
+
+fn decode_index_entry(entry: &[u8]) -> (EventHash, u64, u64) {
+    let hash = EventHash::new(&entry[..HASH_SIZE]);
+    let segment = LittleEndian::read_u64(&entry[HASH_SIZE..40]);
+    let offset = LittleEndian::read_u64(&entry[40..48]);
+    (hash, segment, offset)
+}
+
+#[inline]
+fn open_append(path: &Path) -> Result<File, Error> {
+    Ok(OpenOptions::new()
+        .read(true)
+        .append(true)
+        .create(true)
+        .open(path)?)
+}
+
+// What the graph queries need of an event
+#[derive(Clone)]
+struct Vertex {
+    creator: PeerId,
+    parents: Option<ParentsPair>,
+}
+
+// The shape of the graph, kept in memory while the events stay on disk
+#[derive(Clone, Default)]
+struct Topology {
+    children: HashMap<EventHash, Vec<EventHash>>,
+    heights: HashMap<EventHash, usize>,
+    last_events: BTreeMap<PeerId, EventHash>,
+    vertices: BTreeMap<EventHash, Vertex>,
+}
+
+impl Topology {
+    fn add(&mut self, hash: &EventHash, event: &Event<ParentsPair>) {
+        if self.vertices.contains_key(hash) {
+            return;
+        }
+        if let Some(ParentsPair(self_parent, other_parent)) = event.parents() {
+            for parent in [self_parent, other_parent].iter() {
+                self.children
+                    .entry((*parent).clone())
+                    .or_insert_with(Vec::new)
+                    .push(hash.clone());
+            }
+        }
+        self.vertices.insert(
+            hash.clone(),
+            Vertex {
+                creator: event.creator().clone(),
+                parents: event.parents().clone(),
+            },
+        );
+        self.update_heights(hash.clone());
+    }
+
+    // Events can arrive before their self parent, so once a height is known it's
+    // propagated to the self children that were waiting for it
+    fn update_heights(&mut self, hash: EventHash) {
+        let mut pending = vec![hash];
+        while let Some(current) = pending.pop() {
+            let vertex = &self.vertices[&current];
+            let height = match vertex.parents.as_ref() {
+                Some(ParentsPair(self_parent, _)) => self.heights.get(self_parent).map(|h| h + 1),
+                None => Some(0),
+            };
+            if let Some(height) = height {
+                let creator = vertex.creator.clone();
+                let is_last = self
+                    .last_events
+                    .get(&creator)
+                    .map(|last| self.heights[last] < height)
+                    .unwrap_or(true);
+                if is_last {
+                    self.last_events.insert(creator, current.clone());
+                }
+                self.heights.insert(current.clone(), height);
+                pending.extend(self.self_children(&current));
+            }
+        }
+    }
+
+    fn self_children(&self, hash: &EventHash) -> Vec<EventHash> {
+        self.children
+            .get(hash)
+            .map(|children| {
+                children
+                    .iter()
+                    .filter(|c| match self.vertices[*c].parents.as_ref() {
+                        Some(ParentsPair(self_parent, _)) => self_parent == hash,
+                        None => false,
+                    })
+                    .cloned()
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    // Children of a removed event keep their heights, but their ancestry stops there
+    fn remove(&mut self, id: &EventHash) {
+        let vertex = match self.vertices.remove(id) {
+            Some(vertex) => vertex,
+            None => return,
+        };
+        if let Some(ParentsPair(self_parent, other_parent)) = vertex.parents.as_ref() {
+            for parent in [self_parent, other_parent].iter() {
+                if let Some(children) = self.children.get_mut(*parent) {
+                    children.retain(|c| c != id);
+                }
+            }
+        }
+        self.children.remove(id);
+        self.heights.remove(id);
+        if self.last_events.get(&vertex.creator) == Some(id) {
+            match vertex.parents {
+                Some(ParentsPair(ref self_parent, _))
+                    if self.vertices.contains_key(self_parent) =>
+                {
+                    self.last_events
+                        .insert(vertex.creator.clone(), self_parent.clone());
+                }
+                _ => {
+                    self.last_events.remove(&vertex.creator);
+                }
+            }
+        }
+    }
+
+    // The walk stops at parents that aren't in the graph anymore because they were removed
+    fn walk<'a>(
+        &'a self,
+        id: &'a EventHash,
+        self_parents: bool,
+    ) -> Result<Vec<&'a EventHash>, Error> {
+        if !self.vertices.contains_key(id) {
+            return Err(event_not_found());
+        }
+        let mut walked = vec![id];
+        let mut current = id;
+        while let Some(ParentsPair(self_parent, other_parent)) =
+            self.vertices[current].parents.as_ref()
+        {
+            let parent = if self_parents {
+                self_parent
+            } else {
+                other_parent
+            };
+            if !self.vertices.contains_key(parent) {
+                break;
+            }
+            walked.push(parent);
+            current = parent;
+        }
+        Ok(walked)
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Location {
+    segment: u64,
+    offset: u64,
+    len: u64,
+}
+
+// Segment files and the events read last, behind a lock so they can be read through &self.
+// Cached events are evicted in the order they were read.
+struct Reader {
+    cache: HashMap<EventHash, Event<ParentsPair>>,
+    files: HashMap<u64, File>,
+    max_cached_events: usize,
+    order: VecDeque<EventHash>,
+}
+
+impl Reader {
+    fn record(&mut self, location: &Location) -> Result<Vec<u8>, Error> {
+        let file = self
+            .files
+            .get_mut(&location.segment)
+            .ok_or_else(corrupted_log)?;
+        let mut record = vec![0; location.len as usize];
+        file.seek(SeekFrom::Start(location.offset))?;
+        file.read_exact(&mut record)?;
+        Ok(record)
+    }
+
+    fn event(
+        &mut self,
+        hash: &EventHash,
+        location: &Location,
+    ) -> Result<Event<ParentsPair>, Error> {
+        if let Some(event) = self.cache.get(hash) {
+            return Ok(event.clone());
+        }
+        let (record_hash, event) =
+            decode_record(&self.record(location)?).ok_or_else(corrupted_log)?;
+        if &record_hash != hash {
+            return Err(corrupted_log());
+        }
+        if self.max_cached_events > 0 {
+            while self.order.len() >= self.max_cached_events {
+                if let Some(evicted) = self.order.pop_front() {
+                    self.cache.remove(&evicted);
+                }
+            }
+            self.order.push_back(hash.clone());
+            self.cache.insert(hash.clone(), event.clone());
+        }
+        Ok(event)
+    }
+
+    #[inline]
+    fn evict(&mut self, hash: &EventHash) {
+        self.cache.remove(hash);
+    }
+}
+
+// Live are the bytes of the records that are still the last of their event
+#[derive(Default)]
+struct Segment {
+    len: u64,
+    live: u64,
+}
+
+struct SegmentLog {
+    dir: PathBuf,
+    index: File,
+    // Where the last record of every event is
+    locations: HashMap<EventHash, Location>,
+    max_segment_size: u64,
+    reader: Mutex<Reader>,
+    removed: File,
+    segment: File,
+    segment_id: u64,
+    segments: BTreeMap<u64, Segment>,
+}
+
+impl SegmentLog {
+    fn read(&self, hash: &EventHash) -> Result<Event<ParentsPair>, Error> {
+        let location = self.locations.get(hash).ok_or_else(event_not_found)?;
+        get_from_mutex!(self.reader, ResourceHashgraphPoisonError)?.event(hash, location)
+    }
+
+    fn append(&mut self, hash: &EventHash, event: &Event<ParentsPair>) -> Result<(), Error> {
+        let record = encode_record(hash, event)?;
+        self.append_record(hash, &record)?;
+        get_from_mutex!(self.reader, ResourceHashgraphPoisonError)?.evict(hash);
+        Ok(())
+    }
+
+    fn append_record(&mut self, hash: &EventHash, record: &[u8]) -> Result<(), Error> {
+        let record_len = record.len() as u64;
+        let segment_len = self.segments[&self.segment_id].len;
+        if segment_len > 0 && segment_len + record_len > self.max_segment_size {
+            self.roll()?;
+        }
+        self.segment.write_all(record)?;
+        self.segment.sync_data()?;
+        let location = Location {
+            segment: self.segment_id,
+            offset: self.segments[&self.segment_id].len,
+            len: record_len,
+        };
+        if let Some(segment) = self.segments.get_mut(&self.segment_id) {
+            segment.len += record_len;
+            segment.live += record_len;
+        }
+        // The index is written after the record is durable, so a crash in between only
+        // leaves a record that gets re-indexed on the next open
+        self.index.write_all(&encode_index_entry(
+            hash,
+            location.segment,
+            location.offset,
+        )?)?;
+        if let Some(previous) = self.locations.insert(hash.clone(), location) {
+            self.release(&previous);
+        }
+        Ok(())
+    }
+
+    fn remove(&mut self, hash: &EventHash) -> Result<(), Error> {
+        self.removed.write_all(hash.as_ref())?;
+        self.removed.sync_data()?;
+        if let Some(location) = self.locations.remove(hash) {
+            self.release(&location);
+        }
+        get_from_mutex!(self.reader, ResourceHashgraphPoisonError)?.evict(hash);
+        Ok(())
+    }
+
+    #[inline]
+    fn release(&mut self, location: &Location) {
+        if let Some(segment) = self.segments.get_mut(&location.segment) {
+            segment.live -= location.len;
+        }
+    }
+
+    // Copies the live records of the sealed segments that are mostly dead forward, and
+    // deletes them once the index doesn't point to them anymore
+    fn compact(&mut self) -> Result<(), Error> {
+        let active = self.segment_id;
+        let compacted: Vec<u64> = self
+            .segments
+            .iter()
+            .filter(|(id, segment)| **id != active && segment.live * 2 < segment.len.max(1))
+            .map(|(id, _)| *id)
+            .collect();
+        if compacted.is_empty() {
+            return Ok(());
+        }
+        let mut moved: Vec<(EventHash, Location)> = self
+            .locations
+            .iter()
+            .filter(|(_, location)| compacted.contains(&location.segment))
+            .map(|(hash, location)| (hash.clone(), *location))
+            .collect();
+        moved.sort_by_key(|(_, location)| (location.segment, location.offset));
+        for (hash, location) in moved {
+            let record =
+                get_from_mutex!(self.reader, ResourceHashgraphPoisonError)?.record(&location)?;
+            self.append_record(&hash, &record)?;
+        }
+        self.rewrite_index()?;
+        self.removed.set_len(0)?;
+        let mut reader = get_from_mutex!(self.reader, ResourceHashgraphPoisonError)?;
+        for id in compacted {
+            reader.files.remove(&id);
+            self.segments.remove(&id);
+            remove_file(segment_path(&self.dir, id))?;
+        }
+        Ok(())
+    }
+
+    // The new index is durable before it replaces the old one
+    fn rewrite_index(&mut self) -> Result<(), Error> {
+        let mut entries: Vec<(&EventHash, &Location)> = self.locations.iter().collect();
+        entries.sort_by_key(|(_, location)| (location.segment, location.offset));
+        let mut index = Vec::with_capacity((entries.len() + 1) * INDEX_ENTRY_SIZE);
+        for (hash, location) in entries {
+            index.extend(encode_index_entry(hash, location.segment, location.offset)?);
+        }
+        let end = self.segments[&self.segment_id].len;
+        index.extend(encode_index_entry(&end_marker(), self.segment_id, end)?);
+        let tmp_path = self.dir.join(INDEX_TMP_FILE);
+        let mut tmp = File::create(&tmp_path)?;
+        tmp.write_all(&index)?;
+        tmp.sync_all()?;
+        rename(&tmp_path, self.dir.join(INDEX_FILE))?;
+        self.index = open_append(&self.dir.join(INDEX_FILE))?;
+        Ok(())
+    }
+
+    fn roll(&mut self) -> Result<(), Error> {
+        let segment_id = self.segment_id + 1;
+        let path = segment_path(&self.dir, segment_id);
+        self.segment = open_append(&path)?;
+        get_from_mutex!(self.reader, ResourceHashgraphPoisonError)?
+            .files
+            .insert(segment_id, open_append(&path)?);
+        self.segment_id = segment_id;
+        self.segments.insert(segment_id, Segment::default());
+        Ok(())
+    }
+}
+
+// The event handed out by get_mut, written back once the graph is changed again or dropped
+struct Pending {
+    event: Event<ParentsPair>,
+    hash: EventHash,
+    original: Event<ParentsPair>,
+}
+
+pub struct DiskHashgraph {
+    // Where the events are without a log
+    events: HashMap<EventHash, Event<ParentsPair>>,
+    log: Option<SegmentLog>,
+    pending: Option<Pending>,
+    topology: Topology,
+}
+
+impl DiskHashgraph {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<DiskHashgraph, Error> {
+        DiskHashgraph::open_with_limits(path, DEFAULT_SEGMENT_SIZE, DEFAULT_MAX_CACHED_EVENTS)
+    }
+
+    pub fn open_with_segment_size<P: AsRef<Path>>(
+        path: P,
+        max_segment_size: u64,
+    ) -> Result<DiskHashgraph, Error> {
+        DiskHashgraph::open_with_limits(path, max_segment_size, DEFAULT_MAX_CACHED_EVENTS)
+    }
+
+    pub fn open_with_limits<P: AsRef<Path>>(
+        path: P,
+        max_segment_size: u64,
+        max_cached_events: usize,
+    ) -> Result<DiskHashgraph, Error> {
+        let dir = path.as_ref().to_path_buf();
+        create_dir_all(&dir)?;
+        let mut segment_ids = list_segments(&dir)?;
+        if segment_ids.is_empty() {
+            segment_ids.push(0);
+        }
+        let last_segment_id = segment_ids[segment_ids.len() - 1];
+        let mut files = HashMap::new();
+        let mut lens = BTreeMap::new();
+        for id in segment_ids {
+            let file = open_append(&segment_path(&dir, id))?;
+            lens.insert(id, file.metadata()?.len());
+            files.insert(id, file);
+        }
+
+        let mut topology = Topology::default();
+        let mut locations = HashMap::new();
+        let mut index = read_file(&dir.join(INDEX_FILE))?;
+        let indexed_len = index.len() - index.len() % INDEX_ENTRY_SIZE;
+        index.truncate(indexed_len);
+        let mut resume_from = (0, 0);
+        for entry in index.chunks(INDEX_ENTRY_SIZE) {
+            let (hash, segment, offset) = decode_index_entry(entry);
+            if hash == end_marker() {
+                resume_from = (segment, offset);
+                continue;
+            }
+            let file = files.get_mut(&segment).ok_or_else(corrupted_log)?;
+            let (record_hash, event, len) =
+                read_record_at(file, lens[&segment], offset)?.ok_or_else(corrupted_log)?;
+            if record_hash != hash {
+                return Err(corrupted_log());
+            }
+            topology.add(&hash, &event);
+            locations.insert(
+                hash,
+                Location {
+                    segment,
+                    offset,
+                    len,
+                },
+            );
+            resume_from = (segment, offset + len);
+        }
+
+        // Replay records that made it to a segment but not to the index
+        let mut missing_entries = Vec::new();
+        let mut last_segment_len = lens[&last_segment_id];
+        for (id, segment_len) in lens.range(resume_from.0..) {
+            let file = files.get_mut(id).ok_or_else(corrupted_log)?;
+            let mut offset = if *id == resume_from.0 {
+                resume_from.1
+            } else {
+                0
+            };
+            while offset < *segment_len {
+                match read_record_at(file, *segment_len, offset)? {
+                    Some((hash, event, len)) => {
+                        missing_entries.extend(encode_index_entry(&hash, *id, offset)?);
+                        topology.add(&hash, &event);
+                        locations.insert(
+                            hash,
+                            Location {
+                                segment: *id,
+                                offset,
+                                len,
+                            },
+                        );
+                        offset += len;
+                    }
+                    // A torn write can only happen at the tail of the last segment
+                    None if *id == last_segment_id => {
+                        warn!(
+                            "Truncating torn record at offset {} of segment {}",
+                            offset, id
+                        );
+                        last_segment_len = offset;
+                        break;
+                    }
+                    None => return Err(corrupted_log()),
+                }
+            }
+        }
+
+        let mut index_file = open_append(&dir.join(INDEX_FILE))?;
+        index_file.set_len(indexed_len as u64)?;
+        index_file.write_all(&missing_entries)?;
+        let segment = open_append(&segment_path(&dir, last_segment_id))?;
+        segment.set_len(last_segment_len)?;
+
+        let removed = read_file(&dir.join(REMOVED_FILE))?;
+        let removed_len = removed.len() - removed.len() % HASH_SIZE;
+        for hash in removed[..removed_len].chunks(HASH_SIZE) {
+            let hash = EventHash::new(hash);
+            if locations.remove(&hash).is_some() {
+                topology.remove(&hash);
+            }
+        }
+        let removed_file = open_append(&dir.join(REMOVED_FILE))?;
+        removed_file.set_len(removed_len as u64)?;
+
+        let mut segments: BTreeMap<u64, Segment> = lens
+            .into_iter()
+            .map(|(id, len)| (id, Segment { len, live: 0 }))
+            .collect();
+        if let Some(segment) = segments.get_mut(&last_segment_id) {
+            segment.len = last_segment_len;
+        }
+        for location in locations.values() {
+            if let Some(segment) = segments.get_mut(&location.segment) {
+                segment.live += location.len;
+            }
+        }
+
+        let mut log = SegmentLog {
+            dir,
+            index: index_file,
+            locations,
+            max_segment_size,
+            reader: Mutex::new(Reader {
+                cache: HashMap::new(),
+                files,
+                max_cached_events,
+                order: VecDeque::new(),
+            }),
+            removed: removed_file,
+            segment,
+            segment_id: last_segment_id,
+            segments,
+        };
+        log.compact()?;
+        Ok(DiskHashgraph {
+            events: HashMap::new(),
+            log: Some(log),
+            pending: None,
+            topology,
+        })
+    }
+
+    pub fn path(&self) -> Option<&Path> {
+        self.log.as_ref().map(|l| l.dir.as_path())
+    }
+
+    // Writes back the event handed out by get_mut, if it changed
+    fn flush(&mut self) -> Result<(), Error> {
+        let pending = match self.pending.take() {
+            Some(pending) => pending,
+            None => return Ok(()),
+        };
+        match self.log.as_mut() {
+            Some(log) if pending.event != pending.original => {
+                log.append(&pending.hash, &pending.event)?;
+                log.compact()
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+impl Drop for DiskHashgraph {
+    fn drop(&mut self) {
+        if let Err(e) = self.flush() {
+            warn!("Couldn't write back the last changed event: {}", e);
+        }
+    }
+}
+
+impl fmt::Debug for DiskHashgraph {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("DiskHashgraph")
+            .field("path", &self.path())
+            .field("events", &self.topology.vertices.len())
+            .finish()
+    }
+}
+
+impl From<HashgraphWire> for DiskHashgraph {
+    fn from(v: HashgraphWire) -> Self {
+        let mut topology = Topology::default();
+        let mut events = HashMap::new();
+        for (hash, event) in v.0.into_iter() {
+            topology.add(&hash, &event);
+            events.insert(hash, event);
+        }
+        DiskHashgraph {
+            events,
+            log: None,
+            pending: None,
+            topology,
+        }
+    }
+}
+
+impl Hashgraph for DiskHashgraph {
+    // With a log the event is read into memory, and written back on the next change
+    fn get_mut(&mut self, id: &EventHash) -> Result<&mut Event<ParentsPair>, Error> {
+        if self.log.is_none() {
+            return self.events.get_mut(id).ok_or_else(event_not_found);
+        }
+        if self.pending.as_ref().map(|p| &p.hash != id).unwrap_or(true) {
+            self.flush()?;
+            let event = match self.log.as_ref() {
+                Some(log) => log.read(id)?,
+                None => return Err(event_not_found()),
+            };
+            self.pending = Some(Pending {
+                event: event.clone(),
+                hash: id.clone(),
+                original: event,
+            });
+        }
+        match self.pending.as_mut() {
+            Some(pending) => Ok(&mut pending.event),
+            None => Err(event_not_found()),
+        }
+    }
+
+    fn get(&self, id: &EventHash) -> Result<Cow<Event<ParentsPair>>, Error> {
+        if let Some(pending) = self.pending.as_ref().filter(|p| &p.hash == id) {
+            return Ok(Cow::Borrowed(&pending.event));
+        }
+        match self.log.as_ref() {
+            Some(log) => Ok(Cow::Owned(log.read(id)?)),
+            None => self
+                .events
+                .get(id)
+                .map(Cow::Borrowed)
+                .ok_or_else(event_not_found),
+        }
+    }
+
+    fn insert(&mut self, hash: EventHash, event: Event<ParentsPair>) -> Result<(), Error> {
+        self.flush()?;
+        match self.log.as_mut() {
+            Some(log) => {
+                log.append(&hash, &event)?;
+                log.compact()?;
+            }
+            None => {
+                self.events.insert(hash.clone(), event.clone());
+            }
+        }
+        self.topology.add(&hash, &event);
+        Ok(())
+    }
+
+    fn remove(&mut self, id: &EventHash) -> Result<Event<ParentsPair>, Error> {
+        self.flush()?;
+        let event = self.get(id)?.into_owned();
+        match self.log.as_mut() {
+            Some(log) => {
+                log.remove(id)?;
+                log.compact()?;
+            }
+            None => {
+                self.events.remove(id);
+            }
+        }
+        self.topology.remove(id);
+        Ok(event)
+    }
+
+    fn ancestors<'a>(&'a self, id: &'a EventHash) -> Result<Vec<&'a EventHash>, Error> {
+        let mut other_ancestors = self.other_ancestors(id)?;
+        let self_ancestors = self.self_ancestors(id)?;
+        other_ancestors.retain(|h| *h != id);
+        other_ancestors.extend(self_ancestors.into_iter());
+        Ok(other_ancestors)
+    }
+
+    fn other_ancestors<'a>(&'a self, id: &'a EventHash) -> Result<Vec<&'a EventHash>, Error> {
+        self.topology.walk(id, false)
+    }
+
+    fn self_ancestors<'a>(&'a self, id: &'a EventHash) -> Result<Vec<&'a EventHash>, Error> {
+        self.topology.walk(id, true)
+    }
+
+    #[inline]
+    fn higher(&self, a: &EventHash, b: &EventHash) -> Result<bool, Error> {
+        let a_height = self.height(a)?;
+        let b_height = self.height(b)?;
+        Ok(a_height > b_height || (a_height == b_height && a == b))
+    }
+
+    fn events_parents_can_see(
+        &self,
+        hash: &EventHash,
+    ) -> Result<HashMap<PeerId, EventHash>, Error> {
+        let parents = match self.topology.vertices.get(hash) {
+            Some(vertex) => vertex.parents.clone(),
+            None => return Err(event_not_found()),
+        };
+        match parents {
+            Some(ParentsPair(self_parent, other_parent)) => {
+                let self_parent_event = self.get(&self_parent)?;
+                let other_parent_event = self.get(&other_parent)?;
+                let mut result = self_parent_event.can_see().clone();
+                for (k, other) in other_parent_event.can_see().iter() {
+                    let is_higher = match result.get(k) {
+                        Some(value) => self.higher(other, value)?,
+                        None => true,
+                    };
+                    if is_higher {
+                        result.insert(k.clone(), other.clone());
+                    }
+                }
+                Ok(result)
+            }
+            None => Ok(HashMap::new()),
+        }
+    }
+
+    fn difference<H: Hashgraph>(&self, g: H) -> Vec<EventHash> {
+        self.topology
+            .vertices
+            .keys()
+            .filter(|e| !g.contains_key(e))
+            .cloned()
+            .collect()
+    }
+
+    fn is_valid_event(&self, event: &Event<ParentsPair>) -> Result<bool, Error> {
+        match event.parents() {
+            Some(ParentsPair(self_parent, other_parent)) => Ok(
+                match (
+                    self.topology.vertices.get(self_parent),
+                    self.topology.vertices.get(other_parent),
+                ) {
+                    (Some(self_parent), Some(other_parent)) => {
+                        &self_parent.creator == event.creator()
+                            && &other_parent.creator != event.creator()
+                    }
+                    _ => false,
+                },
+            ),
+            None => Ok(true),
+        }
+    }
+
+    fn contains_key(&self, id: &EventHash) -> bool {
+        self.topology.vertices.contains_key(id)
+    }
+
+    // Fails if an event can't be read back, instead of sending the rest
+    fn wire(&self) -> Result<HashgraphWire, Error> {
+        let events = sort_topologically(self, self.get_events())?;
+        self.wire_events(&events)
+    }
+
+    fn find_roots(&self) -> Vec<EventHash> {
+        self.topology
+            .vertices
+            .iter()
+            .filter(|(_, vertex)| vertex.parents.is_none())
+            .map(|(hash, _)| hash.clone())
+            .collect()
+    }
+
+    fn find_self_child(&self, eh: &EventHash) -> Result<Option<EventHash>, Error> {
+        Ok(self.topology.self_children(eh).into_iter().next())
+    }
+
+    fn get_events(&self) -> Vec<EventHash> {
+        self.topology.vertices.keys().cloned().collect()
+    }
+
+    fn height(&self, id: &EventHash) -> Result<usize, Error> {
+        self.topology
+            .heights
+            .get(id)
+            .cloned()
+            .ok_or_else(event_not_found)
+    }
+
+    fn children(&self, id: &EventHash) -> Vec<EventHash> {
+        self.topology.children.get(id).cloned().unwrap_or_default()
+    }
+
+    fn last_event_of(&self, creator: &PeerId) -> Option<EventHash> {
+        self.topology.last_events.get(creator).cloned()
+    }
+
+    fn known_heights(&self) -> KnownHeights {
+        self.topology
+            .last_events
+            .iter()
+            .map(|(creator, last)| (creator.clone(), self.topology.heights[last]))
+            .collect()
+    }
+
+    // Events whose height is unknown hang from a missing self parent, so they're sent too
+    fn missing_events(&self, known: &KnownHeights) -> Result<Vec<EventHash>, Error> {
+        let missing = self
+            .topology
+            .vertices
+            .iter()
+            .filter(|(hash, vertex)| {
+                match (self.topology.heights.get(*hash), known.get(&vertex.creator)) {
+                    (Some(height), Some(known_height)) => height > known_height,
+                    _ => true,
+                }
+            })
+            .map(|(hash, _)| hash.clone())
+            .collect();
+        sort_topologically(self, missing)
+    }
+
+    fn wire_events(&self, events: &[EventHash]) -> Result<HashgraphWire, Error> {
+        let mut wire = Vec::with_capacity(events.len());
+        for hash in events {
+            wire.push((hash.clone(), self.get(hash)?.into_owned()));
+        }
+        Ok(HashgraphWire(wire))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{segment_path, DiskHashgraph, INDEX_FILE, REMOVED_FILE};
+    use crate::event::{event_hash::EventHash, parents::ParentsPair, Event};
+    use crate::hashgraph::Hashgraph;
+    use std::env::temp_dir;
+    use std::fs::{metadata, remove_dir_all, OpenOptions};
+    use std::io::Write;
+    use std::path::PathBuf;
+    use std::process;
+
+    fn create_dir(name: &str) -> PathBuf {
+        let dir = temp_dir().join(format!("lachesis-{}-{}", name, process::id()));
+        let _ = remove_dir_all(&dir);
+        dir
+    }
+
+    fn create_events() -> Vec<(EventHash, Event<ParentsPair>)> {
+        let event1 = Event::new(vec![b"42".to_vec()], None, vec![1]);
+        let hash1 = event1.hash().unwrap();
+        let event2 = Event::new(vec![b"fish".to_vec()], None, vec![2]);
+        let hash2 = event2.hash().unwrap();
+        let event3 = Event::new(
+            vec![b"ford prefect".to_vec()],
+            Some(ParentsPair(hash1.clone(), hash2.clone())),
+            vec![1],
+        );
+        let hash3 = event3.hash().unwrap();
+        vec![(hash1, event1), (hash2, event2), (hash3, event3)]
+    }
+
+    #[test]
+    fn it_should_keep_events_after_reopening() {
+        let dir = create_dir("reopen");
+        let events = create_events();
+        {
+            let mut hashgraph = DiskHashgraph::open(&dir).unwrap();
+            for (hash, event) in events.iter() {
+                hashgraph.insert(hash.clone(), event.clone()).unwrap();
+            }
+        }
+        let hashgraph = DiskHashgraph::open(&dir).unwrap();
+        for (hash, event) in events.iter() {
+            assert_eq!(*hashgraph.get(hash).unwrap(), *event);
+        }
+        let mut expected = vec![&events[0].0, &events[2].0];
+        expected.sort();
+        let mut actual = hashgraph.self_ancestors(&events[2].0).unwrap();
+        actual.sort();
+        assert_eq!(expected, actual);
+        assert_eq!(hashgraph.find_roots().len(), 2);
+        remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn it_should_roll_segments() {
+        let dir = create_dir("roll");
+        let events = create_events();
+        {
+            let mut hashgraph = DiskHashgraph::open_with_segment_size(&dir, 1).unwrap();
+            for (hash, event) in events.iter() {
+                hashgraph.insert(hash.clone(), event.clone()).unwrap();
+            }
+        }
+        assert!(metadata(segment_path(&dir, 2)).is_ok());
+        let hashgraph = DiskHashgraph::open_with_segment_size(&dir, 1).unwrap();
+        assert_eq!(hashgraph.get_events().len(), events.len());
+        remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn it_should_truncate_a_torn_record() {
+        let dir = create_dir("torn");
+        let events = create_events();
+        {
+            let mut hashgraph = DiskHashgraph::open(&dir).unwrap();
+            hashgraph
+                .insert(events[0].0.clone(), events[0].1.clone())
+                .unwrap();
+        }
+        let clean_len = metadata(segment_path(&dir, 0)).unwrap().len();
+        {
+            let mut segment = OpenOptions::new()
+                .append(true)
+                .open(segment_path(&dir, 0))
+                .unwrap();
+            segment
+                .write_all(&[42, 0, 0, 0, 0, 0, 0, 0, 1, 2, 3])
+                .unwrap();
+        }
+        {
+            let mut hashgraph = DiskHashgraph::open(&dir).unwrap();
+            assert_eq!(metadata(segment_path(&dir, 0)).unwrap().len(), clean_len);
+            hashgraph
+                .insert(events[1].0.clone(), events[1].1.clone())
+                .unwrap();
+        }
+        let hashgraph = DiskHashgraph::open(&dir).unwrap();
+        assert!(hashgraph.contains_key(&events[0].0));
+        assert!(hashgraph.contains_key(&events[1].0));
+        remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn it_should_reindex_records_missing_from_the_index() {
+        let dir = create_dir("reindex");
+        let events = create_events();
+        {
+            let mut hashgraph = DiskHashgraph::open(&dir).unwrap();
+            for (hash, event) in events.iter() {
+                hashgraph.insert(hash.clone(), event.clone()).unwrap();
+            }
+        }
+        let index_len = metadata(dir.join(INDEX_FILE)).unwrap().len();
+        OpenOptions::new()
+            .write(true)
+            .open(dir.join(INDEX_FILE))
+            .unwrap()
+            .set_len(index_len / 2)
+            .unwrap();
+        let hashgraph = DiskHashgraph::open(&dir).unwrap();
+        assert_eq!(hashgraph.get_events().len(), events.len());
+        assert_eq!(metadata(dir.join(INDEX_FILE)).unwrap().len(), index_len);
+        remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn copies_should_not_write_to_the_log() {
+        let dir = create_dir("clone");
+        let events = create_events();
+        {
+            let hashgraph = DiskHashgraph::open(&dir).unwrap();
+            let mut copy = DiskHashgraph::from(hashgraph.wire().unwrap());
+            copy.insert(events[0].0.clone(), events[0].1.clone())
+                .unwrap();
+            assert!(copy.path().is_none());
+        }
+        let hashgraph = DiskHashgraph::open(&dir).unwrap();
+        assert!(!hashgraph.contains_key(&events[0].0));
+        remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn it_should_keep_removed_events_out_after_reopening() {
+        let dir = create_dir("remove");
+        let events = create_events();
+        {
+            let mut hashgraph = DiskHashgraph::open(&dir).unwrap();
+            for (hash, event) in events.iter() {
+                hashgraph.insert(hash.clone(), event.clone()).unwrap();
+            }
+            hashgraph.remove(&events[0].0).unwrap();
+        }
+        {
+            let mut removed = OpenOptions::new()
+                .append(true)
+                .open(dir.join(REMOVED_FILE))
+                .unwrap();
+            removed.write_all(&[1, 2, 3]).unwrap();
+        }
+        let hashgraph = DiskHashgraph::open(&dir).unwrap();
+        assert!(!hashgraph.contains_key(&events[0].0));
+        assert!(hashgraph.contains_key(&events[1].0));
+        assert!(hashgraph.contains_key(&events[2].0));
+        assert_eq!(metadata(dir.join(REMOVED_FILE)).unwrap().len(), 32);
+        remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn it_should_keep_changes_made_through_get_mut() {
+        let dir = create_dir("get-mut");
+        let events = create_events();
+        {
+            let mut hashgraph = DiskHashgraph::open(&dir).unwrap();
+            for (hash, event) in events.iter() {
+                hashgraph.insert(hash.clone(), event.clone()).unwrap();
+            }
+            hashgraph
+                .get_mut(&events[0].0)
+                .unwrap()
+                .set_round_received(3);
+            hashgraph.get_mut(&events[1].0).unwrap().set_round(1);
+            assert_eq!(
+                hashgraph.get(&events[0].0).unwrap().round_received(),
+                Some(3)
+            );
+        }
+        let hashgraph = DiskHashgraph::open(&dir).unwrap();
+        assert_eq!(
+            hashgraph.get(&events[0].0).unwrap().round_received(),
+            Some(3)
+        );
+        assert_eq!(hashgraph.get(&events[1].0).unwrap().round().unwrap(), 1);
+        remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn it_should_bound_the_cached_events() {
+        let dir = create_dir("cache");
+        let events = create_events();
+        let mut hashgraph = DiskHashgraph::open_with_limits(&dir, 1024, 2).unwrap();
+        for (hash, event) in events.iter() {
+            hashgraph.insert(hash.clone(), event.clone()).unwrap();
+        }
+        for (hash, event) in events.iter() {
+            assert_eq!(*hashgraph.get(hash).unwrap(), *event);
+        }
+        let cached = match hashgraph.log.as_ref() {
+            Some(log) => log.reader.lock().unwrap().cache.len(),
+            None => panic!("The hashgraph should have a log"),
+        };
+        assert_eq!(cached, 2);
+        drop(hashgraph);
+        remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn it_should_delete_segments_without_live_records() {
+        let dir = create_dir("compact");
+        let events = create_events();
+        {
+            let mut hashgraph = DiskHashgraph::open_with_segment_size(&dir, 1).unwrap();
+            for (hash, event) in events.iter() {
+                hashgraph.insert(hash.clone(), event.clone()).unwrap();
+            }
+            hashgraph.remove(&events[0].0).unwrap();
+            assert!(metadata(segment_path(&dir, 0)).is_err());
+            assert_eq!(metadata(dir.join(REMOVED_FILE)).unwrap().len(), 0);
+            hashgraph
+                .get_mut(&events[1].0)
+                .unwrap()
+                .set_round_received(1);
+        }
+        assert!(metadata(segment_path(&dir, 1)).is_err());
+        let hashgraph = DiskHashgraph::open_with_segment_size(&dir, 1).unwrap();
+        assert!(!hashgraph.contains_key(&events[0].0));
+        assert_eq!(
+            hashgraph.get(&events[1].0).unwrap().round_received(),
+            Some(1)
+        );
+        assert_eq!(*hashgraph.get(&events[2].0).unwrap(), events[2].1);
+        remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,348 +1,649 @@
+use crate::clock::Clock;
 use crate::errors::{
-    HashgraphError, HashgraphErrorType, ResourceFramesPoisonError, ResourceHashgraphPoisonError,
-    ResourceHeadPoisonError,
+    is_timeout, is_unknown_parents, NodeError, NodeErrorType, ResourceConsensusPoisonError,
+    ResourceFramesPoisonError, ResourceHashgraphPoisonError, ResourceHeadPoisonError,
+    ResourceNetworkPoisonError, ResourceSubscribersPoisonError, ResourceTransactionsPoisonError,
 };
 use crate::event::event_hash::EventHash;
+use crate::event::event_signature::EventSignature;
 use crate::event::Event;
+use crate::hashgraph::KnownHeights;
 use crate::lachesis::opera::Opera;
-use crate::node::Node;
-use crate::peer::{Peer, PeerId};
+use crate::node::{FinalizedEvent, Node};
+use crate::peer::{
+    announcement::PeerAnnouncement,
+    peer_score::{PeerScoreReport, ScoringConfig},
+    peer_table::PeerTable,
+    Peer, PeerDiscovery, PeerId,
+};
+use crate::printable_hash::PrintableHash;
+use crate::swirlds::consensus_config::{two_thirds, DEFAULT_MAX_CLOCK_DRIFT};
+use crate::transaction::{TransactionId, TransactionStatus, TransactionStatuses};
+use bincode::{deserialize, serialize};
 use failure::Error;
-use rand::prelude::IteratorRandom;
 use rand::Rng;
 use ring::signature::Ed25519KeyPair;
-use std::collections::{HashMap, HashSet};
-use std::sync::atomic::{AtomicUsize, Ordering};
-use std::sync::Mutex;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::path::PathBuf;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex};
 
 pub mod frame;
 pub mod opera;
 pub mod parents_list;
 
-use self::frame::Frame;
 use self::opera::OperaWire;
 use self::parents_list::ParentsList;
 
+// Every H-th frame of an election, voters without a super majority to follow flip a coin
 const H: usize = 3;
 
-pub struct Lachesis<P: Peer<Opera> + Clone> {
-    current_frame: AtomicUsize,
-    frames: Mutex<Vec<Frame>>,
+// The middle bit of the voter hash
+#[inline]
+fn coin(voter: &EventHash) -> bool {
+    let bytes = voter.as_ref();
+    bytes[bytes.len() / 2] & 1 == 1
+}
+
+// The roots of the frame created by the given validators, in hash order
+fn validator_roots(
+    opera: &Opera,
+    frame: usize,
+    validators: &[PeerId],
+) -> Result<Vec<EventHash>, Error> {
+    let mut roots = Vec::new();
+    for root in opera.roots(frame) {
+        if validators
+            .binary_search(opera.get_event_ref(&root)?.event.creator())
+            .is_ok()
+        {
+            roots.push(root);
+        }
+    }
+    Ok(roots)
+}
+
+// Payloads starting with it are validators proposals, which count when their event creator
+// is a validator
+const VALIDATORS_PROPOSAL: &[u8] = b"lachesis:validators:";
+
+#[inline]
+fn validators_proposal(payload: &[u8]) -> Option<Vec<PeerId>> {
+    if !payload.starts_with(VALIDATORS_PROPOSAL) {
+        return None;
+    }
+    deserialize(&payload[VALIDATORS_PROPOSAL.len()..]).ok()
+}
+
+// The events finalized so far, and the frame whose Atropos is elected next. Proposals are
+// the validators who proposed each set since the last change.
+#[derive(Default)]
+struct FinalizedFrames {
+    creators: HashSet<PeerId>,
+    events: Vec<Event<ParentsList>>,
+    hashes: HashSet<EventHash>,
+    next: usize,
+    proposals: BTreeMap<Vec<PeerId>, BTreeSet<PeerId>>,
+}
+
+pub struct Lachesis<P: Peer<Opera, KnownHeights> + Clone> {
+    clock: Arc<dyn Clock>,
+    // Held while electing and finalizing, so concurrent runs only overlap while syncing
+    consensus: Mutex<()>,
+    frames: Mutex<FinalizedFrames>,
     head: Mutex<Option<EventHash>>,
     k: usize,
-    network: HashMap<PeerId, P>,
+    network: Mutex<PeerTable<P>>,
     opera: Mutex<Opera>,
     pk: Ed25519KeyPair,
+    subscribers: Mutex<Vec<Sender<FinalizedEvent<ParentsList>>>>,
+    transaction_statuses: Mutex<TransactionStatuses>,
+    transactions: Mutex<Vec<Vec<u8>>>,
 }
 
-impl<P: Peer<Opera> + Clone> Lachesis<P> {
-    pub fn new(k: usize, pk: Ed25519KeyPair) -> Lachesis<P> {
-        let frame = Frame::new(0);
-        let current_frame = AtomicUsize::new(frame.id());
-        let frames = Mutex::new(vec![frame]);
-        let network = HashMap::new();
-        let opera = Mutex::new(Opera::new());
-        let head = Mutex::new(None);
-        Lachesis {
-            current_frame,
-            frames,
-            head,
+impl<P: Peer<Opera, KnownHeights> + Clone> Lachesis<P> {
+    // Every node of the network has to start with the same validators
+    pub fn new(
+        k: usize,
+        pk: Ed25519KeyPair,
+        clock: Arc<dyn Clock>,
+        validators: Vec<PeerId>,
+    ) -> Result<Lachesis<P>, Error> {
+        Lachesis::with_scoring(k, pk, clock, validators, ScoringConfig::default())
+    }
+
+    // The node starts with an event of its own, so it has a head to offer its peers
+    pub fn with_scoring(
+        k: usize,
+        pk: Ed25519KeyPair,
+        clock: Arc<dyn Clock>,
+        validators: Vec<PeerId>,
+        scoring: ScoringConfig,
+    ) -> Result<Lachesis<P>, Error> {
+        let network = Mutex::new(PeerTable::with_scoring(scoring, clock.clone()));
+        let mut opera = Opera::new();
+        opera.set_validators(0, validators)?;
+        let node = Lachesis {
+            clock,
+            consensus: Mutex::new(()),
+            frames: Mutex::new(FinalizedFrames::default()),
+            head: Mutex::new(None),
             k,
             network,
-            opera,
+            opera: Mutex::new(opera),
             pk,
+            subscribers: Mutex::new(Vec::new()),
+            transaction_statuses: Mutex::new(TransactionStatuses::default()),
+            transactions: Mutex::new(Vec::new()),
+        };
+        {
+            let mut opera = get_from_mutex!(node.opera, ResourceHashgraphPoisonError)?;
+            node.create_head(&mut opera, None)?;
         }
+        Ok(node)
     }
 
-    pub fn add_peer(&mut self, p: P) {
-        self.network.insert(p.id().clone(), p);
+    pub fn get_id(&self) -> PeerId {
+        self.pk.public_key_bytes().to_vec()
     }
 
-    #[inline]
-    fn select_peers<R: Rng>(&self, rng: &mut R) -> Result<Vec<P>, Error> {
-        Ok(self
-            .network
-            .values()
-            .choose_multiple(rng, self.k - 1)
-            .into_iter()
-            .map(|p| p.clone())
-            .collect())
-    }
-
-    fn sync<R: Rng>(&self, rng: &mut R) -> Result<(), Error> {
-        let peers = self.select_peers(rng)?;
-        let mut opera = get_from_mutex!(self.opera, ResourceHashgraphPoisonError)?;
-        let mut parent_hashes = vec![];
-        let peer_id = self.pk.public_key_bytes().to_vec();
-        for p in peers {
-            let (h, new_events) = p.get_sync(peer_id.clone(), Some(&opera))?;
-            opera.sync(new_events);
-            parent_hashes.push(h);
-        }
-        let parents = ParentsList(parent_hashes);
-        let new_head = Event::new(vec![], Some(parents), peer_id.clone());
-        let new_head_hash = new_head.hash()?;
-        let mut head = get_from_mutex!(self.head, ResourceHeadPoisonError)?;
-        *head = Some(new_head_hash.clone());
-        opera.insert(
-            new_head_hash.clone(),
-            new_head,
-            self.current_frame.load(Ordering::Relaxed),
-        )?;
+    // Peers are only gossiped with, they become validators through a validators proposal
+    pub fn add_peer(&self, p: P) -> Result<(), Error> {
+        let mut network = get_from_mutex!(self.network, ResourceNetworkPoisonError)?;
+        network.insert(p.id().clone(), Arc::new(p));
         Ok(())
     }
 
-    fn root_selection(&self) -> Result<(), Error> {
-        let new_frame = self.assign_new_roots()?;
-        self.maybe_create_new_frame(new_frame)?;
-        Ok(())
+    // Proposes the validators as a transaction. They take over from the frame after the one
+    // where more than 2/3 of the validators proposed them, once every one of them has an
+    // event finalized, so every node switches at the same frame.
+    pub fn propose_validators(&self, mut validators: Vec<PeerId>) -> Result<TransactionId, Error> {
+        validators.sort();
+        validators.dedup();
+        let mut msg = VALIDATORS_PROPOSAL.to_vec();
+        msg.extend(serialize(&validators)?);
+        let mut transactions = get_from_mutex!(self.transactions, ResourceTransactionsPoisonError)?;
+        let id = TransactionId::new(&msg);
+        get_from_mutex!(self.transaction_statuses, ResourceTransactionsPoisonError)?
+            .add_pending(id.clone());
+        transactions.push(msg);
+        Ok(id)
     }
 
-    fn clotho_selection(&self) -> Result<(), Error> {
-        let current_frame_id = self.current_frame.load(Ordering::Relaxed);
-        if current_frame_id > 0 {
-            let (current_frame, previous_frame): (Frame, Frame) =
-                self.get_frame_and_previous_frame(current_frame_id)?;
-            for root in previous_frame.root_set.iter() {
-                let seen_by = self.get_how_many_can_see(&current_frame, root)?;
-                if seen_by > self.network.len() / 3 {
-                    self.set_clotho(root)?;
-                    self.set_clotho_time(root, current_frame_id)?;
-                }
-            }
-        }
-        Ok(())
+    #[inline]
+    fn select_peers<R: Rng>(&self, rng: &mut R) -> Result<Vec<Arc<P>>, Error> {
+        let network = get_from_mutex!(self.network, ResourceNetworkPoisonError)?;
+        network.choose_multiple(rng, self.k - 1)
     }
 
-    fn get_how_many_can_see(
-        &self,
-        current_frame: &Frame,
-        root: &EventHash,
-    ) -> Result<usize, Error> {
-        let opera = get_from_mutex!(self.opera, ResourceHashgraphPoisonError)?;
-        let mut error: Option<Error> = None;
-
-        let count = current_frame
-            .root_set
-            .iter()
-            .map(|eh| match opera.can_see(&*eh, root) {
-                Ok(seen) => Some(seen),
+    fn sync<R: Rng>(&self, rng: &mut R) -> Result<(), Error> {
+        let peers = self.select_peers(rng)?;
+        let peer_id = self.get_id();
+        // The graph isn't held during the requests, as the peers may be syncing with this
+        // node at the same time
+        let known = get_from_mutex!(self.opera, ResourceHashgraphPoisonError)?.known_heights();
+        let mut syncs = Vec::with_capacity(peers.len());
+        let mut failure = None;
+        for p in peers {
+            match p.get_sync(peer_id.clone(), Some(&known)) {
+                Ok(sync) => syncs.push((p, sync)),
+                // The other peers are still synced with
                 Err(e) => {
-                    error = Some(e);
-                    None
+                    warn!(
+                        "[Node {:?}] Sync with {:?} failed: {}",
+                        peer_id.printable_hash(),
+                        p.id().printable_hash(),
+                        e
+                    );
+                    get_from_mutex!(self.network, ResourceNetworkPoisonError)?
+                        .record_failure(p.id(), is_timeout(&e))?;
+                    failure = Some(e);
                 }
-            })
-            .filter(|eh| eh.is_some())
-            .map(|eh| eh.unwrap())
-            .count();
-        if error.is_some() {
-            return Err(error.unwrap());
+            }
+        }
+        if let (true, Some(e)) = (syncs.is_empty(), failure) {
+            return Err(e);
         }
-        Ok(count)
-    }
-
-    fn set_clotho(&self, root: &EventHash) -> Result<(), Error> {
         let mut opera = get_from_mutex!(self.opera, ResourceHashgraphPoisonError)?;
-        opera.set_clotho(root)?;
-        Ok(())
+        // The self parent goes first so the node's own events form a chain
+        let mut parent_hashes: Vec<EventHash> =
+            get_from_mutex!(self.head, ResourceHeadPoisonError)?
+                .iter()
+                .cloned()
+                .collect();
+        // The same drift Swirlds nodes allow by default
+        let max_timestamp = self.clock.now()? + DEFAULT_MAX_CLOCK_DRIFT;
+        for (p, (h, new_events)) in syncs {
+            let rejected = opera.sync(new_events, max_timestamp);
+            let mut network = get_from_mutex!(self.network, ResourceNetworkPoisonError)?;
+            for e in rejected.iter() {
+                warn!("[Node {:?}] {}", peer_id.printable_hash(), e);
+            }
+            let n_invalid = rejected.iter().filter(|e| !is_unknown_parents(e)).count();
+            if n_invalid > 0 {
+                network.record_invalid_events(p.id(), n_invalid)?;
+            }
+            network.record_sync(p.id());
+            // A head that was rejected can't be a parent
+            if opera.contains_key(&h) {
+                parent_hashes.push(h);
+            }
+        }
+        self.create_head(&mut opera, Some(ParentsList(parent_hashes)))
     }
 
-    fn get_frame_and_previous_frame(
-        &self,
-        current_frame_id: usize,
-    ) -> Result<(Frame, Frame), Error> {
-        let frames = get_from_mutex!(self.frames, ResourceFramesPoisonError)?;
-        let current_frame = &frames[current_frame_id];
-        let previous_frame = &frames[current_frame_id - 1];
-        Ok((current_frame.clone(), previous_frame.clone()))
+    // Transactions submitted since the last head are carried in the new one
+    fn create_head(&self, opera: &mut Opera, parents: Option<ParentsList>) -> Result<(), Error> {
+        let transactions = {
+            let mut transactions =
+                get_from_mutex!(self.transactions, ResourceTransactionsPoisonError)?;
+            transactions.drain(..).collect()
+        };
+        let lamport_timestamp = opera.next_lamport_timestamp(&parents)?;
+        let mut new_head = Event::new(transactions, parents, self.get_id());
+        new_head.set_timestamp(self.clock.now()?);
+        new_head.set_lamport_timestamp(lamport_timestamp);
+        let new_head_hash = new_head.hash()?;
+        let signature = self.pk.sign(new_head_hash.as_ref());
+        new_head.sign(EventSignature::new(signature.as_ref()));
+        get_from_mutex!(self.transaction_statuses, ResourceTransactionsPoisonError)?
+            .add_included(&new_head.payload(), &new_head_hash);
+        opera.insert(new_head_hash.clone(), new_head)?;
+        let mut head = get_from_mutex!(self.head, ResourceHeadPoisonError)?;
+        *head = Some(new_head_hash);
+        Ok(())
     }
 
-    fn set_clotho_time(&self, hash: &EventHash, current_frame_id: usize) -> Result<(), Error> {
-        let mut frames = get_from_mutex!(self.frames, ResourceFramesPoisonError)?;
-        let frame = &mut frames[current_frame_id - 1];
-        let current_frame = self.current_frame.load(Ordering::Relaxed);
-        let cloth_frame_id = frame.id();
-        for d in 3..(current_frame - cloth_frame_id) {
-            let previous_frame = cloth_frame_id - d;
-            for root in frame.root_set.clone().iter() {
-                if d == 3 {
-                    self.set_clotho_time_from_event(root, frame)?;
-                } else {
-                    self.set_clotho_from_reslection(hash, root, previous_frame, d)?;
-                }
+    // Elects the Atropos of each frame in turn, for as long as the frames above are enough to
+    // decide it, and finalizes the events under it
+    fn atropos_selection(&self) -> Result<(), Error> {
+        loop {
+            let frame = get_from_mutex!(self.frames, ResourceFramesPoisonError)?.next;
+            let atropos = match self.elect_atropos(frame)? {
+                Some(atropos) => atropos,
+                None => return Ok(()),
+            };
+            if let Some(atropos) = atropos {
+                self.finalize(frame, &atropos)?;
             }
+            get_from_mutex!(self.frames, ResourceFramesPoisonError)?.next = frame + 1;
         }
-        Ok(())
-    }
-
-    fn set_clotho_time_from_event(&self, root: &EventHash, frame: &mut Frame) -> Result<(), Error> {
-        let opera = get_from_mutex!(self.opera, ResourceHashgraphPoisonError)?;
-        let event = opera.get_event(root)?;
-        frame.set_clotho_time(root.clone(), event.lamport_timestamp);
-        Ok(())
     }
 
-    fn set_clotho_from_reslection(
-        &self,
-        hash: &EventHash,
-        root: &EventHash,
-        previous_frame: usize,
-        d: usize,
-    ) -> Result<(), Error> {
-        let mut frames = get_from_mutex!(self.frames, ResourceFramesPoisonError)?;
-        let frame: &mut Frame = &mut frames[previous_frame];
-        let t = self.clotho_time_reselection(frame.root_set.clone())?;
+    // The roots of the next frame vote for the validators whose root they see, and the roots
+    // of every frame after that vote as most of the roots they see did. A validator is decided
+    // once more than 2/3 of the roots a voter sees agree on it, and on every H-th frame the
+    // voters without such a majority flip a coin so the election can't stall. The elected
+    // roots are the Clothos, with their Lamport timestamp as consensus time, and the one with
+    // the lowest is the Atropos. Only the roots of the frame validators are candidates and
+    // voters. None while there aren't enough frames to decide every validator, and Some(None)
+    // if none was elected.
+    fn elect_atropos(&self, frame: usize) -> Result<Option<Option<EventHash>>, Error> {
         let mut opera = get_from_mutex!(self.opera, ResourceHashgraphPoisonError)?;
-
-        let mut error: Option<Error> = None;
-
-        let k = frame
-            .root_set
-            .iter()
-            .map(|h| match opera.get_event(h) {
-                Ok(event) => Some(event.lamport_timestamp),
-                Err(e) => {
-                    error = Some(e);
-                    None
-                }
-            })
-            .filter(|t1| t1.is_some() && t == t1.unwrap())
-            .count();
-        if error.is_some() {
-            return Err(error.unwrap());
-        } else if d % H > 0 {
-            if k > self.network.len() * 2 / 3 {
-                opera.set_consensus_time(hash, t)?;
-            }
-            frame.set_clotho_time(root.clone(), t);
-        } else {
-            let t = frame
-                .root_set
-                .iter()
-                .map(|h: &EventHash| -> Option<usize> {
-                    match opera.get_event(h) {
-                        Ok(event) => Some(event.lamport_timestamp),
-                        Err(e) => {
-                            error = Some(e);
-                            None
+        let validators = opera.validators(frame).to_vec();
+        let super_majority = two_thirds(validators.len());
+        // A forking validator may have several roots in a frame, the lowest hash stands
+        let mut candidates: HashMap<PeerId, EventHash> = HashMap::new();
+        for root in validator_roots(&opera, frame, &validators)? {
+            let creator = opera.get_event_ref(&root)?.event.creator().clone();
+            candidates.entry(creator).or_insert(root);
+        }
+        let mut decided: BTreeMap<PeerId, bool> = BTreeMap::new();
+        let mut votes: HashMap<(EventHash, PeerId), bool> = HashMap::new();
+        let mut voting_frame = frame + 1;
+        while decided.len() < validators.len() && voting_frame < opera.frames() {
+            let round = voting_frame - frame;
+            let previous_roots = validator_roots(&opera, voting_frame - 1, &validators)?;
+            let mut next_votes = HashMap::new();
+            for voter in validator_roots(&opera, voting_frame, &validators)? {
+                let mut seen_roots = Vec::new();
+                if round > 1 {
+                    for root in previous_roots.iter() {
+                        if opera.can_see(&voter, root)? {
+                            seen_roots.push(root.clone());
                         }
                     }
-                })
-                .filter(|h: &Option<usize>| h.is_some())
-                .map(|h: Option<usize>| h.unwrap())
-                .min()
-                .ok_or(Error::from(HashgraphError::new(
-                    HashgraphErrorType::NoLamportTimeSet,
-                )))?;
-            frame.set_clotho_time(root.clone(), t);
+                }
+                for validator in validators.iter() {
+                    let vote = if round == 1 {
+                        match candidates.get(validator) {
+                            Some(candidate) => opera.can_see(&voter, candidate)?,
+                            None => false,
+                        }
+                    } else {
+                        let yes = seen_roots
+                            .iter()
+                            .filter(|r| {
+                                votes.get(&((*r).clone(), validator.clone())) == Some(&true)
+                            })
+                            .count();
+                        let no = seen_roots.len() - yes;
+                        if yes > super_majority {
+                            decided.entry(validator.clone()).or_insert(true);
+                            true
+                        } else if no > super_majority {
+                            decided.entry(validator.clone()).or_insert(false);
+                            false
+                        } else if round % H == 0 {
+                            coin(&voter)
+                        } else {
+                            yes >= no
+                        }
+                    };
+                    next_votes.insert((voter.clone(), validator.clone()), vote);
+                }
+            }
+            votes = next_votes;
+            voting_frame += 1;
         }
-        if error.is_some() {
-            return Err(error.unwrap());
+        if decided.len() < validators.len() {
+            return Ok(None);
         }
-        Ok(())
-    }
-
-    fn clotho_time_reselection(&self, root_set: HashSet<EventHash>) -> Result<usize, Error> {
-        let mut counts: HashMap<usize, usize> = HashMap::with_capacity(root_set.len());
-        let opera = get_from_mutex!(self.opera, ResourceHashgraphPoisonError)?;
-        for r in root_set {
-            let event = opera.get_event(&r)?;
-            let count = counts
-                .get(&event.lamport_timestamp)
-                .map(|v: &usize| v.clone())
-                .unwrap_or(0);
-            counts.insert(event.lamport_timestamp, count + 1);
+        let mut atropos: Option<(usize, EventHash)> = None;
+        for (validator, elected) in decided.iter() {
+            let clotho = match candidates.get(validator) {
+                Some(clotho) if *elected => clotho,
+                _ => continue,
+            };
+            let time = opera.get_event_ref(clotho)?.lamport_timestamp;
+            opera.set_clotho(clotho, time)?;
+            let candidate = (time, clotho.clone());
+            if atropos.as_ref().map(|a| candidate < *a).unwrap_or(true) {
+                atropos = Some(candidate);
+            }
         }
-        let max_count = counts
-            .values()
-            .min()
-            .ok_or(Error::from(HashgraphError::new(
-                HashgraphErrorType::NoLamportTimeSet,
-            )))?
-            .clone();
-        let time = counts
-            .iter()
-            .filter(|(_t, c)| c.clone() == &max_count)
-            .map(|(t, _c)| t.clone())
-            .min()
-            .ok_or(Error::from(HashgraphError::new(
-                HashgraphErrorType::NoLamportTimeSet,
-            )))?;
-        Ok(time)
+        if let Some((_, atropos)) = atropos.as_ref() {
+            opera.set_atropos(atropos)?;
+        }
+        Ok(Some(atropos.map(|(_, atropos)| atropos)))
     }
 
-    fn assign_new_roots(&self) -> Result<Vec<EventHash>, Error> {
+    // The events the Atropos sees that weren't finalized yet go in Lamport order, with hashes
+    // breaking ties, and take its timestamp as their consensus timestamp
+    fn finalize(&self, frame: usize, atropos: &EventHash) -> Result<(), Error> {
         let mut opera = get_from_mutex!(self.opera, ResourceHashgraphPoisonError)?;
-        let mut new_root = vec![];
-        let mut new_frame = vec![];
-        for e in opera.unfamous_events().clone() {
-            let is_root =
-                e.flag_table.is_empty() || e.flag_table.len() > 2 / 3 * self.network.len();
-            if is_root {
-                let hash = e.event.hash()?;
-                new_root.push(hash.clone());
-                if !e.flag_table.is_empty() {
-                    new_frame.push(hash);
-                }
+        let mut finalized = get_from_mutex!(self.frames, ResourceFramesPoisonError)?;
+        let consensus_timestamp = opera.get_event_ref(atropos)?.event.timestamp()?;
+        let mut keys = Vec::new();
+        let mut visited = HashSet::new();
+        let mut to_visit = vec![atropos.clone()];
+        while let Some(h) = to_visit.pop() {
+            if finalized.hashes.contains(&h) || !visited.insert(h.clone()) {
+                continue;
             }
+            let event = opera.get_event_ref(&h)?;
+            if let Some(ps) = event.event.parents() {
+                to_visit.extend(ps.0.iter().cloned());
+            }
+            keys.push((event.lamport_timestamp, h));
         }
-        for h in new_root {
-            opera.set_root(&h)?;
+        keys.sort();
+        let mut transaction_statuses =
+            get_from_mutex!(self.transaction_statuses, ResourceTransactionsPoisonError)?;
+        let mut subscribers = get_from_mutex!(self.subscribers, ResourceSubscribersPoisonError)?;
+        let start = finalized.events.len();
+        for (_, h) in keys.into_iter() {
+            let mut event = opera.get_event_ref(&h)?.event.clone();
+            event.set_consensus_timestamp(consensus_timestamp);
+            event.set_round_received(frame);
+            let consensus_index = finalized.events.len();
+            transaction_statuses.add_committed(
+                &event.payload(),
+                consensus_index,
+                frame,
+                consensus_timestamp,
+            );
+            let finalized_event = FinalizedEvent {
+                consensus_index,
+                event: event.clone(),
+                round_received: frame,
+            };
+            // Subscribers that hung up are dropped
+            subscribers.retain(|subscriber| subscriber.send(finalized_event.clone()).is_ok());
+            finalized.hashes.insert(h);
+            finalized.creators.insert(event.creator().clone());
+            finalized.events.push(event);
         }
-        Ok(new_frame)
+        self.change_validators(&mut opera, &mut finalized, frame, start)
     }
 
-    fn maybe_create_new_frame(&self, new_frame: Vec<EventHash>) -> Result<(), Error> {
-        let mut opera = get_from_mutex!(self.opera, ResourceHashgraphPoisonError)?;
-        if !new_frame.is_empty() {
-            let mut new_current_frame = Frame::new(self.current_frame.load(Ordering::Relaxed) + 1);
-            let new_current_frame_id = new_current_frame.id();
-            self.current_frame
-                .store(new_current_frame_id, Ordering::Relaxed);
-            for h in new_frame {
-                opera.change_frame(&h, new_current_frame_id)?;
-                new_current_frame.add(h);
+    // The proposals in the events finalized, in their order, so every node counts the same
+    // ones. The first set that more than 2/3 of the frame validators proposed and whose
+    // members all have an event finalized takes over from the next frame.
+    fn change_validators(
+        &self,
+        opera: &mut Opera,
+        finalized: &mut FinalizedFrames,
+        frame: usize,
+        start: usize,
+    ) -> Result<(), Error> {
+        let validators = opera.validators(frame).to_vec();
+        let mut proposed = Vec::new();
+        for event in finalized.events[start..].iter() {
+            if validators.binary_search(event.creator()).is_err() {
+                continue;
             }
-            let mut frames = get_from_mutex!(self.frames, ResourceFramesPoisonError)?;
-            frames.push(new_current_frame);
+            for proposal in event
+                .payload()
+                .iter()
+                .filter_map(|p| validators_proposal(p))
+            {
+                proposed.push((proposal, event.creator().clone()));
+            }
+        }
+        for (proposal, creator) in proposed.into_iter() {
+            finalized
+                .proposals
+                .entry(proposal)
+                .or_insert_with(BTreeSet::new)
+                .insert(creator);
+        }
+        let threshold = two_thirds(validators.len());
+        let accepted = finalized
+            .proposals
+            .iter()
+            .find(|(proposal, proposers)| {
+                proposers.len() > threshold
+                    && proposal.iter().all(|v| finalized.creators.contains(v))
+            })
+            .map(|(proposal, _)| proposal.clone());
+        if let Some(accepted) = accepted {
+            opera.set_validators(frame + 1, accepted)?;
+            finalized.proposals.clear();
         }
         Ok(())
     }
 }
 
-impl<P: Peer<Opera> + Clone> Node for Lachesis<P> {
+impl<P: Peer<Opera, KnownHeights> + Clone> Node for Lachesis<P> {
     type D = OperaWire;
+    type K = KnownHeights;
     type P = ParentsList;
     fn run<R: Rng>(&self, rng: &mut R) -> Result<(), Error> {
         self.sync(rng)?;
-        self.root_selection()?;
-        self.clotho_selection()?;
-        Ok(())
+        let _consensus = get_from_mutex!(self.consensus, ResourceConsensusPoisonError)?;
+        self.atropos_selection()
     }
 
-    fn respond_message(&self, known: Option<OperaWire>) -> Result<(EventHash, OperaWire), Error> {
-        let mut opera = get_from_mutex!(self.opera, ResourceHashgraphPoisonError)?;
+    fn respond_message(
+        &self,
+        known: Option<KnownHeights>,
+    ) -> Result<(EventHash, OperaWire), Error> {
+        let opera = get_from_mutex!(self.opera, ResourceHashgraphPoisonError)?;
         let head = get_from_mutex!(self.head, ResourceHeadPoisonError)?;
         let resp = match known {
-            Some(remote) => {
-                if remote.lamport_timestamp > opera.lamport_timestamp {
-                    opera.set_lamport(remote.lamport_timestamp);
-                }
-                opera.diff(remote)
-            }
+            Some(known) => opera.missing_events(&known),
             None => opera.wire(),
         };
         match head.clone() {
             Some(cloned_head) => Ok((cloned_head, resp)),
-            None => Err(format_err!("head.clone() returned None")),
+            None => Err(Error::from(NodeError::new(NodeErrorType::NoHead))),
         }
     }
 
-    fn add_transaction(&self, _msg: Vec<u8>) -> Result<(), Error> {
-        Ok(())
+    // Validators proposals only go through propose_validators
+    fn add_transaction(&self, msg: Vec<u8>) -> Result<TransactionId, Error> {
+        if msg.starts_with(VALIDATORS_PROPOSAL) {
+            return Err(Error::from(NodeError::new(NodeErrorType::ReservedPayload)));
+        }
+        let mut transactions = get_from_mutex!(self.transactions, ResourceTransactionsPoisonError)?;
+        let id = TransactionId::new(&msg);
+        get_from_mutex!(self.transaction_statuses, ResourceTransactionsPoisonError)?
+            .add_pending(id.clone());
+        transactions.push(msg);
+        Ok(id)
+    }
+
+    fn transaction_status(&self, id: &TransactionId) -> Result<Option<TransactionStatus>, Error> {
+        let transaction_statuses =
+            get_from_mutex!(self.transaction_statuses, ResourceTransactionsPoisonError)?;
+        Ok(transaction_statuses.get(id))
     }
 
     fn get_ordered_events(&self) -> Result<Vec<Event<ParentsList>>, Error> {
-        Ok(Vec::new())
+        let frames = get_from_mutex!(self.frames, ResourceFramesPoisonError)?;
+        Ok(frames.events.clone())
+    }
+
+    fn subscribe(&self) -> Result<Receiver<FinalizedEvent<ParentsList>>, Error> {
+        let mut subscribers = get_from_mutex!(self.subscribers, ResourceSubscribersPoisonError)?;
+        let (sender, receiver) = channel();
+        subscribers.push(sender);
+        Ok(receiver)
+    }
+    fn peer_scores(&self) -> Result<Vec<PeerScoreReport>, Error> {
+        let network = get_from_mutex!(self.network, ResourceNetworkPoisonError)?;
+        network.scores()
+    }
+}
+
+impl<P: Peer<Opera, KnownHeights> + Clone> PeerDiscovery<P> for Lachesis<P> {
+    fn add_announced_peer(
+        &self,
+        announcement: PeerAnnouncement,
+        peer: Arc<P>,
+    ) -> Result<bool, Error> {
+        let mut network = get_from_mutex!(self.network, ResourceNetworkPoisonError)?;
+        network.insert_announced(announcement, peer)
+    }
+
+    fn choose_peer<R: Rng>(&self, rng: &mut R) -> Result<Arc<P>, Error> {
+        let network = get_from_mutex!(self.network, ResourceNetworkPoisonError)?;
+        network
+            .choose(rng)?
+            .ok_or_else(|| Error::from(NodeError::new(NodeErrorType::EmptyNetwork)))
+    }
+
+    fn open_peer_table<F>(&self, path: PathBuf, create_peer: F) -> Result<usize, Error>
+    where
+        F: Fn(&PeerAnnouncement) -> Arc<P>,
+    {
+        let mut network = get_from_mutex!(self.network, ResourceNetworkPoisonError)?;
+        network.open(path, create_peer)
+    }
+
+    fn peer_announcements(&self) -> Result<Vec<PeerAnnouncement>, Error> {
+        let network = get_from_mutex!(self.network, ResourceNetworkPoisonError)?;
+        Ok(network.announcements())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::opera::OperaEventType;
+    use super::Lachesis;
+    use crate::node::Node;
+    use crate::simulation::{sim_config::SimConfig, sim_peer::SimPeer, Simulation, LACHESIS_K};
+    use crate::transaction::TransactionStatus;
+
+    #[test]
+    fn it_should_commit_the_transactions_of_its_own_events() {
+        let mut simulation = Simulation::lachesis(SimConfig::default()).unwrap();
+        let node = simulation.nodes()[0].clone();
+        let receiver = node.subscribe().unwrap();
+        let id = node.add_transaction(vec![42]).unwrap();
+        assert_eq!(
+            node.transaction_status(&id).unwrap(),
+            Some(TransactionStatus::Pending)
+        );
+        simulation.run_until(5_000).unwrap();
+        let index = match node.transaction_status(&id).unwrap() {
+            Some(TransactionStatus::Committed(index, _, _)) => index,
+            status => panic!("The transaction wasn't committed: {:?}", status),
+        };
+        let ordered = node.get_ordered_events().unwrap();
+        assert_eq!(ordered[index].payload(), vec![vec![42]]);
+        assert_eq!(receiver.try_iter().count(), ordered.len());
+        // The Atropos comes last among the events under it
+        let atropos = node
+            .opera
+            .lock()
+            .unwrap()
+            .get_event(&ordered.last().unwrap().hash().unwrap())
+            .unwrap();
+        assert_eq!(atropos.event_type(), &OperaEventType::Atropos);
+        assert!(atropos.frame() < node.frames.lock().unwrap().next);
+        assert!(simulation.nodes()[1]
+            .get_ordered_events()
+            .unwrap()
+            .iter()
+            .any(|e| e.payload() == vec![vec![42]]));
+    }
+
+    #[test]
+    fn it_should_only_elect_the_roots_of_the_frame_validators() {
+        let mut simulation = Simulation::new(SimConfig::default(), |kp, clock, ids| {
+            Lachesis::<SimPeer>::new(LACHESIS_K, kp, clock, ids[..3].to_vec())
+        })
+        .unwrap();
+        let outsider = simulation.nodes()[3].get_id();
+        simulation.run_until(5_000).unwrap();
+        simulation.check_consistency().unwrap();
+        let node = simulation.nodes()[0].clone();
+        let ordered = node.get_ordered_events().unwrap();
+        assert!(!ordered.is_empty());
+        assert!(node.opera.lock().unwrap().frames() > 1);
+        let opera = node.opera.lock().unwrap();
+        for event in ordered.iter() {
+            let event_type = opera
+                .get_event_ref(&event.hash().unwrap())
+                .unwrap()
+                .event_type();
+            if event_type == &OperaEventType::Atropos {
+                assert_ne!(event.creator(), &outsider);
+            }
+        }
+    }
+
+    #[test]
+    fn it_should_change_the_validators_at_the_same_frame_on_every_node() {
+        let mut simulation = Simulation::new(SimConfig::default(), |kp, clock, ids| {
+            Lachesis::<SimPeer>::new(LACHESIS_K, kp, clock, ids[..3].to_vec())
+        })
+        .unwrap();
+        let ids: Vec<_> = simulation.nodes().iter().map(|n| n.get_id()).collect();
+        let outsider = ids[3].clone();
+        assert!(simulation.nodes()[3]
+            .add_transaction(super::VALIDATORS_PROPOSAL.to_vec())
+            .is_err());
+        for node in simulation.nodes()[..3].iter() {
+            node.propose_validators(ids.clone()).unwrap();
+        }
+        simulation.run_until(20_000).unwrap();
+        simulation.check_consistency().unwrap();
+        let frames = simulation
+            .nodes()
+            .iter()
+            .map(|n| n.frames.lock().unwrap().next)
+            .min()
+            .unwrap();
+        let first = simulation.nodes()[0].opera.lock().unwrap();
+        assert!(first.validators(frames).contains(&outsider));
+        for node in simulation.nodes()[1..].iter() {
+            let opera = node.opera.lock().unwrap();
+            for frame in 0..=frames {
+                assert_eq!(opera.validators(frame), first.validators(frame));
+            }
+        }
     }
 }
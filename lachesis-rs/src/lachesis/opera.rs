@@ -1,237 +1,799 @@
+use super::frame::Frame;
 use super::parents_list::ParentsList;
-use crate::errors::{HashgraphError, HashgraphErrorType};
+use crate::errors::{EventError, EventErrorType, HashgraphError, HashgraphErrorType};
 use crate::event::event_hash::EventHash;
 use crate::event::Event;
+use crate::hashgraph::KnownHeights;
+use crate::peer::PeerId;
+use crate::swirlds::consensus_config::two_thirds;
 use failure::Error;
+use std::cmp::max;
 use std::collections::{BTreeMap, HashMap, HashSet};
 use std::iter::FromIterator;
 
-#[derive(Clone, Deserialize, PartialEq, Serialize)]
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
 pub enum OperaEventType {
+    Atropos,
+    // A root elected by the roots above it, along with its consensus time
     Clotho(Option<usize>),
     Root,
     Undefined,
 }
 
+impl Default for OperaEventType {
+    fn default() -> OperaEventType {
+        OperaEventType::Undefined
+    }
+}
+
+// What an event knows of each creator: its highest event, along with that event height in
+// the creator's chain, and whether the creator forked. It's always computed locally.
+#[derive(Clone, Default)]
+struct Ancestry {
+    clock: HashMap<PeerId, (usize, EventHash)>,
+    forkers: HashSet<PeerId>,
+}
+
 #[derive(Clone, Deserialize, Serialize)]
 pub struct OperaEvent {
+    #[serde(skip)]
+    ancestry: Ancestry,
     pub event: Event<ParentsList>,
-    pub flag_table: HashSet<EventHash>,
+    // Frames and types are worked out locally too, so peers can't make them up
+    #[serde(skip)]
     frame: usize,
+    // The one signed in the event, once checked against its parents
+    #[serde(skip)]
     pub lamport_timestamp: usize,
+    #[serde(skip)]
     event_type: OperaEventType,
 }
 
+impl OperaEvent {
+    pub fn frame(&self) -> usize {
+        self.frame
+    }
+
+    pub fn event_type(&self) -> &OperaEventType {
+        &self.event_type
+    }
+}
+
+// The event has to be the one its hash was computed from, signed by its creator
+#[inline]
+fn verify(hash: &EventHash, event: &Event<ParentsList>) -> Result<(), Error> {
+    if event.hash()? != *hash {
+        return Err(Error::from(EventError::new(EventErrorType::HashMismatch {
+            hash: hash.clone(),
+        })));
+    }
+    event
+        .signature()?
+        .verify(event, event.creator())
+        .map_err(|_| {
+            Error::from(EventError::new(EventErrorType::InvalidSignature {
+                hash: hash.clone(),
+            }))
+        })
+}
+
+// Events from too far in the future would drag the consensus timestamps with them
+#[inline]
+fn check_timestamp(
+    hash: &EventHash,
+    event: &Event<ParentsList>,
+    max_timestamp: u64,
+) -> Result<(), Error> {
+    if event.timestamp()? > max_timestamp {
+        return Err(Error::from(EventError::new(
+            EventErrorType::TooFarInTheFuture { hash: hash.clone() },
+        )));
+    }
+    Ok(())
+}
+
 pub struct Opera {
+    frames: Vec<Frame>,
     graph: HashMap<EventHash, OperaEvent>,
-    pub lamport_timestamp: usize,
+    // The events of each creator at each height, more than one means it forked
+    heights: HashMap<(PeerId, usize), Vec<EventHash>>,
+    // Each set applies from its frame until the next one
+    validators: BTreeMap<usize, Vec<PeerId>>,
 }
 
 impl Opera {
     pub fn new() -> Opera {
         let graph = HashMap::new();
         Opera {
+            frames: Vec::new(),
             graph,
-            lamport_timestamp: 0,
+            heights: HashMap::new(),
+            validators: BTreeMap::new(),
         }
     }
 
-    pub fn sync(&mut self, other: Opera) {
-        for (eh, ev) in other.graph {
-            self.graph.insert(eh, ev);
+    // Events that don't match their hash, aren't signed by their creator, are timestamped
+    // after max_timestamp or whose parents are unknown are left out, and the reasons they were
+    // returned
+    pub fn sync(&mut self, other: Opera, max_timestamp: u64) -> Vec<Error> {
+        let mut rejected = Vec::new();
+        let mut pending: HashMap<EventHash, OperaEvent> = HashMap::new();
+        for (eh, ev) in other.graph.into_iter() {
+            if self.graph.contains_key(&eh) {
+                continue;
+            }
+            match verify(&eh, &ev.event)
+                .and_then(|_| check_timestamp(&eh, &ev.event, max_timestamp))
+            {
+                Ok(()) => {
+                    pending.insert(eh, ev);
+                }
+                Err(e) => rejected.push(e),
+            }
         }
-        if self.lamport_timestamp < other.lamport_timestamp {
-            self.lamport_timestamp = other.lamport_timestamp;
+        // Events are added parents first, so every clock and frame can be built from its
+        // parents'
+        loop {
+            let ready: Vec<EventHash> = pending
+                .iter()
+                .filter(|(_, ev)| match ev.event.parents() {
+                    Some(ps) => ps.0.iter().all(|p| self.graph.contains_key(p)),
+                    None => true,
+                })
+                .map(|(eh, _)| eh.clone())
+                .collect();
+            if ready.is_empty() {
+                break;
+            }
+            for eh in ready {
+                if let Some(ev) = pending.remove(&eh) {
+                    if let Err(e) = self.insert(eh, ev.event) {
+                        rejected.push(e);
+                    }
+                }
+            }
         }
+        for (eh, _) in pending.into_iter() {
+            rejected.push(Error::from(EventError::new(
+                EventErrorType::UnknownParents { hash: eh },
+            )));
+        }
+        rejected
     }
 
     pub fn wire(&self) -> OperaWire {
         OperaWire {
             graph: BTreeMap::from_iter(self.graph.clone().into_iter()),
-            lamport_timestamp: self.lamport_timestamp,
         }
     }
 
-    pub fn insert(
-        &mut self,
-        hash: EventHash,
-        event: Event<ParentsList>,
-        frame: usize,
-    ) -> Result<(), Error> {
-        self.lamport_timestamp += 1;
-        let flag_table = match event.parents() {
-            None => HashSet::with_capacity(0),
-            Some(ps) => self.parent_list_to_flag_table(ps)?,
-        };
+    // Every parent has to be there already, and the event Lamport timestamp has to be one
+    // above the highest of theirs
+    pub fn insert(&mut self, hash: EventHash, event: Event<ParentsList>) -> Result<(), Error> {
+        let lamport_timestamp = self.next_lamport_timestamp(event.parents())?;
+        if event.lamport_timestamp() != Some(lamport_timestamp) {
+            return Err(Error::from(EventError::new(
+                EventErrorType::InvalidLamportTimestamp { hash },
+            )));
+        }
+        let ancestry = self.ancestry(&hash, &event)?;
+        let (frame, is_root) = self.frame_of(&event, &ancestry)?;
+        let event_type = self.place(&hash, frame, is_root);
+        let height = ancestry.clock[event.creator()].0;
+        self.heights
+            .entry((event.creator().clone(), height))
+            .or_insert_with(Vec::new)
+            .push(hash.clone());
         self.graph.insert(
             hash,
             OperaEvent {
+                ancestry,
                 event,
-                flag_table,
                 frame,
-                event_type: OperaEventType::Undefined,
-                lamport_timestamp: self.lamport_timestamp,
+                lamport_timestamp,
+                event_type,
             },
         );
         Ok(())
     }
 
-    pub fn unfamous_events(&self) -> Vec<&OperaEvent> {
-        self.graph
-            .values()
-            .filter(|e| e.event_type != OperaEventType::Root)
-            .collect()
+    // The one an event with the given parents gets, 0 for events without any
+    pub fn next_lamport_timestamp(&self, parents: &Option<ParentsList>) -> Result<usize, Error> {
+        let mut lamport_timestamp = 0;
+        if let Some(ps) = parents {
+            for p in ps.0.iter() {
+                lamport_timestamp = max(
+                    lamport_timestamp,
+                    self.get_event_ref(p)?.lamport_timestamp + 1,
+                );
+            }
+        }
+        Ok(lamport_timestamp)
+    }
+
+    pub fn contains_key(&self, h: &EventHash) -> bool {
+        self.graph.contains_key(h)
     }
 
-    pub fn get_event_mut(&mut self, h: &EventHash) -> Result<&mut OperaEvent, Error> {
-        self.graph.get_mut(h).ok_or(Error::from(HashgraphError::new(
+    pub fn get_event(&self, h: &EventHash) -> Result<OperaEvent, Error> {
+        self.get_event_ref(h).map(|v| v.clone())
+    }
+
+    pub fn get_event_ref(&self, h: &EventHash) -> Result<&OperaEvent, Error> {
+        self.graph.get(h).ok_or(Error::from(HashgraphError::new(
             HashgraphErrorType::EventNotFound,
         )))
     }
 
-    pub fn get_event(&self, h: &EventHash) -> Result<OperaEvent, Error> {
-        self.graph
-            .get(h)
-            .map(|v| v.clone())
-            .ok_or(Error::from(HashgraphError::new(
-                HashgraphErrorType::EventNotFound,
-            )))
+    // How many frames have roots so far
+    pub fn frames(&self) -> usize {
+        self.frames.len()
     }
 
-    pub fn set_root(&mut self, h: &EventHash) -> Result<(), Error> {
-        let mut e = self
-            .graph
-            .get_mut(h)
-            .ok_or(Error::from(HashgraphError::new(
-                HashgraphErrorType::EventNotFound,
-            )))?;
-        e.event_type = OperaEventType::Root;
-        e.flag_table = HashSet::new();
+    // The validators apply from the frame on, until the next frame they're set for. The
+    // events placed above the roots of that frame or later ones are placed again, in Lamport
+    // order so parents go first, as the roots they see may count differently now.
+    pub fn set_validators(
+        &mut self,
+        frame: usize,
+        mut validators: Vec<PeerId>,
+    ) -> Result<(), Error> {
+        validators.sort();
+        validators.dedup();
+        self.validators.insert(frame, validators);
+        let mut moved = Vec::new();
+        for (h, ev) in self.graph.iter() {
+            if self.parents_frame(&ev.event)?.map(|f| f >= frame) == Some(true) {
+                moved.push((ev.lamport_timestamp, h.clone()));
+            }
+        }
+        moved.sort();
+        for (_, h) in moved.iter() {
+            let old_frame = self.get_event_ref(h)?.frame;
+            if let Some(f) = self.frames.get_mut(old_frame) {
+                f.root_set.remove(h);
+            }
+        }
+        for (_, h) in moved.into_iter() {
+            let (frame, is_root) = {
+                let ev = self.get_event_ref(&h)?;
+                self.frame_of(&ev.event, &ev.ancestry)?
+            };
+            let event_type = self.place(&h, frame, is_root);
+            let ev = self.get_event_mut(&h)?;
+            ev.frame = frame;
+            ev.event_type = event_type;
+        }
+        while self
+            .frames
+            .last()
+            .map(|f| f.root_set.is_empty())
+            .unwrap_or(false)
+        {
+            self.frames.pop();
+        }
         Ok(())
     }
 
-    pub fn set_clotho(&mut self, h: &EventHash) -> Result<(), Error> {
-        let mut e = self
-            .graph
-            .get_mut(h)
-            .ok_or(Error::from(HashgraphError::new(
-                HashgraphErrorType::EventNotFound,
-            )))?;
-        e.event_type = OperaEventType::Clotho(None);
-        Ok(())
+    // Sorted, so every node goes through them the same way
+    pub fn validators(&self, frame: usize) -> &[PeerId] {
+        self.validators
+            .range(..=frame)
+            .next_back()
+            .map(|(_, validators)| validators.as_slice())
+            .unwrap_or(&[])
     }
 
-    pub fn set_consensus_time(&mut self, h: &EventHash, time: usize) -> Result<(), Error> {
-        let mut e = self.get_event_mut(h)?;
-        e.event_type = OperaEventType::Clotho(Some(time));
-        Ok(())
+    // In hash order, so every node goes through them the same way
+    pub fn roots(&self, frame: usize) -> Vec<EventHash> {
+        let mut roots: Vec<EventHash> = self
+            .frames
+            .get(frame)
+            .map(|f| f.root_set.iter().cloned().collect())
+            .unwrap_or_default();
+        roots.sort();
+        roots
     }
 
-    pub fn change_frame(&mut self, h: &EventHash, frame: usize) -> Result<(), Error> {
-        let mut e = self
-            .graph
-            .get_mut(h)
-            .ok_or(Error::from(HashgraphError::new(
-                HashgraphErrorType::EventNotFound,
-            )))?;
-        e.frame = frame;
+    pub fn set_clotho(&mut self, h: &EventHash, time: usize) -> Result<(), Error> {
+        self.get_event_mut(h)?.event_type = OperaEventType::Clotho(Some(time));
         Ok(())
     }
 
-    fn parent_list_to_flag_table(&mut self, ps: &ParentsList) -> Result<HashSet<EventHash>, Error> {
-        let mut ft = HashSet::new();
-        for p in ps.0.iter() {
-            let event = self
-                .graph
-                .get(p)
-                .ok_or(Error::from(HashgraphError::new(
-                    HashgraphErrorType::EventNotFound,
-                )))?
-                .clone();
-            if event.event_type == OperaEventType::Root {
-                ft.insert(p.clone());
-            }
-            ft = ft.union(&event.flag_table).map(|e| e.clone()).collect();
-        }
-        Ok(ft)
+    pub fn set_atropos(&mut self, h: &EventHash) -> Result<(), Error> {
+        self.get_event_mut(h)?.event_type = OperaEventType::Atropos;
+        Ok(())
     }
 
-    pub fn set_lamport(&mut self, lamport_timestamp: usize) {
-        self.lamport_timestamp = lamport_timestamp;
+    // The height of the highest event of every creator, what a requester tells its peers
+    pub fn known_heights(&self) -> KnownHeights {
+        let mut known = KnownHeights::new();
+        for (creator, height) in self.heights.keys() {
+            let highest = known.entry(creator.clone()).or_insert(*height);
+            *highest = max(*highest, *height);
+        }
+        known
     }
 
-    pub fn diff(&self, wire: OperaWire) -> OperaWire {
-        let local_keys: Vec<&EventHash> = self.graph.keys().collect();
-        let remote_keys: Vec<&EventHash> = wire.graph.keys().collect();
-        let diff_keys = local_keys
-            .into_iter()
-            .filter(|k| !remote_keys.contains(k))
-            .map(|k| match self.graph.get(k) {
-                Some(graph_at_k) => Some((k.clone(), graph_at_k.clone())),
-                None => None,
+    // The events above the heights the requester knows. A forked creator is only known up to
+    // its highest branch, so the events of the others may arrive without their parents.
+    pub fn missing_events(&self, known: &KnownHeights) -> OperaWire {
+        let graph = self
+            .graph
+            .iter()
+            .filter(|(_, ev)| {
+                let creator = ev.event.creator();
+                match (ev.ancestry.clock.get(creator), known.get(creator)) {
+                    (Some((height, _)), Some(known_height)) => height > known_height,
+                    _ => true,
+                }
             })
-            .filter(|k| k.is_some())
-            .map(|k| k.unwrap())
+            .map(|(hash, ev)| (hash.clone(), ev.clone()))
             .collect();
-        OperaWire {
-            graph: diff_keys,
-            lamport_timestamp: self.lamport_timestamp,
-        }
+        OperaWire { graph }
     }
 
+    // Events don't see the ones of creators that forked among their ancestors
     pub fn can_see(&self, seer: &EventHash, seen: &EventHash) -> Result<bool, Error> {
         if seer == seen {
-            Ok(true)
-        } else {
-            let ancestors = self.get_ancestors(seer)?;
-            Ok(ancestors.contains(seen))
+            return Ok(true);
         }
+        let seer_event = self.get_event_ref(seer)?;
+        let seen_event = self.get_event_ref(seen)?;
+        self.sees(&seer_event.ancestry, seen_event)
     }
 
-    fn get_ancestors(&self, hash: &EventHash) -> Result<Vec<EventHash>, Error> {
-        let event = self
-            .graph
-            .get(hash)
-            .ok_or(Error::from(HashgraphError::new(
-                HashgraphErrorType::EventNotFound,
-            )))?
-            .clone();
-        let result = match event.event.parents() {
-            None => vec![],
-            Some(p) => {
-                let mut base = p.0.clone();
-                let mut prev =
-                    p.0.iter()
-                        .map(|ph| match self.get_ancestors(ph) {
-                            Ok(ancestors) => Some(ancestors),
-                            Err(e) => {
-                                debug!(target: "swirlds", "{}", e);
-                                return None;
-                            }
-                        })
-                        .filter(|ph| ph.is_some())
-                        .map(|v| v.unwrap().into_iter())
-                        .flatten()
-                        .collect();
-                base.append(&mut prev);
-                base
+    fn sees(&self, seer: &Ancestry, seen: &OperaEvent) -> Result<bool, Error> {
+        let creator = seen.event.creator();
+        if seer.forkers.contains(creator) {
+            return Ok(false);
+        }
+        match (seer.clock.get(creator), seen.ancestry.clock.get(creator)) {
+            (Some(highest), Some(seen)) => self.is_in_chain(creator, highest, seen),
+            _ => Ok(false),
+        }
+    }
+
+    // Whether the low event is the high one or one of its self ancestors. When the creator
+    // has a single event at that height it has to be, otherwise the self parents are walked.
+    fn is_in_chain(
+        &self,
+        creator: &PeerId,
+        high: &(usize, EventHash),
+        low: &(usize, EventHash),
+    ) -> Result<bool, Error> {
+        if high.0 < low.0 {
+            return Ok(false);
+        }
+        let at_height = self.heights.get(&(creator.clone(), low.0));
+        if at_height.map(|events| events.len() == 1) == Some(true) {
+            return Ok(true);
+        }
+        let mut current = high.1.clone();
+        for _ in low.0..high.0 {
+            current = self.get_event_ref(&current)?.event.self_parent()?;
+        }
+        Ok(current == low.1)
+    }
+
+    #[inline]
+    fn get_event_mut(&mut self, h: &EventHash) -> Result<&mut OperaEvent, Error> {
+        self.graph.get_mut(h).ok_or(Error::from(HashgraphError::new(
+            HashgraphErrorType::EventNotFound,
+        )))
+    }
+
+    // Roots are added to their frame
+    fn place(&mut self, hash: &EventHash, frame: usize, is_root: bool) -> OperaEventType {
+        if !is_root {
+            return OperaEventType::Undefined;
+        }
+        while self.frames.len() <= frame {
+            self.frames.push(Frame::default());
+        }
+        self.frames[frame].add(hash.clone());
+        OperaEventType::Root
+    }
+
+    // An event is a frame above its parents when it sees more than 2/3 of the roots of their
+    // frame, counting only the ones created by that frame validators, and the first event of
+    // its creator in a frame is a root of it. It only depends on the event ancestors, so every
+    // node puts it in the same frame.
+    fn frame_of(
+        &self,
+        event: &Event<ParentsList>,
+        ancestry: &Ancestry,
+    ) -> Result<(usize, bool), Error> {
+        let frame = match self.parents_frame(event)? {
+            Some(frame) => frame,
+            None => return Ok((0, true)),
+        };
+        let validators = self.validators(frame);
+        let mut seen_roots = 0;
+        for root in self.roots(frame).iter().filter_map(|r| self.graph.get(r)) {
+            if validators.binary_search(root.event.creator()).is_ok()
+                && self.sees(ancestry, root)?
+            {
+                seen_roots += 1;
             }
+        }
+        if seen_roots > two_thirds(validators.len()) {
+            return Ok((frame + 1, true));
+        }
+        let self_parent = self.get_event_ref(&event.self_parent()?)?;
+        Ok((
+            frame,
+            self_parent.event.creator() != event.creator() || self_parent.frame < frame,
+        ))
+    }
+
+    // The highest frame among the event parents, None for events without any
+    fn parents_frame(&self, event: &Event<ParentsList>) -> Result<Option<usize>, Error> {
+        let parents = match event.parents() {
+            Some(ps) if !ps.0.is_empty() => ps,
+            _ => return Ok(None),
         };
-        Ok(result)
+        let mut frame = 0;
+        for p in parents.0.iter() {
+            frame = max(frame, self.get_event_ref(p)?.frame);
+        }
+        Ok(Some(frame))
+    }
+
+    // An event height is one above its self parent, or 0 if its first parent isn't one. A
+    // creator forked once two of its events aren't in the same chain: the event can be below
+    // what its parents know of its own creator, or its parents can know of different branches.
+    fn ancestry(&self, hash: &EventHash, event: &Event<ParentsList>) -> Result<Ancestry, Error> {
+        let creator = event.creator();
+        let mut ancestry = Ancestry::default();
+        let mut height = 0;
+        if let Some(ps) = event.parents() {
+            for (i, p) in ps.0.iter().enumerate() {
+                let parent = self.get_event_ref(p)?;
+                if i == 0 && parent.event.creator() == creator {
+                    height = parent.ancestry.clock[creator].0 + 1;
+                }
+                ancestry
+                    .forkers
+                    .extend(parent.ancestry.forkers.iter().cloned());
+                for (c, last) in parent.ancestry.clock.iter() {
+                    let known = match ancestry.clock.get(c) {
+                        Some(known) => known.clone(),
+                        None => {
+                            ancestry.clock.insert(c.clone(), last.clone());
+                            continue;
+                        }
+                    };
+                    let (low, high) = if known.0 <= last.0 {
+                        (known, last.clone())
+                    } else {
+                        (last.clone(), known)
+                    };
+                    if !self.is_in_chain(c, &high, &low)? {
+                        ancestry.forkers.insert(c.clone());
+                    }
+                    ancestry.clock.insert(c.clone(), high);
+                }
+            }
+        }
+        if ancestry.clock.get(creator).map(|known| known.0 >= height) == Some(true) {
+            ancestry.forkers.insert(creator.clone());
+        }
+        ancestry
+            .clock
+            .insert(creator.clone(), (height, hash.clone()));
+        Ok(ancestry)
     }
 }
 
 #[derive(Deserialize, Serialize)]
 pub struct OperaWire {
     graph: BTreeMap<EventHash, OperaEvent>,
-    pub lamport_timestamp: usize,
 }
 
 impl OperaWire {
     pub fn into_opera(self) -> Opera {
         Opera {
+            frames: Vec::new(),
             graph: HashMap::from_iter(self.graph.into_iter()),
-            lamport_timestamp: self.lamport_timestamp,
+            heights: HashMap::new(),
+            validators: BTreeMap::new(),
         }
     }
 }
+
+proptest! {
+    #[test]
+    fn can_see_should_match_the_ancestors_traversal(
+        steps in proptest::collection::vec(
+            (0u8..4, proptest::collection::vec(0usize..1000, 0..3)),
+            1..30
+        )
+    ) {
+        let mut opera = Opera::new();
+        opera.set_validators(0, (0u8..4).map(|c| vec![c]).collect()).unwrap();
+        let mut hashes: Vec<EventHash> = Vec::new();
+        let mut last_of_creator: HashMap<u8, EventHash> = HashMap::new();
+        for (i, (creator, picks)) in steps.iter().enumerate() {
+            let mut parents: Vec<EventHash> =
+                last_of_creator.get(creator).into_iter().cloned().collect();
+            if !hashes.is_empty() {
+                parents.extend(picks.iter().map(|p| hashes[p % hashes.len()].clone()));
+            }
+            let parents = if parents.is_empty() { None } else { Some(ParentsList(parents)) };
+            let mut event = Event::new(vec![i.to_string().into_bytes()], parents, vec![*creator]);
+            event.set_lamport_timestamp(opera.next_lamport_timestamp(event.parents()).unwrap());
+            let hash = event.hash().unwrap();
+            opera.insert(hash.clone(), event).unwrap();
+            last_of_creator.insert(*creator, hash.clone());
+            hashes.push(hash);
+        }
+        for seer in hashes.iter() {
+            let mut ancestors: std::collections::HashSet<EventHash> =
+                std::collections::HashSet::new();
+            let mut to_visit = vec![seer.clone()];
+            while let Some(h) = to_visit.pop() {
+                if ancestors.insert(h.clone()) {
+                    if let Some(ps) = opera.get_event(&h).unwrap().event.parents() {
+                        to_visit.extend(ps.0.iter().cloned());
+                    }
+                }
+            }
+            for seen in hashes.iter() {
+                assert_eq!(ancestors.contains(seen), opera.can_see(seer, seen).unwrap());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Ancestry, Opera, OperaEvent, OperaEventType};
+    use crate::errors::{EventError, EventErrorType};
+    use crate::event::{event_hash::EventHash, event_signature::EventSignature, Event};
+    use crate::hashgraph::KnownHeights;
+    use crate::lachesis::parents_list::ParentsList;
+    use ring::{rand, signature};
+
+    fn create_signed_event(
+        kp: &signature::Ed25519KeyPair,
+        creator: &signature::Ed25519KeyPair,
+        payload: &[u8],
+        parents: Option<ParentsList>,
+        lamport_timestamp: usize,
+    ) -> (EventHash, Event<ParentsList>) {
+        let mut event = Event::new(
+            vec![payload.to_vec()],
+            parents,
+            creator.public_key_bytes().to_vec(),
+        );
+        event.set_lamport_timestamp(lamport_timestamp);
+        event.set_timestamp(0);
+        let hash = event.hash().unwrap();
+        event.sign(EventSignature::new(kp.sign(hash.as_ref()).as_ref()));
+        (hash, event)
+    }
+
+    fn create_key_pair() -> signature::Ed25519KeyPair {
+        let pkcs8_bytes =
+            signature::Ed25519KeyPair::generate_pkcs8(&rand::SystemRandom::new()).unwrap();
+        signature::Ed25519KeyPair::from_pkcs8(untrusted::Input::from(&pkcs8_bytes)).unwrap()
+    }
+
+    fn opera_event(event: Event<ParentsList>) -> OperaEvent {
+        OperaEvent {
+            ancestry: Ancestry::default(),
+            event,
+            frame: 0,
+            lamport_timestamp: 0,
+            event_type: OperaEventType::Undefined,
+        }
+    }
+
+    #[test]
+    fn it_should_only_sync_signed_events_with_known_parents() {
+        let kp = create_key_pair();
+        let forger = create_key_pair();
+        let (genesis_hash, genesis) = create_signed_event(&kp, &kp, b"0", None, 0);
+        let after_genesis = || Some(ParentsList(vec![genesis_hash.clone()]));
+        let (valid_hash, valid) = create_signed_event(&kp, &kp, b"1", after_genesis(), 1);
+        let (mismatch_hash, _) = create_signed_event(&kp, &kp, b"2", after_genesis(), 1);
+        let (_, mismatch) = create_signed_event(&kp, &kp, b"3", after_genesis(), 1);
+        let (forged_hash, forged) = create_signed_event(&forger, &kp, b"4", after_genesis(), 1);
+        let mut unsigned = Event::new(vec![b"5".to_vec()], after_genesis(), vec![1]);
+        unsigned.set_lamport_timestamp(1);
+        let unsigned_hash = unsigned.hash().unwrap();
+        let (unknown_hash, _) = create_signed_event(&kp, &kp, b"6", None, 0);
+        let (orphan_hash, orphan) =
+            create_signed_event(&kp, &kp, b"7", Some(ParentsList(vec![unknown_hash])), 1);
+        let mut remote = Opera::new();
+        for (hash, event) in vec![
+            (genesis_hash.clone(), genesis),
+            (valid_hash.clone(), valid),
+            (mismatch_hash.clone(), mismatch),
+            (forged_hash.clone(), forged),
+            (unsigned_hash.clone(), unsigned),
+            (orphan_hash.clone(), orphan),
+        ] {
+            remote.graph.insert(hash, opera_event(event));
+        }
+        let mut opera = Opera::new();
+        let rejected = opera.sync(remote, 0);
+        assert!(opera.contains_key(&genesis_hash));
+        assert!(opera.contains_key(&valid_hash));
+        for hash in vec![mismatch_hash, forged_hash, unsigned_hash, orphan_hash] {
+            assert!(!opera.contains_key(&hash));
+        }
+        let mut reasons: Vec<&str> = rejected
+            .iter()
+            .map(
+                |e| match e.downcast_ref::<EventError>().map(|e| e.error_type()) {
+                    Some(EventErrorType::HashMismatch { .. }) => "hash",
+                    Some(EventErrorType::InvalidSignature { .. }) => "signature",
+                    Some(EventErrorType::NoSignature { .. }) => "unsigned",
+                    Some(EventErrorType::UnknownParents { .. }) => "parents",
+                    _ => "other",
+                },
+            )
+            .collect();
+        reasons.sort();
+        assert_eq!(reasons, vec!["hash", "parents", "signature", "unsigned"]);
+    }
+
+    #[test]
+    fn it_should_derive_lamport_timestamps_from_parents() {
+        let kp = create_key_pair();
+        let other = create_key_pair();
+        let (a_hash, a) = create_signed_event(&kp, &kp, b"a", None, 0);
+        let (b_hash, b) = create_signed_event(&other, &other, b"b", None, 0);
+        let (c_hash, c) =
+            create_signed_event(&kp, &kp, b"c", Some(ParentsList(vec![a_hash.clone()])), 1);
+        let (d_hash, d) = create_signed_event(
+            &other,
+            &other,
+            b"d",
+            Some(ParentsList(vec![b_hash.clone(), c_hash.clone()])),
+            2,
+        );
+        // Signed by its creator, but one above where its parents put it
+        let (skewed_hash, skewed) = create_signed_event(
+            &kp,
+            &kp,
+            b"e",
+            Some(ParentsList(vec![c_hash.clone(), d_hash.clone()])),
+            4,
+        );
+        let mut remote = Opera::new();
+        for (hash, event) in vec![
+            (a_hash.clone(), a),
+            (b_hash.clone(), b),
+            (c_hash.clone(), c),
+            (d_hash.clone(), d),
+            (skewed_hash.clone(), skewed),
+        ] {
+            remote.graph.insert(hash, opera_event(event));
+        }
+        let mut opera = Opera::new();
+        let rejected = opera.sync(remote, 0);
+        assert_eq!(rejected.len(), 1);
+        match rejected[0]
+            .downcast_ref::<EventError>()
+            .map(|e| e.error_type())
+        {
+            Some(EventErrorType::InvalidLamportTimestamp { hash }) => {
+                assert_eq!(hash, &skewed_hash)
+            }
+            _ => panic!("Unexpected error: {}", rejected[0]),
+        }
+        for (hash, lamport_timestamp) in
+            vec![(a_hash, 0), (b_hash, 0), (c_hash, 1), (d_hash.clone(), 2)]
+        {
+            assert_eq!(
+                opera.get_event_ref(&hash).unwrap().lamport_timestamp,
+                lamport_timestamp
+            );
+        }
+        assert_eq!(
+            opera
+                .next_lamport_timestamp(&Some(ParentsList(vec![d_hash])))
+                .unwrap(),
+            3
+        );
+    }
+
+    #[test]
+    fn it_should_not_sync_events_from_too_far_in_the_future() {
+        let kp = create_key_pair();
+        let create_event = |payload: &[u8], timestamp: u64| {
+            let mut event =
+                Event::new(vec![payload.to_vec()], None, kp.public_key_bytes().to_vec());
+            event.set_lamport_timestamp(0);
+            event.set_timestamp(timestamp);
+            let hash = event.hash().unwrap();
+            event.sign(EventSignature::new(kp.sign(hash.as_ref()).as_ref()));
+            (hash, event)
+        };
+        let (timely_hash, timely) = create_event(b"timely", 1_000);
+        let (future_hash, future) = create_event(b"future", 1_001);
+        let mut remote = Opera::new();
+        remote
+            .graph
+            .insert(timely_hash.clone(), opera_event(timely));
+        remote
+            .graph
+            .insert(future_hash.clone(), opera_event(future));
+        let mut opera = Opera::new();
+        let rejected = opera.sync(remote, 1_000);
+        assert!(opera.contains_key(&timely_hash));
+        assert!(!opera.contains_key(&future_hash));
+        assert_eq!(rejected.len(), 1);
+        match rejected[0]
+            .downcast_ref::<EventError>()
+            .map(|e| e.error_type())
+        {
+            Some(EventErrorType::TooFarInTheFuture { hash }) => assert_eq!(hash, &future_hash),
+            _ => panic!("Unexpected error: {}", rejected[0]),
+        }
+    }
+
+    #[test]
+    fn it_should_not_see_creators_that_forked_among_the_ancestors() {
+        let mut opera = Opera::new();
+        let mut insert = |payload: &[u8], creator: u8, parents: Vec<EventHash>| {
+            let parents = if parents.is_empty() {
+                None
+            } else {
+                Some(ParentsList(parents))
+            };
+            let mut event = Event::new(vec![payload.to_vec()], parents, vec![creator]);
+            event.set_lamport_timestamp(opera.next_lamport_timestamp(event.parents()).unwrap());
+            let hash = event.hash().unwrap();
+            opera.insert(hash.clone(), event).unwrap();
+            hash
+        };
+        let genesis = insert(b"genesis", 0, vec![]);
+        let left = insert(b"left", 0, vec![genesis.clone()]);
+        let right = insert(b"right", 0, vec![genesis.clone()]);
+        let other = insert(b"other", 1, vec![]);
+        let sees_left = insert(b"sees left", 1, vec![other.clone(), left.clone()]);
+        let sees_both = insert(b"sees both", 1, vec![sees_left.clone(), right.clone()]);
+        assert!(opera.can_see(&sees_left, &left).unwrap());
+        assert!(opera.can_see(&sees_left, &genesis).unwrap());
+        assert!(!opera.can_see(&sees_left, &right).unwrap());
+        assert!(!opera.can_see(&sees_both, &genesis).unwrap());
+        assert!(!opera.can_see(&sees_both, &right).unwrap());
+        assert!(opera.can_see(&sees_both, &other).unwrap());
+    }
+
+    #[test]
+    fn it_should_only_send_the_events_above_the_known_heights() {
+        let mut opera = Opera::new();
+        let mut insert = |payload: &[u8], creator: u8, parents: Vec<EventHash>| {
+            let parents = if parents.is_empty() {
+                None
+            } else {
+                Some(ParentsList(parents))
+            };
+            let mut event = Event::new(vec![payload.to_vec()], parents, vec![creator]);
+            event.set_lamport_timestamp(opera.next_lamport_timestamp(event.parents()).unwrap());
+            let hash = event.hash().unwrap();
+            opera.insert(hash.clone(), event).unwrap();
+            hash
+        };
+        let genesis = insert(b"genesis", 0, vec![]);
+        let first = insert(b"first", 0, vec![genesis.clone()]);
+        let other = insert(b"other", 1, vec![]);
+        let known = opera.known_heights();
+        let second = insert(b"second", 0, vec![first.clone(), other.clone()]);
+        let third = insert(b"third", 2, vec![]);
+        assert_eq!(known.get(&vec![0]), Some(&1));
+        assert_eq!(known.get(&vec![1]), Some(&0));
+        let missing = opera.missing_events(&known);
+        let mut sent: Vec<&EventHash> = missing.graph.keys().collect();
+        sent.sort();
+        let mut expected = vec![&second, &third];
+        expected.sort();
+        assert_eq!(sent, expected);
+        assert_eq!(opera.missing_events(&KnownHeights::new()).graph.len(), 5);
+    }
+}
@@ -14,6 +14,7 @@ macro_rules! get_from_mutex {
     };
 }
 
+mod clock;
 mod errors;
 mod event;
 mod hashgraph;
@@ -23,14 +24,29 @@ mod peer;
 mod printable_hash;
 mod round;
 mod server;
+pub mod simulation;
 mod swirlds;
 pub mod tcp_server;
+mod transaction;
 
-pub use crate::event::{event_hash::EventHash, Event};
-pub use crate::hashgraph::{BTreeHashgraph, Hashgraph, HashgraphWire};
+pub use crate::clock::{Clock, ManualClock, SystemClock};
+pub use crate::event::{event_hash::EventHash, fork_evidence::ForkEvidence, Event};
+pub use crate::hashgraph::{
+    disk_hashgraph::DiskHashgraph, BTreeHashgraph, Hashgraph, HashgraphWire, KnownHeights,
+};
 pub use crate::lachesis::Lachesis;
-pub use crate::node::Node;
-pub use crate::peer::{Peer, PeerId};
-pub use crate::server::ws_message::InternodeMessage;
+pub use crate::node::{FinalizedEvent, Node};
+pub use crate::peer::{
+    announcement::PeerAnnouncement,
+    peer_score::{PeerScoreReport, ScoringConfig},
+    peer_table::PeerTable,
+    Peer, PeerDiscovery, PeerId,
+};
+pub use crate::server::ws_message::{InternodeError, InternodeMessage, PROTOCOL_VERSION};
+pub use crate::server::ws_peer::WsPeer;
 pub use crate::server::Server;
-pub use crate::swirlds::Swirlds;
+pub use crate::swirlds::{
+    consensus_config::{CoinStrategy, ConsensusConfig, MiddleBitCoin, TestCoin},
+    Swirlds,
+};
+pub use crate::transaction::{TransactionId, TransactionStatus};
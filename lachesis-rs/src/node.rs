@@ -1,19 +1,40 @@
 use crate::event::event_hash::EventHash;
 use crate::event::parents::Parents;
 use crate::event::Event;
+use crate::peer::peer_score::PeerScoreReport;
+use crate::transaction::{TransactionId, TransactionStatus};
 use failure::Error;
 use rand::Rng;
 use serde::Serialize;
+use std::sync::mpsc::Receiver;
+
+// An event that reached consensus, along with its position in the consensus order
+#[derive(Clone, Debug, PartialEq)]
+pub struct FinalizedEvent<P: Parents + Clone + Serialize> {
+    pub consensus_index: usize,
+    pub event: Event<P>,
+    pub round_received: usize,
+}
 
 pub trait Node {
     type D;
+    // What a requester sends about the events it already has
+    type K;
     type P: Parents + Clone + Serialize;
 
     fn run<R: Rng>(&self, rng: &mut R) -> Result<(), Error>;
 
-    fn respond_message(&self, known: Option<Self::D>) -> Result<(EventHash, Self::D), Error>;
+    fn respond_message(&self, known: Option<Self::K>) -> Result<(EventHash, Self::D), Error>;
+
+    fn add_transaction(&self, msg: Vec<u8>) -> Result<TransactionId, Error>;
 
-    fn add_transaction(&self, msg: Vec<u8>) -> Result<(), Error>;
+    // None if the node hasn't seen the transaction
+    fn transaction_status(&self, id: &TransactionId) -> Result<Option<TransactionStatus>, Error>;
 
     fn get_ordered_events(&self) -> Result<Vec<Event<Self::P>>, Error>;
+
+    // Yields every event finalized after subscribing, once and in consensus order
+    fn subscribe(&self) -> Result<Receiver<FinalizedEvent<Self::P>>, Error>;
+
+    fn peer_scores(&self) -> Result<Vec<PeerScoreReport>, Error>;
 }
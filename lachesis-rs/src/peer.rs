@@ -1,10 +1,40 @@
 use crate::event::event_hash::EventHash;
 use failure::Error;
+use rand::Rng;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+pub mod announcement;
+pub mod peer_score;
+pub mod peer_table;
+
+use self::announcement::PeerAnnouncement;
 
 pub type PeerId = Vec<u8>;
 
-pub trait Peer<H>: Send + Sync {
-    fn get_sync(&self, pk: PeerId, known: Option<&H>) -> Result<(EventHash, H), Error>;
+// K is what the requester tells the peer it already knows, so it only gets what it's missing
+pub trait Peer<H, K = H>: Send + Sync {
+    fn get_sync(&self, pk: PeerId, known: Option<&K>) -> Result<(EventHash, H), Error>;
     fn address(&self) -> String;
     fn id(&self) -> &PeerId;
 }
+
+// Nodes whose network grows with the peers they learn about from other peers
+pub trait PeerDiscovery<P> {
+    // True if the peer wasn't known at that address
+    fn add_announced_peer(
+        &self,
+        announcement: PeerAnnouncement,
+        peer: Arc<P>,
+    ) -> Result<bool, Error>;
+
+    fn choose_peer<R: Rng>(&self, rng: &mut R) -> Result<Arc<P>, Error>;
+
+    // Adds the peers stored in the file and persists the announced peers there. Returns how
+    // many peers it added.
+    fn open_peer_table<F>(&self, path: PathBuf, create_peer: F) -> Result<usize, Error>
+    where
+        F: Fn(&PeerAnnouncement) -> Arc<P>;
+
+    fn peer_announcements(&self) -> Result<Vec<PeerAnnouncement>, Error>;
+}
@@ -0,0 +1,80 @@
+use crate::errors::{NodeError, NodeErrorType};
+use crate::peer::PeerId;
+use bincode::serialize;
+use failure::Error;
+use ring::signature::{self, Ed25519KeyPair};
+
+const ANNOUNCEMENT_CONTEXT: &[u8] = b"lachesis-rs peer announcement";
+
+// A peer telling where it can be reached, signed with its own key so nobody else can
+// redirect its id to another address
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct PeerAnnouncement {
+    pub address: String,
+    pub id: PeerId,
+    // Newer announcements of a peer replace older ones
+    pub timestamp: u64,
+    signature: Vec<u8>,
+}
+
+impl PeerAnnouncement {
+    pub fn new(
+        pk: &Ed25519KeyPair,
+        address: String,
+        timestamp: u64,
+    ) -> Result<PeerAnnouncement, Error> {
+        let id = pk.public_key_bytes().to_vec();
+        let signature = pk
+            .sign(&PeerAnnouncement::signed_bytes(&address, &id, timestamp)?)
+            .as_ref()
+            .to_vec();
+        Ok(PeerAnnouncement {
+            address,
+            id,
+            timestamp,
+            signature,
+        })
+    }
+
+    pub fn verify(&self) -> Result<(), Error> {
+        let msg = PeerAnnouncement::signed_bytes(&self.address, &self.id, self.timestamp)?;
+        signature::verify(
+            &signature::ED25519,
+            untrusted::Input::from(&self.id),
+            untrusted::Input::from(&msg),
+            untrusted::Input::from(&self.signature),
+        )
+        .map_err(|_| {
+            Error::from(NodeError::new(NodeErrorType::InvalidPeerAnnouncement(
+                self.id.clone(),
+            )))
+        })
+    }
+
+    #[inline]
+    fn signed_bytes(address: &str, id: &PeerId, timestamp: u64) -> Result<Vec<u8>, Error> {
+        Ok(serialize(&(ANNOUNCEMENT_CONTEXT, address, id, timestamp))?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::PeerAnnouncement;
+    use ring::{rand, signature};
+
+    #[test]
+    fn it_should_only_verify_announcements_signed_by_the_peer() {
+        let rng = rand::SystemRandom::new();
+        let pkcs8_bytes = signature::Ed25519KeyPair::generate_pkcs8(&rng).unwrap();
+        let kp =
+            signature::Ed25519KeyPair::from_pkcs8(untrusted::Input::from(&pkcs8_bytes)).unwrap();
+        let announcement = PeerAnnouncement::new(&kp, String::from("127.0.0.1:9000"), 1).unwrap();
+        assert!(announcement.verify().is_ok());
+        let mut redirected = announcement.clone();
+        redirected.address = String::from("127.0.0.1:9001");
+        assert!(redirected.verify().is_err());
+        let mut impersonated = announcement.clone();
+        impersonated.id = vec![1; 32];
+        assert!(impersonated.verify().is_err());
+    }
+}
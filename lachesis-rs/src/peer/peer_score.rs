@@ -0,0 +1,186 @@
+use crate::clock::duration_millis;
+use crate::peer::PeerId;
+use std::cmp::min;
+use std::time::Duration;
+
+const DEFAULT_BACKOFF_BASE: u64 = 1_000;
+const DEFAULT_BAN_DURATION: u64 = 3_600_000;
+const DEFAULT_BAN_THRESHOLD: i64 = -100;
+const DEFAULT_MAX_BACKOFF: u64 = 300_000;
+const INVALID_EVENT_PENALTY: i64 = 25;
+const MAX_SCORE: i64 = 100;
+// Doublings past this one would only overflow the max backoff
+const MAX_BACKOFF_EXPONENT: u32 = 16;
+
+#[derive(Clone, Debug)]
+pub struct ScoringConfig {
+    // How long an unreachable peer is skipped after its first failure, doubled on each
+    // failure after that
+    pub backoff_base: Duration,
+    pub ban_duration: Duration,
+    // Peers whose score drops to it are banned
+    pub ban_threshold: i64,
+    pub max_backoff: Duration,
+}
+
+impl Default for ScoringConfig {
+    fn default() -> ScoringConfig {
+        ScoringConfig {
+            backoff_base: Duration::from_millis(DEFAULT_BACKOFF_BASE),
+            ban_duration: Duration::from_millis(DEFAULT_BAN_DURATION),
+            ban_threshold: DEFAULT_BAN_THRESHOLD,
+            max_backoff: Duration::from_millis(DEFAULT_MAX_BACKOFF),
+        }
+    }
+}
+
+// How well a peer behaved so far. Unreachable peers are only backed off, as being offline
+// isn't misbehaving, while invalid events lower the score until the peer gets banned.
+// Times are the milliseconds of the node clock.
+#[derive(Clone, Debug, Default)]
+pub struct PeerScore {
+    banned_until: Option<u64>,
+    consecutive_failures: u32,
+    failures: u64,
+    forks: u64,
+    invalid_events: u64,
+    retry_at: Option<u64>,
+    score: i64,
+    syncs: u64,
+    timeouts: u64,
+}
+
+impl PeerScore {
+    pub fn is_banned(&self, now: u64) -> bool {
+        self.banned_until.map(|until| now < until).unwrap_or(false)
+    }
+
+    // Peers backing off or banned aren't selected to sync with
+    pub fn is_available(&self, now: u64) -> bool {
+        !self.is_banned(now) && self.retry_at.map(|at| now >= at).unwrap_or(true)
+    }
+
+    pub fn record_sync(&mut self) {
+        self.syncs += 1;
+        self.score = min(self.score + 1, MAX_SCORE);
+        self.consecutive_failures = 0;
+        self.retry_at = None;
+    }
+
+    pub fn record_failure(&mut self, timed_out: bool, now: u64, config: &ScoringConfig) {
+        self.failures += 1;
+        if timed_out {
+            self.timeouts += 1;
+        }
+        let exponent = min(self.consecutive_failures, MAX_BACKOFF_EXPONENT);
+        let backoff = min(config.backoff_base * 2u32.pow(exponent), config.max_backoff);
+        self.consecutive_failures += 1;
+        self.retry_at = Some(now + duration_millis(backoff));
+    }
+
+    pub fn record_invalid_events(&mut self, n: usize, now: u64, config: &ScoringConfig) {
+        self.invalid_events += n as u64;
+        self.score -= INVALID_EVENT_PENALTY * n as i64;
+        if self.score <= config.ban_threshold {
+            self.ban(now, config);
+        }
+    }
+
+    // A fork is signed proof of misbehaviour, so it's banned right away
+    pub fn record_fork(&mut self, now: u64, config: &ScoringConfig) {
+        self.forks += 1;
+        self.ban(now, config);
+    }
+
+    pub fn report(&self, id: PeerId, now: u64) -> PeerScoreReport {
+        let remaining =
+            |until: Option<u64>| until.map(|until| until.saturating_sub(now)).unwrap_or(0);
+        PeerScoreReport {
+            backoff_ms: remaining(self.retry_at),
+            banned_ms: remaining(self.banned_until),
+            failures: self.failures,
+            forks: self.forks,
+            id,
+            invalid_events: self.invalid_events,
+            score: self.score,
+            syncs: self.syncs,
+            timeouts: self.timeouts,
+        }
+    }
+
+    // The ban is the punishment, so the peer starts over once it's lifted
+    #[inline]
+    fn ban(&mut self, now: u64, config: &ScoringConfig) {
+        self.banned_until = Some(now + duration_millis(config.ban_duration));
+        self.score = 0;
+    }
+}
+
+// A peer score as shown to API users, with the time left to wait in milliseconds
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct PeerScoreReport {
+    pub backoff_ms: u64,
+    pub banned_ms: u64,
+    pub failures: u64,
+    pub forks: u64,
+    pub id: PeerId,
+    pub invalid_events: u64,
+    pub score: i64,
+    pub syncs: u64,
+    pub timeouts: u64,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{duration_millis, PeerScore, ScoringConfig};
+
+    #[test]
+    fn it_should_back_off_exponentially_from_unreachable_peers() {
+        let config = ScoringConfig::default();
+        let now = 1_000;
+        let mut score = PeerScore::default();
+        score.record_failure(true, now, &config);
+        assert!(!score.is_available(now));
+        assert!(score.is_available(now + duration_millis(config.backoff_base)));
+        score.record_failure(false, now, &config);
+        assert!(!score.is_available(now + duration_millis(config.backoff_base)));
+        assert!(score.is_available(now + duration_millis(config.backoff_base * 2)));
+        for _ in 0..40 {
+            score.record_failure(false, now, &config);
+        }
+        assert!(score.is_available(now + duration_millis(config.max_backoff)));
+        let report = score.report(vec![1], now);
+        assert_eq!(report.failures, 42);
+        assert_eq!(report.timeouts, 1);
+        assert_eq!(report.score, 0);
+        score.record_sync();
+        assert!(score.is_available(now));
+    }
+
+    #[test]
+    fn it_should_ban_peers_sending_invalid_events_for_a_while() {
+        let config = ScoringConfig::default();
+        let now = 1_000;
+        let mut score = PeerScore::default();
+        score.record_invalid_events(3, now, &config);
+        assert!(score.is_available(now));
+        score.record_invalid_events(1, now, &config);
+        assert!(score.is_banned(now));
+        assert!(!score.is_available(now + 1));
+        assert!(score.is_available(now + duration_millis(config.ban_duration)));
+        assert_eq!(score.report(vec![1], now).invalid_events, 4);
+    }
+
+    #[test]
+    fn it_should_ban_forking_peers_right_away() {
+        let config = ScoringConfig::default();
+        let now = 1_000;
+        let mut score = PeerScore::default();
+        score.record_sync();
+        score.record_fork(now, &config);
+        assert!(score.is_banned(now));
+        let report = score.report(vec![1], now);
+        assert_eq!(report.forks, 1);
+        assert_eq!(report.banned_ms, 3_600_000);
+    }
+}
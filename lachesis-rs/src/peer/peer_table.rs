@@ -0,0 +1,331 @@
+use crate::clock::{Clock, SystemClock};
+use crate::peer::announcement::PeerAnnouncement;
+use crate::peer::peer_score::{PeerScore, PeerScoreReport, ScoringConfig};
+use crate::peer::PeerId;
+use bincode::{deserialize, serialize};
+use failure::Error;
+use rand::seq::IteratorRandom;
+use rand::Rng;
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+// The peers a node syncs with. The ones it learnt through announcements are written to
+// disk, so the node finds them again on restart; the rest come from its configuration.
+// Peers are only chosen while their score lets them, and always in the same order, so a
+// seeded rng picks the same ones.
+pub struct PeerTable<P> {
+    announcements: HashMap<PeerId, PeerAnnouncement>,
+    // Backoffs and bans are measured with the node clock
+    clock: Arc<dyn Clock>,
+    path: Option<PathBuf>,
+    peers: BTreeMap<PeerId, Arc<P>>,
+    scores: BTreeMap<PeerId, PeerScore>,
+    scoring: ScoringConfig,
+}
+
+impl<P> PeerTable<P> {
+    pub fn new() -> PeerTable<P> {
+        PeerTable::with_scoring(ScoringConfig::default(), Arc::new(SystemClock))
+    }
+
+    pub fn with_scoring(scoring: ScoringConfig, clock: Arc<dyn Clock>) -> PeerTable<P> {
+        PeerTable {
+            announcements: HashMap::new(),
+            clock,
+            path: None,
+            peers: BTreeMap::new(),
+            scores: BTreeMap::new(),
+            scoring,
+        }
+    }
+
+    pub fn insert(&mut self, id: PeerId, peer: Arc<P>) {
+        self.peers.insert(id, peer);
+    }
+
+    // True if the peer is new or moved to another address
+    pub fn insert_announced(
+        &mut self,
+        announcement: PeerAnnouncement,
+        peer: Arc<P>,
+    ) -> Result<bool, Error> {
+        announcement.verify()?;
+        match self.insert_verified(announcement, peer) {
+            Some(added) => {
+                self.persist()?;
+                Ok(added)
+            }
+            None => Ok(false),
+        }
+    }
+
+    // Adds the peers announced in the file and starts writing the announced peers to it.
+    // Returns how many peers it added.
+    pub fn open<F>(&mut self, path: PathBuf, create_peer: F) -> Result<usize, Error>
+    where
+        F: Fn(&PeerAnnouncement) -> Arc<P>,
+    {
+        let stored: Vec<PeerAnnouncement> = if path.exists() {
+            deserialize(&fs::read(&path)?)?
+        } else {
+            Vec::new()
+        };
+        let mut n_added = 0;
+        for announcement in stored {
+            if let Err(e) = announcement.verify() {
+                warn!("Dropping a stored peer announcement: {}", e);
+                continue;
+            }
+            let peer = create_peer(&announcement);
+            if let Some(true) = self.insert_verified(announcement, peer) {
+                n_added += 1;
+            }
+        }
+        self.path = Some(path);
+        self.persist()?;
+        Ok(n_added)
+    }
+
+    pub fn announcements(&self) -> Vec<PeerAnnouncement> {
+        self.announcements.values().cloned().collect()
+    }
+
+    pub fn get(&self, id: &PeerId) -> Option<Arc<P>> {
+        self.peers.get(id).cloned()
+    }
+
+    pub fn ids(&self) -> impl Iterator<Item = &PeerId> {
+        self.peers.keys()
+    }
+
+    pub fn choose<R: Rng>(&self, rng: &mut R) -> Result<Option<Arc<P>>, Error> {
+        Ok(self.available()?.choose(rng).cloned())
+    }
+
+    pub fn choose_multiple<R: Rng>(
+        &self,
+        rng: &mut R,
+        amount: usize,
+    ) -> Result<Vec<Arc<P>>, Error> {
+        Ok(self
+            .available()?
+            .choose_multiple(rng, amount)
+            .into_iter()
+            .cloned()
+            .collect())
+    }
+
+    pub fn record_sync(&mut self, id: &PeerId) {
+        self.score_mut(id).record_sync();
+    }
+
+    pub fn record_failure(&mut self, id: &PeerId, timed_out: bool) -> Result<(), Error> {
+        let now = self.clock.now()?;
+        let scoring = self.scoring.clone();
+        self.score_mut(id).record_failure(timed_out, now, &scoring);
+        Ok(())
+    }
+
+    pub fn record_invalid_events(&mut self, id: &PeerId, n: usize) -> Result<(), Error> {
+        let now = self.clock.now()?;
+        let scoring = self.scoring.clone();
+        self.score_mut(id).record_invalid_events(n, now, &scoring);
+        Ok(())
+    }
+
+    pub fn record_fork(&mut self, id: &PeerId) -> Result<(), Error> {
+        let now = self.clock.now()?;
+        let scoring = self.scoring.clone();
+        self.score_mut(id).record_fork(now, &scoring);
+        Ok(())
+    }
+
+    // Every peer in the table, whether it has been scored yet or not
+    pub fn scores(&self) -> Result<Vec<PeerScoreReport>, Error> {
+        let now = self.clock.now()?;
+        let unscored = PeerScore::default();
+        Ok(self
+            .peers
+            .keys()
+            .map(|id| {
+                self.scores
+                    .get(id)
+                    .unwrap_or(&unscored)
+                    .report(id.clone(), now)
+            })
+            .collect())
+    }
+
+    pub fn len(&self) -> usize {
+        self.peers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.peers.is_empty()
+    }
+
+    #[inline]
+    fn available(&self) -> Result<impl Iterator<Item = &Arc<P>>, Error> {
+        let now = self.clock.now()?;
+        let scores = &self.scores;
+        Ok(self
+            .peers
+            .iter()
+            .filter(move |(id, _)| scores.get(*id).map(|s| s.is_available(now)).unwrap_or(true))
+            .map(|(_, peer)| peer))
+    }
+
+    // None if the announcement isn't newer than the one known, otherwise whether the peer is
+    // new or moved to another address
+    fn insert_verified(&mut self, announcement: PeerAnnouncement, peer: Arc<P>) -> Option<bool> {
+        let moved = match self.announcements.get(&announcement.id) {
+            Some(known) if known.timestamp >= announcement.timestamp => return None,
+            Some(known) => known.address != announcement.address,
+            None => true,
+        };
+        let added = moved || !self.peers.contains_key(&announcement.id);
+        if added {
+            self.peers.insert(announcement.id.clone(), peer);
+        }
+        self.announcements
+            .insert(announcement.id.clone(), announcement);
+        Some(added)
+    }
+
+    #[inline]
+    fn score_mut(&mut self, id: &PeerId) -> &mut PeerScore {
+        self.scores.entry(id.clone()).or_default()
+    }
+
+    fn persist(&self) -> Result<(), Error> {
+        if let Some(path) = &self.path {
+            let announcements: Vec<&PeerAnnouncement> = self.announcements.values().collect();
+            // Written aside and renamed, as checkpoints are
+            let tmp_path = path.with_extension("tmp");
+            fs::write(&tmp_path, serialize(&announcements)?)?;
+            fs::rename(&tmp_path, path)?;
+        }
+        Ok(())
+    }
+}
+
+impl<P> Default for PeerTable<P> {
+    fn default() -> PeerTable<P> {
+        PeerTable::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::PeerTable;
+    use crate::clock::ManualClock;
+    use crate::peer::announcement::PeerAnnouncement;
+    use crate::peer::peer_score::ScoringConfig;
+    use ring::signature;
+    use std::env::temp_dir;
+    use std::fs;
+    use std::process;
+    use std::sync::Arc;
+
+    fn create_key_pair() -> signature::Ed25519KeyPair {
+        let rng = ring::rand::SystemRandom::new();
+        let pkcs8_bytes = signature::Ed25519KeyPair::generate_pkcs8(&rng).unwrap();
+        signature::Ed25519KeyPair::from_pkcs8(untrusted::Input::from(&pkcs8_bytes)).unwrap()
+    }
+
+    fn create_peer(announcement: &PeerAnnouncement) -> Arc<String> {
+        Arc::new(announcement.address.clone())
+    }
+
+    #[test]
+    fn it_should_keep_the_newest_announcement_of_each_peer() {
+        let kp = create_key_pair();
+        let mut table: PeerTable<String> = PeerTable::new();
+        let first = PeerAnnouncement::new(&kp, String::from("a:1"), 1).unwrap();
+        let moved = PeerAnnouncement::new(&kp, String::from("a:2"), 2).unwrap();
+        assert!(table
+            .insert_announced(first.clone(), Arc::new(String::from("a:1")))
+            .unwrap());
+        assert!(table
+            .insert_announced(moved.clone(), Arc::new(String::from("a:2")))
+            .unwrap());
+        assert!(!table
+            .insert_announced(first, Arc::new(String::from("a:1")))
+            .unwrap());
+        assert_eq!(table.len(), 1);
+        assert_eq!(table.announcements(), vec![moved.clone()]);
+        assert_eq!(*table.get(&moved.id).unwrap(), String::from("a:2"));
+    }
+
+    #[test]
+    fn it_should_find_announced_peers_again_after_a_restart() {
+        let path = temp_dir().join(format!("lachesis-peer-table-{}.peers", process::id()));
+        let _ = fs::remove_file(&path);
+        let announcement =
+            PeerAnnouncement::new(&create_key_pair(), String::from("a:1"), 1).unwrap();
+        let mut table: PeerTable<String> = PeerTable::new();
+        assert_eq!(table.open(path.clone(), create_peer).unwrap(), 0);
+        table
+            .insert_announced(announcement.clone(), Arc::new(String::from("a:1")))
+            .unwrap();
+        let mut restarted: PeerTable<String> = PeerTable::new();
+        assert_eq!(restarted.open(path.clone(), create_peer).unwrap(), 1);
+        assert_eq!(restarted.announcements(), vec![announcement.clone()]);
+        assert_eq!(
+            *restarted.get(&announcement.id).unwrap(),
+            String::from("a:1")
+        );
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn it_should_persist_newer_announcements_at_the_same_address() {
+        let path = temp_dir().join(format!("lachesis-peer-table-{}.renewed", process::id()));
+        let _ = fs::remove_file(&path);
+        let kp = create_key_pair();
+        let first = PeerAnnouncement::new(&kp, String::from("a:1"), 1).unwrap();
+        let renewed = PeerAnnouncement::new(&kp, String::from("a:1"), 2).unwrap();
+        let mut table: PeerTable<String> = PeerTable::new();
+        table.open(path.clone(), create_peer).unwrap();
+        assert!(table
+            .insert_announced(first, Arc::new(String::from("a:1")))
+            .unwrap());
+        assert!(!table
+            .insert_announced(renewed.clone(), Arc::new(String::from("a:1")))
+            .unwrap());
+        let mut restarted: PeerTable<String> = PeerTable::new();
+        restarted.open(path.clone(), create_peer).unwrap();
+        assert_eq!(restarted.announcements(), vec![renewed]);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn it_should_only_choose_peers_that_arent_banned() {
+        let clock = Arc::new(ManualClock::new(1_000));
+        let mut table: PeerTable<String> =
+            PeerTable::with_scoring(ScoringConfig::default(), clock.clone());
+        table.insert(vec![1], Arc::new(String::from("a:1")));
+        table.insert(vec![2], Arc::new(String::from("a:2")));
+        table.record_fork(&vec![1]).unwrap();
+        let mut rng = rand::thread_rng();
+        for _ in 0..10 {
+            assert_eq!(
+                *table.choose(&mut rng).unwrap().unwrap(),
+                String::from("a:2")
+            );
+        }
+        table.record_failure(&vec![2], true).unwrap();
+        assert!(table.choose(&mut rng).unwrap().is_none());
+        assert_eq!(table.len(), 2);
+        assert_eq!(table.scores().unwrap().len(), 2);
+        clock.advance(1_000).unwrap();
+        assert_eq!(
+            *table.choose(&mut rng).unwrap().unwrap(),
+            String::from("a:2")
+        );
+        clock.advance(3_600_000).unwrap();
+        assert_eq!(table.choose_multiple(&mut rng, 2).unwrap().len(), 2);
+    }
+}
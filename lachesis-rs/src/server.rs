@@ -2,6 +2,9 @@ use actix_web::{http, middleware, server, App};
 
 use actix::prelude::*;
 
+use crate::node::Node;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
 use std::sync::Arc;
 use std::sync::Mutex;
 
@@ -9,78 +12,187 @@ mod heartbeat;
 pub mod http_handler;
 pub mod ws_handler;
 pub mod ws_message;
+pub mod ws_peer;
 
 use self::heartbeat::Heartbeat;
 use self::http_handler::{check_transaction_status, get_peers, heartbeat, submit_transaction};
-use self::ws_handler::ws_index;
+use self::ws_handler::{ws_index, WsResponder};
 pub struct Server;
 
-#[derive(Clone)]
-pub struct AppState {
+pub struct AppState<N: Node + Send + Sync + 'static> {
     counter: Arc<Mutex<usize>>,
     heartbeat_counter: Addr<Heartbeat>,
+    node: Arc<N>,
+    ws_responder: Option<Addr<WsResponder<N>>>,
+}
+
+impl<N: Node + Send + Sync + 'static> Clone for AppState<N> {
+    fn clone(&self) -> AppState<N> {
+        AppState {
+            counter: self.counter.clone(),
+            heartbeat_counter: self.heartbeat_counter.clone(),
+            node: self.node.clone(),
+            ws_responder: self.ws_responder.clone(),
+        }
+    }
+}
+
+// The internode `/ws` endpoint is only there if the state has a responder for it
+fn create_routes<N: Node + Send + Sync + 'static>(state: AppState<N>) -> App<AppState<N>>
+where
+    N::D: Serialize,
+    N::K: DeserializeOwned,
+{
+    let serves_ws = state.ws_responder.is_some();
+    let app = App::with_state(state)
+        .middleware(middleware::Logger::default())
+        .resource("/transaction", |r| {
+            r.method(http::Method::POST).a(submit_transaction::<N>)
+        })
+        .resource("/transaction/{id}", |r| {
+            r.method(http::Method::GET).a(check_transaction_status::<N>)
+        })
+        .resource("/peer", |r| r.method(http::Method::GET).f(get_peers::<N>))
+        .resource("/heartbeat", |r| {
+            r.method(http::Method::GET).f(heartbeat::<N>)
+        });
+    if serves_ws {
+        app.resource("/ws", |r| r.method(http::Method::GET).f(ws_index::<N>))
+    } else {
+        app
+    }
 }
 
 impl Server {
-    pub fn create_app() -> App<AppState> {
+    pub fn create_app<N: Node + Send + Sync + 'static>(node: Arc<N>) -> App<AppState<N>>
+    where
+        N::D: Serialize,
+        N::K: DeserializeOwned,
+    {
+        Server::build_app(node, None)
+    }
+
+    // Serves the unauthenticated `/ws` endpoint too, see ws_index
+    pub fn create_app_with_ws<N: Node + Send + Sync + 'static>(
+        node: Arc<N>,
+        ws_threads: usize,
+    ) -> App<AppState<N>>
+    where
+        N::D: Serialize,
+        N::K: DeserializeOwned,
+    {
+        Server::build_app(node, Some(ws_threads))
+    }
+
+    pub fn init<N: Node + Send + Sync + 'static>(
+        node: Arc<N>,
+    ) -> server::HttpServer<App<AppState<N>>, impl Fn() -> App<AppState<N>> + Send + Clone + 'static>
+    where
+        N::D: Serialize,
+        N::K: DeserializeOwned,
+    {
+        Server::build_server(node, None)
+    }
+
+    // Serves the unauthenticated `/ws` endpoint too, see ws_index
+    pub fn init_with_ws<N: Node + Send + Sync + 'static>(
+        node: Arc<N>,
+        ws_threads: usize,
+    ) -> server::HttpServer<App<AppState<N>>, impl Fn() -> App<AppState<N>> + Send + Clone + 'static>
+    where
+        N::D: Serialize,
+        N::K: DeserializeOwned,
+    {
+        Server::build_server(node, Some(ws_threads))
+    }
+
+    fn build_app<N: Node + Send + Sync + 'static>(
+        node: Arc<N>,
+        ws_threads: Option<usize>,
+    ) -> App<AppState<N>>
+    where
+        N::D: Serialize,
+        N::K: DeserializeOwned,
+    {
         let addr = Arbiter::start(move |_| Heartbeat { count: 0 });
 
         let counter = Arc::new(Mutex::new(0));
 
-        App::with_state(AppState {
+        let ws_responder = ws_threads.map(|threads| WsResponder::start(node.clone(), threads));
+
+        create_routes(AppState {
             counter: counter.clone(),
             heartbeat_counter: addr.clone(),
+            node,
+            ws_responder,
         })
-        .middleware(middleware::Logger::default())
-        .resource("/transaction", |r| {
-            r.method(http::Method::POST).a(submit_transaction)
-        })
-        .resource("/transaction/{id}", |r| {
-            r.method(http::Method::GET).a(check_transaction_status)
-        })
-        .resource("/peer", |r| r.method(http::Method::GET).f(get_peers))
-        .resource("/heartbeat", |r| r.method(http::Method::GET).f(heartbeat))
-        .resource("/ws", |r| r.method(http::Method::GET).f(ws_index))
     }
 
-    pub fn init(
-    ) -> server::HttpServer<App<AppState>, impl Fn() -> App<AppState> + Send + Clone + 'static>
+    fn build_server<N: Node + Send + Sync + 'static>(
+        node: Arc<N>,
+        ws_threads: Option<usize>,
+    ) -> server::HttpServer<App<AppState<N>>, impl Fn() -> App<AppState<N>> + Send + Clone + 'static>
+    where
+        N::D: Serialize,
+        N::K: DeserializeOwned,
     {
         let counter: Arc<Mutex<usize>> = Arc::new(Mutex::new(0));
 
         let addr: Addr<Heartbeat> = Arbiter::start(move |_| Heartbeat { count: 0 });
 
-        server::new(move || -> App<AppState> {
-            App::with_state(AppState {
+        let ws_responder = ws_threads.map(|threads| WsResponder::start(node.clone(), threads));
+
+        server::new(move || -> App<AppState<N>> {
+            create_routes(AppState {
                 counter: counter.clone(),
                 heartbeat_counter: addr.clone(),
+                node: node.clone(),
+                ws_responder: ws_responder.clone(),
             })
-            .middleware(middleware::Logger::default())
-            .resource("/transaction", |r| {
-                r.method(http::Method::POST).a(submit_transaction)
-            })
-            .resource("/transaction/{id}", |r| {
-                r.method(http::Method::GET).a(check_transaction_status)
-            })
-            .resource("/peer", |r| r.method(http::Method::GET).f(get_peers))
-            .resource("/heartbeat", |r| r.method(http::Method::GET).f(heartbeat))
-            .resource("/ws", |r| r.method(http::Method::GET).f(ws_index))
         })
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::http_handler::SubmitTransaction;
+    use super::http_handler::{
+        encode_transaction_id, PeerList, SubmitTransaction, TransactionStatus,
+    };
 
+    use super::ws_peer::WsPeer;
     use super::*;
+    use crate::clock::SystemClock;
+    use crate::hashgraph::{BTreeHashgraph, Hashgraph};
+    use crate::peer::Peer;
+    use crate::swirlds::{consensus_config::ConsensusConfig, Swirlds};
+    use crate::tcp_server::TcpPeer;
     use actix_web::test::TestServer;
     use actix_web::HttpMessage;
     use futures::future::Future;
+    use ring::{rand, signature};
+    use std::thread;
+    use std::time::Duration;
+
+    fn create_node() -> Arc<Swirlds<TcpPeer, BTreeHashgraph>> {
+        let rng = rand::SystemRandom::new();
+        let pkcs8_bytes = signature::Ed25519KeyPair::generate_pkcs8(&rng).unwrap();
+        let kp =
+            signature::Ed25519KeyPair::from_pkcs8(untrusted::Input::from(&pkcs8_bytes)).unwrap();
+        Arc::new(
+            Swirlds::new(
+                kp,
+                BTreeHashgraph::new(),
+                ConsensusConfig::default(),
+                Arc::new(SystemClock),
+            )
+            .unwrap(),
+        )
+    }
 
     #[test]
     fn test_submit_transaction() {
-        let mut server = TestServer::with_factory(Server::create_app);
+        let node = create_node();
+        let mut server = TestServer::with_factory(move || Server::create_app(node.clone()));
 
         let request = server
             .client(http::Method::POST, "/transaction")
@@ -96,25 +208,83 @@ mod tests {
 
     #[test]
     fn test_get_peers() {
-        let mut server = TestServer::with_factory(Server::create_app);
+        let node = create_node();
+        let identity = signature::Ed25519KeyPair::from_pkcs8(untrusted::Input::from(
+            &signature::Ed25519KeyPair::generate_pkcs8(&rand::SystemRandom::new()).unwrap(),
+        ))
+        .unwrap();
+        let peer = TcpPeer::new(
+            String::from("127.0.0.1:9000"),
+            vec![1; 32],
+            Arc::new(identity),
+            Duration::from_secs(1),
+        );
+        node.add_node(Arc::new(peer)).unwrap();
+        let mut server = TestServer::with_factory(move || Server::create_app(node.clone()));
 
         let request = server.client(http::Method::GET, "/peer").finish().unwrap();
 
         let response = server.execute(request.send()).unwrap();
         assert!(response.status().is_success());
+        let peer_list: PeerList = server.execute(response.json()).unwrap();
+        assert_eq!(peer_list.peers.len(), 1);
+        assert_eq!(peer_list.peers[0].id, base64::encode(&vec![1; 32]));
+        assert_eq!(peer_list.peers[0].banned_ms, 0);
     }
 
     #[test]
     fn test_check_transaction_status() {
-        let mut server = TestServer::with_factory(Server::create_app);
+        let node = create_node();
+        let id = node.add_transaction(b"WEfwef".to_vec()).unwrap();
+        let mut server = TestServer::with_factory(move || Server::create_app(node.clone()));
 
         let request = server
-            .client(http::Method::GET, "/transaction/0x81732be82h")
+            .client(
+                http::Method::GET,
+                &format!("/transaction/{}", encode_transaction_id(&id)),
+            )
             .finish()
             .unwrap();
 
         let response = server.execute(request.send()).unwrap();
-        println!("{:?}", response.body().wait());
         assert!(response.status().is_success());
+        let status: TransactionStatus = server.execute(response.json()).unwrap();
+        assert_eq!(status, TransactionStatus::Pending);
+
+        let request = server
+            .client(http::Method::GET, "/transaction/0x81732be82h")
+            .finish()
+            .unwrap();
+
+        let response = server.execute(request.send()).unwrap();
+        assert_eq!(response.status(), http::StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn test_ws_peer_sync() {
+        let node = create_node();
+        let head = node.get_head().unwrap();
+        let peer_id = node.get_id();
+        let server = TestServer::with_factory(move || Server::create_app_with_ws(node.clone(), 1));
+        let peer = WsPeer::new(server.url("/ws"), peer_id);
+
+        // The peer runs an actix system of its own, so it can't share the test server thread
+        let (remote_head, hashgraph): (_, BTreeHashgraph) =
+            thread::spawn(move || peer.get_sync(vec![1], None).unwrap())
+                .join()
+                .unwrap();
+        assert_eq!(remote_head, head);
+        assert!(hashgraph.contains_key(&head));
+    }
+
+    #[test]
+    fn test_ws_is_not_served_by_default() {
+        let node = create_node();
+        let mut server = TestServer::with_factory(move || Server::create_app(node.clone()));
+
+        let request = server.client(http::Method::GET, "/ws").finish().unwrap();
+
+        let response = server.execute(request.send()).unwrap();
+        assert_eq!(response.status(), http::StatusCode::NOT_FOUND);
     }
 }
@@ -4,6 +4,10 @@ use futures::{future::result, Future};
 
 use super::AppState;
 
+use crate::node::Node;
+use crate::peer::peer_score::PeerScoreReport;
+use crate::transaction::{self, TransactionId};
+
 use super::heartbeat::GetHeartbeatCount;
 
 use actix::prelude::*;
@@ -19,36 +23,118 @@ pub struct CheckTransactionStatus {
     id: String,
 }
 
+// A peer with its score, so operators can see who is backed off or banned and why
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Peer {
-    id: String,
+    pub id: String,
+    pub score: i64,
+    pub syncs: u64,
+    pub failures: u64,
+    pub timeouts: u64,
+    pub invalid_events: u64,
+    pub forks: u64,
+    pub backoff_ms: u64,
+    pub banned_ms: u64,
+}
+
+impl From<PeerScoreReport> for Peer {
+    fn from(report: PeerScoreReport) -> Peer {
+        Peer {
+            id: base64::encode(&report.id),
+            score: report.score,
+            syncs: report.syncs,
+            failures: report.failures,
+            timeouts: report.timeouts,
+            invalid_events: report.invalid_events,
+            forks: report.forks,
+            backoff_ms: report.backoff_ms,
+            banned_ms: report.banned_ms,
+        }
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct PeerList {
-    peers: Vec<Peer>,
+    pub peers: Vec<Peer>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
+pub struct SubmittedTransaction {
+    pub id: String,
+}
+
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
 pub enum TransactionStatus {
-    Complete,
+    Committed {
+        consensus_index: usize,
+        round_received: usize,
+        consensus_timestamp: u64,
+    },
+    Included {
+        event: String,
+    },
     Pending,
-    Failed,
+    Unknown,
+}
+
+impl From<Option<transaction::TransactionStatus>> for TransactionStatus {
+    fn from(status: Option<transaction::TransactionStatus>) -> TransactionStatus {
+        match status {
+            Some(transaction::TransactionStatus::Committed(
+                consensus_index,
+                round_received,
+                consensus_timestamp,
+            )) => TransactionStatus::Committed {
+                consensus_index,
+                round_received,
+                consensus_timestamp,
+            },
+            Some(transaction::TransactionStatus::Included(event)) => TransactionStatus::Included {
+                event: base64::encode_config(&event, base64::URL_SAFE_NO_PAD),
+            },
+            Some(transaction::TransactionStatus::Pending) => TransactionStatus::Pending,
+            None => TransactionStatus::Unknown,
+        }
+    }
 }
 
-pub fn submit_transaction(
-    req: &HttpRequest<AppState>,
+// Ids travel in URLs, so they're url safe base64
+pub fn encode_transaction_id(id: &TransactionId) -> String {
+    base64::encode_config(id, base64::URL_SAFE_NO_PAD)
+}
+
+fn decode_transaction_id(id: &str) -> Option<TransactionId> {
+    let bytes = base64::decode_config(id, base64::URL_SAFE_NO_PAD).ok()?;
+    if bytes.len() != 32 {
+        return None;
+    }
+    let mut a: [u8; 32] = [0; 32];
+    a.copy_from_slice(&bytes);
+    Some(TransactionId(a))
+}
+
+pub fn submit_transaction<N: Node + Send + Sync + 'static>(
+    req: &HttpRequest<AppState<N>>,
 ) -> Box<Future<Item = HttpResponse, Error = Error>> {
+    let node = req.state().node.clone();
     req.json()
         .from_err()
-        .and_then(|val: SubmitTransaction| {
+        .and_then(move |val: SubmitTransaction| {
             debug!("model: {:?}", val);
-            Ok(HttpResponse::Ok().json(val)) // <- send response
+            match node.add_transaction(val.payload.into_bytes()) {
+                Ok(id) => Ok(HttpResponse::Ok().json(SubmittedTransaction {
+                    id: encode_transaction_id(&id),
+                })),
+                Err(e) => {
+                    error!("Error adding transaction: {}", e);
+                    Ok(HttpResponse::InternalServerError().finish())
+                }
+            }
         })
         .responder()
 }
 
-pub fn heartbeat(req: &HttpRequest<AppState>) -> HttpResponse {
+pub fn heartbeat<N: Node + Send + Sync + 'static>(req: &HttpRequest<AppState<N>>) -> HttpResponse {
     debug!("{:?}", req);
 
     *(req.state().counter.lock().unwrap()) += 1;
@@ -71,18 +157,36 @@ pub fn heartbeat(req: &HttpRequest<AppState>) -> HttpResponse {
     ))
 }
 
-pub fn check_transaction_status(
-    req: &HttpRequest<AppState>,
+pub fn check_transaction_status<N: Node + Send + Sync + 'static>(
+    req: &HttpRequest<AppState<N>>,
 ) -> Box<Future<Item = HttpResponse, Error = Error>> {
-    let _transaction_id = req.match_info().get("id").expect("no id provided");
-
-    result(Ok(HttpResponse::Ok().json(TransactionStatus::Failed))).responder()
+    let transaction_id = req.match_info().get("id").expect("no id provided");
+    let response = match decode_transaction_id(transaction_id) {
+        Some(id) => match req.state().node.transaction_status(&id) {
+            Ok(status) => HttpResponse::Ok().json(TransactionStatus::from(status)),
+            Err(e) => {
+                error!("Error getting transaction status: {}", e);
+                HttpResponse::InternalServerError().finish()
+            }
+        },
+        None => HttpResponse::BadRequest().finish(),
+    };
+
+    result(Ok(response)).responder()
 }
 
-pub fn get_peers(_req: &HttpRequest<AppState>) -> Box<Future<Item = HttpResponse, Error = Error>> {
-    let peers = vec![Peer {
-        id: "wefwef".to_string(),
-    }];
+pub fn get_peers<N: Node + Send + Sync + 'static>(
+    req: &HttpRequest<AppState<N>>,
+) -> Box<Future<Item = HttpResponse, Error = Error>> {
+    let response = match req.state().node.peer_scores() {
+        Ok(scores) => HttpResponse::Ok().json(PeerList {
+            peers: scores.into_iter().map(Peer::from).collect(),
+        }),
+        Err(e) => {
+            error!("Error getting peer scores: {}", e);
+            HttpResponse::InternalServerError().finish()
+        }
+    };
 
-    result(Ok(HttpResponse::Ok().json(peers))).responder()
+    result(Ok(response)).responder()
 }
@@ -1,22 +1,86 @@
 use actix::prelude::*;
 use actix_web::{ws, Error, HttpRequest, HttpResponse};
 use bincode::deserialize;
+use bytes::Bytes;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
 
-use super::ws_message::InternodeMessage;
+use std::sync::Arc;
+
+use super::ws_message::{respond, InternodeError, InternodeMessage};
 use super::AppState;
+use crate::node::Node;
+
+// Unlike the TCP transport, /ws doesn't authenticate nor encrypt anything: anyone can sync
+// claiming to be any requester. That's why the server only serves it when asked to.
+pub fn ws_index<N: Node + Send + Sync + 'static>(
+    r: &HttpRequest<AppState<N>>,
+) -> Result<HttpResponse, Error>
+where
+    N::D: Serialize,
+    N::K: DeserializeOwned,
+{
+    match r.state().ws_responder.clone() {
+        Some(responder) => {
+            info!("Websocket handshake");
+            ws::start(r, Ws(responder))
+        }
+        None => Ok(HttpResponse::NotFound().finish()),
+    }
+}
+
+// Answers the requests of every websocket on threads of its own, as nodes block on their locks
+pub struct WsResponder<N: Node + Send + Sync + 'static>(Arc<N>);
+
+impl<N: Node + Send + Sync + 'static> WsResponder<N>
+where
+    N::D: Serialize,
+    N::K: DeserializeOwned,
+{
+    pub fn start(node: Arc<N>, threads: usize) -> Addr<WsResponder<N>> {
+        SyncArbiter::start(threads, move || WsResponder(node.clone()))
+    }
+}
 
-pub fn ws_index(r: &HttpRequest<AppState>) -> Result<HttpResponse, Error> {
-    info!("Websocket handshake");
-    ws::start(r, Ws)
+impl<N: Node + Send + Sync + 'static> Actor for WsResponder<N> {
+    type Context = SyncContext<Self>;
+}
+
+struct Respond(Bytes);
+
+impl Message for Respond {
+    type Result = Result<InternodeMessage, ()>;
+}
+
+impl<N: Node + Send + Sync + 'static> Handler<Respond> for WsResponder<N>
+where
+    N::D: Serialize,
+    N::K: DeserializeOwned,
+{
+    type Result = Result<InternodeMessage, ()>;
+
+    fn handle(&mut self, msg: Respond, _: &mut Self::Context) -> Self::Result {
+        Ok(match deserialize::<InternodeMessage>(&msg.0) {
+            Ok(message) => respond(&*self.0, &message),
+            Err(e) => {
+                warn!("Undecodable internode message: {}", e);
+                InternodeMessage::Error(InternodeError::BadRequest)
+            }
+        })
+    }
 }
 
-struct Ws;
+struct Ws<N: Node + Send + Sync + 'static>(Addr<WsResponder<N>>);
 
-impl Actor for Ws {
-    type Context = ws::WebsocketContext<Self, AppState>;
+impl<N: Node + Send + Sync + 'static> Actor for Ws<N> {
+    type Context = ws::WebsocketContext<Self, AppState<N>>;
 }
 
-impl StreamHandler<ws::Message, ws::ProtocolError> for Ws {
+impl<N: Node + Send + Sync + 'static> StreamHandler<ws::Message, ws::ProtocolError> for Ws<N>
+where
+    N::D: Serialize,
+    N::K: DeserializeOwned,
+{
     fn handle(&mut self, msg: ws::Message, ctx: &mut Self::Context) {
         match msg {
             ws::Message::Ping(msg) => {
@@ -26,9 +90,20 @@ impl StreamHandler<ws::Message, ws::ProtocolError> for Ws {
                 ctx.ping(&msg);
             }
             ws::Message::Text(text) => ctx.text(text),
+            // Waits for the response before handling anything else, so they go out in order
             ws::Message::Binary(mut bin) => {
-                let decoded: InternodeMessage = deserialize(&bin.take()).unwrap();
-                info!("{:?}", decoded);
+                ctx.wait(self.0.send(Respond(bin.take())).into_actor(self).then(
+                    |response, _, ctx| {
+                        match response {
+                            Ok(Ok(response)) => ctx.binary(response),
+                            _ => {
+                                warn!("Internode responder is gone");
+                                ctx.stop();
+                            }
+                        }
+                        fut::ok(())
+                    },
+                ))
             }
             ws::Message::Close(_) => {
                 ctx.stop();
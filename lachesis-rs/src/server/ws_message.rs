@@ -2,13 +2,127 @@ use actix::*;
 
 use bytes::Bytes;
 
+use crate::errors::{ProtocolError, ProtocolErrorType};
+use crate::event::event_hash::EventHash;
+use crate::node::Node;
+use crate::peer::{announcement::PeerAnnouncement, PeerId};
+use crate::printable_hash::PrintableHash;
 use actix_web::Binary;
-use bincode::serialize;
+use bincode::{deserialize, serialize};
+use failure::Error;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::fmt;
 
+// Bump it whenever InternodeMessage changes
+pub const PROTOCOL_VERSION: u32 = 4;
+
+// The known state and the events are bincode encoded, as each consensus algorithm
+// has its own wire types
 #[derive(Message, Serialize, Deserialize, Clone, Debug)]
 pub enum InternodeMessage {
-    SyncRequest,
-    SyncResponse,
+    SyncRequest {
+        version: u32,
+        requester: PeerId,
+        known: Option<Vec<u8>>,
+    },
+    SyncResponse {
+        head: EventHash,
+        events: Vec<u8>,
+    },
+    // The requester announces itself and gets the peers the responder knows back
+    PeersRequest {
+        version: u32,
+        announcement: PeerAnnouncement,
+    },
+    PeersResponse {
+        peers: Vec<PeerAnnouncement>,
+    },
+    Error(InternodeError),
+}
+
+impl InternodeMessage {
+    // The head and the encoded events of a sync response, or what went wrong
+    pub(crate) fn into_sync_response(self) -> Result<(EventHash, Vec<u8>), Error> {
+        match self {
+            InternodeMessage::SyncResponse { head, events } => Ok((head, events)),
+            InternodeMessage::Error(e) => Err(Error::from(ProtocolError::new(
+                ProtocolErrorType::Remote(e),
+            ))),
+            _ => Err(Error::from(ProtocolError::new(
+                ProtocolErrorType::UnexpectedMessage,
+            ))),
+        }
+    }
+
+    // The peers of a peers response, or what went wrong
+    pub(crate) fn into_peers_response(self) -> Result<Vec<PeerAnnouncement>, Error> {
+        match self {
+            InternodeMessage::PeersResponse { peers } => Ok(peers),
+            InternodeMessage::Error(e) => Err(Error::from(ProtocolError::new(
+                ProtocolErrorType::Remote(e),
+            ))),
+            _ => Err(Error::from(ProtocolError::new(
+                ProtocolErrorType::UnexpectedMessage,
+            ))),
+        }
+    }
+}
+
+// Answers a request the same way whatever the transport it came through
+pub(crate) fn respond<N: Node>(node: &N, message: &InternodeMessage) -> InternodeMessage
+where
+    N::D: Serialize,
+    N::K: DeserializeOwned,
+{
+    match message {
+        InternodeMessage::SyncRequest { version, .. } if *version != PROTOCOL_VERSION => {
+            InternodeMessage::Error(InternodeError::UnsupportedVersion(PROTOCOL_VERSION))
+        }
+        InternodeMessage::SyncRequest {
+            requester, known, ..
+        } => {
+            debug!("Sync request from {:?}", requester.printable_hash());
+            let known = match known.as_ref().map(|k| deserialize(k)).transpose() {
+                Ok(known) => known,
+                Err(_) => return InternodeMessage::Error(InternodeError::BadRequest),
+            };
+            let response = node.respond_message(known).and_then(|(head, wire)| {
+                Ok(InternodeMessage::SyncResponse {
+                    head,
+                    events: serialize(&wire)?,
+                })
+            });
+            match response {
+                Ok(response) => response,
+                Err(e) => InternodeMessage::Error(InternodeError::Internal(e.to_string())),
+            }
+        }
+        _ => InternodeMessage::Error(InternodeError::BadRequest),
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub enum InternodeError {
+    BadRequest,
+    Internal(String),
+    // The requester isn't the peer that authenticated the connection
+    Unauthorized,
+    // Carries the version the responder speaks
+    UnsupportedVersion(u32),
+}
+
+impl fmt::Display for InternodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            InternodeError::BadRequest => write!(f, "Bad request"),
+            InternodeError::Internal(e) => write!(f, "Internal error: {}", e),
+            InternodeError::Unauthorized => write!(f, "Requester isn't the authenticated peer"),
+            InternodeError::UnsupportedVersion(v) => {
+                write!(f, "Unsupported protocol version, expected {}", v)
+            }
+        }
+    }
 }
 
 impl Into<Binary> for InternodeMessage {
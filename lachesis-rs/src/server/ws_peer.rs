@@ -0,0 +1,108 @@
+use actix::System;
+use actix_web::ws::{Client, Message};
+use bincode::{deserialize, serialize};
+use failure::Error;
+use futures::{Future, Stream};
+
+use super::ws_message::{InternodeMessage, PROTOCOL_VERSION};
+use crate::errors::{ProtocolError, ProtocolErrorType};
+use crate::event::event_hash::EventHash;
+use crate::hashgraph::{BTreeHashgraph, HashgraphWire, KnownHeights};
+use crate::lachesis::opera::{Opera, OperaWire};
+use crate::peer::{Peer, PeerId};
+
+// Syncs through the `/ws` endpoint of the peer server, as `http://host:port/ws`
+#[derive(Clone)]
+pub struct WsPeer {
+    pub address: String,
+    pub id: PeerId,
+}
+
+impl WsPeer {
+    pub fn new(address: String, id: PeerId) -> WsPeer {
+        WsPeer { address, id }
+    }
+
+    // Every sync opens its own connection, on a system of its own, as get_sync is blocking
+    fn request(&self, pk: PeerId, known: Option<Vec<u8>>) -> Result<(EventHash, Vec<u8>), Error> {
+        let request = InternodeMessage::SyncRequest {
+            version: PROTOCOL_VERSION,
+            requester: pk,
+            known,
+        };
+        let mut system = System::new("ws-peer");
+        let response = system.block_on(
+            Client::new(self.address.as_str())
+                .connect()
+                .map_err(Error::from)
+                .and_then(move |(reader, mut writer)| {
+                    writer.binary(request);
+                    reader
+                        .into_future()
+                        .map(move |(message, _)| {
+                            writer.close(None);
+                            message
+                        })
+                        .map_err(|(e, _)| Error::from(e))
+                }),
+        )?;
+        let response: InternodeMessage = match response {
+            Some(Message::Binary(mut bin)) => deserialize(&bin.take())?,
+            Some(_) => {
+                return Err(Error::from(ProtocolError::new(
+                    ProtocolErrorType::UnexpectedMessage,
+                )));
+            }
+            None => {
+                return Err(Error::from(ProtocolError::new(
+                    ProtocolErrorType::ConnectionClosed,
+                )));
+            }
+        };
+        response.into_sync_response()
+    }
+}
+
+impl Peer<BTreeHashgraph, KnownHeights> for WsPeer {
+    fn get_sync(
+        &self,
+        pk: PeerId,
+        known: Option<&KnownHeights>,
+    ) -> Result<(EventHash, BTreeHashgraph), Error> {
+        let known = match known {
+            Some(known) => Some(serialize(known)?),
+            None => None,
+        };
+        let (eh, events) = self.request(pk, known)?;
+        let wire: HashgraphWire = deserialize(&events)?;
+        Ok((eh, BTreeHashgraph::from(wire)))
+    }
+    fn address(&self) -> String {
+        self.address.clone()
+    }
+    fn id(&self) -> &PeerId {
+        &self.id
+    }
+}
+
+impl Peer<Opera, KnownHeights> for WsPeer {
+    fn get_sync(
+        &self,
+        pk: PeerId,
+        known: Option<&KnownHeights>,
+    ) -> Result<(EventHash, Opera), Error> {
+        let known = match known {
+            Some(known) => Some(serialize(known)?),
+            None => None,
+        };
+        let (eh, events) = self.request(pk, known)?;
+        let wire: OperaWire = deserialize(&events)?;
+        Ok((eh, wire.into_opera()))
+    }
+    fn address(&self) -> String {
+        self.address.clone()
+    }
+    fn id(&self) -> &PeerId {
+        &self.id
+    }
+}
@@ -0,0 +1,323 @@
+use self::sim_config::{SimAction, SimConfig};
+use self::sim_network::{SimNetwork, SimStats};
+use self::sim_peer::SimPeer;
+use crate::clock::{Clock, ManualClock};
+use crate::errors::{NodeError, NodeErrorType};
+use crate::event::event_hash::EventHash;
+use crate::hashgraph::BTreeHashgraph;
+use crate::lachesis::Lachesis;
+use crate::node::Node;
+use crate::peer::PeerId;
+use crate::printable_hash::PrintableHash;
+use crate::swirlds::{consensus_config::ConsensusConfig, Swirlds};
+use failure::Error;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use ring::signature::Ed25519KeyPair;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::cmp::Reverse;
+use std::collections::{BTreeSet, BinaryHeap, VecDeque};
+use std::sync::Arc;
+
+pub mod sim_config;
+pub mod sim_network;
+pub mod sim_peer;
+
+// Lachesis runs with this many peers per sync, as it does over TCP
+pub(crate) const LACHESIS_K: usize = 3;
+
+// Nodes the simulation can connect to each other
+pub trait SimNode {
+    fn get_id(&self) -> PeerId;
+    fn add_sim_peer(&self, peer: SimPeer) -> Result<(), Error>;
+}
+
+impl SimNode for Swirlds<SimPeer, BTreeHashgraph> {
+    fn get_id(&self) -> PeerId {
+        Swirlds::get_id(self)
+    }
+    fn add_sim_peer(&self, peer: SimPeer) -> Result<(), Error> {
+        self.add_node(Arc::new(peer))
+    }
+}
+
+impl SimNode for Lachesis<SimPeer> {
+    fn get_id(&self) -> PeerId {
+        Lachesis::get_id(self)
+    }
+    fn add_sim_peer(&self, peer: SimPeer) -> Result<(), Error> {
+        self.add_peer(peer)
+    }
+}
+
+// Runs N nodes over a simulated network, one run at a time in virtual time order, so the
+// same seed always leads to the same syncs
+pub struct Simulation<N> {
+    // Every node reads the virtual time from it
+    clock: Arc<ManualClock>,
+    failed_runs: usize,
+    gossip_interval: u64,
+    network: Arc<SimNetwork>,
+    nodes: Vec<Arc<N>>,
+    // When each node runs next
+    queue: BinaryHeap<Reverse<(u64, usize)>>,
+    rng: StdRng,
+    script: VecDeque<(u64, SimAction)>,
+}
+
+impl Simulation<Swirlds<SimPeer, BTreeHashgraph>> {
+    pub fn swirlds(config: SimConfig) -> Result<Self, Error> {
+        Simulation::new(config, |kp, clock, _| {
+            Swirlds::new(kp, BTreeHashgraph::new(), ConsensusConfig::default(), clock)
+        })
+    }
+}
+
+impl Simulation<Lachesis<SimPeer>> {
+    pub fn lachesis(config: SimConfig) -> Result<Self, Error> {
+        Simulation::new(config, |kp, clock, ids| {
+            Lachesis::new(LACHESIS_K, kp, clock, ids.to_vec())
+        })
+    }
+}
+
+impl<N> Simulation<N>
+where
+    N: Node + SimNode + Send + Sync + 'static,
+    N::D: Serialize,
+    N::K: DeserializeOwned,
+{
+    // Node keys come from the seed too, and every node is connected to every other one. Each
+    // node is created knowing the ids of all of them.
+    pub fn new<F>(config: SimConfig, mut create_node: F) -> Result<Simulation<N>, Error>
+    where
+        F: FnMut(Ed25519KeyPair, Arc<dyn Clock>, &[PeerId]) -> Result<N, Error>,
+    {
+        let clock = Arc::new(ManualClock::new(0));
+        let mut rng = StdRng::seed_from_u64(config.seed);
+        let network = Arc::new(SimNetwork::new(
+            rng.gen(),
+            config.default_link.clone(),
+            config.timeout,
+        ));
+        let mut key_pairs = Vec::with_capacity(config.n_nodes);
+        for _ in 0..config.n_nodes {
+            let seed: [u8; 32] = rng.gen();
+            key_pairs.push(Ed25519KeyPair::from_seed_unchecked(
+                untrusted::Input::from(&seed),
+            )?);
+        }
+        let ids: Vec<PeerId> = key_pairs
+            .iter()
+            .map(|kp| kp.public_key_bytes().to_vec())
+            .collect();
+        let mut nodes = Vec::with_capacity(config.n_nodes);
+        for kp in key_pairs.into_iter() {
+            let node_clock: Arc<dyn Clock> = clock.clone();
+            let node = Arc::new(create_node(kp, node_clock, &ids)?);
+            network.register(node.get_id(), Arc::new(Arc::downgrade(&node)))?;
+            nodes.push(node);
+        }
+        for node in nodes.iter() {
+            for peer in nodes.iter() {
+                if peer.get_id() != node.get_id() {
+                    node.add_sim_peer(SimPeer::new(peer.get_id(), network.clone()))?;
+                }
+            }
+        }
+        // Nodes start at random times within the first gossip interval
+        let mut queue = BinaryHeap::with_capacity(nodes.len());
+        for i in 0..nodes.len() {
+            queue.push(Reverse((rng.gen_range(0, config.gossip_interval), i)));
+        }
+        let mut script = config.script;
+        script.sort_by_key(|(at, _)| *at);
+        Ok(Simulation {
+            clock,
+            failed_runs: 0,
+            gossip_interval: config.gossip_interval,
+            network,
+            nodes,
+            queue,
+            rng,
+            script: script.into_iter().collect(),
+        })
+    }
+
+    pub fn nodes(&self) -> &[Arc<N>] {
+        &self.nodes
+    }
+
+    pub fn network(&self) -> &Arc<SimNetwork> {
+        &self.network
+    }
+
+    // Runs that failed, mostly because of lost messages
+    pub fn failed_runs(&self) -> usize {
+        self.failed_runs
+    }
+
+    pub fn stats(&self) -> Result<SimStats, Error> {
+        self.network.stats()
+    }
+
+    // Runs every node due up to the given time. A node runs again a gossip interval after
+    // its previous run ended, and a run lasts as long as its round trips.
+    pub fn run_until(&mut self, until: u64) -> Result<(), Error> {
+        while let Some(Reverse((at, i))) = self.queue.peek().cloned() {
+            if at > until {
+                break;
+            }
+            self.queue.pop();
+            self.apply_script(at)?;
+            self.clock.set(at)?;
+            self.network.start_run(at)?;
+            if let Err(e) = self.nodes[i].run(&mut self.rng) {
+                debug!(
+                    "[Sim {}] Node {:?} run failed: {}",
+                    at,
+                    self.nodes[i].get_id().printable_hash(),
+                    e
+                );
+                self.failed_runs += 1;
+            }
+            let ended = at + self.network.elapsed()?;
+            self.queue.push(Reverse((ended + self.gossip_interval, i)));
+        }
+        self.apply_script(until)?;
+        self.clock.set(until)?;
+        self.network.start_run(until)
+    }
+
+    // Nodes may be behind others, but the events they all finalized must be in the same order
+    pub fn check_consistency(&self) -> Result<(), Error> {
+        let mut longest: Vec<EventHash> = Vec::new();
+        let mut orders = Vec::with_capacity(self.nodes.len());
+        for node in self.nodes.iter() {
+            let order = node
+                .get_ordered_events()?
+                .iter()
+                .map(|e| e.hash())
+                .collect::<Result<Vec<EventHash>, Error>>()?;
+            if order.len() > longest.len() {
+                longest = order.clone();
+            }
+            orders.push((node.get_id(), order));
+        }
+        for (id, order) in orders {
+            if let Some(position) = order.iter().zip(longest.iter()).position(|(a, b)| a != b) {
+                return Err(Error::from(NodeError::new(
+                    NodeErrorType::InconsistentOrder(id, position),
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    fn apply_script(&mut self, now: u64) -> Result<(), Error> {
+        while self
+            .script
+            .front()
+            .map(|(at, _)| *at <= now)
+            .unwrap_or(false)
+        {
+            if let Some((_, action)) = self.script.pop_front() {
+                self.apply(action)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn apply(&self, action: SimAction) -> Result<(), Error> {
+        match action {
+            SimAction::Partition(groups) => self.network.partition(
+                groups
+                    .iter()
+                    .map(|group| group.iter().map(|i| self.nodes[*i].get_id()).collect())
+                    .collect::<Vec<BTreeSet<PeerId>>>(),
+            ),
+            SimAction::Heal => self.network.heal(),
+            SimAction::SetLink { from, to, link } => {
+                self.network
+                    .set_link(self.nodes[from].get_id(), self.nodes[to].get_id(), link)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::sim_config::{LinkConfig, SimAction, SimConfig};
+    use super::Simulation;
+    use crate::node::Node;
+
+    fn lossy_config(seed: u64) -> SimConfig {
+        SimConfig {
+            default_link: LinkConfig {
+                drop_rate: 0.1,
+                jitter: 20,
+                latency: 10,
+            },
+            script: vec![
+                (1_000, SimAction::Partition(vec![vec![0, 1], vec![2, 3]])),
+                (2_000, SimAction::Heal),
+            ],
+            seed,
+            ..SimConfig::default()
+        }
+    }
+
+    #[test]
+    fn it_should_finalize_the_same_order_on_every_swirlds_node() {
+        let mut simulation = Simulation::swirlds(lossy_config(1)).unwrap();
+        simulation.run_until(10_000).unwrap();
+        simulation.check_consistency().unwrap();
+        let stats = simulation.stats().unwrap();
+        assert!(stats.delivered > 0);
+        assert!(stats.dropped > 0);
+        assert!(stats.partitioned > 0);
+        assert!(simulation
+            .nodes()
+            .iter()
+            .any(|n| !n.get_ordered_events().unwrap().is_empty()));
+    }
+
+    #[test]
+    fn it_should_finalize_the_same_order_on_every_lachesis_node() {
+        let mut simulation = Simulation::lachesis(lossy_config(2)).unwrap();
+        simulation.run_until(10_000).unwrap();
+        simulation.check_consistency().unwrap();
+        assert!(simulation
+            .nodes()
+            .iter()
+            .all(|n| !n.get_ordered_events().unwrap().is_empty()));
+    }
+
+    #[test]
+    fn it_should_lose_the_same_messages_for_the_same_seed() {
+        let run = |seed| {
+            let mut simulation = Simulation::swirlds(lossy_config(seed)).unwrap();
+            simulation.run_until(3_000).unwrap();
+            (simulation.stats().unwrap(), simulation.failed_runs())
+        };
+        assert_eq!(run(3), run(3));
+    }
+
+    #[test]
+    fn it_should_finalize_the_same_events_for_the_same_seed() {
+        let run = |seed| {
+            let mut simulation = Simulation::swirlds(lossy_config(seed)).unwrap();
+            simulation.run_until(5_000).unwrap();
+            simulation.nodes()[0]
+                .get_ordered_events()
+                .unwrap()
+                .iter()
+                .map(|e| (e.hash().unwrap(), e.consensus_timestamp()))
+                .collect::<Vec<_>>()
+        };
+        let first = run(5);
+        assert!(!first.is_empty());
+        assert_eq!(first, run(5));
+    }
+}
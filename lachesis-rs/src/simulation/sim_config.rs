@@ -0,0 +1,65 @@
+// Times are in virtual milliseconds, which pass only as the simulation says so
+const DEFAULT_GOSSIP_INTERVAL: u64 = 100;
+const DEFAULT_LATENCY: u64 = 10;
+const DEFAULT_N_NODES: usize = 4;
+const DEFAULT_TIMEOUT: u64 = 1_000;
+
+// One direction of the link between two nodes
+#[derive(Clone, Debug)]
+pub struct LinkConfig {
+    // Chance, between 0 and 1, of a message being lost
+    pub drop_rate: f64,
+    // Up to this much is added to the latency of every message
+    pub jitter: u64,
+    pub latency: u64,
+}
+
+impl Default for LinkConfig {
+    fn default() -> LinkConfig {
+        LinkConfig {
+            drop_rate: 0.0,
+            jitter: 0,
+            latency: DEFAULT_LATENCY,
+        }
+    }
+}
+
+// Nodes are referred to by their index in the simulation
+#[derive(Clone, Debug)]
+pub enum SimAction {
+    // Nodes only reach the ones in their own group, and nodes left out reach nobody
+    Partition(Vec<Vec<usize>>),
+    Heal,
+    SetLink {
+        from: usize,
+        to: usize,
+        link: LinkConfig,
+    },
+}
+
+#[derive(Clone, Debug)]
+pub struct SimConfig {
+    // Used by every link without one of its own
+    pub default_link: LinkConfig,
+    pub gossip_interval: u64,
+    pub n_nodes: usize,
+    // Actions and the time they happen at
+    pub script: Vec<(u64, SimAction)>,
+    // Two simulations with the same seed and config run the same way
+    pub seed: u64,
+    // Syncs whose round trip takes longer are lost
+    pub timeout: u64,
+}
+
+impl Default for SimConfig {
+    fn default() -> SimConfig {
+        SimConfig {
+            default_link: LinkConfig::default(),
+            gossip_interval: DEFAULT_GOSSIP_INTERVAL,
+            n_nodes: DEFAULT_N_NODES,
+            script: Vec::new(),
+            seed: 0,
+            timeout: DEFAULT_TIMEOUT,
+        }
+    }
+}
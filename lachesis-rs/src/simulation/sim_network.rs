@@ -0,0 +1,253 @@
+use super::sim_config::LinkConfig;
+use crate::errors::{ProtocolError, ProtocolErrorType, ResourceSimNetworkPoisonError};
+use crate::node::Node;
+use crate::peer::PeerId;
+use crate::server::ws_message::{respond, InternodeError, InternodeMessage};
+use bincode::{deserialize, serialize};
+use failure::Error;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::sync::{Arc, Mutex, Weak};
+
+// Answers the requests sent to a node, the way its transport would
+pub(crate) trait Responder: Send + Sync {
+    fn respond(&self, message: &InternodeMessage) -> InternodeMessage;
+}
+
+// Nodes hold the network through their peers, so it only keeps weak references to them
+impl<N> Responder for Weak<N>
+where
+    N: Node + Send + Sync,
+    N::D: Serialize,
+    N::K: DeserializeOwned,
+{
+    fn respond(&self, message: &InternodeMessage) -> InternodeMessage {
+        match self.upgrade() {
+            Some(node) => respond(node.as_ref(), message),
+            None => InternodeMessage::Error(InternodeError::Internal(String::from(
+                "The node was dropped",
+            ))),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SimStats {
+    pub delivered: usize,
+    pub dropped: usize,
+    pub partitioned: usize,
+}
+
+struct SimState {
+    default_link: LinkConfig,
+    // Virtual time spent in round trips since the current run started
+    elapsed: u64,
+    links: HashMap<(PeerId, PeerId), LinkConfig>,
+    now: u64,
+    partition: Option<Vec<BTreeSet<PeerId>>>,
+    responders: BTreeMap<PeerId, Arc<dyn Responder>>,
+    rng: StdRng,
+    stats: SimStats,
+    timeout: u64,
+}
+
+impl SimState {
+    fn can_reach(&self, from: &PeerId, to: &PeerId) -> bool {
+        match &self.partition {
+            Some(groups) => groups
+                .iter()
+                .any(|group| group.contains(from) && group.contains(to)),
+            None => true,
+        }
+    }
+
+    fn latency(&mut self, from: &PeerId, to: &PeerId) -> Option<u64> {
+        let link = self
+            .links
+            .get(&(from.clone(), to.clone()))
+            .unwrap_or(&self.default_link)
+            .clone();
+        if self.rng.gen_bool(link.drop_rate) {
+            return None;
+        }
+        Some(link.latency + self.rng.gen_range(0, link.jitter + 1))
+    }
+
+    // None if the request or its response are lost
+    fn round_trip(&mut self, from: &PeerId, to: &PeerId) -> Option<u64> {
+        if !self.can_reach(from, to) {
+            self.stats.partitioned += 1;
+            return None;
+        }
+        let round_trip = match (self.latency(from, to), self.latency(to, from)) {
+            (Some(there), Some(back)) => there + back,
+            _ => {
+                self.stats.dropped += 1;
+                return None;
+            }
+        };
+        if round_trip > self.timeout {
+            self.stats.dropped += 1;
+            return None;
+        }
+        self.stats.delivered += 1;
+        Some(round_trip)
+    }
+}
+
+// Carries the messages between the nodes of a simulation. Every random choice comes from a
+// seeded rng, and time only moves when the simulation moves it.
+pub struct SimNetwork {
+    state: Mutex<SimState>,
+}
+
+impl SimNetwork {
+    pub fn new(seed: u64, default_link: LinkConfig, timeout: u64) -> SimNetwork {
+        SimNetwork {
+            state: Mutex::new(SimState {
+                default_link,
+                elapsed: 0,
+                links: HashMap::new(),
+                now: 0,
+                partition: None,
+                responders: BTreeMap::new(),
+                rng: StdRng::seed_from_u64(seed),
+                stats: SimStats::default(),
+                timeout,
+            }),
+        }
+    }
+
+    pub(crate) fn register(&self, id: PeerId, responder: Arc<dyn Responder>) -> Result<(), Error> {
+        let mut state = get_from_mutex!(self.state, ResourceSimNetworkPoisonError)?;
+        state.responders.insert(id, responder);
+        Ok(())
+    }
+
+    pub fn now(&self) -> Result<u64, Error> {
+        Ok(get_from_mutex!(self.state, ResourceSimNetworkPoisonError)?.now)
+    }
+
+    pub fn stats(&self) -> Result<SimStats, Error> {
+        Ok(get_from_mutex!(self.state, ResourceSimNetworkPoisonError)?
+            .stats
+            .clone())
+    }
+
+    pub fn set_link(&self, from: PeerId, to: PeerId, link: LinkConfig) -> Result<(), Error> {
+        let mut state = get_from_mutex!(self.state, ResourceSimNetworkPoisonError)?;
+        state.links.insert((from, to), link);
+        Ok(())
+    }
+
+    pub fn partition(&self, groups: Vec<BTreeSet<PeerId>>) -> Result<(), Error> {
+        let mut state = get_from_mutex!(self.state, ResourceSimNetworkPoisonError)?;
+        state.partition = Some(groups);
+        Ok(())
+    }
+
+    pub fn heal(&self) -> Result<(), Error> {
+        let mut state = get_from_mutex!(self.state, ResourceSimNetworkPoisonError)?;
+        state.partition = None;
+        Ok(())
+    }
+
+    // Moves the clock to when a node starts a run
+    pub(crate) fn start_run(&self, now: u64) -> Result<(), Error> {
+        let mut state = get_from_mutex!(self.state, ResourceSimNetworkPoisonError)?;
+        state.now = now;
+        state.elapsed = 0;
+        Ok(())
+    }
+
+    // How long the round trips of the current run took
+    pub(crate) fn elapsed(&self) -> Result<u64, Error> {
+        Ok(get_from_mutex!(self.state, ResourceSimNetworkPoisonError)?.elapsed)
+    }
+
+    // Lost messages take the whole timeout to be noticed, as with a real transport. The
+    // answer is given right away, with the state the responder has when the request is sent.
+    pub(crate) fn exchange(
+        &self,
+        from: &PeerId,
+        to: &PeerId,
+        request: &InternodeMessage,
+    ) -> Result<InternodeMessage, Error> {
+        let responder = {
+            let mut state = get_from_mutex!(self.state, ResourceSimNetworkPoisonError)?;
+            match state.round_trip(from, to) {
+                Some(round_trip) => {
+                    state.elapsed += round_trip;
+                    state.responders.get(to).cloned()
+                }
+                None => {
+                    let timeout = state.timeout;
+                    state.elapsed += timeout;
+                    return Err(Error::from(ProtocolError::new(ProtocolErrorType::TimedOut)));
+                }
+            }
+        };
+        let responder = responder
+            .ok_or_else(|| Error::from(ProtocolError::new(ProtocolErrorType::ConnectionClosed)))?;
+        // Messages go through bincode, as they would on the wire
+        let request: InternodeMessage = deserialize(&serialize(request)?)?;
+        let response = responder.respond(&request);
+        Ok(deserialize(&serialize(&response)?)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::sim_config::LinkConfig;
+    use super::{SimNetwork, SimStats};
+    use std::collections::BTreeSet;
+    use std::iter::FromIterator;
+
+    #[test]
+    fn it_should_only_deliver_within_a_partition() {
+        let network = SimNetwork::new(0, LinkConfig::default(), 1_000);
+        let (a, b, c) = (vec![1], vec![2], vec![3]);
+        network
+            .partition(vec![
+                BTreeSet::from_iter(vec![a.clone(), b.clone()]),
+                BTreeSet::from_iter(vec![c.clone()]),
+            ])
+            .unwrap();
+        let mut state = network.state.lock().unwrap();
+        assert_eq!(state.round_trip(&a, &b), Some(20));
+        assert_eq!(state.round_trip(&a, &c), None);
+        state.partition = None;
+        assert_eq!(state.round_trip(&a, &c), Some(20));
+        assert_eq!(
+            state.stats,
+            SimStats {
+                delivered: 2,
+                dropped: 0,
+                partitioned: 1,
+            }
+        );
+    }
+
+    #[test]
+    fn it_should_drop_the_same_messages_for_the_same_seed() {
+        let link = LinkConfig {
+            drop_rate: 0.5,
+            jitter: 10,
+            latency: 10,
+        };
+        let round_trips = |seed| {
+            let network = SimNetwork::new(seed, link.clone(), 1_000);
+            let mut state = network.state.lock().unwrap();
+            (0..100)
+                .map(|_| state.round_trip(&vec![1], &vec![2]))
+                .collect::<Vec<Option<u64>>>()
+        };
+        let first = round_trips(7);
+        assert_eq!(first, round_trips(7));
+        assert!(first.iter().any(|rt| rt.is_none()));
+        assert!(first.iter().any(|rt| rt.is_some()));
+    }
+}
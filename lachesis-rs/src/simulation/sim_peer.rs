@@ -0,0 +1,78 @@
+use super::sim_network::SimNetwork;
+use crate::event::event_hash::EventHash;
+use crate::hashgraph::{BTreeHashgraph, HashgraphWire, KnownHeights};
+use crate::lachesis::opera::{Opera, OperaWire};
+use crate::peer::{Peer, PeerId};
+use crate::printable_hash::PrintableHash;
+use crate::server::ws_message::{InternodeMessage, PROTOCOL_VERSION};
+use bincode::{deserialize, serialize};
+use failure::Error;
+use std::sync::Arc;
+
+// Syncs with another node of the same simulated network
+#[derive(Clone)]
+pub struct SimPeer {
+    pub id: PeerId,
+    network: Arc<SimNetwork>,
+}
+
+impl SimPeer {
+    pub fn new(id: PeerId, network: Arc<SimNetwork>) -> SimPeer {
+        SimPeer { id, network }
+    }
+
+    fn request(&self, pk: PeerId, known: Option<Vec<u8>>) -> Result<(EventHash, Vec<u8>), Error> {
+        let request = InternodeMessage::SyncRequest {
+            version: PROTOCOL_VERSION,
+            requester: pk.clone(),
+            known,
+        };
+        self.network
+            .exchange(&pk, &self.id, &request)?
+            .into_sync_response()
+    }
+}
+
+impl Peer<BTreeHashgraph, KnownHeights> for SimPeer {
+    fn get_sync(
+        &self,
+        pk: PeerId,
+        known: Option<&KnownHeights>,
+    ) -> Result<(EventHash, BTreeHashgraph), Error> {
+        let known = match known {
+            Some(known) => Some(serialize(known)?),
+            None => None,
+        };
+        let (eh, events) = self.request(pk, known)?;
+        let wire: HashgraphWire = deserialize(&events)?;
+        Ok((eh, BTreeHashgraph::from(wire)))
+    }
+    fn address(&self) -> String {
+        format!("sim:{}", self.id.printable_hash())
+    }
+    fn id(&self) -> &PeerId {
+        &self.id
+    }
+}
+
+impl Peer<Opera, KnownHeights> for SimPeer {
+    fn get_sync(
+        &self,
+        pk: PeerId,
+        known: Option<&KnownHeights>,
+    ) -> Result<(EventHash, Opera), Error> {
+        let known = match known {
+            Some(known) => Some(serialize(known)?),
+            None => None,
+        };
+        let (eh, events) = self.request(pk, known)?;
+        let wire: OperaWire = deserialize(&events)?;
+        Ok((eh, wire.into_opera()))
+    }
+    fn address(&self) -> String {
+        format!("sim:{}", self.id.printable_hash())
+    }
+    fn id(&self) -> &PeerId {
+        &self.id
+    }
+}
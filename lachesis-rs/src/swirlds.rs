@@ -1,35 +1,47 @@
+use crate::clock::{duration_millis, Clock};
 use crate::errors::*;
 use crate::event::{
-    event_hash::EventHash, event_signature::EventSignature, parents::ParentsPair, Event,
+    event_hash::EventHash, event_signature::EventSignature, fork_evidence::ForkEvidence,
+    parents::ParentsPair, Event, EventMetadata,
+};
+use crate::hashgraph::{sort_topologically, Hashgraph, HashgraphWire, KnownHeights};
+use crate::node::{FinalizedEvent, Node};
+use crate::peer::{
+    announcement::PeerAnnouncement,
+    peer_score::{PeerScoreReport, ScoringConfig},
+    peer_table::PeerTable,
+    Peer, PeerDiscovery, PeerId,
 };
-use crate::hashgraph::{Hashgraph, HashgraphWire};
-use crate::node::Node;
-use crate::peer::{Peer, PeerId};
 use crate::printable_hash::PrintableHash;
 use crate::round::Round;
+use crate::transaction::{TransactionId, TransactionStatus, TransactionStatuses};
+use bincode::{deserialize, serialize};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
 use failure::Error;
-use rand::prelude::IteratorRandom;
 use rand::Rng;
 use ring::signature;
-use std::cmp::Ordering;
 use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::fmt;
+use std::fs::{rename, File};
+use std::io::{Read, Write};
 use std::iter::FromIterator;
 use std::marker::PhantomData;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, Receiver, Sender};
 use std::sync::MutexGuard;
 use std::sync::{Arc, Mutex};
-use std::time::{SystemTime, UNIX_EPOCH};
 
-// const C is the frequency of coin toss rounds
-// Swirlds paper requires C > 2; let keep it prime
-const C: usize = 3;
+pub mod consensus_config;
+
+use self::consensus_config::ConsensusConfig;
+
+// Bump it whenever Checkpoint changes
+const CHECKPOINT_VERSION: u32 = 6;
 
 #[inline]
-fn get_current_timestamp() -> u64 {
-    SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .expect("Time went back")
-        .as_secs()
+fn median(mut values: Vec<u64>) -> Option<u64> {
+    values.sort();
+    values.get(values.len() / 2).cloned()
 }
 
 #[inline]
@@ -43,19 +55,79 @@ fn get_round_pairs(r: &Round) -> Vec<(usize, EventHash)> {
     r.witnesses().iter().map(|w| (r.id, w.clone())).collect()
 }
 
-struct NodeInternalState<P: Peer<H>, H: Hashgraph> {
+struct NodeInternalState<P: Peer<H, KnownHeights>, H: Hashgraph> {
     consensus: BTreeSet<usize>,
-    network: HashMap<PeerId, Arc<P>>,
+    forks: HashMap<PeerId, ForkEvidence<ParentsPair>>,
+    // The node and the peers it was configured with, the ones discovered aren't members
+    members: HashSet<PeerId>,
+    network: PeerTable<P>,
     ordered_events: Vec<Event<ParentsPair>>,
     pending_events: HashSet<EventHash>,
+    pruning_window: Option<usize>,
     rounds: Vec<Round>,
+    // Stake tables by the first round they apply to
+    stakes: BTreeMap<usize, HashMap<PeerId, usize>>,
+    subscribers: Vec<Sender<FinalizedEvent<ParentsPair>>>,
     super_majority: usize,
+    transaction_statuses: TransactionStatuses,
     transactions: Vec<Vec<u8>>,
     votes: HashMap<(EventHash, EventHash), bool>,
     _phantom: PhantomData<H>,
 }
 
-impl<P: Peer<H>, H: Hashgraph + Clone + fmt::Debug> fmt::Debug for Swirlds<P, H> {
+#[derive(Debug, PartialEq)]
+enum FameDecision {
+    Decided(bool),
+    Vote(bool),
+}
+
+// How much the votes of every creator weigh in a round. Without a stake table every
+// member weighs one, and creators caught forking don't weigh anything. Anyone else
+// weighs nothing, so keys made up to gossip can't sway rounds nor fame.
+struct RoundStakes {
+    forks: HashSet<PeerId>,
+    stakes: HashMap<PeerId, usize>,
+    super_majority: usize,
+}
+
+impl RoundStakes {
+    #[inline]
+    fn of(&self, peer: &PeerId) -> usize {
+        if self.forks.contains(peer) {
+            return 0;
+        }
+        self.stakes.get(peer).cloned().unwrap_or(0)
+    }
+
+    // Only members have witnesses, forking or not
+    #[inline]
+    fn is_member(&self, peer: &PeerId) -> bool {
+        self.stakes
+            .get(peer)
+            .map(|stake| *stake > 0)
+            .unwrap_or(false)
+    }
+}
+
+// Checkpoint files are the version as a little endian u32 followed by this, bincode encoded
+#[derive(Deserialize, Serialize)]
+struct Checkpoint {
+    consensus: BTreeSet<usize>,
+    events: BTreeMap<EventHash, EventMetadata>,
+    forks: HashMap<PeerId, ForkEvidence<ParentsPair>>,
+    head: Option<EventHash>,
+    ordered_events: Vec<Event<ParentsPair>>,
+    pending_events: HashSet<EventHash>,
+    rounds: Vec<Round>,
+    stakes: BTreeMap<usize, HashMap<PeerId, usize>>,
+    transaction_statuses: TransactionStatuses,
+    transactions: Vec<Vec<u8>>,
+    votes: HashMap<(EventHash, EventHash), bool>,
+}
+
+impl<P: Peer<H, KnownHeights>, H: Hashgraph + From<HashgraphWire> + fmt::Debug> fmt::Debug
+    for Swirlds<P, H>
+{
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         fn print_arrows(f: &mut fmt::Formatter, n_nodes: usize) -> fmt::Result {
             for _ in 0..3 {
@@ -163,15 +235,15 @@ impl<P: Peer<H>, H: Hashgraph + Clone + fmt::Debug> fmt::Debug for Swirlds<P, H>
         let head = get_from_mutex!(self.head, ResourceHeadPoisonError)
             .unwrap()
             .clone();
-        let network: &HashMap<PeerId, Arc<P>> = &state.network;
+        let network: &PeerTable<P> = &state.network;
         let hashgraph = get_from_mutex!(self.hashgraph, ResourceHashgraphPoisonError).unwrap();
         writeln!(f, "Node ID: {:?}", self.get_id().printable_hash())?;
         writeln!(f, "Head: {:?}", head.map(|h| h.printable_hash()))?;
         let roots: Vec<EventHash> = hashgraph.find_roots();
         let mut last_event_per_peer: BTreeMap<PeerId, Option<EventHash>> = BTreeMap::new();
-        for peer in network.keys() {
+        for peer in network.ids() {
             for root in roots.iter() {
-                let e: &Event<ParentsPair> = hashgraph.get(root).unwrap();
+                let e = hashgraph.get(root).unwrap();
                 if e.creator() == peer {
                     last_event_per_peer.insert(peer.clone(), Some(root.clone()));
                 }
@@ -181,7 +253,7 @@ impl<P: Peer<H>, H: Hashgraph + Clone + fmt::Debug> fmt::Debug for Swirlds<P, H>
             }
         }
         for root in roots.iter() {
-            let e: &Event<ParentsPair> = hashgraph.get(root).unwrap();
+            let e = hashgraph.get(root).unwrap();
             if e.creator() == &self.get_id() {
                 last_event_per_peer.insert(self.get_id().clone(), Some(root.clone()));
             }
@@ -225,7 +297,12 @@ impl<P: Peer<H>, H: Hashgraph + Clone + fmt::Debug> fmt::Debug for Swirlds<P, H>
     }
 }
 
-pub struct Swirlds<P: Peer<H>, H: Hashgraph + Clone + fmt::Debug> {
+pub struct Swirlds<P: Peer<H, KnownHeights>, H: Hashgraph + From<HashgraphWire> + fmt::Debug> {
+    clock: Arc<dyn Clock>,
+    config: ConsensusConfig,
+    // Held from merging the events of a sync to ordering them, so concurrent runs only
+    // overlap while fetching
+    consensus: Mutex<()>,
     hashgraph: Mutex<H>,
     head: Mutex<Option<EventHash>>,
     // TODO: Plain keys in memory? Not great. See https://stackoverflow.com/a/1263421 for possible
@@ -234,20 +311,50 @@ pub struct Swirlds<P: Peer<H>, H: Hashgraph + Clone + fmt::Debug> {
     state: Mutex<NodeInternalState<P, H>>,
 }
 
-impl<H: Hashgraph + Clone + fmt::Debug, P: Peer<H>> Swirlds<P, H> {
-    pub fn new(pk: signature::Ed25519KeyPair, hashgraph: H) -> Result<Self, Error> {
+impl<H: Hashgraph + From<HashgraphWire> + fmt::Debug, P: Peer<H, KnownHeights>> Swirlds<P, H> {
+    pub fn new(
+        pk: signature::Ed25519KeyPair,
+        hashgraph: H,
+        config: ConsensusConfig,
+        clock: Arc<dyn Clock>,
+    ) -> Result<Self, Error> {
+        Swirlds::with_scoring(pk, hashgraph, config, clock, ScoringConfig::default())
+    }
+
+    // Scoring is part of the gossip and not the consensus, so it goes to the peer table
+    pub fn with_scoring(
+        pk: signature::Ed25519KeyPair,
+        hashgraph: H,
+        config: ConsensusConfig,
+        clock: Arc<dyn Clock>,
+        scoring: ScoringConfig,
+    ) -> Result<Self, Error> {
+        if config.coin_frequency <= 2 {
+            return Err(Error::from(NodeError::new(
+                NodeErrorType::InvalidCoinFrequency(config.coin_frequency),
+            )));
+        }
         let state = Mutex::new(NodeInternalState {
             consensus: BTreeSet::new(),
-            network: HashMap::new(),
+            forks: HashMap::new(),
+            members: HashSet::from_iter(vec![pk.public_key_bytes().to_vec()]),
+            network: PeerTable::with_scoring(scoring, clock.clone()),
             ordered_events: Vec::new(),
             pending_events: HashSet::new(),
+            pruning_window: None,
             rounds: Vec::new(),
+            stakes: BTreeMap::new(),
+            subscribers: Vec::new(),
             super_majority: 0,
+            transaction_statuses: TransactionStatuses::default(),
             transactions: Vec::new(),
             votes: HashMap::new(),
             _phantom: PhantomData,
         });
         let node = Swirlds {
+            clock,
+            config,
+            consensus: Mutex::new(()),
             hashgraph: Mutex::new(hashgraph),
             head: Mutex::new(None),
             pk,
@@ -257,25 +364,150 @@ impl<H: Hashgraph + Clone + fmt::Debug, P: Peer<H>> Swirlds<P, H> {
         Ok(node)
     }
 
+    pub fn with_stakes(
+        pk: signature::Ed25519KeyPair,
+        hashgraph: H,
+        config: ConsensusConfig,
+        clock: Arc<dyn Clock>,
+        stakes: HashMap<PeerId, usize>,
+    ) -> Result<Self, Error> {
+        let node = Swirlds::new(pk, hashgraph, config, clock)?;
+        node.update_stakes(0, stakes)?;
+        Ok(node)
+    }
+
+    // The new stakes apply from `round` on. It can't have started yet, so every node
+    // weighs the votes of a round with the same table.
+    pub fn update_stakes(&self, round: usize, stakes: HashMap<PeerId, usize>) -> Result<(), Error> {
+        let mut state = get_from_mutex!(self.state, ResourceNodeInternalStatePoisonError)?;
+        if round < state.rounds.len() {
+            return Err(Error::from(NodeError::new(
+                NodeErrorType::RoundAlreadyStarted(round),
+            )));
+        }
+        state.stakes.insert(round, stakes);
+        Ok(())
+    }
+
+    pub fn checkpoint<T: AsRef<Path>>(&self, path: T) -> Result<(), Error> {
+        let head = get_from_mutex!(self.head, ResourceHeadPoisonError)?.clone();
+        let checkpoint = {
+            let hashgraph = get_from_mutex!(self.hashgraph, ResourceHashgraphPoisonError)?;
+            let state = get_from_mutex!(self.state, ResourceNodeInternalStatePoisonError)?;
+            let mut events = BTreeMap::new();
+            for eh in hashgraph.get_events() {
+                let metadata = hashgraph.get(&eh)?.metadata();
+                events.insert(eh, metadata);
+            }
+            Checkpoint {
+                consensus: state.consensus.clone(),
+                events,
+                forks: state.forks.clone(),
+                head,
+                ordered_events: state.ordered_events.clone(),
+                pending_events: state.pending_events.clone(),
+                rounds: state.rounds.clone(),
+                stakes: state.stakes.clone(),
+                transaction_statuses: state.transaction_statuses.clone(),
+                transactions: state.transactions.clone(),
+                votes: state.votes.clone(),
+            }
+        };
+        // Written aside and renamed so a crash never leaves a half written checkpoint
+        let path = path.as_ref();
+        let tmp_path = path.with_extension("tmp");
+        {
+            let mut file = File::create(&tmp_path)?;
+            file.write_u32::<LittleEndian>(CHECKPOINT_VERSION)?;
+            file.write_all(&serialize(&checkpoint)?)?;
+            file.sync_all()?;
+        }
+        rename(&tmp_path, path)?;
+        Ok(())
+    }
+
+    pub fn restore<T: AsRef<Path>>(
+        path: T,
+        pk: signature::Ed25519KeyPair,
+        mut hashgraph: H,
+        config: ConsensusConfig,
+        clock: Arc<dyn Clock>,
+        scoring: ScoringConfig,
+    ) -> Result<Self, Error> {
+        let mut content = Vec::new();
+        File::open(path)?.read_to_end(&mut content)?;
+        let mut reader = &content[..];
+        let version = reader.read_u32::<LittleEndian>()?;
+        if version != CHECKPOINT_VERSION {
+            return Err(Error::from(NodeError::new(
+                NodeErrorType::UnsupportedCheckpointVersion(version),
+            )));
+        }
+        let checkpoint: Checkpoint = deserialize(reader)?;
+        for (eh, metadata) in checkpoint.events.into_iter() {
+            hashgraph.get_mut(&eh)?.set_metadata(metadata);
+        }
+        if let Some(head) = checkpoint.head.as_ref() {
+            if hashgraph.get(head)?.creator().as_slice() != pk.public_key_bytes() {
+                return Err(Error::from(NodeError::new(
+                    NodeErrorType::CheckpointKeyMismatch,
+                )));
+            }
+        }
+        let state = Mutex::new(NodeInternalState {
+            consensus: checkpoint.consensus,
+            forks: checkpoint.forks,
+            members: HashSet::from_iter(vec![pk.public_key_bytes().to_vec()]),
+            network: PeerTable::with_scoring(scoring, clock.clone()),
+            ordered_events: checkpoint.ordered_events,
+            pending_events: checkpoint.pending_events,
+            pruning_window: None,
+            rounds: checkpoint.rounds,
+            stakes: checkpoint.stakes,
+            subscribers: Vec::new(),
+            super_majority: 0,
+            transaction_statuses: checkpoint.transaction_statuses,
+            transactions: checkpoint.transactions,
+            votes: checkpoint.votes,
+            _phantom: PhantomData,
+        });
+        Ok(Swirlds {
+            clock,
+            config,
+            consensus: Mutex::new(()),
+            hashgraph: Mutex::new(hashgraph),
+            head: Mutex::new(checkpoint.head),
+            pk,
+            state,
+        })
+    }
+
     #[inline]
     pub fn add_node(&self, peer: Arc<P>) -> Result<(), Error> {
         let super_majority = {
             let mut state = get_from_mutex!(self.state, ResourceNodeInternalStatePoisonError)?;
+            state.members.insert(peer.id().clone());
             state.network.insert(peer.id().clone(), peer);
-            state.network.len() * 2 / 3
+            (self.config.super_majority)(state.network.len())
         };
         self.set_super_majority(super_majority)?;
         Ok(())
     }
 
-    pub fn sync(&self, remote_head: EventHash, remote_hg: H) -> Result<Vec<EventHash>, Error> {
+    // Invalid events in the remote graph count against the peer it came from
+    pub fn sync(
+        &self,
+        from: &PeerId,
+        remote_head: EventHash,
+        remote_hg: H,
+    ) -> Result<Vec<EventHash>, Error> {
         info!(
             "[Node {:?}] Syncing with head {:?}",
             self.get_id().printable_hash(),
             remote_head.printable_hash()
         );
         debug!("{:?}", self);
-        let mut res = self.merge_hashgraph(remote_hg.clone())?;
+        let mut res = self.merge_hashgraph(from, &remote_hg)?;
         info!(
             "[Node {:?}] Merging {:?}",
             self.get_id().printable_hash(),
@@ -286,7 +518,7 @@ impl<H: Hashgraph + Clone + fmt::Debug, P: Peer<H>> Swirlds<P, H> {
         debug!("{:?}", self);
 
         if res.len() > 0 {
-            let new_head = self.maybe_change_head(remote_head, remote_hg.clone())?;
+            let new_head = self.maybe_change_head(remote_head, &remote_hg)?;
             res.extend(new_head.into_iter());
         }
         Ok(res)
@@ -315,30 +547,22 @@ impl<H: Hashgraph + Clone + fmt::Debug, P: Peer<H>> Swirlds<P, H> {
     pub fn decide_fame(&self) -> Result<BTreeSet<usize>, Error> {
         let mut famous_events = HashMap::new();
         let mut rounds_done = BTreeSet::new();
-        let super_majority = self.get_super_majority()?;
         for (round, veh) in self.get_voters()?.into_iter() {
             let witnesses = self.get_round_witnesses(round, &veh)?;
+            // The witnesses voting here are from the previous round
+            let stakes = self.get_round_stakes(round.saturating_sub(1))?;
             for (ur, eh) in self.get_undetermined_events(round)? {
                 if round - ur == 1 {
                     self.vote(veh.clone(), eh.clone(), witnesses.contains(&eh))?;
                 } else {
-                    let (vote, stake) = self.get_vote(&witnesses, &eh)?;
-                    if (round - ur) % C > 0 {
-                        if stake > super_majority {
-                            famous_events.insert(eh, vote);
+                    let (vote, stake) = self.get_vote(&stakes, &witnesses, &eh)?;
+                    let is_super_majority = stake > stakes.super_majority;
+                    match self.fame_decision(&veh, round - ur, vote, is_super_majority)? {
+                        FameDecision::Decided(famous) => {
+                            famous_events.insert(eh, famous);
                             rounds_done.insert(ur);
-                        } else {
-                            self.vote(veh.clone(), eh, vote)?;
-                        }
-                    } else {
-                        if stake > super_majority {
-                            self.vote(veh.clone(), eh, vote)?;
-                        } else {
-                            let hashgraph =
-                                get_from_mutex!(self.hashgraph, ResourceHashgraphPoisonError)?;
-                            let new_vote = hashgraph.get(&veh)?.signature()?.as_ref()[0] != 0;
-                            self.vote(veh.clone(), eh, new_vote)?;
                         }
+                        FameDecision::Vote(vote) => self.vote(veh.clone(), eh, vote)?,
                     }
                 }
             }
@@ -373,18 +597,113 @@ impl<H: Hashgraph + Clone + fmt::Debug, P: Peer<H>> Swirlds<P, H> {
         Ok(new_consensus)
     }
 
-    pub fn find_order(&self, new_consensus: BTreeSet<usize>) -> Result<(), Error> {
-        let mut state = get_from_mutex!(self.state, ResourceNodeInternalStatePoisonError)?;
+    // What a voter `distance` rounds ahead of a witness does about its fame, given the
+    // majority of the votes it can strongly see
+    #[inline]
+    fn fame_decision(
+        &self,
+        voter: &EventHash,
+        distance: usize,
+        vote: bool,
+        is_super_majority: bool,
+    ) -> Result<FameDecision, Error> {
+        if distance % self.config.coin_frequency > 0 {
+            if is_super_majority {
+                Ok(FameDecision::Decided(vote))
+            } else {
+                Ok(FameDecision::Vote(vote))
+            }
+        } else if is_super_majority {
+            Ok(FameDecision::Vote(vote))
+        } else {
+            let hashgraph = get_from_mutex!(self.hashgraph, ResourceHashgraphPoisonError)?;
+            let voter = hashgraph.get(voter)?;
+            Ok(FameDecision::Vote(self.config.coin.flip(&voter)?))
+        }
+    }
+
+    // Returns the events that got their round received, each of them only once
+    pub fn find_order(&self, new_consensus: BTreeSet<usize>) -> Result<Vec<EventHash>, Error> {
+        let mut received = Vec::new();
         for round in new_consensus {
             let unique_famous_witnesses = self.get_unique_famous_witnesses(round)?;
-            for eh in state.pending_events.clone() {
+            if unique_famous_witnesses.is_empty() {
+                continue;
+            }
+            let pending_events = {
+                let state = get_from_mutex!(self.state, ResourceNodeInternalStatePoisonError)?;
+                state.pending_events.clone()
+            };
+            for eh in pending_events {
                 let is_round_received = self.is_round_received(&unique_famous_witnesses, &eh)?;
                 if is_round_received {
                     self.set_received_information(&eh, round, &unique_famous_witnesses)?;
+                    let mut state =
+                        get_from_mutex!(self.state, ResourceNodeInternalStatePoisonError)?;
                     state.pending_events.remove(&eh);
+                    received.push(eh);
+                }
+            }
+        }
+        Ok(received)
+    }
+
+    // Events received more than `retention_window` rounds before the last consensus round
+    // are dropped, along with their votes, rounds and transaction statuses. The last event
+    // of every creator and whatever the remaining events can see are kept so new events can
+    // still be validated and assigned a round.
+    pub fn prune(&self, retention_window: usize) -> Result<Vec<EventHash>, Error> {
+        let mut hashgraph = get_from_mutex!(self.hashgraph, ResourceHashgraphPoisonError)?;
+        let mut state = get_from_mutex!(self.state, ResourceNodeInternalStatePoisonError)?;
+        let cutoff = match state.consensus.iter().last() {
+            Some(last) if *last > retention_window => last - retention_window,
+            _ => return Ok(Vec::new()),
+        };
+        let mut keep = HashSet::new();
+        let mut creators = HashSet::new();
+        for eh in hashgraph.get_events() {
+            let event = hashgraph.get(&eh)?;
+            creators.insert(event.creator().clone());
+            if event.round_received().map(|r| r >= cutoff).unwrap_or(true) {
+                keep.insert(eh);
+            }
+        }
+        keep.extend(creators.iter().filter_map(|c| hashgraph.last_event_of(c)));
+        for eh in keep.clone() {
+            for seen in hashgraph.get(&eh)?.can_see().values() {
+                if hashgraph.contains_key(seen) {
+                    keep.insert(seen.clone());
                 }
             }
         }
+        let pruned: HashSet<EventHash> = HashSet::from_iter(
+            hashgraph
+                .get_events()
+                .into_iter()
+                .filter(|eh| !keep.contains(eh)),
+        );
+        for eh in pruned.iter() {
+            hashgraph.remove(eh)?;
+        }
+        state
+            .votes
+            .retain(|(voter, eh), _| !pruned.contains(voter) && !pruned.contains(eh));
+        for round in state.rounds.iter_mut().take(cutoff) {
+            *round = Round::new(round.id);
+        }
+        state.transaction_statuses.prune(cutoff);
+        info!(
+            "[Node {:?}] Pruned {} events below round {}",
+            self.get_id().printable_hash(),
+            pruned.len(),
+            cutoff
+        );
+        Ok(pruned.into_iter().collect())
+    }
+
+    pub fn set_pruning_window(&self, retention_window: Option<usize>) -> Result<(), Error> {
+        let mut state = get_from_mutex!(self.state, ResourceNodeInternalStatePoisonError)?;
+        state.pruning_window = retention_window;
         Ok(())
     }
 
@@ -392,9 +711,10 @@ impl<H: Hashgraph + Clone + fmt::Debug, P: Peer<H>> Swirlds<P, H> {
         self.pk.public_key_bytes().to_vec()
     }
 
+    // A copy in memory, as the graph may be kept on disk
     pub fn get_hashgraph(&self) -> Result<H, Error> {
         let hashgraph = get_from_mutex!(self.hashgraph, ResourceHashgraphPoisonError)?;
-        Ok(hashgraph.clone())
+        Ok(H::from(hashgraph.wire()?))
     }
 
     pub fn get_head(&self) -> Result<EventHash, Error> {
@@ -404,46 +724,92 @@ impl<H: Hashgraph + Clone + fmt::Debug, P: Peer<H>> Swirlds<P, H> {
             .ok_or(Error::from(NodeError::new(NodeErrorType::NoHead)))
     }
 
+    pub fn get_fork_evidence(&self) -> Result<Vec<ForkEvidence<ParentsPair>>, Error> {
+        let state = get_from_mutex!(self.state, ResourceNodeInternalStatePoisonError)?;
+        Ok(state.forks.values().cloned().collect())
+    }
+
     pub fn get_peer(&self, id: &PeerId) -> Result<Arc<P>, Error> {
         let state = get_from_mutex!(self.state, ResourceNodeInternalStatePoisonError)?;
         state
             .network
             .get(id)
-            .map(|v| v.clone())
             .ok_or(Error::from(NodeError::new(NodeErrorType::PeerNotFound(
                 id.clone(),
             ))))
     }
 
-    fn update_order(&self) -> Result<(), Error> {
-        let mut state = get_from_mutex!(self.state, ResourceNodeInternalStatePoisonError)?;
+    // Received events are appended to the order by round received, then by consensus
+    // timestamp and then by their signature whitened with the famous witnesses of the round
+    fn update_order(&self, received: Vec<EventHash>) -> Result<(), Error> {
+        let mut whiteners: HashMap<usize, [u8; 64]> = HashMap::new();
+        let mut keys = Vec::with_capacity(received.len());
+        for eh in received.into_iter() {
+            let (round_received, consensus_timestamp, signature) = {
+                let hashgraph = get_from_mutex!(self.hashgraph, ResourceHashgraphPoisonError)?;
+                let event = hashgraph.get(&eh)?;
+                let round_received = event.round_received().ok_or(Error::from(EventError::new(
+                    EventErrorType::RoundNotSet { hash: eh.clone() },
+                )))?;
+                let consensus_timestamp =
+                    event
+                        .consensus_timestamp()
+                        .ok_or(Error::from(EventError::new(EventErrorType::NoTimestamp {
+                            hash: eh.clone(),
+                        })))?;
+                (round_received, consensus_timestamp, event.signature()?)
+            };
+            if !whiteners.contains_key(&round_received) {
+                whiteners.insert(round_received, self.get_whitener(round_received)?);
+            }
+            let whitened_signature: Vec<u8> = signature
+                .0
+                .iter()
+                .zip(whiteners[&round_received].iter())
+                .map(|(s, w)| s ^ w)
+                .collect();
+            keys.push((round_received, consensus_timestamp, whitened_signature, eh));
+        }
+        keys.sort();
         let hashgraph = get_from_mutex!(self.hashgraph, ResourceHashgraphPoisonError)?;
-        let events: Vec<EventHash> = hashgraph.get_events();
-        let rounds: Vec<usize> = state.consensus.clone().into_iter().collect();
-        let mut hashe_pairs: Vec<(u64, Event<ParentsPair>)> = rounds
-            .into_iter()
-            .flat_map(|r| {
-                events
-                    .iter()
-                    .map(|h| (h.clone(), hashgraph.get(h).unwrap().maybe_round()))
-                    .filter(|(_, h): &(EventHash, Option<usize>)| h.is_some())
-                    .map(|(h, r): (EventHash, Option<usize>)| (h, r.expect("can't happen")))
-                    .filter(move |(_, cr): &(EventHash, usize)| r == cr.clone())
-                    .map(|(h, _): (EventHash, usize)| h)
-            })
-            .map(|h| hashgraph.get(&h))
-            .collect::<Result<Vec<&Event<ParentsPair>>, Error>>()?
-            .into_iter()
-            .map(|v| v.clone())
-            .filter(|e: &Event<ParentsPair>| e.timestamp().is_ok())
-            .map(|e: Event<ParentsPair>| (e.timestamp().expect("can't happen"), e))
-            .collect::<Vec<(u64, Event<ParentsPair>)>>();
-        hashe_pairs.sort_by_key(|(t, _)| t.clone());
-        let events: Vec<Event<ParentsPair>> = hashe_pairs.into_iter().map(|(_, e)| e).collect();
-        state.ordered_events = events;
+        let mut state = get_from_mutex!(self.state, ResourceNodeInternalStatePoisonError)?;
+        for (round_received, consensus_timestamp, _, eh) in keys.into_iter() {
+            let event = hashgraph.get(&eh)?.into_owned();
+            let consensus_index = state.ordered_events.len();
+            state.transaction_statuses.add_committed(
+                &event.payload(),
+                consensus_index,
+                round_received,
+                consensus_timestamp,
+            );
+            let finalized = FinalizedEvent {
+                consensus_index,
+                event: event.clone(),
+                round_received,
+            };
+            // Subscribers that hung up are dropped
+            state
+                .subscribers
+                .retain(|subscriber| subscriber.send(finalized.clone()).is_ok());
+            state.ordered_events.push(event);
+        }
         Ok(())
     }
 
+    #[inline]
+    fn get_whitener(&self, round: usize) -> Result<[u8; 64], Error> {
+        let unique_famous_witnesses = self.get_unique_famous_witnesses(round)?;
+        let hashgraph = get_from_mutex!(self.hashgraph, ResourceHashgraphPoisonError)?;
+        let mut whitener = [0; 64];
+        for w in unique_famous_witnesses.iter() {
+            let signature = hashgraph.get(w)?.signature()?;
+            for (w, s) in whitener.iter_mut().zip(signature.0.iter()) {
+                *w ^= s;
+            }
+        }
+        Ok(whitener)
+    }
+
     #[inline]
     fn update_famous_events(&self, famous_events: HashMap<EventHash, bool>) -> Result<(), Error> {
         let mut hashgraph = get_from_mutex!(self.hashgraph, ResourceHashgraphPoisonError)?;
@@ -473,6 +839,24 @@ impl<H: Hashgraph + Clone + fmt::Debug, P: Peer<H>> Swirlds<P, H> {
         Ok(state.super_majority)
     }
 
+    #[inline]
+    fn get_round_stakes(&self, round: usize) -> Result<RoundStakes, Error> {
+        let state = get_from_mutex!(self.state, ResourceNodeInternalStatePoisonError)?;
+        let forks = state.forks.keys().cloned().collect();
+        Ok(match state.stakes.range(..=round).next_back() {
+            Some((_, stakes)) => RoundStakes {
+                forks,
+                stakes: stakes.clone(),
+                super_majority: (self.config.super_majority)(stakes.values().sum()),
+            },
+            None => RoundStakes {
+                forks,
+                stakes: state.members.iter().map(|id| (id.clone(), 1)).collect(),
+                super_majority: state.super_majority,
+            },
+        })
+    }
+
     #[inline]
     fn vote(&self, veh: EventHash, eh: EventHash, vote: bool) -> Result<(), Error> {
         let mut state = get_from_mutex!(self.state, ResourceNodeInternalStatePoisonError)?;
@@ -482,8 +866,12 @@ impl<H: Hashgraph + Clone + fmt::Debug, P: Peer<H>> Swirlds<P, H> {
 
     #[inline]
     fn maybe_add_witness_to_round(&self, round: usize, eh: &EventHash) -> Result<(), Error> {
+        let stakes = self.get_round_stakes(round)?;
         let hashgraph = get_from_mutex!(self.hashgraph, ResourceHashgraphPoisonError)?;
         let event = hashgraph.get(&eh)?;
+        if !stakes.is_member(event.creator()) {
+            return Ok(());
+        }
         if round == 0 || round > hashgraph.get(&event.self_parent()?)?.round()? {
             let creator = event.creator().clone();
             self.add_witness_to_round(round, creator, eh)?;
@@ -554,10 +942,15 @@ impl<H: Hashgraph + Clone + fmt::Debug, P: Peer<H>> Swirlds<P, H> {
             .filter(|eh| eh.is_some())
             .map(|eh| eh.unwrap())
             .collect::<Vec<u64>>();
-        let times_sum: u64 = times.iter().sum();
-        let new_time = times_sum / times.len() as u64;
         let event = hashgraph.get_mut(hash)?;
-        event.set_timestamp(new_time);
+        match median(times) {
+            Some(consensus_timestamp) => event.set_consensus_timestamp(consensus_timestamp),
+            None => debug!(
+                target: "swirlds",
+                "No timestamp deciders for {:?}",
+                hash.printable_hash()
+            ),
+        }
         event.set_round_received(round);
         Ok(())
     }
@@ -570,15 +963,16 @@ impl<H: Hashgraph + Clone + fmt::Debug, P: Peer<H>> Swirlds<P, H> {
     ) -> Result<HashSet<EventHash>, Error> {
         let mut result = HashSet::new();
         let hashgraph = get_from_mutex!(self.hashgraph, ResourceHashgraphPoisonError)?;
+        // For every witness, the earliest of its self ancestors that has the event as ancestor
         for unique_famous_witness in unique_famous_witnesses {
-            let self_ancestors = hashgraph.self_ancestors(unique_famous_witness)?.into_iter();
-            for self_ancestor in self_ancestors {
-                let ancestors = hashgraph.ancestors(self_ancestor)?;
-                let event = hashgraph.get(self_ancestor)?;
-                if ancestors.contains(&hash) && !event.is_self_parent(hash)? {
-                    result.insert(self_ancestor.clone());
+            let mut decider = None;
+            for self_ancestor in hashgraph.self_ancestors(unique_famous_witness)? {
+                if !hashgraph.ancestors(self_ancestor)?.contains(&hash) {
+                    break;
                 }
+                decider = Some(self_ancestor.clone());
             }
+            result.extend(decider.into_iter());
         }
         Ok(result)
     }
@@ -653,31 +1047,42 @@ impl<H: Hashgraph + Clone + fmt::Debug, P: Peer<H>> Swirlds<P, H> {
     #[inline]
     fn get_vote(
         &self,
+        stakes: &RoundStakes,
         witnesses: &HashSet<EventHash>,
         eh: &EventHash,
     ) -> Result<(bool, usize), Error> {
-        let total = self.get_votes_for_event(witnesses, eh)?;
-        if total >= witnesses.len() / 2 {
-            Ok((true, total))
+        let (yes, no) = self.get_votes_for_event(stakes, witnesses, eh)?;
+        if yes >= no {
+            Ok((true, yes))
         } else {
-            Ok((false, witnesses.len() - total))
+            Ok((false, no))
         }
     }
 
     #[inline]
     fn get_votes_for_event(
         &self,
+        stakes: &RoundStakes,
         witnesses: &HashSet<EventHash>,
         eh: &EventHash,
-    ) -> Result<usize, Error> {
+    ) -> Result<(usize, usize), Error> {
+        let creators = {
+            let hashgraph = get_from_mutex!(self.hashgraph, ResourceHashgraphPoisonError)?;
+            witnesses
+                .iter()
+                .map(|w| Ok((w.clone(), hashgraph.get(w)?.creator().clone())))
+                .collect::<Result<Vec<(EventHash, PeerId)>, Error>>()?
+        };
         let state = get_from_mutex!(self.state, ResourceNodeInternalStatePoisonError)?;
-        let mut total = 0;
-        for w in witnesses {
-            if state.votes[&(w.clone(), eh.clone())] {
-                total += 1;
+        let (mut yes, mut no) = (0, 0);
+        for (w, creator) in creators {
+            match state.votes.get(&(w, eh.clone())) {
+                Some(true) => yes += stakes.of(&creator),
+                Some(false) => no += stakes.of(&creator),
+                None => {}
             }
         }
-        Ok(total)
+        Ok((yes, no))
     }
 
     #[inline]
@@ -688,21 +1093,15 @@ impl<H: Hashgraph + Clone + fmt::Debug, P: Peer<H>> Swirlds<P, H> {
         let state = get_from_mutex!(self.state, ResourceNodeInternalStatePoisonError)?;
         let a = (next_consensus..round)
             .filter(|r| !state.consensus.contains(r))
-            .map(|r| get_round_pairs(&state.rounds[r]).into_iter())
-            .flatten()
-            .map(|(_, h)| match hashgraph.get(&h) {
-                Ok(hg) => Some(hg),
+            .flat_map(|r| get_round_pairs(&state.rounds[r]).into_iter())
+            .filter(|(_, h)| match hashgraph.get(h) {
+                Ok(hg) => hg.is_undefined(),
                 Err(e) => {
                     debug!(target: "swirlds", "{}", e);
-                    return None;
+                    false
                 }
             })
-            .filter(|hg_opt| hg_opt.is_some())
-            .map(|hg_opt| hg_opt.unwrap())
-            .filter(|hg| hg.is_undefined())
-            .map(|hg| hg.hash())
-            .map(|hg| hg.unwrap())
-            .map(|ef| (round, ef))
+            .map(|(_, h)| (round, h))
             .collect();
         Ok(a)
     }
@@ -716,14 +1115,14 @@ impl<H: Hashgraph + Clone + fmt::Debug, P: Peer<H>> Swirlds<P, H> {
         if round == 0 {
             Ok(HashSet::new())
         } else {
-            let hits = self.get_round_hits(round, hash)?;
             let prev_round = round - 1;
-            let super_majority = self.get_super_majority()?;
+            let stakes = self.get_round_stakes(prev_round)?;
+            let hits = self.get_round_hits(&stakes, round, hash)?;
             let state = get_from_mutex!(self.state, ResourceNodeInternalStatePoisonError)?;
             let r = &state.rounds[prev_round];
             let map_iter = hits
                 .into_iter()
-                .filter(|(_, v)| *v > super_majority)
+                .filter(|(_, v)| *v > stakes.super_majority)
                 .map(|(c, _)| r.witnesses_map()[&c].clone());
             Ok(HashSet::from_iter(map_iter))
         }
@@ -732,6 +1131,7 @@ impl<H: Hashgraph + Clone + fmt::Debug, P: Peer<H>> Swirlds<P, H> {
     #[inline]
     fn get_round_hits(
         &self,
+        stakes: &RoundStakes,
         round: usize,
         hash: &EventHash,
     ) -> Result<HashMap<PeerId, usize>, Error> {
@@ -748,7 +1148,8 @@ impl<H: Hashgraph + Clone + fmt::Debug, P: Peer<H>> Swirlds<P, H> {
                     for (_creator, _event_hash) in possible_witness.can_see().iter() {
                         let r = hashgraph.get(_event_hash)?.round()?;
                         if r == prev_round {
-                            let new_val = hits.get(creator).map(|v| *v + 1).unwrap_or(1);
+                            let stake = stakes.of(_creator);
+                            let new_val = hits.get(creator).map(|v| *v + stake).unwrap_or(stake);
                             hits.insert(creator.clone(), new_val);
                         }
                     }
@@ -786,10 +1187,9 @@ impl<H: Hashgraph + Clone + fmt::Debug, P: Peer<H>> Swirlds<P, H> {
 
     #[inline]
     fn assign_round(&self, hash: &EventHash) -> Result<usize, Error> {
-        let is_root = {
-            let hashgraph = get_from_mutex!(self.hashgraph, ResourceHashgraphPoisonError)?;
-            hashgraph.get(hash)?.is_root()
-        };
+        let is_root = get_from_mutex!(self.hashgraph, ResourceHashgraphPoisonError)?
+            .get(hash)?
+            .is_root();
         if is_root {
             let mut hashgraph = get_from_mutex!(self.hashgraph, ResourceHashgraphPoisonError)?;
             assign_round(hashgraph.get_mut(&hash)?, 0)
@@ -805,10 +1205,14 @@ impl<H: Hashgraph + Clone + fmt::Debug, P: Peer<H>> Swirlds<P, H> {
             hashgraph.events_parents_can_see(hash)?
         };
         let mut r = self.get_parents_round(hash)?;
-        let hits = self.get_hits_per_events(r, &events_parents_can_see)?;
-        let sm = self.get_super_majority()?;
-        let votes = hits.values().map(|v| v.clone()).filter(|v| *v > sm);
-        if votes.sum::<usize>() > sm {
+        let stakes = self.get_round_stakes(r)?;
+        let hits = self.get_hits_per_events(&stakes, r, &events_parents_can_see)?;
+        let strongly_seen_stake: usize = hits
+            .iter()
+            .filter(|(_, v)| **v > stakes.super_majority)
+            .map(|(c, _)| stakes.of(c))
+            .sum();
+        if strongly_seen_stake > stakes.super_majority {
             r += 1;
         }
         self.set_events_parents_can_see(hash, events_parents_can_see)?;
@@ -819,19 +1223,20 @@ impl<H: Hashgraph + Clone + fmt::Debug, P: Peer<H>> Swirlds<P, H> {
     #[inline]
     fn get_hits_per_events(
         &self,
+        stakes: &RoundStakes,
         r: usize,
         events_parents_can_see: &HashMap<PeerId, EventHash>,
     ) -> Result<HashMap<PeerId, usize>, Error> {
         let hashgraph = get_from_mutex!(self.hashgraph, ResourceHashgraphPoisonError)?;
         let mut hits: HashMap<PeerId, usize> = HashMap::new();
-        for (_, h) in events_parents_can_see.iter() {
+        for (seer, h) in events_parents_can_see.iter() {
             let event = hashgraph.get(h)?;
             if event.round()? == r {
                 for (_c, _h) in event.can_see().iter() {
                     let seen_event = hashgraph.get(_h)?;
                     if seen_event.round()? == r {
                         let prev = hits.get(_c).map(|v| v.clone()).unwrap_or(0);
-                        hits.insert(_c.clone(), prev + 1);
+                        hits.insert(_c.clone(), prev + stakes.of(seer));
                     }
                 }
             }
@@ -846,7 +1251,7 @@ impl<H: Hashgraph + Clone + fmt::Debug, P: Peer<H>> Swirlds<P, H> {
         let parents = event.parents().clone().ok_or(Error::from(EventError::new(
             EventErrorType::NoParents { hash: hash.clone() },
         )))?;
-        parents.max_round(hashgraph.clone())
+        parents.max_round(&*hashgraph)
     }
 
     #[inline]
@@ -862,50 +1267,38 @@ impl<H: Hashgraph + Clone + fmt::Debug, P: Peer<H>> Swirlds<P, H> {
     }
 
     #[inline]
-    fn merge_hashgraph(&self, remote_hg: H) -> Result<Vec<EventHash>, Error> {
-        let mut diff = {
+    fn merge_hashgraph(&self, from: &PeerId, remote_hg: &H) -> Result<Vec<EventHash>, Error> {
+        // The remote graph may be partial, so parents missing from it must be known here
+        let diff = {
             let hashgraph = get_from_mutex!(self.hashgraph, ResourceHashgraphPoisonError)?;
-            remote_hg.difference(hashgraph.clone())
+            let diff = remote_hg
+                .get_events()
+                .into_iter()
+                .filter(|eh| !hashgraph.contains_key(eh))
+                .collect();
+            sort_topologically(remote_hg, diff)?
         };
-        let mut error: Option<Error> = None;
-        diff.sort_by(|h1, h2| {
-            if error.is_some() {
-                return Ordering::Less;
+        let mut res = Vec::with_capacity(diff.len());
+        let mut n_invalid = 0;
+        let mut n_orphans = 0;
+        for eh in diff.into_iter() {
+            let event = remote_hg.get(&eh)?;
+            let height = self.get_height_after_merge(&remote_hg, &event)?;
+            if let Some(sibling) = self.find_fork(&eh, &event)? {
+                self.add_fork_evidence(&sibling, &event)?;
+                continue;
             }
-
-            match remote_hg.higher(h1, h2) {
-                Ok(h1_higher) => match remote_hg.higher(h2, h1) {
-                    Ok(h2_higher) => {
-                        if h1_higher {
-                            Ordering::Greater
-                        } else if h2_higher {
-                            Ordering::Less
-                        } else {
-                            Ordering::Equal
-                        }
-                    }
-                    Err(e) => {
-                        error = Some(e);
-                        Ordering::Less
-                    }
-                },
-                Err(e) => {
-                    error = Some(e);
-                    Ordering::Less
-                }
+            // Anything else that isn't above what we have from its creator was pruned
+            if self.is_behind(height, event.creator())? {
+                continue;
             }
-        });
-        if error.is_some() {
-            return Err(error.unwrap());
-        }
-        let mut res = Vec::with_capacity(diff.len());
-        for eh in diff.clone().into_iter() {
-            let is_valid_event = {
-                let event = remote_hg.get(&eh)?;
-                self.is_valid_event(&eh, event)
-            }?;
-            if is_valid_event {
-                self.add_event(remote_hg.get(&eh)?.clone())?;
+            // A peer that pruned the parents isn't misbehaving, we're too far behind it
+            if self.has_missing_parents(&event)? {
+                n_orphans += 1;
+                continue;
+            }
+            if self.is_valid_event(&eh, &event)? {
+                self.add_event(event.into_owned())?;
                 res.push(eh);
             } else {
                 warn!(
@@ -913,20 +1306,143 @@ impl<H: Hashgraph + Clone + fmt::Debug, P: Peer<H>> Swirlds<P, H> {
                     self.get_id().printable_hash(),
                     eh.printable_hash()
                 );
+                n_invalid += 1;
             }
         }
+        if n_orphans > 0 {
+            warn!(
+                "[Node {:?}] Missing the parents of {} events from {:?}",
+                self.get_id().printable_hash(),
+                n_orphans,
+                from.printable_hash()
+            );
+        }
+        if n_invalid > 0 {
+            let mut state = get_from_mutex!(self.state, ResourceNodeInternalStatePoisonError)?;
+            state.network.record_invalid_events(from, n_invalid)?;
+        }
         Ok(res)
     }
 
+    // Heights in a partial remote graph are only known if the whole self chain was sent
+    #[inline]
+    fn get_height_after_merge(
+        &self,
+        remote_hg: &H,
+        event: &Event<ParentsPair>,
+    ) -> Result<Option<usize>, Error> {
+        match event.parents() {
+            Some(ParentsPair(self_parent, _)) => {
+                let hashgraph = get_from_mutex!(self.hashgraph, ResourceHashgraphPoisonError)?;
+                let self_parent_height = if hashgraph.contains_key(self_parent) {
+                    hashgraph.height(self_parent).ok()
+                } else {
+                    remote_hg.height(self_parent).ok()
+                };
+                Ok(self_parent_height.map(|h| h + 1))
+            }
+            None => Ok(Some(0)),
+        }
+    }
+
+    // Looks for an event we already have from the same creator on top of the same self
+    // parent. For roots that's any other root of the creator.
+    #[inline]
+    fn find_fork(
+        &self,
+        hash: &EventHash,
+        event: &Event<ParentsPair>,
+    ) -> Result<Option<EventHash>, Error> {
+        let hashgraph = get_from_mutex!(self.hashgraph, ResourceHashgraphPoisonError)?;
+        let sibling = match event.parents() {
+            Some(ParentsPair(self_parent, _)) => hashgraph.find_self_child(self_parent)?,
+            None => match hashgraph.last_event_of(event.creator()) {
+                Some(last) => hashgraph
+                    .self_ancestors(&last)?
+                    .last()
+                    .map(|first| (*first).clone()),
+                None => None,
+            },
+        };
+        Ok(sibling.filter(|s| {
+            s != hash
+                && hashgraph
+                    .get(s)
+                    .map(|e| e.creator() == event.creator() && e.is_root() == event.is_root())
+                    .unwrap_or(false)
+        }))
+    }
+
+    #[inline]
+    fn add_fork_evidence(
+        &self,
+        sibling: &EventHash,
+        event: &Event<ParentsPair>,
+    ) -> Result<(), Error> {
+        let evidence = {
+            let hashgraph = get_from_mutex!(self.hashgraph, ResourceHashgraphPoisonError)?;
+            ForkEvidence::new(hashgraph.get(sibling)?.into_owned(), event.clone())
+        };
+        if !evidence.is_valid().unwrap_or(false) {
+            warn!(
+                "[Node {:?}] Invalid fork of {:?} ignored",
+                self.get_id().printable_hash(),
+                sibling.printable_hash()
+            );
+            return Ok(());
+        }
+        warn!(
+            "[Node {:?}] Creator {:?} forked on {:?}",
+            self.get_id().printable_hash(),
+            evidence.creator.printable_hash(),
+            sibling.printable_hash()
+        );
+        let mut state = get_from_mutex!(self.state, ResourceNodeInternalStatePoisonError)?;
+        state.network.record_fork(&evidence.creator)?;
+        state
+            .forks
+            .entry(evidence.creator.clone())
+            .or_insert(evidence);
+        Ok(())
+    }
+
+    #[inline]
+    fn is_behind(&self, height: Option<usize>, creator: &PeerId) -> Result<bool, Error> {
+        let hashgraph = get_from_mutex!(self.hashgraph, ResourceHashgraphPoisonError)?;
+        Ok(match (height, hashgraph.last_event_of(creator)) {
+            (Some(height), Some(last)) => height <= hashgraph.height(&last)?,
+            _ => false,
+        })
+    }
+
+    #[inline]
+    fn has_missing_parents(&self, event: &Event<ParentsPair>) -> Result<bool, Error> {
+        let hashgraph = get_from_mutex!(self.hashgraph, ResourceHashgraphPoisonError)?;
+        Ok(match event.parents() {
+            Some(ParentsPair(self_parent, other_parent)) => {
+                !hashgraph.contains_key(self_parent) || !hashgraph.contains_key(other_parent)
+            }
+            None => false,
+        })
+    }
+
     #[inline]
     fn maybe_change_head(
         &self,
         remote_head: EventHash,
-        remote_hg: H,
+        remote_hg: &H,
     ) -> Result<Option<EventHash>, Error> {
-        let remote_head_event = remote_hg.get(&remote_head)?.clone();
+        // A partial graph doesn't carry the remote head if we already had it
+        let remote_head_event = if remote_hg.contains_key(&remote_head) {
+            remote_hg.get(&remote_head)?.into_owned()
+        } else {
+            let hashgraph = get_from_mutex!(self.hashgraph, ResourceHashgraphPoisonError)?;
+            hashgraph.get(&remote_head)?.into_owned()
+        };
 
-        if self.is_valid_event(&remote_head, &remote_head_event)? {
+        if self.is_valid_event(&remote_head, &remote_head_event)?
+            && self.find_fork(&remote_head, &remote_head_event)?.is_none()
+        {
             let current_head = self.get_head()?;
             let parents = ParentsPair(current_head, remote_head);
             Ok(Some(self.create_new_head(Some(parents), None)?))
@@ -941,6 +1457,9 @@ impl<H: Hashgraph + Clone + fmt::Debug, P: Peer<H>> Swirlds<P, H> {
         event_hash: &EventHash,
         event: &Event<ParentsPair>,
     ) -> Result<bool, Error> {
+        if !self.is_timely(event)? {
+            return Ok(false);
+        }
         event.is_valid(event_hash).and_then(|b| {
             if !b {
                 Ok(false)
@@ -951,15 +1470,23 @@ impl<H: Hashgraph + Clone + fmt::Debug, P: Peer<H>> Swirlds<P, H> {
         })
     }
 
+    // Events from too far in the future would drag the consensus timestamps with them
+    #[inline]
+    fn is_timely(&self, event: &Event<ParentsPair>) -> Result<bool, Error> {
+        let limit = self.clock.now()? + duration_millis(self.config.max_clock_drift);
+        Ok(event
+            .timestamp()
+            .map(|timestamp| timestamp <= limit)
+            .unwrap_or(false))
+    }
+
     #[inline]
     fn select_peer<R: Rng>(&self, rng: &mut R) -> Result<Arc<P>, Error> {
         let state = get_from_mutex!(self.state, ResourceNodeInternalStatePoisonError)?;
         state
             .network
-            .values()
-            .choose(rng)
+            .choose(rng)?
             .ok_or(Error::from(NodeError::new(NodeErrorType::EmptyNetwork)))
-            .map(|p| p.clone())
     }
 
     fn create_new_head(
@@ -987,9 +1514,7 @@ impl<H: Hashgraph + Clone + fmt::Debug, P: Peer<H>> Swirlds<P, H> {
             self.pk.public_key_bytes().to_vec(),
         );
         state.transactions = Vec::new();
-        if event.is_root() {
-            event.set_timestamp(get_current_timestamp())
-        }
+        event.set_timestamp(self.clock.now()?);
         round.iter().for_each(|r| event.set_round(r.clone()));
         let hash = event.hash()?;
         let signature = self.pk.sign(hash.as_ref());
@@ -1000,78 +1525,162 @@ impl<H: Hashgraph + Clone + fmt::Debug, P: Peer<H>> Swirlds<P, H> {
     #[inline]
     fn add_event(&self, e: Event<ParentsPair>) -> Result<(), Error> {
         let hash = e.hash()?;
-        self.add_pending_event(hash.clone())?;
+        self.add_pending_event(hash.clone(), &e)?;
         let mut hashgraph = get_from_mutex!(self.hashgraph, ResourceHashgraphPoisonError)?;
-        Ok(hashgraph.insert(hash, e))
+        hashgraph.insert(hash, e)
     }
 
     #[inline]
-    fn add_pending_event(&self, e: EventHash) -> Result<(), Error> {
+    fn add_pending_event(&self, hash: EventHash, e: &Event<ParentsPair>) -> Result<(), Error> {
         let mut state = get_from_mutex!(self.state, ResourceNodeInternalStatePoisonError)?;
-        state.pending_events.insert(e);
+        state.transaction_statuses.add_included(&e.payload(), &hash);
+        state.pending_events.insert(hash);
         Ok(())
     }
 }
 
-impl<P: Peer<H>, H: Hashgraph + Clone + fmt::Debug> Node for Swirlds<P, H> {
+impl<P: Peer<H, KnownHeights>, H: Hashgraph + From<HashgraphWire> + fmt::Debug> Node
+    for Swirlds<P, H>
+{
     type D = HashgraphWire;
+    type K = KnownHeights;
     type P = ParentsPair;
     fn run<R: Rng>(&self, rng: &mut R) -> Result<(), Error> {
-        let (head, hg) = {
-            let peer = self.select_peer(rng)?;
-            peer.get_sync(self.pk.public_key_bytes().to_vec(), None)?
+        let peer = self.select_peer(rng)?;
+        let known = get_from_mutex!(self.hashgraph, ResourceHashgraphPoisonError)?.known_heights();
+        let (head, hg) = match peer.get_sync(self.pk.public_key_bytes().to_vec(), Some(&known)) {
+            Ok(sync) => sync,
+            Err(e) => {
+                let mut state = get_from_mutex!(self.state, ResourceNodeInternalStatePoisonError)?;
+                state.network.record_failure(peer.id(), is_timeout(&e))?;
+                return Err(e);
+            }
         };
-        let new_events = self.sync(head, hg)?;
+        let _consensus = get_from_mutex!(self.consensus, ResourceConsensusPoisonError)?;
+        let new_events = self.sync(peer.id(), head, hg)?;
+        get_from_mutex!(self.state, ResourceNodeInternalStatePoisonError)?
+            .network
+            .record_sync(peer.id());
         self.divide_rounds(new_events)?;
         let new_consensus = self.decide_fame()?;
-        self.find_order(new_consensus)?;
-        self.update_order()?;
+        let received = self.find_order(new_consensus)?;
+        self.update_order(received)?;
+        let pruning_window = {
+            let state = get_from_mutex!(self.state, ResourceNodeInternalStatePoisonError)?;
+            state.pruning_window
+        };
+        if let Some(retention_window) = pruning_window {
+            self.prune(retention_window)?;
+        }
         Ok(())
     }
 
     fn respond_message(
         &self,
-        _k: Option<HashgraphWire>,
+        known: Option<KnownHeights>,
     ) -> Result<(EventHash, HashgraphWire), Error> {
         let head = self.get_head()?;
-        let hashgraph = self.get_hashgraph()?;
-        let wire = hashgraph.wire();
+        let hashgraph = get_from_mutex!(self.hashgraph, ResourceHashgraphPoisonError)?;
+        let wire = match known {
+            Some(known) => hashgraph.wire_events(&hashgraph.missing_events(&known)?)?,
+            None => hashgraph.wire()?,
+        };
         Ok((head, wire))
     }
 
-    fn add_transaction(&self, msg: Vec<u8>) -> Result<(), Error> {
+    fn add_transaction(&self, msg: Vec<u8>) -> Result<TransactionId, Error> {
         let mut state = get_from_mutex!(self.state, ResourceNodeInternalStatePoisonError)?;
+        let id = TransactionId::new(&msg);
+        state.transaction_statuses.add_pending(id.clone());
         state.transactions.push(msg);
-        Ok(())
+        Ok(id)
+    }
+
+    fn transaction_status(&self, id: &TransactionId) -> Result<Option<TransactionStatus>, Error> {
+        let state = get_from_mutex!(self.state, ResourceNodeInternalStatePoisonError)?;
+        Ok(state.transaction_statuses.get(id))
     }
 
     fn get_ordered_events(&self) -> Result<Vec<Event<ParentsPair>>, Error> {
         let state = get_from_mutex!(self.state, ResourceNodeInternalStatePoisonError)?;
         Ok(state.ordered_events.clone())
     }
+
+    fn subscribe(&self) -> Result<Receiver<FinalizedEvent<ParentsPair>>, Error> {
+        let mut state = get_from_mutex!(self.state, ResourceNodeInternalStatePoisonError)?;
+        let (sender, receiver) = channel();
+        state.subscribers.push(sender);
+        Ok(receiver)
+    }
+    fn peer_scores(&self) -> Result<Vec<PeerScoreReport>, Error> {
+        let state = get_from_mutex!(self.state, ResourceNodeInternalStatePoisonError)?;
+        state.network.scores()
+    }
+}
+
+impl<P: Peer<H, KnownHeights>, H: Hashgraph + From<HashgraphWire> + fmt::Debug> PeerDiscovery<P>
+    for Swirlds<P, H>
+{
+    // Discovered peers are only gossiped with. Anyone can announce peers, so they aren't
+    // members: they don't change the super majority nor weigh in rounds and fame.
+    fn add_announced_peer(
+        &self,
+        announcement: PeerAnnouncement,
+        peer: Arc<P>,
+    ) -> Result<bool, Error> {
+        let mut state = get_from_mutex!(self.state, ResourceNodeInternalStatePoisonError)?;
+        state.network.insert_announced(announcement, peer)
+    }
+
+    fn choose_peer<R: Rng>(&self, rng: &mut R) -> Result<Arc<P>, Error> {
+        self.select_peer(rng)
+    }
+
+    fn open_peer_table<F>(&self, path: PathBuf, create_peer: F) -> Result<usize, Error>
+    where
+        F: Fn(&PeerAnnouncement) -> Arc<P>,
+    {
+        let mut state = get_from_mutex!(self.state, ResourceNodeInternalStatePoisonError)?;
+        state.network.open(path, create_peer)
+    }
+
+    fn peer_announcements(&self) -> Result<Vec<PeerAnnouncement>, Error> {
+        let state = get_from_mutex!(self.state, ResourceNodeInternalStatePoisonError)?;
+        Ok(state.network.announcements())
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::Swirlds;
+    use super::consensus_config::{ConsensusConfig, TestCoin};
+    use super::{FameDecision, Swirlds};
+    use crate::clock::{Clock, ManualClock, SystemClock};
     use crate::event::{
         event_hash::EventHash, event_signature::EventSignature, parents::ParentsPair, Event,
     };
     use crate::hashgraph::*;
-    use crate::peer::{Peer, PeerId};
+    use crate::node::Node;
+    use crate::peer::{
+        announcement::PeerAnnouncement, peer_score::ScoringConfig, Peer, PeerDiscovery, PeerId,
+    };
+    use crate::transaction::{TransactionId, TransactionStatus};
     use ring::digest::{digest, SHA256};
     use ring::{rand, signature};
-    use std::collections::HashSet;
+    use std::collections::{BTreeSet, HashMap, HashSet};
     use std::iter::FromIterator;
     use std::sync::Arc;
 
     fn create_node() -> Swirlds<TestDummyPeer, BTreeHashgraph> {
+        create_node_with_clock(Arc::new(SystemClock))
+    }
+
+    fn create_node_with_clock(clock: Arc<dyn Clock>) -> Swirlds<TestDummyPeer, BTreeHashgraph> {
         let rng = rand::SystemRandom::new();
         let pkcs8_bytes = signature::Ed25519KeyPair::generate_pkcs8(&rng).unwrap();
         let kp =
             signature::Ed25519KeyPair::from_pkcs8(untrusted::Input::from(&pkcs8_bytes)).unwrap();
         let hashgraph = BTreeHashgraph::new();
-        Swirlds::new(kp, hashgraph).unwrap()
+        Swirlds::new(kp, hashgraph, ConsensusConfig::default(), clock).unwrap()
     }
 
     fn create_useless_peer(id: PeerId) -> Arc<TestDummyPeer> {
@@ -1091,11 +1700,11 @@ mod tests {
         id: PeerId,
     }
 
-    impl Peer<BTreeHashgraph> for TestDummyPeer {
+    impl Peer<BTreeHashgraph, KnownHeights> for TestDummyPeer {
         fn get_sync(
             &self,
             _pk: PeerId,
-            _h: Option<&BTreeHashgraph>,
+            _h: Option<&KnownHeights>,
         ) -> Result<(EventHash, BTreeHashgraph), failure::Error> {
             Ok((self.head.clone(), self.hashgraph.clone()))
         }
@@ -1123,6 +1732,14 @@ mod tests {
         assert_eq!(node.get_super_majority().unwrap(), 2);
         node.add_node(peer4).unwrap();
         assert_eq!(node.get_super_majority().unwrap(), 2);
+        let rng = rand::SystemRandom::new();
+        let pkcs8_bytes = signature::Ed25519KeyPair::generate_pkcs8(&rng).unwrap();
+        let kp =
+            signature::Ed25519KeyPair::from_pkcs8(untrusted::Input::from(&pkcs8_bytes)).unwrap();
+        let announcement = PeerAnnouncement::new(&kp, String::from("127.0.0.1:9000"), 0).unwrap();
+        let announced = create_useless_peer(kp.public_key_bytes().to_vec());
+        assert!(node.add_announced_peer(announcement, announced).unwrap());
+        assert_eq!(node.get_super_majority().unwrap(), 2);
     }
 
     #[test]
@@ -1139,7 +1756,7 @@ mod tests {
         );
         let hashgraph = node.hashgraph.lock().unwrap();
         assert!(hashgraph.contains_key(&hash));
-        assert_eq!(hashgraph.get(&hash).unwrap(), &event);
+        assert_eq!(*hashgraph.get(&hash).unwrap(), event);
     }
 
     #[test]
@@ -1168,7 +1785,7 @@ mod tests {
         let head = node.head.lock().unwrap().clone().unwrap().clone();
         let event = {
             let hashgraph = node.hashgraph.lock().unwrap();
-            hashgraph.get(&head).unwrap().clone()
+            hashgraph.get(&head).unwrap().into_owned()
         };
         assert!(node.is_valid_event(&head, &event).unwrap());
     }
@@ -1179,7 +1796,7 @@ mod tests {
         let head = node.head.lock().unwrap().clone().unwrap().clone();
         let event = {
             let hashgraph = node.hashgraph.lock().unwrap();
-            hashgraph.get(&head).unwrap().clone()
+            hashgraph.get(&head).unwrap().into_owned()
         };
         use ring::digest::{digest, SHA256};
         let real_hash = EventHash::new(digest(&SHA256, &vec![1]).as_ref());
@@ -1195,6 +1812,7 @@ mod tests {
             Some(ParentsPair(head.clone(), head.clone())),
             node.pk.public_key_bytes().to_vec(),
         );
+        event.set_timestamp(0);
         let hash = event.hash().unwrap();
         let signature = node.pk.sign(hash.as_ref()).as_ref().to_vec();
         event.sign(EventSignature::new(signature.as_ref()));
@@ -1212,7 +1830,7 @@ mod tests {
             let mutex_guard = remote_node.hashgraph.lock().unwrap();
             (*mutex_guard).clone()
         };
-        node.maybe_change_head(remote_head.clone(), remote_hashgraph)
+        node.maybe_change_head(remote_head.clone(), &remote_hashgraph)
             .unwrap();
         let new_head = node.head.lock().unwrap().clone().unwrap().clone();
         let hashgraph = node.hashgraph.lock().unwrap();
@@ -1234,7 +1852,7 @@ mod tests {
         };
         use ring::digest::{digest, SHA256};
         let real_hash = EventHash::new(digest(&SHA256, &vec![1]).as_ref());
-        node.maybe_change_head(real_hash.clone(), remote_hashgraph)
+        node.maybe_change_head(real_hash.clone(), &remote_hashgraph)
             .unwrap();
     }
 
@@ -1252,10 +1870,578 @@ mod tests {
             (*mutex_guard).clone()
         };
         println!("prev merge");
-        node.merge_hashgraph(remote_hashgraph).unwrap();
+        node.merge_hashgraph(&remote_node.get_id(), &remote_hashgraph)
+            .unwrap();
         println!("post merge");
         let hashgraph = node.hashgraph.lock().unwrap();
         assert!(hashgraph.contains_key(&head));
         assert!(hashgraph.contains_key(&remote_head));
     }
+
+    #[test]
+    fn it_should_merge_only_the_missing_events() {
+        let node = create_node();
+        let remote_node = create_node();
+        let head = node.get_head().unwrap();
+        let remote_head = remote_node.get_head().unwrap();
+        node.merge_hashgraph(&remote_node.get_id(), &remote_node.get_hashgraph().unwrap())
+            .unwrap();
+        remote_node
+            .merge_hashgraph(&node.get_id(), &node.get_hashgraph().unwrap())
+            .unwrap();
+        let remote_head1 = remote_node
+            .create_new_head(Some(ParentsPair(remote_head.clone(), head.clone())), None)
+            .unwrap();
+        let remote_head2 = remote_node
+            .create_new_head(Some(ParentsPair(remote_head1.clone(), head.clone())), None)
+            .unwrap();
+        let known = node.get_hashgraph().unwrap().known_heights();
+        let (responded_head, wire) = remote_node.respond_message(Some(known)).unwrap();
+        assert_eq!(responded_head, remote_head2);
+        let partial = BTreeHashgraph::from(wire);
+        let mut sent = partial.get_events();
+        sent.sort();
+        let mut expected = vec![remote_head1.clone(), remote_head2.clone()];
+        expected.sort();
+        assert_eq!(sent, expected);
+        assert_eq!(
+            node.merge_hashgraph(&remote_node.get_id(), &partial)
+                .unwrap(),
+            vec![remote_head1, remote_head2]
+        );
+    }
+
+    #[test]
+    fn it_should_prune_events_below_the_retention_window() {
+        let node = create_node();
+        let remote_node = create_node();
+        let a0 = node.get_head().unwrap();
+        let b0 = remote_node.get_head().unwrap();
+        node.merge_hashgraph(&remote_node.get_id(), &remote_node.get_hashgraph().unwrap())
+            .unwrap();
+        let a1 = node
+            .create_new_head(Some(ParentsPair(a0.clone(), b0.clone())), None)
+            .unwrap();
+        remote_node
+            .merge_hashgraph(&node.get_id(), &node.get_hashgraph().unwrap())
+            .unwrap();
+        let b1 = remote_node
+            .create_new_head(Some(ParentsPair(b0.clone(), a1.clone())), None)
+            .unwrap();
+        node.merge_hashgraph(&remote_node.get_id(), &remote_node.get_hashgraph().unwrap())
+            .unwrap();
+        let a2 = node
+            .create_new_head(Some(ParentsPair(a1.clone(), b1.clone())), None)
+            .unwrap();
+        node.divide_rounds(vec![
+            a0.clone(),
+            b0.clone(),
+            a1.clone(),
+            b1.clone(),
+            a2.clone(),
+        ])
+        .unwrap();
+        {
+            let mut hashgraph = node.hashgraph.lock().unwrap();
+            for eh in [&a0, &b0, &a1].iter() {
+                let event = hashgraph.get_mut(eh).unwrap();
+                event.set_round_received(0);
+                event.set_consensus_timestamp(0);
+            }
+        }
+        {
+            let mut state = node.state.lock().unwrap();
+            state.consensus = BTreeSet::from_iter(vec![0, 1, 2].into_iter());
+            state.votes.insert((a1.clone(), a0.clone()), true);
+            state.votes.insert((a2.clone(), a1.clone()), true);
+        }
+        node.update_order(vec![a0.clone(), b0.clone(), a1.clone()])
+            .unwrap();
+        let ordered_events = node.get_ordered_events().unwrap();
+        assert_eq!(ordered_events.len(), 3);
+        assert!(node.prune(2).unwrap().is_empty());
+
+        let mut pruned = node.prune(1).unwrap();
+        pruned.sort();
+        let mut expected = vec![a0.clone(), b0.clone()];
+        expected.sort();
+        assert_eq!(pruned, expected);
+        {
+            let hashgraph = node.hashgraph.lock().unwrap();
+            assert!(!hashgraph.contains_key(&a0));
+            assert!(!hashgraph.contains_key(&b0));
+            assert!(hashgraph.contains_key(&a1));
+            assert!(hashgraph.contains_key(&b1));
+            assert!(hashgraph.contains_key(&a2));
+            let state = node.state.lock().unwrap();
+            assert!(!state.votes.contains_key(&(a1.clone(), a0.clone())));
+            assert!(state.votes.contains_key(&(a2.clone(), a1.clone())));
+            assert!(state.rounds[0].witnesses().is_empty());
+        }
+        assert_eq!(node.get_ordered_events().unwrap(), ordered_events);
+
+        assert!(node
+            .merge_hashgraph(&remote_node.get_id(), &remote_node.get_hashgraph().unwrap())
+            .unwrap()
+            .is_empty());
+        let hashgraph = node.hashgraph.lock().unwrap();
+        assert!(!hashgraph.contains_key(&a0));
+        assert!(!hashgraph.contains_key(&b0));
+    }
+
+    #[test]
+    fn it_shouldnt_blame_pruned_peers_for_the_parents_they_cant_send() {
+        let node = create_node();
+        let remote_node = create_node();
+        let a0 = node.get_head().unwrap();
+        let b0 = remote_node.get_head().unwrap();
+        node.merge_hashgraph(&remote_node.get_id(), &remote_node.get_hashgraph().unwrap())
+            .unwrap();
+        let a1 = node
+            .create_new_head(Some(ParentsPair(a0.clone(), b0.clone())), None)
+            .unwrap();
+        node.hashgraph
+            .lock()
+            .unwrap()
+            .get_mut(&a0)
+            .unwrap()
+            .set_round_received(0);
+        node.state.lock().unwrap().consensus = BTreeSet::from_iter(vec![0, 1, 2].into_iter());
+        assert_eq!(node.prune(1).unwrap(), vec![a0]);
+
+        let fresh = create_node();
+        fresh.add_node(create_useless_peer(node.get_id())).unwrap();
+        let known = fresh.get_hashgraph().unwrap().known_heights();
+        let (head, wire) = node.respond_message(Some(known)).unwrap();
+        assert_eq!(head, a1);
+        assert_eq!(
+            fresh
+                .merge_hashgraph(&node.get_id(), &BTreeHashgraph::from(wire))
+                .unwrap(),
+            vec![b0]
+        );
+        assert!(!fresh.get_hashgraph().unwrap().contains_key(&a1));
+        let scores = fresh.peer_scores().unwrap();
+        assert_eq!(scores[0].invalid_events, 0);
+    }
+
+    #[test]
+    fn it_should_weigh_votes_by_stake() {
+        let rng = rand::SystemRandom::new();
+        let pkcs8_bytes = signature::Ed25519KeyPair::generate_pkcs8(&rng).unwrap();
+        let kp =
+            signature::Ed25519KeyPair::from_pkcs8(untrusted::Input::from(&pkcs8_bytes)).unwrap();
+        let stakes = HashMap::from_iter(vec![(vec![1], 5), (vec![2], 1), (vec![3], 1)]);
+        let node: Swirlds<TestDummyPeer, BTreeHashgraph> = Swirlds::with_stakes(
+            kp,
+            BTreeHashgraph::new(),
+            ConsensusConfig::default(),
+            Arc::new(SystemClock),
+            stakes,
+        )
+        .unwrap();
+        let head = node.get_head().unwrap();
+        let mut witnesses = HashSet::new();
+        for (creator, vote) in vec![(vec![1], true), (vec![2], false), (vec![3], false)] {
+            let event = Event::new(vec![], None, creator);
+            let hash = event.hash().unwrap();
+            node.add_event(event).unwrap();
+            node.vote(hash.clone(), head.clone(), vote).unwrap();
+            witnesses.insert(hash);
+        }
+        let stakes = node.get_round_stakes(0).unwrap();
+        assert_eq!(stakes.super_majority, 4);
+        assert_eq!(stakes.of(&node.get_id()), 0);
+        assert_eq!(
+            node.get_vote(&stakes, &witnesses, &head).unwrap(),
+            (true, 5)
+        );
+    }
+
+    #[test]
+    fn it_should_update_stakes_only_for_rounds_not_started() {
+        let node = create_node();
+        assert_eq!(node.get_round_stakes(0).unwrap().of(&vec![1]), 0);
+        node.add_node(create_useless_peer(vec![1])).unwrap();
+        let stakes = node.get_round_stakes(0).unwrap();
+        assert_eq!(stakes.of(&vec![1]), 1);
+        assert_eq!(stakes.super_majority, node.get_super_majority().unwrap());
+        node.update_stakes(0, HashMap::from_iter(vec![(vec![1], 3)]))
+            .unwrap();
+        node.divide_rounds(vec![node.get_head().unwrap()]).unwrap();
+        assert!(node
+            .update_stakes(0, HashMap::from_iter(vec![(vec![1], 6)]))
+            .is_err());
+        node.update_stakes(2, HashMap::from_iter(vec![(vec![1], 6)]))
+            .unwrap();
+        assert_eq!(node.get_round_stakes(1).unwrap().of(&vec![1]), 3);
+        assert_eq!(node.get_round_stakes(2).unwrap().of(&vec![1]), 6);
+        assert_eq!(node.get_round_stakes(9).unwrap().super_majority, 4);
+    }
+
+    #[test]
+    fn it_shouldnt_count_creators_that_arent_members() {
+        let node = create_node();
+        let head = node.get_head().unwrap();
+        let outsider = Event::new(vec![], None, vec![9]);
+        let outsider_hash = outsider.hash().unwrap();
+        node.add_event(outsider).unwrap();
+        node.divide_rounds(vec![head.clone(), outsider_hash])
+            .unwrap();
+        let stakes = node.get_round_stakes(0).unwrap();
+        assert_eq!(stakes.of(&vec![9]), 0);
+        assert_eq!(stakes.of(&node.get_id()), 1);
+        assert_eq!(node.state.lock().unwrap().rounds[0].witnesses(), vec![head]);
+    }
+
+    #[test]
+    fn it_should_keep_evidence_of_forks() {
+        let node = create_node();
+        let remote_node = create_node();
+        let a0 = node.get_head().unwrap();
+        let b0 = remote_node.get_head().unwrap();
+        remote_node
+            .merge_hashgraph(&node.get_id(), &node.get_hashgraph().unwrap())
+            .unwrap();
+        remote_node.add_transaction(vec![42]).unwrap();
+        let (event_a, hash_a) = remote_node
+            .create_event(Some(ParentsPair(b0.clone(), a0.clone())), None)
+            .unwrap();
+        remote_node.add_transaction(vec![7]).unwrap();
+        let (event_b, hash_b) = remote_node
+            .create_event(Some(ParentsPair(b0.clone(), a0.clone())), None)
+            .unwrap();
+        let mut remote_hashgraph = remote_node.get_hashgraph().unwrap();
+        remote_hashgraph.insert(hash_a.clone(), event_a).unwrap();
+        remote_hashgraph.insert(hash_b.clone(), event_b).unwrap();
+
+        let merged = node
+            .merge_hashgraph(&remote_node.get_id(), &remote_hashgraph)
+            .unwrap();
+        assert_eq!(merged.len(), 2);
+        assert!(merged.contains(&b0));
+        let evidence = node.get_fork_evidence().unwrap();
+        assert_eq!(evidence.len(), 1);
+        assert_eq!(evidence[0].creator, remote_node.get_id());
+        assert!(evidence[0].is_valid().unwrap());
+        let hashgraph = node.get_hashgraph().unwrap();
+        assert!(hashgraph.contains_key(&hash_a) != hashgraph.contains_key(&hash_b));
+        let stakes = node.get_round_stakes(0).unwrap();
+        assert_eq!(stakes.of(&remote_node.get_id()), 0);
+        assert_eq!(stakes.of(&node.get_id()), 1);
+    }
+
+    #[test]
+    fn it_should_order_by_round_received_timestamp_and_signature() {
+        let node = create_node();
+        let mut hashes = vec![node.get_head().unwrap()];
+        for _ in 0..3 {
+            let remote_node = create_node();
+            hashes.push(remote_node.get_head().unwrap());
+            node.merge_hashgraph(&remote_node.get_id(), &remote_node.get_hashgraph().unwrap())
+                .unwrap();
+        }
+        node.divide_rounds(hashes.clone()).unwrap();
+        node.maybe_add_new_round(1).unwrap();
+        {
+            let mut hashgraph = node.hashgraph.lock().unwrap();
+            for (eh, round_received, timestamp) in vec![
+                (&hashes[0], 1, 10),
+                (&hashes[1], 0, 20),
+                (&hashes[2], 1, 5),
+                (&hashes[3], 1, 5),
+            ] {
+                let event = hashgraph.get_mut(eh).unwrap();
+                event.set_round_received(round_received);
+                event.set_consensus_timestamp(timestamp);
+            }
+        }
+        node.update_order(hashes.clone()).unwrap();
+        let mut tied = vec![hashes[2].clone(), hashes[3].clone()];
+        {
+            let hashgraph = node.hashgraph.lock().unwrap();
+            tied.sort_by_key(|eh| hashgraph.get(eh).unwrap().signature().unwrap().0.to_vec());
+        }
+        let expected = vec![
+            hashes[1].clone(),
+            tied[0].clone(),
+            tied[1].clone(),
+            hashes[0].clone(),
+        ];
+        let ordered: Vec<EventHash> = node
+            .get_ordered_events()
+            .unwrap()
+            .iter()
+            .map(|e| e.hash().unwrap())
+            .collect();
+        assert_eq!(ordered, expected);
+    }
+
+    #[test]
+    fn it_should_receive_every_event_only_once() {
+        let node = create_node();
+        let head = node.get_head().unwrap();
+        node.divide_rounds(vec![head.clone()]).unwrap();
+        let mut famous_events = HashMap::new();
+        famous_events.insert(head.clone(), true);
+        node.update_famous_events(famous_events).unwrap();
+        let consensus = BTreeSet::from_iter(vec![0].into_iter());
+        assert_eq!(
+            node.find_order(consensus.clone()).unwrap(),
+            vec![head.clone()]
+        );
+        assert!(node.find_order(consensus).unwrap().is_empty());
+        let hashgraph = node.get_hashgraph().unwrap();
+        let event = hashgraph.get(&head).unwrap();
+        assert_eq!(event.round_received(), Some(0));
+        assert_eq!(event.consensus_timestamp(), event.timestamp().ok());
+    }
+
+    #[test]
+    fn subscribers_should_get_finalized_events_in_order() {
+        let node = create_node();
+        let head = node.get_head().unwrap();
+        node.divide_rounds(vec![head.clone()]).unwrap();
+        let mut famous_events = HashMap::new();
+        famous_events.insert(head.clone(), true);
+        node.update_famous_events(famous_events).unwrap();
+        let subscription = node.subscribe().unwrap();
+        drop(node.subscribe().unwrap());
+        let received = node
+            .find_order(BTreeSet::from_iter(vec![0].into_iter()))
+            .unwrap();
+        node.update_order(received).unwrap();
+        let finalized = subscription.try_recv().unwrap();
+        assert_eq!(finalized.consensus_index, 0);
+        assert_eq!(finalized.round_received, 0);
+        assert_eq!(finalized.event.hash().unwrap(), head);
+        assert!(subscription.try_recv().is_err());
+        assert_eq!(node.state.lock().unwrap().subscribers.len(), 1);
+    }
+
+    #[test]
+    fn it_should_track_the_status_of_transactions() {
+        let node = create_node();
+        let id = node.add_transaction(b"fish".to_vec()).unwrap();
+        assert_eq!(id, TransactionId::new(b"fish"));
+        assert_eq!(
+            node.transaction_status(&id).unwrap(),
+            Some(TransactionStatus::Pending)
+        );
+        let head = node.create_new_head(None, Some(0)).unwrap();
+        assert_eq!(
+            node.transaction_status(&id).unwrap(),
+            Some(TransactionStatus::Included(head.clone()))
+        );
+        node.divide_rounds(vec![head.clone()]).unwrap();
+        let mut famous_events = HashMap::new();
+        famous_events.insert(head.clone(), true);
+        node.update_famous_events(famous_events).unwrap();
+        let received = node
+            .find_order(BTreeSet::from_iter(vec![0].into_iter()))
+            .unwrap();
+        node.update_order(received).unwrap();
+        let timestamp = node
+            .get_hashgraph()
+            .unwrap()
+            .get(&head)
+            .unwrap()
+            .consensus_timestamp()
+            .unwrap();
+        assert_eq!(
+            node.transaction_status(&id).unwrap(),
+            Some(TransactionStatus::Committed(0, 0, timestamp))
+        );
+        assert_eq!(
+            node.transaction_status(&TransactionId::new(b"42")).unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn it_should_take_the_median() {
+        assert_eq!(super::median(vec![]), None);
+        assert_eq!(super::median(vec![3, 1, 2]), Some(2));
+        assert_eq!(super::median(vec![40, 1, 3, 2]), Some(3));
+    }
+
+    #[test]
+    fn it_should_flip_the_coin_on_coin_rounds_without_super_majority() {
+        let rng = rand::SystemRandom::new();
+        let pkcs8_bytes = signature::Ed25519KeyPair::generate_pkcs8(&rng).unwrap();
+        let kp =
+            signature::Ed25519KeyPair::from_pkcs8(untrusted::Input::from(&pkcs8_bytes)).unwrap();
+        let config = ConsensusConfig {
+            coin: Box::new(TestCoin(false)),
+            coin_frequency: 5,
+            ..ConsensusConfig::default()
+        };
+        let node: Swirlds<TestDummyPeer, BTreeHashgraph> =
+            Swirlds::new(kp, BTreeHashgraph::new(), config, Arc::new(SystemClock)).unwrap();
+        let voter = node.get_head().unwrap();
+        assert_eq!(
+            node.fame_decision(&voter, 3, true, true).unwrap(),
+            FameDecision::Decided(true)
+        );
+        assert_eq!(
+            node.fame_decision(&voter, 3, true, false).unwrap(),
+            FameDecision::Vote(true)
+        );
+        assert_eq!(
+            node.fame_decision(&voter, 5, true, true).unwrap(),
+            FameDecision::Vote(true)
+        );
+        assert_eq!(
+            node.fame_decision(&voter, 10, true, false).unwrap(),
+            FameDecision::Vote(false)
+        );
+    }
+
+    #[test]
+    fn it_should_reject_events_from_too_far_in_the_future() {
+        let head_of = |node: &Swirlds<TestDummyPeer, BTreeHashgraph>| {
+            let head = node.get_head().unwrap();
+            let event = node
+                .hashgraph
+                .lock()
+                .unwrap()
+                .get(&head)
+                .unwrap()
+                .into_owned();
+            (head, event)
+        };
+        let clock = Arc::new(ManualClock::new(1_000_000));
+        let node = create_node_with_clock(clock.clone());
+        assert_eq!(head_of(&node).1.timestamp().unwrap(), 1_000_000);
+        let (hash, event) = head_of(&create_node_with_clock(Arc::new(ManualClock::new(
+            1_030_000,
+        ))));
+        assert!(node.is_valid_event(&hash, &event).unwrap());
+        let (hash, event) = head_of(&create_node_with_clock(Arc::new(ManualClock::new(
+            1_030_001,
+        ))));
+        assert!(!node.is_valid_event(&hash, &event).unwrap());
+        clock.advance(1).unwrap();
+        assert!(node.is_valid_event(&hash, &event).unwrap());
+    }
+
+    #[test]
+    fn it_should_reject_coin_frequencies_below_three() {
+        let rng = rand::SystemRandom::new();
+        let pkcs8_bytes = signature::Ed25519KeyPair::generate_pkcs8(&rng).unwrap();
+        let kp =
+            signature::Ed25519KeyPair::from_pkcs8(untrusted::Input::from(&pkcs8_bytes)).unwrap();
+        let config = ConsensusConfig {
+            coin_frequency: 2,
+            ..ConsensusConfig::default()
+        };
+        let node: Result<Swirlds<TestDummyPeer, BTreeHashgraph>, failure::Error> =
+            Swirlds::new(kp, BTreeHashgraph::new(), config, Arc::new(SystemClock));
+        assert!(node.is_err());
+    }
+
+    fn checkpoint_path(name: &str) -> std::path::PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "lachesis-swirlds-{}-{}.checkpoint",
+            name,
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn it_should_restore_a_checkpoint() {
+        let rng = rand::SystemRandom::new();
+        let pkcs8_bytes = signature::Ed25519KeyPair::generate_pkcs8(&rng).unwrap();
+        let kp =
+            signature::Ed25519KeyPair::from_pkcs8(untrusted::Input::from(&pkcs8_bytes)).unwrap();
+        let node: Swirlds<TestDummyPeer, BTreeHashgraph> = Swirlds::new(
+            kp,
+            BTreeHashgraph::new(),
+            ConsensusConfig::default(),
+            Arc::new(SystemClock),
+        )
+        .unwrap();
+        let remote_node = create_node();
+        node.merge_hashgraph(&remote_node.get_id(), &remote_node.get_hashgraph().unwrap())
+            .unwrap();
+        let events = node.get_hashgraph().unwrap().get_events();
+        node.divide_rounds(events.clone()).unwrap();
+        node.add_transaction(vec![42]).unwrap();
+        let path = checkpoint_path("restore");
+        node.checkpoint(&path).unwrap();
+
+        let kp =
+            signature::Ed25519KeyPair::from_pkcs8(untrusted::Input::from(&pkcs8_bytes)).unwrap();
+        let hashgraph = BTreeHashgraph::from(node.get_hashgraph().unwrap().wire().unwrap());
+        let restored: Swirlds<TestDummyPeer, BTreeHashgraph> = Swirlds::restore(
+            &path,
+            kp,
+            hashgraph,
+            ConsensusConfig::default(),
+            Arc::new(SystemClock),
+            ScoringConfig::default(),
+        )
+        .unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(restored.get_head().unwrap(), node.get_head().unwrap());
+        let original_hashgraph = node.get_hashgraph().unwrap();
+        let restored_hashgraph = restored.get_hashgraph().unwrap();
+        for eh in events.iter() {
+            assert_eq!(
+                restored_hashgraph.get(eh).unwrap().round().unwrap(),
+                original_hashgraph.get(eh).unwrap().round().unwrap()
+            );
+        }
+        let state = node.state.lock().unwrap();
+        let restored_state = restored.state.lock().unwrap();
+        assert_eq!(restored_state.rounds.len(), state.rounds.len());
+        assert_eq!(restored_state.pending_events, state.pending_events);
+        assert_eq!(restored_state.transactions, vec![vec![42]]);
+    }
+
+    #[test]
+    fn it_shouldnt_restore_a_checkpoint_with_another_key() {
+        let node = create_node();
+        let path = checkpoint_path("another-key");
+        node.checkpoint(&path).unwrap();
+        let rng = rand::SystemRandom::new();
+        let pkcs8_bytes = signature::Ed25519KeyPair::generate_pkcs8(&rng).unwrap();
+        let kp =
+            signature::Ed25519KeyPair::from_pkcs8(untrusted::Input::from(&pkcs8_bytes)).unwrap();
+        let hashgraph = BTreeHashgraph::from(node.get_hashgraph().unwrap().wire().unwrap());
+        let restored: Result<Swirlds<TestDummyPeer, BTreeHashgraph>, failure::Error> =
+            Swirlds::restore(
+                &path,
+                kp,
+                hashgraph,
+                ConsensusConfig::default(),
+                Arc::new(SystemClock),
+                ScoringConfig::default(),
+            );
+        std::fs::remove_file(&path).unwrap();
+        assert!(restored.is_err());
+    }
+
+    #[test]
+    fn it_shouldnt_restore_an_unsupported_checkpoint_version() {
+        let path = checkpoint_path("version");
+        std::fs::write(&path, &[0xff, 0xff, 0xff, 0xff]).unwrap();
+        let rng = rand::SystemRandom::new();
+        let pkcs8_bytes = signature::Ed25519KeyPair::generate_pkcs8(&rng).unwrap();
+        let kp =
+            signature::Ed25519KeyPair::from_pkcs8(untrusted::Input::from(&pkcs8_bytes)).unwrap();
+        let restored: Result<Swirlds<TestDummyPeer, BTreeHashgraph>, failure::Error> =
+            Swirlds::restore(
+                &path,
+                kp,
+                BTreeHashgraph::new(),
+                ConsensusConfig::default(),
+                Arc::new(SystemClock),
+                ScoringConfig::default(),
+            );
+        std::fs::remove_file(&path).unwrap();
+        assert!(restored.is_err());
+    }
 }
@@ -0,0 +1,83 @@
+use crate::event::{parents::ParentsPair, Event};
+use failure::Error;
+use std::time::Duration;
+
+// Swirlds paper requires the coin rounds frequency to be > 2; let keep it prime
+const DEFAULT_COIN_FREQUENCY: usize = 3;
+pub(crate) const DEFAULT_MAX_CLOCK_DRIFT: u64 = 30_000;
+
+pub trait CoinStrategy: Send + Sync {
+    fn flip(&self, voter: &Event<ParentsPair>) -> Result<bool, Error>;
+}
+
+// The middle bit of the voter signature, as in the paper
+pub struct MiddleBitCoin;
+
+impl CoinStrategy for MiddleBitCoin {
+    fn flip(&self, voter: &Event<ParentsPair>) -> Result<bool, Error> {
+        let signature = voter.signature()?;
+        Ok(signature.0[signature.0.len() / 2] & 1 == 1)
+    }
+}
+
+// Always lands on the same side, so coin rounds can be reproduced
+pub struct TestCoin(pub bool);
+
+impl CoinStrategy for TestCoin {
+    fn flip(&self, _voter: &Event<ParentsPair>) -> Result<bool, Error> {
+        Ok(self.0)
+    }
+}
+
+#[inline]
+pub fn two_thirds(total: usize) -> usize {
+    total * 2 / 3
+}
+
+pub struct ConsensusConfig {
+    pub coin: Box<dyn CoinStrategy>,
+    pub coin_frequency: usize,
+    // How far ahead of our clock an event timestamp can be before the event is rejected
+    pub max_clock_drift: Duration,
+    // What a sum of votes has to be above to be a super majority of the given total
+    pub super_majority: fn(usize) -> usize,
+}
+
+impl Default for ConsensusConfig {
+    fn default() -> ConsensusConfig {
+        ConsensusConfig {
+            coin: Box::new(MiddleBitCoin),
+            coin_frequency: DEFAULT_COIN_FREQUENCY,
+            max_clock_drift: Duration::from_millis(DEFAULT_MAX_CLOCK_DRIFT),
+            super_majority: two_thirds,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CoinStrategy, MiddleBitCoin, TestCoin};
+    use crate::event::{event_signature::EventSignature, parents::ParentsPair, Event};
+
+    #[test]
+    fn middle_bit_coin_should_read_the_middle_of_the_signature() {
+        let mut event: Event<ParentsPair> = Event::new(vec![], None, vec![1]);
+        let mut signature = [0; 64];
+        event.sign(EventSignature(signature));
+        assert!(!MiddleBitCoin.flip(&event).unwrap());
+        signature[32] = 1;
+        event.sign(EventSignature(signature));
+        assert!(MiddleBitCoin.flip(&event).unwrap());
+        signature[0] = 255;
+        signature[32] = 254;
+        event.sign(EventSignature(signature));
+        assert!(!MiddleBitCoin.flip(&event).unwrap());
+    }
+
+    #[test]
+    fn test_coin_should_be_deterministic() {
+        let event: Event<ParentsPair> = Event::new(vec![], None, vec![1]);
+        assert!(TestCoin(true).flip(&event).unwrap());
+        assert!(!TestCoin(false).flip(&event).unwrap());
+    }
+}
@@ -1,45 +1,248 @@
+use self::gossip_config::GossipConfig;
+use self::secure_channel::{accept_async, Cipher, SecureChannel};
+use crate::clock::SystemClock;
+use crate::errors::{ProtocolError, ProtocolErrorType, ResourceConnectionPoisonError};
 use crate::event::event_hash::EventHash;
-use crate::hashgraph::{BTreeHashgraph, HashgraphWire};
+use crate::hashgraph::{BTreeHashgraph, HashgraphWire, KnownHeights};
 use crate::lachesis::opera::{Opera, OperaWire};
 use crate::lachesis::Lachesis;
 use crate::node::Node;
-use crate::peer::{Peer, PeerId};
-use crate::swirlds::Swirlds;
-use bincode::serialize;
+use crate::peer::{announcement::PeerAnnouncement, Peer, PeerDiscovery, PeerId};
+use crate::printable_hash::PrintableHash;
+use crate::server::ws_message::{respond, InternodeError, InternodeMessage, PROTOCOL_VERSION};
+use crate::swirlds::{consensus_config::ConsensusConfig, Swirlds};
+use bincode::{deserialize, serialize};
+use byteorder::{ByteOrder, LittleEndian, ReadBytesExt, WriteBytesExt};
 use failure::Error;
+use futures::future::{self, Either, Loop};
+use futures::sync::oneshot;
+use futures::{Future, Stream};
+use rand::Rng;
 use ring::rand::SystemRandom;
-use ring::signature;
-use std::io::{Read, Write};
-use std::net::{TcpListener, TcpStream};
-use std::sync::Arc;
-use std::thread::{sleep, spawn, JoinHandle};
-use std::time::Duration;
+use ring::signature::Ed25519KeyPair;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::io::{read_exact, write_all, AsyncRead, AsyncWrite};
+use tokio::prelude::FutureExt;
+use tokio::reactor::Handle;
+use tokio::runtime::TaskExecutor;
+use tokio::timer::{timeout, Interval};
+use tokio_threadpool::blocking;
 
-fn create_lachesis_node(rng: &mut SystemRandom) -> Result<Lachesis<TcpPeer>, Error> {
-    let pkcs8_bytes = signature::Ed25519KeyPair::generate_pkcs8(rng)?;
-    let kp = signature::Ed25519KeyPair::from_pkcs8(untrusted::Input::from(&pkcs8_bytes))?;
-    Ok(Lachesis::new(3, kp))
+pub mod gossip_config;
+pub mod secure_channel;
+
+// Every frame is sent as its length, a little endian u32, followed by its content. Frames this
+// large are only read from the responses of the peers the node asked.
+pub(crate) const MAX_FRAME_SIZE: usize = 128 * 1024 * 1024;
+// The handshake proves the requester holds its key, not that it can be trusted, as any key
+// passes it. Requests only carry what the requester knows or announces, so they stay small.
+const MAX_REQUEST_FRAME_SIZE: usize = 1024 * 1024;
+
+fn check_frame_size(len: usize, max_size: usize) -> Result<(), Error> {
+    if len > max_size {
+        return Err(Error::from(ProtocolError::new(
+            ProtocolErrorType::FrameTooLarge(len),
+        )));
+    }
+    Ok(())
+}
+
+fn frame(payload: &[u8]) -> Result<Vec<u8>, Error> {
+    check_frame_size(payload.len(), MAX_FRAME_SIZE)?;
+    let mut frame = Vec::with_capacity(4 + payload.len());
+    frame.write_u32::<LittleEndian>(payload.len() as u32)?;
+    frame.extend_from_slice(payload);
+    Ok(frame)
+}
+
+pub(crate) fn write_frame<W: Write>(writer: &mut W, payload: &[u8]) -> Result<(), Error> {
+    writer.write_all(&frame(payload)?)?;
+    writer.flush()?;
+    Ok(())
+}
+
+// Returns None if the other side closed the connection between frames
+pub(crate) fn read_frame<R: Read>(
+    reader: &mut R,
+    max_size: usize,
+) -> Result<Option<Vec<u8>>, Error> {
+    let len = match reader.read_u32::<LittleEndian>() {
+        Ok(len) => len as usize,
+        Err(ref e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(Error::from(e)),
+    };
+    check_frame_size(len, max_size)?;
+    let mut payload = vec![0; len];
+    reader.read_exact(&mut payload)?;
+    Ok(Some(payload))
 }
 
-fn create_swirlds_node(rng: &mut SystemRandom) -> Result<Swirlds<TcpPeer, BTreeHashgraph>, Error> {
-    let hashgraph = BTreeHashgraph::new();
-    let pkcs8_bytes = signature::Ed25519KeyPair::generate_pkcs8(rng)?;
-    let kp = signature::Ed25519KeyPair::from_pkcs8(untrusted::Input::from(&pkcs8_bytes))?;
-    Swirlds::new(kp, hashgraph)
+pub(crate) fn write_frame_async<W: AsyncWrite>(
+    writer: W,
+    payload: Vec<u8>,
+) -> impl Future<Item = W, Error = Error> {
+    future::result(frame(&payload))
+        .and_then(|frame| write_all(writer, frame).map_err(Error::from))
+        .map(|(writer, _)| writer)
+}
+
+// Resolves to None if the other side closed the connection between frames
+pub(crate) fn read_frame_async<R: AsyncRead>(
+    reader: R,
+    max_size: usize,
+) -> impl Future<Item = Option<(R, Vec<u8>)>, Error = Error> {
+    read_exact(reader, [0; 4]).then(|result| match result {
+        Ok((reader, len)) => {
+            let len = LittleEndian::read_u32(&len) as usize;
+            Either::A(
+                future::result(check_frame_size(len, max_size)).and_then(move |_| {
+                    read_exact(reader, vec![0; len])
+                        .map(Some)
+                        .map_err(Error::from)
+                }),
+            )
+        }
+        Err(ref e) if e.kind() == ErrorKind::UnexpectedEof => Either::B(future::ok(None)),
+        Err(e) => Either::B(future::err(Error::from(e))),
+    })
+}
+
+#[inline]
+fn timed_out(e: timeout::Error<Error>) -> Error {
+    e.into_inner()
+        .unwrap_or_else(|| Error::from(ProtocolError::new(ProtocolErrorType::TimedOut)))
+}
+
+// The node signs its events with a key pair and authenticates its connections with another
+// one, built from the same pkcs8 document
+fn create_key_pairs(pkcs8_bytes: &[u8]) -> Result<(Ed25519KeyPair, Ed25519KeyPair), Error> {
+    let kp = Ed25519KeyPair::from_pkcs8(untrusted::Input::from(pkcs8_bytes))?;
+    let identity = Ed25519KeyPair::from_pkcs8(untrusted::Input::from(pkcs8_bytes))?;
+    Ok((kp, identity))
 }
 
 pub struct TcpNode<N: Node> {
     pub address: String,
+    pub config: GossipConfig,
+    identity: Arc<Ed25519KeyPair>,
     pub node: N,
 }
 
+impl<N: Node> TcpNode<N> {
+    pub fn id(&self) -> PeerId {
+        self.identity.public_key_bytes().to_vec()
+    }
+
+    // Peers are per node, as connections are authenticated with the node key
+    pub fn peer(&self, address: String, id: PeerId) -> TcpPeer {
+        TcpPeer::new(
+            address,
+            id,
+            self.identity.clone(),
+            self.config.request_timeout,
+        )
+    }
+
+    // Signed with the node key, so the peers it's sent to can pass it along
+    pub fn announcement(&self, address: String) -> Result<PeerAnnouncement, Error> {
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        PeerAnnouncement::new(&self.identity, address, timestamp)
+    }
+}
+
+impl<N: Node + PeerDiscovery<TcpPeer>> TcpNode<N> {
+    // Announces the node at `address` to a random peer and adds the peers it knows.
+    // Returns how many of them were new
+    pub fn discover<R: Rng>(&self, rng: &mut R, address: String) -> Result<usize, Error> {
+        let peer = self.node.choose_peer(rng)?;
+        let announcements = peer.get_peers(self.announcement(address)?)?;
+        let mut added = 0;
+        for announcement in announcements {
+            if announcement.id.as_slice() == self.identity.public_key_bytes() {
+                continue;
+            }
+            let announced = self.peer(announcement.address.clone(), announcement.id.clone());
+            match self
+                .node
+                .add_announced_peer(announcement, Arc::new(announced))
+            {
+                Ok(true) => added += 1,
+                Ok(false) => {}
+                Err(e) => warn!(
+                    "Ignoring a peer announced by {:?}: {}",
+                    peer.id.printable_hash(),
+                    e
+                ),
+            }
+        }
+        Ok(added)
+    }
+
+    // Adds the peers announced before the node restarted. Returns how many there were
+    pub fn open_peer_table(&self, path: PathBuf) -> Result<usize, Error> {
+        self.node.open_peer_table(path, |announcement| {
+            Arc::new(self.peer(announcement.address.clone(), announcement.id.clone()))
+        })
+    }
+
+    // Learns about the requester and answers with every peer the node knows
+    fn answer_peers_request(&self, announcement: &PeerAnnouncement) -> InternodeMessage {
+        if announcement.verify().is_err() {
+            return InternodeMessage::Error(InternodeError::BadRequest);
+        }
+        let peer = self.peer(announcement.address.clone(), announcement.id.clone());
+        let peers = self
+            .node
+            .add_announced_peer(announcement.clone(), Arc::new(peer))
+            .and_then(|_| self.node.peer_announcements());
+        match peers {
+            Ok(peers) => InternodeMessage::PeersResponse { peers },
+            Err(e) => InternodeMessage::Error(InternodeError::Internal(e.to_string())),
+        }
+    }
+}
+
 impl TcpNode<Lachesis<TcpPeer>> {
+    // A node of its own, as its only validator
     pub fn new_lachesis(
         rng: &mut SystemRandom,
         address: String,
+        config: GossipConfig,
+    ) -> Result<TcpNode<Lachesis<TcpPeer>>, Error> {
+        let pkcs8_bytes = Ed25519KeyPair::generate_pkcs8(rng)?;
+        let (kp, _) = create_key_pairs(&pkcs8_bytes)?;
+        let validators = vec![kp.public_key_bytes().to_vec()];
+        TcpNode::lachesis_from_pkcs8(&pkcs8_bytes, address, config, validators)
+    }
+
+    // Every node of the network has to start with the same validators
+    pub fn lachesis_from_pkcs8(
+        pkcs8_bytes: &[u8],
+        address: String,
+        config: GossipConfig,
+        validators: Vec<PeerId>,
     ) -> Result<TcpNode<Lachesis<TcpPeer>>, Error> {
-        let node = create_lachesis_node(rng)?;
-        Ok(TcpNode { address, node })
+        let (kp, identity) = create_key_pairs(pkcs8_bytes)?;
+        let node = Lachesis::with_scoring(
+            3,
+            kp,
+            Arc::new(SystemClock),
+            validators,
+            config.scoring.clone(),
+        )?;
+        Ok(TcpNode {
+            address,
+            config,
+            identity: Arc::new(identity),
+            node,
+        })
     }
 }
 
@@ -47,33 +250,162 @@ impl TcpNode<Swirlds<TcpPeer, BTreeHashgraph>> {
     pub fn new(
         rng: &mut SystemRandom,
         address: String,
+        config: GossipConfig,
+    ) -> Result<TcpNode<Swirlds<TcpPeer, BTreeHashgraph>>, Error> {
+        let pkcs8_bytes = Ed25519KeyPair::generate_pkcs8(rng)?;
+        TcpNode::from_pkcs8(&pkcs8_bytes, address, config)
+    }
+
+    // Every node of the network has to start with the same stakes, the peers it discovers
+    // don't vote
+    pub fn with_stakes(
+        pkcs8_bytes: &[u8],
+        address: String,
+        config: GossipConfig,
+        stakes: HashMap<PeerId, usize>,
     ) -> Result<TcpNode<Swirlds<TcpPeer, BTreeHashgraph>>, Error> {
-        let node = create_swirlds_node(rng)?;
-        Ok(TcpNode { address, node })
+        let (kp, identity) = create_key_pairs(pkcs8_bytes)?;
+        let node = Swirlds::with_scoring(
+            kp,
+            BTreeHashgraph::new(),
+            ConsensusConfig::default(),
+            Arc::new(SystemClock),
+            config.scoring.clone(),
+        )?;
+        node.update_stakes(0, stakes)?;
+        Ok(TcpNode {
+            address,
+            config,
+            identity: Arc::new(identity),
+            node,
+        })
+    }
+
+    pub fn from_pkcs8(
+        pkcs8_bytes: &[u8],
+        address: String,
+        config: GossipConfig,
+    ) -> Result<TcpNode<Swirlds<TcpPeer, BTreeHashgraph>>, Error> {
+        let (kp, identity) = create_key_pairs(pkcs8_bytes)?;
+        let node = Swirlds::with_scoring(
+            kp,
+            BTreeHashgraph::new(),
+            ConsensusConfig::default(),
+            Arc::new(SystemClock),
+            config.scoring.clone(),
+        )?;
+        Ok(TcpNode {
+            address,
+            config,
+            identity: Arc::new(identity),
+            node,
+        })
     }
 }
 
 #[derive(Clone)]
 pub struct TcpPeer {
     pub address: String,
+    // Shared by clones, so every exchange with the peer goes through the same connection
+    connection: Arc<Mutex<Option<SecureChannel>>>,
     pub id: PeerId,
+    identity: Arc<Ed25519KeyPair>,
+    timeout: Duration,
+}
+
+impl TcpPeer {
+    // Connections are refused unless the other end proves it holds the key of `id`
+    pub fn new(
+        address: String,
+        id: PeerId,
+        identity: Arc<Ed25519KeyPair>,
+        timeout: Duration,
+    ) -> TcpPeer {
+        TcpPeer {
+            address,
+            connection: Arc::new(Mutex::new(None)),
+            id,
+            identity,
+            timeout,
+        }
+    }
+
+    // Announces the node to the peer, which answers with the peers it knows
+    pub fn get_peers(
+        &self,
+        announcement: PeerAnnouncement,
+    ) -> Result<Vec<PeerAnnouncement>, Error> {
+        let request = InternodeMessage::PeersRequest {
+            version: PROTOCOL_VERSION,
+            announcement,
+        };
+        self.send_request(&request)?.into_peers_response()
+    }
+
+    fn request(&self, pk: PeerId, known: Option<Vec<u8>>) -> Result<(EventHash, Vec<u8>), Error> {
+        let request = InternodeMessage::SyncRequest {
+            version: PROTOCOL_VERSION,
+            requester: pk,
+            known,
+        };
+        self.send_request(&request)?.into_sync_response()
+    }
+
+    fn send_request(&self, request: &InternodeMessage) -> Result<InternodeMessage, Error> {
+        let mut connection = get_from_mutex!(self.connection, ResourceConnectionPoisonError)?;
+        let reused = connection.is_some();
+        match self.exchange(&mut connection, request) {
+            // The peer may have dropped an idle connection, so it's retried once on a new one
+            Err(_) if reused => self.exchange(&mut connection, request),
+            response => response,
+        }
+    }
+
+    // The connection is dropped on any error, so the next exchange opens a new one
+    fn exchange(
+        &self,
+        connection: &mut Option<SecureChannel>,
+        request: &InternodeMessage,
+    ) -> Result<InternodeMessage, Error> {
+        let mut channel = match connection.take() {
+            Some(channel) => channel,
+            None => SecureChannel::connect(self.connect()?, &self.identity, &self.id)?,
+        };
+        channel.send(request)?;
+        let response = channel.receive()?.ok_or(Error::from(ProtocolError::new(
+            ProtocolErrorType::ConnectionClosed,
+        )))?;
+        *connection = Some(channel);
+        Ok(response)
+    }
+
+    // Every blocking operation on the stream fails after the timeout
+    fn connect(&self) -> Result<TcpStream, Error> {
+        let address = self
+            .address
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| io::Error::from(ErrorKind::AddrNotAvailable))?;
+        let stream = TcpStream::connect_timeout(&address, self.timeout)?;
+        stream.set_read_timeout(Some(self.timeout))?;
+        stream.set_write_timeout(Some(self.timeout))?;
+        Ok(stream)
+    }
 }
 
-impl Peer<BTreeHashgraph> for TcpPeer {
+impl Peer<BTreeHashgraph, KnownHeights> for TcpPeer {
     fn get_sync(
         &self,
-        _pk: PeerId,
-        _k: Option<&BTreeHashgraph>,
+        pk: PeerId,
+        known: Option<&KnownHeights>,
     ) -> Result<(EventHash, BTreeHashgraph), Error> {
-        let mut buffer = Vec::new();
-        let mut stream = TcpStream::connect(&self.address.clone())?;
-        let mut last_received = 0;
-        while last_received == 0 {
-            last_received = stream.read_to_end(&mut buffer)?;
-        }
-        let (eh, wire): (EventHash, HashgraphWire) = bincode::deserialize(&buffer)?;
-        let hashgraph = BTreeHashgraph::from(wire);
-        Ok((eh, hashgraph))
+        let known = match known {
+            Some(known) => Some(serialize(known)?),
+            None => None,
+        };
+        let (eh, events) = self.request(pk, known)?;
+        let wire: HashgraphWire = deserialize(&events)?;
+        Ok((eh, BTreeHashgraph::from(wire)))
     }
     fn address(&self) -> String {
         self.address.clone()
@@ -83,15 +415,18 @@ impl Peer<BTreeHashgraph> for TcpPeer {
     }
 }
 
-impl Peer<Opera> for TcpPeer {
-    fn get_sync(&self, _pk: PeerId, _k: Option<&Opera>) -> Result<(EventHash, Opera), Error> {
-        let mut buffer = Vec::new();
-        let mut stream = TcpStream::connect(&self.address.clone())?;
-        let mut last_received = 0;
-        while last_received == 0 {
-            last_received = stream.read_to_end(&mut buffer)?;
-        }
-        let (eh, wire): (EventHash, OperaWire) = bincode::deserialize(&buffer)?;
+impl Peer<Opera, KnownHeights> for TcpPeer {
+    fn get_sync(
+        &self,
+        pk: PeerId,
+        known: Option<&KnownHeights>,
+    ) -> Result<(EventHash, Opera), Error> {
+        let known = match known {
+            Some(known) => Some(serialize(known)?),
+            None => None,
+        };
+        let (eh, events) = self.request(pk, known)?;
+        let wire: OperaWire = deserialize(&events)?;
         Ok((eh, wire.into_opera()))
     }
     fn address(&self) -> String {
@@ -102,47 +437,472 @@ impl Peer<Opera> for TcpPeer {
     }
 }
 
-pub struct TcpApp(Arc<TcpNode<Swirlds<TcpPeer, BTreeHashgraph>>>);
+// Only the peer that authenticated the connection can sync or announce itself through it
+fn respond_authenticated<N>(
+    node: &TcpNode<N>,
+    peer_id: &PeerId,
+    message: &InternodeMessage,
+) -> InternodeMessage
+where
+    N: Node + PeerDiscovery<TcpPeer>,
+    N::D: Serialize,
+    N::K: DeserializeOwned,
+{
+    match message {
+        InternodeMessage::SyncRequest { requester, .. } if requester != peer_id => {
+            InternodeMessage::Error(InternodeError::Unauthorized)
+        }
+        InternodeMessage::PeersRequest { version, .. } if *version != PROTOCOL_VERSION => {
+            InternodeMessage::Error(InternodeError::UnsupportedVersion(PROTOCOL_VERSION))
+        }
+        InternodeMessage::PeersRequest { announcement, .. } if &announcement.id != peer_id => {
+            InternodeMessage::Error(InternodeError::Unauthorized)
+        }
+        InternodeMessage::PeersRequest { announcement, .. } => {
+            node.answer_peers_request(announcement)
+        }
+        _ => respond(&node.node, message),
+    }
+}
+
+// Runs on the blocking section of the runtime pool, as nodes lock their state
+fn respond_blocking<N>(
+    node: Arc<TcpNode<N>>,
+    peer_id: PeerId,
+    message: InternodeMessage,
+) -> impl Future<Item = InternodeMessage, Error = Error>
+where
+    N: Node + PeerDiscovery<TcpPeer> + Send + Sync + 'static,
+    N::D: Serialize,
+    N::K: DeserializeOwned,
+{
+    future::poll_fn(move || blocking(|| respond_authenticated(&node, &peer_id, &message)))
+        .map_err(Error::from)
+}
+
+// Answers requests on the connection until the requester closes it or stays idle for too long
+fn answer<N>(
+    node: Arc<TcpNode<N>>,
+    stream: tokio::net::TcpStream,
+) -> impl Future<Item = (), Error = Error>
+where
+    N: Node + PeerDiscovery<TcpPeer> + Send + Sync + 'static,
+    N::D: Serialize,
+    N::K: DeserializeOwned,
+{
+    let timeout = node.config.request_timeout;
+    let idle_timeout = node.config.idle_timeout;
+    accept_async(stream, node.identity.clone())
+        .timeout(timeout)
+        .map_err(timed_out)
+        .and_then(move |(stream, cipher)| {
+            future::loop_fn((stream, cipher), move |(stream, cipher)| {
+                let node = node.clone();
+                read_frame_async(stream, MAX_REQUEST_FRAME_SIZE)
+                    .timeout(idle_timeout)
+                    .map_err(timed_out)
+                    .and_then(move |frame| match frame {
+                        Some((stream, frame)) => {
+                            Either::A(answer_frame(node, stream, cipher, frame).map(Loop::Continue))
+                        }
+                        None => Either::B(future::ok(Loop::Break(()))),
+                    })
+            })
+        })
+}
+
+fn answer_frame<N>(
+    node: Arc<TcpNode<N>>,
+    stream: tokio::net::TcpStream,
+    mut cipher: Cipher,
+    frame: Vec<u8>,
+) -> impl Future<Item = (tokio::net::TcpStream, Cipher), Error = Error>
+where
+    N: Node + PeerDiscovery<TcpPeer> + Send + Sync + 'static,
+    N::D: Serialize,
+    N::K: DeserializeOwned,
+{
+    let timeout = node.config.request_timeout;
+    let peer_id = cipher.peer_id().clone();
+    future::result(cipher.open(frame))
+        .and_then(move |message| respond_blocking(node, peer_id, message))
+        .and_then(move |response| {
+            let frame = cipher.seal(&response);
+            future::result(frame)
+                .and_then(move |frame| write_frame_async(stream, frame))
+                .map(move |stream| (stream, cipher))
+        })
+        .timeout(timeout)
+        .map_err(timed_out)
+}
+
+fn log_stats<N: Node>(node: &TcpNode<N>) -> Result<(), Error> {
+    let n_events = node.node.get_ordered_events()?.len();
+    info!(
+        "Node {:?}: Ordered events {:?}",
+        node.id().printable_hash(),
+        n_events
+    );
+    Ok(())
+}
+
+// Starts a sync every gossip interval, unless the node already runs as many as allowed. The
+// node only runs their fetches in parallel, it merges and orders one sync at a time.
+fn gossip<N>(node: Arc<TcpNode<N>>) -> impl Future<Item = (), Error = ()>
+where
+    N: Node + Send + Sync + 'static,
+{
+    let config = node.config.clone();
+    let sync_node = node.clone();
+    Interval::new_interval(config.gossip_interval)
+        .map_err(|e| error!("Gossip timer failed: {}", e))
+        .map(move |_| {
+            let node = sync_node.clone();
+            future::poll_fn(move || blocking(|| node.node.run(&mut rand::thread_rng()))).then(
+                |result| {
+                    match result {
+                        Ok(Err(e)) => warn!("Sync failed: {}", e),
+                        Err(e) => warn!("Sync couldn't run: {}", e),
+                        Ok(Ok(())) => {}
+                    }
+                    Ok(())
+                },
+            )
+        })
+        .buffer_unordered(config.max_concurrent_syncs)
+        .fold(0usize, move |counter, _| {
+            if counter % 100 == 0 {
+                if let Err(e) = log_stats(&node) {
+                    warn!("Couldn't read node stats: {}", e);
+                }
+            }
+            Ok(counter + 1)
+        })
+        .map(|_| ())
+}
+
+// Asks a peer for the peers it knows on start, and then every discovery interval
+fn discovery<N>(node: Arc<TcpNode<N>>, address: String) -> impl Future<Item = (), Error = ()>
+where
+    N: Node + PeerDiscovery<TcpPeer> + Send + Sync + 'static,
+{
+    Interval::new(Instant::now(), node.config.discovery_interval)
+        .map_err(|e| error!("Discovery timer failed: {}", e))
+        .for_each(move |_| {
+            let node = node.clone();
+            let address = address.clone();
+            future::poll_fn(move || {
+                blocking(|| node.discover(&mut rand::thread_rng(), address.clone()))
+            })
+            .then(|result| {
+                match result {
+                    Ok(Ok(0)) => {}
+                    Ok(Ok(n)) => info!("Discovered {} new peers", n),
+                    Ok(Err(e)) => warn!("Discovery failed: {}", e),
+                    Err(e) => warn!("Discovery couldn't run: {}", e),
+                }
+                Ok(())
+            })
+        })
+}
+
+// Stops the node when dropped too
+pub struct TcpAppHandle {
+    pub address: SocketAddr,
+    stop: oneshot::Sender<()>,
+}
+
+impl TcpAppHandle {
+    // Already running syncs and answers finish, but nothing new is started
+    pub fn stop(self) {
+        let _ = self.stop.send(());
+    }
+}
+
+pub struct TcpApp<N: Node>(Arc<TcpNode<N>>);
 
-impl TcpApp {
-    pub fn new(n: Arc<TcpNode<Swirlds<TcpPeer, BTreeHashgraph>>>) -> TcpApp {
+impl<N> TcpApp<N>
+where
+    N: Node + PeerDiscovery<TcpPeer> + Send + Sync + 'static,
+    N::D: Serialize,
+    N::K: DeserializeOwned,
+{
+    pub fn new(n: Arc<TcpNode<N>>) -> TcpApp<N> {
         TcpApp(n)
     }
 
-    pub fn run(self) -> Result<(JoinHandle<()>, JoinHandle<()>), Error> {
-        let answer_thread_node = self.0.clone();
-        let sync_thread_node = self.0.clone();
-        let answer_handle = spawn(move || {
-            let listener = TcpListener::bind(&answer_thread_node.address).unwrap();
-            for stream_result in listener.incoming() {
-                let mut stream = stream_result.unwrap();
-                let message = answer_thread_node.node.respond_message(None).unwrap();
-                let payload = serialize(&message).unwrap();
-                stream.write(&payload).unwrap();
-            }
-            ()
-        });
-        let sync_handle = spawn(move || {
-            let mut rng = rand::thread_rng();
-            let mut counter = 0usize;
-            let node_id = sync_thread_node.node.get_id();
-            loop {
-                if counter % 100 == 0 {
-                    let head = sync_thread_node.node.get_head().unwrap();
-                    let (n_rounds, n_events) = sync_thread_node.node.get_stats().unwrap();
-                    info!(
-                        "Node {:?}: Head {:?} Rounds {:?} Pending events {:?}",
-                        node_id, head, n_rounds, n_events
-                    );
+    // Binds the node address and runs the answer, discovery and gossip loops on the given runtime
+    pub fn spawn(self, executor: &TaskExecutor) -> Result<TcpAppHandle, Error> {
+        let listener = std::net::TcpListener::bind(&self.0.address)?;
+        let listener = tokio::net::TcpListener::from_std(listener, &Handle::default())?;
+        let address = listener.local_addr()?;
+        let (stop, stopped) = oneshot::channel::<()>();
+        let stopped = stopped.shared();
+
+        let node = self.0.clone();
+        let connection_executor = executor.clone();
+        let connection_stopped = stopped.clone();
+        let answers = listener
+            .incoming()
+            .then(Ok::<_, ()>)
+            .for_each(move |stream| {
+                match stream {
+                    Ok(stream) => {
+                        let peer_address = stream.peer_addr();
+                        let connection = answer(node.clone(), stream)
+                            .map_err(move |e| warn!("Error answering {:?}: {}", peer_address, e))
+                            .select2(connection_stopped.clone())
+                            .then(|_| Ok(()));
+                        connection_executor.spawn(connection);
+                    }
+                    Err(e) => warn!("Error accepting a connection: {}", e),
                 }
-                match sync_thread_node.node.run(&mut rng) {
-                    Ok(_) => {}
-                    Err(e) => panic!("Error! {}", e),
-                };
-                counter += 1;
-                sleep(Duration::from_millis(100));
-            }
-        });
-        Ok((answer_handle, sync_handle))
+                Ok(())
+            });
+        executor.spawn(answers.select2(stopped.clone()).then(|_| Ok(())));
+        let public_address = match &self.0.config.public_address {
+            Some(public_address) => public_address.clone(),
+            None => address.to_string(),
+        };
+        executor.spawn(
+            discovery(self.0.clone(), public_address)
+                .select2(stopped.clone())
+                .then(|_| Ok(())),
+        );
+        executor.spawn(gossip(self.0).select2(stopped).then(|_| Ok(())));
+        Ok(TcpAppHandle { address, stop })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::gossip_config::GossipConfig;
+    use super::secure_channel::SecureChannel;
+    use super::{TcpApp, TcpAppHandle, TcpNode, TcpPeer, PROTOCOL_VERSION};
+    use crate::hashgraph::{BTreeHashgraph, Hashgraph, KnownHeights};
+    use crate::lachesis::{opera::Opera, Lachesis};
+    use crate::node::Node;
+    use crate::peer::{Peer, PeerDiscovery};
+    use crate::server::ws_message::{InternodeError, InternodeMessage};
+    use crate::swirlds::Swirlds;
+    use byteorder::{LittleEndian, WriteBytesExt};
+    use futures::Future;
+    use ring::rand::SystemRandom;
+    use serde::de::DeserializeOwned;
+    use serde::Serialize;
+    use std::io::{ErrorKind, Read};
+    use std::net::TcpStream;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::runtime::Runtime;
+
+    // Long enough for the nodes not to gossip during the tests
+    fn create_config() -> GossipConfig {
+        GossipConfig {
+            gossip_interval: Duration::from_secs(3600),
+            ..GossipConfig::default()
+        }
+    }
+
+    fn create_node() -> TcpNode<Swirlds<TcpPeer, BTreeHashgraph>> {
+        let mut rng = SystemRandom::new();
+        TcpNode::new(&mut rng, String::from("127.0.0.1:0"), create_config()).unwrap()
+    }
+
+    fn create_lachesis_node() -> TcpNode<Lachesis<TcpPeer>> {
+        let mut rng = SystemRandom::new();
+        TcpNode::new_lachesis(&mut rng, String::from("127.0.0.1:0"), create_config()).unwrap()
+    }
+
+    fn serve<N>(node: Arc<TcpNode<N>>) -> (Runtime, TcpAppHandle)
+    where
+        N: Node + PeerDiscovery<TcpPeer> + Send + Sync + 'static,
+        N::D: Serialize,
+        N::K: DeserializeOwned,
+    {
+        let runtime = Runtime::new().unwrap();
+        let handle = TcpApp::new(node).spawn(&runtime.executor()).unwrap();
+        (runtime, handle)
+    }
+
+    #[test]
+    fn it_should_sync_several_times_on_one_connection() {
+        let node = Arc::new(create_node());
+        let head = node.node.get_head().unwrap();
+        let (_runtime, handle) = serve(node.clone());
+        let requester = create_node();
+        let peer = requester.peer(handle.address.to_string(), node.node.get_id());
+        let requester_id = requester.node.get_id();
+        let (remote_head, hashgraph): (_, BTreeHashgraph) =
+            peer.get_sync(requester_id.clone(), None).unwrap();
+        assert_eq!(remote_head, head);
+        assert!(hashgraph.contains_key(&head));
+        let known = hashgraph.known_heights();
+        let (remote_head, hashgraph): (_, BTreeHashgraph) =
+            peer.get_sync(requester_id, Some(&known)).unwrap();
+        assert_eq!(remote_head, head);
+        assert!(hashgraph.get_events().is_empty());
+    }
+
+    #[test]
+    fn it_should_only_send_lachesis_events_the_requester_doesnt_know() {
+        let node = Arc::new(create_lachesis_node());
+        let (head, _) = node.node.respond_message(None).unwrap();
+        let (_runtime, handle) = serve(node.clone());
+        let requester = create_lachesis_node();
+        let peer = requester.peer(handle.address.to_string(), node.id());
+        let (remote_head, opera): (_, Opera) = peer.get_sync(requester.id(), None).unwrap();
+        assert_eq!(remote_head, head);
+        assert!(opera.contains_key(&head));
+        let known: KnownHeights = vec![(node.id(), 0)].into_iter().collect();
+        let (remote_head, opera): (_, Opera) = peer.get_sync(requester.id(), Some(&known)).unwrap();
+        assert_eq!(remote_head, head);
+        assert!(!opera.contains_key(&head));
+    }
+
+    #[test]
+    fn it_should_reject_a_peer_with_an_unexpected_key() {
+        let node = Arc::new(create_node());
+        let (_runtime, handle) = serve(node);
+        let requester = create_node();
+        let peer = requester.peer(handle.address.to_string(), vec![1; 32]);
+        let sync: Result<(_, BTreeHashgraph), _> = peer.get_sync(requester.node.get_id(), None);
+        let error = sync.err().unwrap().to_string();
+        assert!(error.contains("unexpected key"), "{}", error);
+    }
+
+    #[test]
+    fn it_should_answer_with_typed_errors() {
+        let node = Arc::new(create_node());
+        let node_id = node.node.get_id();
+        let (_runtime, handle) = serve(node);
+        let requester = create_node();
+        let stream = TcpStream::connect(handle.address).unwrap();
+        let mut channel = SecureChannel::connect(stream, &requester.identity, &node_id).unwrap();
+        let request = InternodeMessage::SyncRequest {
+            version: 0,
+            requester: requester.node.get_id(),
+            known: None,
+        };
+        channel.send(&request).unwrap();
+        match channel.receive().unwrap() {
+            Some(InternodeMessage::Error(InternodeError::UnsupportedVersion(_))) => {}
+            m => panic!("Unexpected response {:?}", m),
+        }
+        let request = InternodeMessage::SyncRequest {
+            version: PROTOCOL_VERSION,
+            requester: vec![1],
+            known: None,
+        };
+        channel.send(&request).unwrap();
+        match channel.receive().unwrap() {
+            Some(InternodeMessage::Error(InternodeError::Unauthorized)) => {}
+            m => panic!("Unexpected response {:?}", m),
+        }
+        let request = InternodeMessage::SyncRequest {
+            version: PROTOCOL_VERSION,
+            requester: requester.node.get_id(),
+            known: Some(vec![42]),
+        };
+        channel.send(&request).unwrap();
+        match channel.receive().unwrap() {
+            Some(InternodeMessage::Error(InternodeError::BadRequest)) => {}
+            m => panic!("Unexpected response {:?}", m),
+        }
+    }
+
+    #[test]
+    fn it_should_close_connections_sending_large_handshake_frames() {
+        let node = Arc::new(create_node());
+        let (_runtime, handle) = serve(node);
+        let mut stream = TcpStream::connect(handle.address).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        stream.write_u32::<LittleEndian>(1024 * 1024).unwrap();
+        match stream.read(&mut [0; 1]) {
+            Ok(0) => {}
+            Err(ref e) if e.kind() == ErrorKind::ConnectionReset => {}
+            r => panic!("The connection should be closed, got {:?}", r),
+        }
+    }
+
+    #[test]
+    fn it_should_close_connections_sending_large_requests() {
+        let node = Arc::new(create_node());
+        let node_id = node.node.get_id();
+        let (_runtime, handle) = serve(node);
+        let requester = create_node();
+        let stream = TcpStream::connect(handle.address).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let mut raw = stream.try_clone().unwrap();
+        let mut channel = SecureChannel::connect(stream, &requester.identity, &node_id).unwrap();
+        raw.write_u32::<LittleEndian>(64 * 1024 * 1024).unwrap();
+        match channel.receive() {
+            Ok(None) => {}
+            Err(ref e) if e.to_string().contains("reset") => {}
+            r => panic!("The connection should be closed, got {:?}", r),
+        }
+    }
+
+    #[test]
+    fn it_should_close_idle_connections() {
+        let config = GossipConfig {
+            idle_timeout: Duration::from_millis(100),
+            ..create_config()
+        };
+        let mut rng = SystemRandom::new();
+        let node = Arc::new(TcpNode::new(&mut rng, String::from("127.0.0.1:0"), config).unwrap());
+        let node_id = node.node.get_id();
+        let (_runtime, handle) = serve(node);
+        let requester = create_node();
+        let stream = TcpStream::connect(handle.address).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let mut channel = SecureChannel::connect(stream, &requester.identity, &node_id).unwrap();
+        match channel.receive() {
+            Ok(None) => {}
+            Err(ref e) if e.to_string().contains("reset") => {}
+            r => panic!("The connection should be closed, got {:?}", r),
+        }
+    }
+
+    #[test]
+    fn it_should_discover_peers_through_a_seed() {
+        let seed = Arc::new(create_node());
+        let seed_id = seed.node.get_id();
+        let (_runtime, handle) = serve(seed);
+        let first = create_node();
+        let second = create_node();
+        for node in [&first, &second].iter() {
+            let peer = node.peer(handle.address.to_string(), seed_id.clone());
+            node.node.add_node(Arc::new(peer)).unwrap();
+        }
+        let mut rng = rand::thread_rng();
+        let first_address = String::from("127.0.0.1:9001");
+        assert_eq!(first.discover(&mut rng, first_address.clone()).unwrap(), 0);
+        assert_eq!(
+            second
+                .discover(&mut rng, String::from("127.0.0.1:9002"))
+                .unwrap(),
+            1
+        );
+        let announcements = second.node.peer_announcements().unwrap();
+        assert_eq!(announcements.len(), 1);
+        assert_eq!(announcements[0].id, first.node.get_id());
+        assert_eq!(announcements[0].address, first_address);
+    }
+
+    #[test]
+    fn it_should_stop_answering_once_stopped() {
+        let node = Arc::new(create_node());
+        let (runtime, handle) = serve(node);
+        let address = handle.address;
+        handle.stop();
+        runtime.shutdown_on_idle().wait().unwrap();
+        assert!(TcpStream::connect(address).is_err());
     }
 }
@@ -0,0 +1,41 @@
+use crate::peer::peer_score::ScoringConfig;
+use std::time::Duration;
+
+const DEFAULT_DISCOVERY_INTERVAL: u64 = 30_000;
+const DEFAULT_GOSSIP_INTERVAL: u64 = 100;
+const DEFAULT_IDLE_TIMEOUT: u64 = 60_000;
+const DEFAULT_MAX_CONCURRENT_SYNCS: usize = 4;
+const DEFAULT_REQUEST_TIMEOUT: u64 = 10_000;
+
+#[derive(Clone, Debug)]
+pub struct GossipConfig {
+    // How often the node asks one of its peers for the peers it knows
+    pub discovery_interval: Duration,
+    // How often the node starts a sync with one of its peers
+    pub gossip_interval: Duration,
+    // Connections the requester sends nothing through for this long get closed
+    pub idle_timeout: Duration,
+    // Syncs started while this many are running wait for one of them to finish. Only their
+    // fetches overlap, the node merges and orders one sync at a time.
+    pub max_concurrent_syncs: usize,
+    // Applies to connecting, handshaking and every request and response
+    pub request_timeout: Duration,
+    // The address announced to other peers, if it isn't the one the node binds
+    pub public_address: Option<String>,
+    // How the node backs off and bans the peers it gossips with
+    pub scoring: ScoringConfig,
+}
+
+impl Default for GossipConfig {
+    fn default() -> GossipConfig {
+        GossipConfig {
+            discovery_interval: Duration::from_millis(DEFAULT_DISCOVERY_INTERVAL),
+            gossip_interval: Duration::from_millis(DEFAULT_GOSSIP_INTERVAL),
+            idle_timeout: Duration::from_millis(DEFAULT_IDLE_TIMEOUT),
+            max_concurrent_syncs: DEFAULT_MAX_CONCURRENT_SYNCS,
+            request_timeout: Duration::from_millis(DEFAULT_REQUEST_TIMEOUT),
+            public_address: None,
+            scoring: ScoringConfig::default(),
+        }
+    }
+}
@@ -0,0 +1,325 @@
+use super::{read_frame, read_frame_async, write_frame, write_frame_async, MAX_FRAME_SIZE};
+use crate::errors::{ProtocolError, ProtocolErrorType};
+use crate::peer::PeerId;
+use crate::server::ws_message::{InternodeMessage, PROTOCOL_VERSION};
+use bincode::{deserialize, serialize};
+use byteorder::{ByteOrder, LittleEndian};
+use failure::Error;
+use futures::Future;
+use ring::aead::{self, OpeningKey, SealingKey, CHACHA20_POLY1305};
+use ring::agreement::{self, EphemeralPrivateKey, X25519};
+use ring::digest::{digest, Digest, SHA256};
+use ring::rand::SystemRandom;
+use ring::signature::{self, Ed25519KeyPair};
+use ring::{hkdf, hmac};
+use std::net::TcpStream;
+use std::sync::Arc;
+
+// Both nodes send a hello with an ephemeral X25519 key and their Ed25519 key. Then each one
+// signs the transcript of both hellos, so the ephemeral keys are bound to the nodes holding
+// the Ed25519 keys. The X25519 shared secret is expanded into a ChaCha20-Poly1305 key per
+// direction, and every frame after that is sealed with a counter as nonce.
+const HANDSHAKE_CONTEXT: &[u8] = b"lachesis-rs handshake";
+const INITIATOR_KEY_INFO: &[u8] = b"initiator to responder";
+const RESPONDER_KEY_INFO: &[u8] = b"responder to initiator";
+const KEY_LEN: usize = 32;
+// Hellos and signatures take less than a hundred bytes, and anyone can send them
+const MAX_HANDSHAKE_FRAME_SIZE: usize = 256;
+const NONCE_LEN: usize = 12;
+
+#[derive(Deserialize, Serialize)]
+struct Hello {
+    ephemeral_key: Vec<u8>,
+    id: PeerId,
+    version: u32,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Role {
+    Initiator,
+    Responder,
+}
+
+impl Role {
+    // Signatures cover the role too, so a node can't reflect the signature it got back
+    fn tag(self) -> u8 {
+        match self {
+            Role::Initiator => 0,
+            Role::Responder => 1,
+        }
+    }
+
+    fn remote(self) -> Role {
+        match self {
+            Role::Initiator => Role::Responder,
+            Role::Responder => Role::Initiator,
+        }
+    }
+}
+
+// The handshake steps, apart from the I/O, so blocking and async connections share them
+struct Handshake {
+    ephemeral_key: EphemeralPrivateKey,
+    hello: Vec<u8>,
+    role: Role,
+}
+
+impl Handshake {
+    fn new(identity: &Ed25519KeyPair, role: Role) -> Result<Handshake, Error> {
+        let rng = SystemRandom::new();
+        let ephemeral_key = EphemeralPrivateKey::generate(&X25519, &rng)?;
+        let mut ephemeral_public_key = vec![0; ephemeral_key.public_key_len()];
+        ephemeral_key.compute_public_key(&mut ephemeral_public_key)?;
+        let hello = serialize(&Hello {
+            ephemeral_key: ephemeral_public_key,
+            id: identity.public_key_bytes().to_vec(),
+            version: PROTOCOL_VERSION,
+        })?;
+        Ok(Handshake {
+            ephemeral_key,
+            hello,
+            role,
+        })
+    }
+
+    fn sign(
+        self,
+        identity: &Ed25519KeyPair,
+        remote_hello_bytes: &[u8],
+    ) -> Result<SignedHandshake, Error> {
+        let remote_hello: Hello = deserialize(remote_hello_bytes)?;
+        if remote_hello.version != PROTOCOL_VERSION {
+            return Err(Error::from(ProtocolError::new(
+                ProtocolErrorType::UnsupportedVersion(remote_hello.version),
+            )));
+        }
+        let mut transcript = HANDSHAKE_CONTEXT.to_vec();
+        match self.role {
+            Role::Initiator => {
+                transcript.extend_from_slice(&self.hello);
+                transcript.extend_from_slice(remote_hello_bytes);
+            }
+            Role::Responder => {
+                transcript.extend_from_slice(remote_hello_bytes);
+                transcript.extend_from_slice(&self.hello);
+            }
+        }
+        let transcript_hash = digest(&SHA256, &transcript);
+        let to_sign = [transcript_hash.as_ref(), &[self.role.tag()]].concat();
+        Ok(SignedHandshake {
+            ephemeral_key: self.ephemeral_key,
+            remote_hello,
+            role: self.role,
+            signature: identity.sign(&to_sign).as_ref().to_vec(),
+            transcript_hash,
+        })
+    }
+}
+
+struct SignedHandshake {
+    ephemeral_key: EphemeralPrivateKey,
+    remote_hello: Hello,
+    role: Role,
+    signature: Vec<u8>,
+    transcript_hash: Digest,
+}
+
+impl SignedHandshake {
+    fn finish(self, remote_signature: &[u8]) -> Result<Cipher, Error> {
+        let to_verify = [self.transcript_hash.as_ref(), &[self.role.remote().tag()]].concat();
+        signature::verify(
+            &signature::ED25519,
+            untrusted::Input::from(&self.remote_hello.id),
+            untrusted::Input::from(&to_verify),
+            untrusted::Input::from(remote_signature),
+        )
+        .map_err(|_| {
+            Error::from(ProtocolError::new(
+                ProtocolErrorType::InvalidHandshakeSignature,
+            ))
+        })?;
+
+        let salt = hmac::SigningKey::new(&SHA256, self.transcript_hash.as_ref());
+        let role = self.role;
+        let (sealing_key, opening_key) = agreement::agree_ephemeral(
+            self.ephemeral_key,
+            &X25519,
+            untrusted::Input::from(&self.remote_hello.ephemeral_key),
+            Error::from(ProtocolError::new(ProtocolErrorType::HandshakeFailed)),
+            |shared_secret| {
+                let prk = hkdf::extract(&salt, shared_secret);
+                let mut initiator_key = [0; KEY_LEN];
+                let mut responder_key = [0; KEY_LEN];
+                hkdf::expand(&prk, INITIATOR_KEY_INFO, &mut initiator_key);
+                hkdf::expand(&prk, RESPONDER_KEY_INFO, &mut responder_key);
+                let (sealing, opening) = match role {
+                    Role::Initiator => (initiator_key, responder_key),
+                    Role::Responder => (responder_key, initiator_key),
+                };
+                Ok((
+                    SealingKey::new(&CHACHA20_POLY1305, &sealing)?,
+                    OpeningKey::new(&CHACHA20_POLY1305, &opening)?,
+                ))
+            },
+        )?;
+
+        Ok(Cipher {
+            opening_key,
+            peer_id: self.remote_hello.id,
+            receive_counter: 0,
+            sealing_key,
+            send_counter: 0,
+        })
+    }
+}
+
+pub(crate) struct Cipher {
+    opening_key: OpeningKey,
+    peer_id: PeerId,
+    receive_counter: u64,
+    sealing_key: SealingKey,
+    send_counter: u64,
+}
+
+impl Cipher {
+    pub(crate) fn peer_id(&self) -> &PeerId {
+        &self.peer_id
+    }
+
+    pub(crate) fn seal(&mut self, message: &InternodeMessage) -> Result<Vec<u8>, Error> {
+        let mut in_out = serialize(message)?;
+        in_out.extend_from_slice(&[0; aead::MAX_TAG_LEN]);
+        let nonce = Cipher::nonce(self.send_counter);
+        let len = aead::seal_in_place(
+            &self.sealing_key,
+            &nonce,
+            &[],
+            &mut in_out,
+            aead::MAX_TAG_LEN,
+        )?;
+        self.send_counter += 1;
+        in_out.truncate(len);
+        Ok(in_out)
+    }
+
+    pub(crate) fn open(&mut self, mut frame: Vec<u8>) -> Result<InternodeMessage, Error> {
+        let nonce = Cipher::nonce(self.receive_counter);
+        let plaintext = aead::open_in_place(&self.opening_key, &nonce, &[], 0, &mut frame)
+            .map_err(|_| Error::from(ProtocolError::new(ProtocolErrorType::DecryptionFailed)))?;
+        self.receive_counter += 1;
+        Ok(deserialize(plaintext)?)
+    }
+
+    #[inline]
+    fn nonce(counter: u64) -> [u8; NONCE_LEN] {
+        let mut nonce = [0; NONCE_LEN];
+        LittleEndian::write_u64(&mut nonce[4..], counter);
+        nonce
+    }
+}
+
+#[inline]
+fn connection_closed() -> Error {
+    Error::from(ProtocolError::new(ProtocolErrorType::ConnectionClosed))
+}
+
+// Answers the handshake of a node connecting to this one, without blocking the runtime
+pub(crate) fn accept_async(
+    stream: tokio::net::TcpStream,
+    identity: Arc<Ed25519KeyPair>,
+) -> impl Future<Item = (tokio::net::TcpStream, Cipher), Error = Error> {
+    futures::future::result(Handshake::new(&identity, Role::Responder))
+        .and_then(|handshake| {
+            read_frame_async(stream, MAX_HANDSHAKE_FRAME_SIZE).and_then(|frame| {
+                let (stream, remote_hello) = frame.ok_or_else(connection_closed)?;
+                Ok((stream, handshake, remote_hello))
+            })
+        })
+        .and_then(move |(stream, handshake, remote_hello)| {
+            let hello = handshake.hello.clone();
+            let signed = handshake.sign(&identity, &remote_hello);
+            write_frame_async(stream, hello).and_then(|stream| Ok((stream, signed?)))
+        })
+        .and_then(|(stream, signed)| {
+            read_frame_async(stream, MAX_HANDSHAKE_FRAME_SIZE).and_then(|frame| {
+                let (stream, remote_signature) = frame.ok_or_else(connection_closed)?;
+                Ok((stream, signed, remote_signature))
+            })
+        })
+        .and_then(|(stream, signed, remote_signature)| {
+            let signature = signed.signature.clone();
+            write_frame_async(stream, signature).and_then(move |stream| {
+                let cipher = signed.finish(&remote_signature)?;
+                Ok((stream, cipher))
+            })
+        })
+}
+
+pub struct SecureChannel {
+    cipher: Cipher,
+    stream: TcpStream,
+}
+
+impl SecureChannel {
+    // Fails unless the other node proves it holds the key of `expected`
+    pub fn connect(
+        stream: TcpStream,
+        identity: &Ed25519KeyPair,
+        expected: &PeerId,
+    ) -> Result<SecureChannel, Error> {
+        let channel = SecureChannel::handshake(stream, identity, Role::Initiator)?;
+        if channel.peer_id() != expected {
+            return Err(Error::from(ProtocolError::new(
+                ProtocolErrorType::PeerKeyMismatch(channel.peer_id().clone()),
+            )));
+        }
+        Ok(channel)
+    }
+
+    pub fn accept(stream: TcpStream, identity: &Ed25519KeyPair) -> Result<SecureChannel, Error> {
+        SecureChannel::handshake(stream, identity, Role::Responder)
+    }
+
+    pub fn peer_id(&self) -> &PeerId {
+        self.cipher.peer_id()
+    }
+
+    pub fn send(&mut self, message: &InternodeMessage) -> Result<(), Error> {
+        let frame = self.cipher.seal(message)?;
+        write_frame(&mut self.stream, &frame)
+    }
+
+    // Returns None if the other side closed the connection between messages
+    pub fn receive(&mut self) -> Result<Option<InternodeMessage>, Error> {
+        match read_frame(&mut self.stream, MAX_FRAME_SIZE)? {
+            Some(frame) => Ok(Some(self.cipher.open(frame)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn handshake(
+        mut stream: TcpStream,
+        identity: &Ed25519KeyPair,
+        role: Role,
+    ) -> Result<SecureChannel, Error> {
+        let handshake = Handshake::new(identity, role)?;
+        let remote_hello = SecureChannel::exchange(&mut stream, role, &handshake.hello)?;
+        let signed = handshake.sign(identity, &remote_hello)?;
+        let remote_signature = SecureChannel::exchange(&mut stream, role, &signed.signature)?;
+        let cipher = signed.finish(&remote_signature)?;
+        Ok(SecureChannel { cipher, stream })
+    }
+
+    // The initiator always speaks first
+    fn exchange(stream: &mut TcpStream, role: Role, frame: &[u8]) -> Result<Vec<u8>, Error> {
+        if role == Role::Initiator {
+            write_frame(stream, frame)?;
+        }
+        let remote_frame =
+            read_frame(stream, MAX_HANDSHAKE_FRAME_SIZE)?.ok_or_else(connection_closed)?;
+        if role == Role::Responder {
+            write_frame(stream, frame)?;
+        }
+        Ok(remote_frame)
+    }
+}
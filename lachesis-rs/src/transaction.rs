@@ -0,0 +1,144 @@
+use crate::event::event_hash::EventHash;
+use ring::digest::{digest, SHA256};
+use std::collections::HashMap;
+
+#[derive(Clone, Debug, Deserialize, Eq, Hash, Ord, PartialEq, PartialOrd, Serialize)]
+pub struct TransactionId(pub [u8; 32]);
+
+impl TransactionId {
+    pub fn new(payload: &[u8]) -> TransactionId {
+        let mut a: [u8; 32] = [0; 32];
+        a.copy_from_slice(digest(&SHA256, payload).as_ref());
+        TransactionId(a)
+    }
+}
+
+impl AsRef<[u8]> for TransactionId {
+    fn as_ref(&self) -> &[u8] {
+        self.0.as_ref()
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub enum TransactionStatus {
+    Pending,
+    Included(EventHash),
+    // Consensus index, round received and consensus timestamp of the event carrying it
+    Committed(usize, usize, u64),
+}
+
+// Statuses only move forward, so a payload submitted again keeps its furthest status.
+// Only payloads submitted to this node are tracked, not everything merged from peers.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub(crate) struct TransactionStatuses(HashMap<TransactionId, TransactionStatus>);
+
+impl TransactionStatuses {
+    pub(crate) fn add_pending(&mut self, id: TransactionId) {
+        self.0.entry(id).or_insert(TransactionStatus::Pending);
+    }
+
+    pub(crate) fn add_included(&mut self, payload: &[Vec<u8>], event: &EventHash) {
+        for transaction in payload {
+            if let Some(status) = self.0.get_mut(&TransactionId::new(transaction)) {
+                if *status == TransactionStatus::Pending {
+                    *status = TransactionStatus::Included(event.clone());
+                }
+            }
+        }
+    }
+
+    pub(crate) fn add_committed(
+        &mut self,
+        payload: &[Vec<u8>],
+        consensus_index: usize,
+        round_received: usize,
+        consensus_timestamp: u64,
+    ) {
+        for transaction in payload {
+            if let Some(status) = self.0.get_mut(&TransactionId::new(transaction)) {
+                match status {
+                    TransactionStatus::Committed(_, _, _) => {}
+                    _ => {
+                        *status = TransactionStatus::Committed(
+                            consensus_index,
+                            round_received,
+                            consensus_timestamp,
+                        )
+                    }
+                }
+            }
+        }
+    }
+
+    // Forgets the transactions committed in rounds below the cutoff
+    pub(crate) fn prune(&mut self, cutoff: usize) {
+        self.0.retain(|_, status| match status {
+            TransactionStatus::Committed(_, round_received, _) => *round_received >= cutoff,
+            _ => true,
+        });
+    }
+
+    pub(crate) fn get(&self, id: &TransactionId) -> Option<TransactionStatus> {
+        self.0.get(id).cloned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{TransactionId, TransactionStatus, TransactionStatuses};
+    use crate::event::event_hash::EventHash;
+
+    #[test]
+    fn transaction_statuses_should_only_move_forward() {
+        let mut statuses = TransactionStatuses::default();
+        let transaction = b"fish".to_vec();
+        let id = TransactionId::new(&transaction);
+        assert_eq!(statuses.get(&id), None);
+        statuses.add_pending(id.clone());
+        assert_eq!(statuses.get(&id), Some(TransactionStatus::Pending));
+        let event = EventHash([1; 32]);
+        statuses.add_included(&[transaction.clone()], &event);
+        statuses.add_included(&[transaction.clone()], &EventHash([2; 32]));
+        statuses.add_pending(id.clone());
+        assert_eq!(statuses.get(&id), Some(TransactionStatus::Included(event)));
+        statuses.add_committed(&[transaction.clone()], 4, 2, 42);
+        statuses.add_committed(&[transaction.clone()], 5, 3, 43);
+        assert_eq!(
+            statuses.get(&id),
+            Some(TransactionStatus::Committed(4, 2, 42))
+        );
+    }
+
+    #[test]
+    fn transaction_statuses_should_only_track_submitted_transactions() {
+        let mut statuses = TransactionStatuses::default();
+        let transaction = b"fish".to_vec();
+        let id = TransactionId::new(&transaction);
+        statuses.add_included(&[transaction.clone()], &EventHash([1; 32]));
+        statuses.add_committed(&[transaction.clone()], 4, 2, 42);
+        assert_eq!(statuses.get(&id), None);
+    }
+
+    #[test]
+    fn transaction_statuses_should_forget_transactions_committed_below_the_cutoff() {
+        let mut statuses = TransactionStatuses::default();
+        let old = b"fish".to_vec();
+        let recent = b"42".to_vec();
+        let pending = b"ford prefect".to_vec();
+        for transaction in vec![&old, &recent, &pending] {
+            statuses.add_pending(TransactionId::new(transaction));
+        }
+        statuses.add_committed(&[old.clone()], 0, 1, 42);
+        statuses.add_committed(&[recent.clone()], 1, 3, 43);
+        statuses.prune(2);
+        assert_eq!(statuses.get(&TransactionId::new(&old)), None);
+        assert_eq!(
+            statuses.get(&TransactionId::new(&recent)),
+            Some(TransactionStatus::Committed(1, 3, 43))
+        );
+        assert_eq!(
+            statuses.get(&TransactionId::new(&pending)),
+            Some(TransactionStatus::Pending)
+        );
+    }
+}